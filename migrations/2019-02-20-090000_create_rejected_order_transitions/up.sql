@@ -0,0 +1,12 @@
+CREATE TABLE rejected_order_transitions (
+    id             UUID      PRIMARY KEY DEFAULT uuid_generate_v4(),
+    parent         UUID      NOT NULL REFERENCES orders (id) ON DELETE CASCADE,
+    committer      INTEGER   NOT NULL,
+    committer_role VARCHAR   NOT NULL,
+    from_state     VARCHAR   NOT NULL,
+    to_state       VARCHAR   NOT NULL,
+    attempted_at   TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    comment        VARCHAR
+);
+
+CREATE INDEX rejected_order_transitions_parent_idx ON rejected_order_transitions (parent);
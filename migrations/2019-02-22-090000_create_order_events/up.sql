@@ -0,0 +1,15 @@
+-- Orders may be deleted, so events do not reference them
+CREATE TABLE order_events (
+    id              BIGSERIAL PRIMARY KEY,
+    order_id        UUID      NOT NULL,
+    event_type      VARCHAR   NOT NULL,
+    payload         JSONB     NOT NULL,
+    created_at      TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    status          VARCHAR   NOT NULL DEFAULT 'pending',
+    attempts        INTEGER   NOT NULL DEFAULT 0,
+    next_attempt_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    last_error      VARCHAR
+);
+
+CREATE INDEX order_events_pending_idx ON order_events (next_attempt_at) WHERE status = 'pending';
+CREATE INDEX order_events_order_id_idx ON order_events (order_id);
@@ -0,0 +1,12 @@
+CREATE TABLE saga_notifications (
+    id              BIGSERIAL PRIMARY KEY,
+    order_id        UUID      NOT NULL REFERENCES orders (id) ON DELETE CASCADE,
+    status          VARCHAR   NOT NULL DEFAULT 'pending',
+    attempts        INTEGER   NOT NULL DEFAULT 0,
+    last_error      VARCHAR,
+    created_at      TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    acknowledged_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE UNIQUE INDEX saga_notifications_order_id_idx ON saga_notifications (order_id);
+CREATE INDEX saga_notifications_pending_idx ON saga_notifications (id) WHERE status = 'pending';
@@ -0,0 +1,12 @@
+CREATE TABLE job_runs (
+    id              BIGSERIAL PRIMARY KEY,
+    job_name        VARCHAR   NOT NULL,
+    started_at      TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    finished_at     TIMESTAMP WITH TIME ZONE,
+    status          VARCHAR   NOT NULL DEFAULT 'running',
+    items_processed INTEGER   NOT NULL DEFAULT 0,
+    items_changed   INTEGER   NOT NULL DEFAULT 0,
+    error           VARCHAR
+);
+
+CREATE INDEX job_runs_job_name_idx ON job_runs (job_name, id);
@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS orders_store_created_at_id_idx;
+DROP INDEX IF EXISTS orders_customer_created_at_id_idx;
+DROP INDEX IF EXISTS orders_created_at_id_idx;
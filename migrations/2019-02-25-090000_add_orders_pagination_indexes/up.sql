@@ -0,0 +1,3 @@
+CREATE INDEX orders_created_at_id_idx ON orders (created_at DESC, id DESC);
+CREATE INDEX orders_customer_created_at_id_idx ON orders (customer, created_at DESC, id DESC);
+CREATE INDEX orders_store_created_at_id_idx ON orders (store, created_at DESC, id DESC);
@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS orders_store_total_amount_id_idx;
+DROP INDEX IF EXISTS orders_store_updated_at_id_idx;
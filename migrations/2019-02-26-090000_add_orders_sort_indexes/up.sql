@@ -0,0 +1,2 @@
+CREATE INDEX orders_store_updated_at_id_idx ON orders (store, updated_at DESC, id DESC);
+CREATE INDEX orders_store_total_amount_id_idx ON orders (store, total_amount DESC, id DESC);
@@ -0,0 +1,5 @@
+DROP INDEX IF EXISTS orders_search_text_trgm_idx;
+DROP INDEX IF EXISTS orders_search_text_fts_idx;
+DROP TRIGGER IF EXISTS set_search_text ON orders;
+DROP FUNCTION IF EXISTS orders_set_search_text();
+ALTER TABLE orders DROP COLUMN IF EXISTS search_text;
@@ -0,0 +1,36 @@
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+
+ALTER TABLE orders ADD COLUMN search_text TEXT NOT NULL DEFAULT '';
+
+CREATE OR REPLACE FUNCTION orders_set_search_text() RETURNS TRIGGER AS $$
+BEGIN
+    NEW.search_text := lower(concat_ws(' ',
+        NEW.receiver_name,
+        NEW.receiver_email,
+        NEW.receiver_phone,
+        NEW.slug::TEXT,
+        NEW.track_id,
+        NEW.address,
+        NEW.street_number,
+        NEW.route,
+        NEW.locality,
+        NEW.political,
+        NEW.postal_code,
+        NEW.administrative_area_level_2,
+        NEW.administrative_area_level_1,
+        NEW.country
+    ));
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER set_search_text BEFORE INSERT OR UPDATE ON orders
+    FOR EACH ROW EXECUTE PROCEDURE orders_set_search_text();
+
+-- Backfill without touching updated_at of existing orders
+ALTER TABLE orders DISABLE TRIGGER set_updated_at;
+UPDATE orders SET search_text = '';
+ALTER TABLE orders ENABLE TRIGGER set_updated_at;
+
+CREATE INDEX orders_search_text_fts_idx ON orders USING GIN (to_tsvector('simple', search_text));
+CREATE INDEX orders_search_text_trgm_idx ON orders USING GIN (search_text gin_trgm_ops);
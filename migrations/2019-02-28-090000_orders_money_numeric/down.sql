@@ -0,0 +1,6 @@
+ALTER TABLE orders
+    ALTER COLUMN price TYPE DOUBLE PRECISION,
+    ALTER COLUMN product_discount TYPE DOUBLE PRECISION,
+    ALTER COLUMN coupon_discount TYPE DOUBLE PRECISION,
+    ALTER COLUMN total_amount TYPE DOUBLE PRECISION,
+    ALTER COLUMN delivery_price TYPE DOUBLE PRECISION;
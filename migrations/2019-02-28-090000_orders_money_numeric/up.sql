@@ -0,0 +1,7 @@
+-- Amounts keep 8 decimal places, enough for minor units of both fiat and crypto currencies
+ALTER TABLE orders
+    ALTER COLUMN price TYPE NUMERIC(18, 8) USING round(price::NUMERIC, 8),
+    ALTER COLUMN product_discount TYPE NUMERIC(18, 8) USING round(product_discount::NUMERIC, 8),
+    ALTER COLUMN coupon_discount TYPE NUMERIC(18, 8) USING round(coupon_discount::NUMERIC, 8),
+    ALTER COLUMN total_amount TYPE NUMERIC(18, 8) USING round(total_amount::NUMERIC, 8),
+    ALTER COLUMN delivery_price TYPE NUMERIC(18, 8) USING round(delivery_price::NUMERIC, 8);
@@ -0,0 +1,48 @@
+-- Orders keep describing their first active line, so that the single product API keeps working
+CREATE TABLE order_lines (
+    id                 UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    order_id           UUID NOT NULL REFERENCES orders (id) ON DELETE CASCADE,
+    position           INTEGER NOT NULL DEFAULT 0,
+    created_from       UUID,
+    store              INTEGER NOT NULL,
+    customer           INTEGER NOT NULL,
+    product            INTEGER NOT NULL,
+    quantity           INTEGER NOT NULL,
+    price              NUMERIC(18, 8) NOT NULL,
+    pre_order          BOOLEAN NOT NULL DEFAULT FALSE,
+    pre_order_days     INTEGER NOT NULL DEFAULT 0,
+    coupon_id          INTEGER,
+    coupon_percent     INTEGER,
+    coupon_discount    NUMERIC(18, 8),
+    product_discount   NUMERIC(18, 8),
+    delivery_company   VARCHAR,
+    company_package_id INTEGER,
+    shipping_id        INTEGER,
+    delivery_price     NUMERIC(18, 8) NOT NULL DEFAULT 0,
+    product_cashback   DOUBLE PRECISION,
+    total_amount       NUMERIC(18, 8) NOT NULL,
+    comment            VARCHAR NOT NULL DEFAULT '',
+    cancelled_at       TIMESTAMP WITH TIME ZONE,
+    created_at         TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+);
+
+CREATE INDEX order_lines_order_id_idx ON order_lines (order_id, position);
+
+-- Every existing order becomes the only line of itself
+INSERT INTO order_lines (
+    id, order_id, created_from, store, customer, product, quantity, price, pre_order, pre_order_days,
+    coupon_id, coupon_percent, coupon_discount, product_discount, delivery_company, company_package_id,
+    shipping_id, delivery_price, product_cashback, total_amount, comment, created_at
+)
+SELECT
+    o.id, o.id, o.created_from, o.store, o.customer, o.product, o.quantity, o.price, o.pre_order, o.pre_order_days,
+    o.coupon_id, o.coupon_percent, o.coupon_discount, o.product_discount, o.delivery_company, o.company_package_id,
+    o.shipping_id, o.delivery_price, o.product_cashback, o.total_amount,
+    COALESCE((
+        SELECT d.comment FROM order_diffs d
+        WHERE d.parent = o.id AND d.state = 'new' AND d.comment IS NOT NULL
+        ORDER BY d.committed_at
+        LIMIT 1
+    ), ''),
+    o.created_at
+FROM orders o;
@@ -0,0 +1,3 @@
+ALTER TABLE order_lines DROP COLUMN IF EXISTS delivery_pricing;
+ALTER TABLE orders DROP COLUMN IF EXISTS delivery_pricing;
+DROP TABLE IF EXISTS delivery_pricing_rules;
@@ -0,0 +1,16 @@
+-- Delivery pricing mode of the store or of the package, the package rule takes precedence
+CREATE TABLE delivery_pricing_rules (
+    id                 SERIAL PRIMARY KEY,
+    store              INTEGER,
+    company_package_id INTEGER,
+    pricing            VARCHAR NOT NULL,
+    created_at         TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    CHECK ((store IS NULL) <> (company_package_id IS NULL))
+);
+
+CREATE UNIQUE INDEX delivery_pricing_rules_store_idx ON delivery_pricing_rules (store) WHERE store IS NOT NULL;
+CREATE UNIQUE INDEX delivery_pricing_rules_company_package_id_idx ON delivery_pricing_rules (company_package_id) WHERE company_package_id IS NOT NULL;
+
+-- Existing orders were charged for delivery of every unit
+ALTER TABLE orders ADD COLUMN delivery_pricing VARCHAR NOT NULL DEFAULT 'per_unit';
+ALTER TABLE order_lines ADD COLUMN delivery_pricing VARCHAR NOT NULL DEFAULT 'per_unit';
@@ -0,0 +1,5 @@
+ALTER TABLE order_lines DROP COLUMN IF EXISTS tax_amount;
+ALTER TABLE orders DROP COLUMN IF EXISTS tax_amount;
+DROP TABLE IF EXISTS order_tax_lines;
+DROP TABLE IF EXISTS product_tax_categories;
+DROP TABLE IF EXISTS tax_rules;
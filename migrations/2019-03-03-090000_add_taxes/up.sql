@@ -0,0 +1,41 @@
+-- Taxes of the country, or of its region when the region is set, for products of the category.
+-- Every rule matching the address and the product adds its own tax line.
+CREATE TABLE tax_rules (
+    id           SERIAL PRIMARY KEY,
+    country      VARCHAR NOT NULL,
+    region       VARCHAR,
+    tax_category VARCHAR NOT NULL DEFAULT 'standard',
+    name         VARCHAR NOT NULL,
+    rate         DOUBLE PRECISION NOT NULL CHECK (rate >= 0),
+    inclusive    BOOLEAN NOT NULL,
+    created_at   TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+);
+
+CREATE UNIQUE INDEX tax_rules_name_idx ON tax_rules (lower(country), lower(coalesce(region, '')), tax_category, name);
+
+-- Products not listed here belong to the 'standard' category
+CREATE TABLE product_tax_categories (
+    product      INTEGER PRIMARY KEY,
+    tax_category VARCHAR NOT NULL,
+    created_at   TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+);
+
+CREATE TABLE order_tax_lines (
+    id             UUID PRIMARY KEY,
+    order_id       UUID NOT NULL REFERENCES orders (id) ON DELETE CASCADE,
+    order_line_id  UUID NOT NULL REFERENCES order_lines (id) ON DELETE CASCADE,
+    store          INTEGER NOT NULL,
+    customer       INTEGER NOT NULL,
+    name           VARCHAR NOT NULL,
+    tax_category   VARCHAR NOT NULL,
+    rate           DOUBLE PRECISION NOT NULL,
+    inclusive      BOOLEAN NOT NULL,
+    taxable_amount NUMERIC NOT NULL,
+    tax_amount     NUMERIC NOT NULL,
+    created_at     TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+);
+
+CREATE INDEX order_tax_lines_order_id_idx ON order_tax_lines (order_id);
+
+ALTER TABLE orders ADD COLUMN tax_amount NUMERIC NOT NULL DEFAULT 0;
+ALTER TABLE order_lines ADD COLUMN tax_amount NUMERIC NOT NULL DEFAULT 0;
@@ -0,0 +1,10 @@
+-- Coupons without a rule give their percent discount on one unit of the product they are attached to.
+-- Fixed amount coupons give the amount in its currency instead of the percent.
+CREATE TABLE coupon_rules (
+    coupon_id    INTEGER PRIMARY KEY,
+    scope        VARCHAR NOT NULL DEFAULT 'unit',
+    fixed_amount NUMERIC CHECK (fixed_amount > 0),
+    currency     VARCHAR,
+    created_at   TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    CHECK ((fixed_amount IS NULL) = (currency IS NULL))
+);
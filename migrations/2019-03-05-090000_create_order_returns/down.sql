@@ -0,0 +1,9 @@
+DELETE FROM saga_notifications WHERE kind <> 'seller_payment';
+DROP INDEX IF EXISTS saga_notifications_return_id_idx;
+DROP INDEX IF EXISTS saga_notifications_order_id_idx;
+CREATE UNIQUE INDEX saga_notifications_order_id_idx ON saga_notifications (order_id);
+ALTER TABLE saga_notifications DROP COLUMN IF EXISTS return_id;
+ALTER TABLE saga_notifications DROP COLUMN IF EXISTS kind;
+
+DROP TABLE IF EXISTS order_return_diffs;
+DROP TABLE IF EXISTS order_returns;
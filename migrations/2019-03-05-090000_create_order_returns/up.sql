@@ -0,0 +1,36 @@
+-- Units of the order the customer sends back. Store and customer repeat the ones of the order for ACL checks.
+CREATE TABLE order_returns (
+    id         UUID      PRIMARY KEY,
+    order_id   UUID      NOT NULL REFERENCES orders (id) ON DELETE CASCADE,
+    customer   INTEGER   NOT NULL,
+    store      INTEGER   NOT NULL,
+    quantity   INTEGER   NOT NULL CHECK (quantity > 0),
+    reason     VARCHAR   NOT NULL,
+    photos     JSONB     NOT NULL DEFAULT '[]',
+    state      VARCHAR   NOT NULL DEFAULT 'requested',
+    track_id   VARCHAR,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+);
+
+CREATE INDEX order_returns_order_id_idx ON order_returns (order_id);
+
+CREATE TABLE order_return_diffs (
+    id             UUID      PRIMARY KEY DEFAULT uuid_generate_v4(),
+    parent         UUID      NOT NULL REFERENCES order_returns (id) ON DELETE CASCADE,
+    committer      INTEGER   NOT NULL,
+    committer_role VARCHAR   NOT NULL,
+    committed_at   TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    state          VARCHAR   NOT NULL,
+    comment        VARCHAR
+);
+
+CREATE INDEX order_return_diffs_parent_idx ON order_return_diffs (parent);
+
+-- Saga is asked to pay the seller once per order and to refund the customer once per return
+ALTER TABLE saga_notifications ADD COLUMN kind VARCHAR NOT NULL DEFAULT 'seller_payment';
+ALTER TABLE saga_notifications ADD COLUMN return_id UUID REFERENCES order_returns (id) ON DELETE CASCADE;
+
+DROP INDEX saga_notifications_order_id_idx;
+CREATE UNIQUE INDEX saga_notifications_order_id_idx ON saga_notifications (order_id) WHERE kind = 'seller_payment';
+CREATE UNIQUE INDEX saga_notifications_return_id_idx ON saga_notifications (return_id);
@@ -0,0 +1,9 @@
+DELETE FROM saga_notifications WHERE kind = 'refund';
+DROP INDEX IF EXISTS saga_notifications_refund_id_idx;
+ALTER TABLE saga_notifications DROP COLUMN IF EXISTS refund_id;
+ALTER TABLE saga_notifications ADD COLUMN return_id UUID REFERENCES order_returns (id) ON DELETE CASCADE;
+CREATE UNIQUE INDEX saga_notifications_return_id_idx ON saga_notifications (return_id);
+
+ALTER TABLE orders DROP COLUMN IF EXISTS refunded_amount;
+
+DROP TABLE IF EXISTS refunds;
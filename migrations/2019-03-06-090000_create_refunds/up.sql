@@ -0,0 +1,30 @@
+-- Money given back to the customer of the order, several partial refunds may be made up to its total amount.
+-- Store and customer repeat the ones of the order for ACL checks.
+CREATE TABLE refunds (
+    id             UUID      PRIMARY KEY,
+    order_id       UUID      NOT NULL REFERENCES orders (id) ON DELETE CASCADE,
+    customer       INTEGER   NOT NULL,
+    store          INTEGER   NOT NULL,
+    return_id      UUID      REFERENCES order_returns (id) ON DELETE SET NULL,
+    amount         NUMERIC   NOT NULL CHECK (amount > 0),
+    currency       VARCHAR   NOT NULL,
+    reason         VARCHAR   NOT NULL,
+    committer      INTEGER   NOT NULL,
+    committer_role VARCHAR   NOT NULL,
+    saga_status    VARCHAR   NOT NULL DEFAULT 'pending',
+    created_at     TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    updated_at     TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+);
+
+CREATE INDEX refunds_order_id_idx ON refunds (order_id);
+CREATE INDEX refunds_created_at_idx ON refunds (created_at);
+CREATE UNIQUE INDEX refunds_return_id_idx ON refunds (return_id);
+
+ALTER TABLE orders ADD COLUMN refunded_amount NUMERIC NOT NULL DEFAULT 0;
+
+-- Saga is asked once per refund, received returns are refunded through refunds as well
+DELETE FROM saga_notifications WHERE kind = 'refund';
+DROP INDEX IF EXISTS saga_notifications_return_id_idx;
+ALTER TABLE saga_notifications DROP COLUMN IF EXISTS return_id;
+ALTER TABLE saga_notifications ADD COLUMN refund_id UUID REFERENCES refunds (id) ON DELETE CASCADE;
+CREATE UNIQUE INDEX saga_notifications_refund_id_idx ON saga_notifications (refund_id);
@@ -0,0 +1,18 @@
+-- Boxes the order is sent in, each one with its own tracking number and possibly its own carrier.
+-- Store and customer repeat the ones of the order for ACL checks.
+CREATE TABLE shipments (
+    id              UUID      PRIMARY KEY,
+    order_id        UUID      NOT NULL REFERENCES orders (id) ON DELETE CASCADE,
+    customer        INTEGER   NOT NULL,
+    store           INTEGER   NOT NULL,
+    carrier         VARCHAR   NOT NULL,
+    tracking_number VARCHAR   NOT NULL,
+    quantity        INTEGER   NOT NULL CHECK (quantity > 0),
+    status          VARCHAR   NOT NULL DEFAULT 'in_transit',
+    created_at      TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    updated_at      TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    delivered_at    TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX shipments_order_id_idx ON shipments (order_id);
+CREATE UNIQUE INDEX shipments_tracking_number_idx ON shipments (order_id, lower(carrier), tracking_number);
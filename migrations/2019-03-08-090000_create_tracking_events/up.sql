@@ -0,0 +1,27 @@
+-- Activities reported by carriers for sent orders and their shipments.
+-- Store and customer repeat the ones of the order for ACL checks.
+CREATE TABLE tracking_events (
+    id              UUID      PRIMARY KEY,
+    order_id        UUID      NOT NULL REFERENCES orders (id) ON DELETE CASCADE,
+    shipment_id     UUID      REFERENCES shipments (id) ON DELETE CASCADE,
+    customer        INTEGER   NOT NULL,
+    store           INTEGER   NOT NULL,
+    carrier         VARCHAR   NOT NULL,
+    tracking_number VARCHAR   NOT NULL,
+    status_code     VARCHAR   NOT NULL,
+    description     VARCHAR   NOT NULL,
+    location        VARCHAR,
+    occurred_at     TIMESTAMP WITH TIME ZONE,
+    created_at      TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+);
+
+CREATE INDEX tracking_events_order_id_idx ON tracking_events (order_id);
+-- Carriers report the whole history on every poll, activities recorded before are skipped
+CREATE UNIQUE INDEX tracking_events_activity_idx ON tracking_events (
+    order_id,
+    carrier,
+    tracking_number,
+    status_code,
+    description,
+    COALESCE(occurred_at, 'epoch'::TIMESTAMP WITH TIME ZONE)
+);
@@ -0,0 +1,2 @@
+ALTER TABLE orders DROP COLUMN IF EXISTS attention_reason;
+ALTER TABLE orders DROP COLUMN IF EXISTS delivery_state;
@@ -0,0 +1,4 @@
+-- Latest state reported by the carrier, delivery rules are applied when it changes
+ALTER TABLE orders ADD COLUMN delivery_state VARCHAR;
+-- Set by delivery rules for the seller to look at the order
+ALTER TABLE orders ADD COLUMN attention_reason VARCHAR;
@@ -0,0 +1,2 @@
+-- Set once the order staying in sent state longer than the tracking window is escalated
+ALTER TABLE orders ADD COLUMN escalated_at TIMESTAMP WITH TIME ZONE;
@@ -0,0 +1,2 @@
+DROP TRIGGER IF EXISTS bump_version ON orders;
+DROP FUNCTION IF EXISTS orders_bump_version();
@@ -0,0 +1,10 @@
+-- Every update of the order makes its version stale, whichever query made it
+CREATE OR REPLACE FUNCTION orders_bump_version() RETURNS TRIGGER AS $$
+BEGIN
+    NEW.version := OLD.version + 1;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER bump_version BEFORE UPDATE ON orders
+    FOR EACH ROW EXECUTE PROCEDURE orders_bump_version();
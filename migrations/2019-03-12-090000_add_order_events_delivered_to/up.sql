@@ -0,0 +1,2 @@
+-- URLs of the webhooks that accepted the event, they are not called again on retries
+ALTER TABLE order_events ADD COLUMN delivered_to JSONB NOT NULL DEFAULT '[]';
@@ -0,0 +1,24 @@
+ALTER TABLE coupon_rules ALTER COLUMN fixed_amount TYPE NUMERIC;
+
+ALTER TABLE refunds ALTER COLUMN amount TYPE NUMERIC;
+
+ALTER TABLE order_tax_lines
+    ALTER COLUMN taxable_amount TYPE NUMERIC,
+    ALTER COLUMN tax_amount TYPE NUMERIC;
+
+ALTER TABLE order_lines
+    ALTER COLUMN price TYPE NUMERIC(18, 8) USING round(price, 8),
+    ALTER COLUMN coupon_discount TYPE NUMERIC(18, 8) USING round(coupon_discount, 8),
+    ALTER COLUMN product_discount TYPE NUMERIC(18, 8) USING round(product_discount, 8),
+    ALTER COLUMN delivery_price TYPE NUMERIC(18, 8) USING round(delivery_price, 8),
+    ALTER COLUMN total_amount TYPE NUMERIC(18, 8) USING round(total_amount, 8),
+    ALTER COLUMN tax_amount TYPE NUMERIC;
+
+ALTER TABLE orders
+    ALTER COLUMN price TYPE NUMERIC(18, 8) USING round(price, 8),
+    ALTER COLUMN product_discount TYPE NUMERIC(18, 8) USING round(product_discount, 8),
+    ALTER COLUMN coupon_discount TYPE NUMERIC(18, 8) USING round(coupon_discount, 8),
+    ALTER COLUMN total_amount TYPE NUMERIC(18, 8) USING round(total_amount, 8),
+    ALTER COLUMN delivery_price TYPE NUMERIC(18, 8) USING round(delivery_price, 8),
+    ALTER COLUMN tax_amount TYPE NUMERIC,
+    ALTER COLUMN refunded_amount TYPE NUMERIC;
@@ -0,0 +1,25 @@
+-- Amounts keep 18 decimal places, enough for the smallest units of crypto currencies
+ALTER TABLE orders
+    ALTER COLUMN price TYPE NUMERIC(38, 18),
+    ALTER COLUMN product_discount TYPE NUMERIC(38, 18),
+    ALTER COLUMN coupon_discount TYPE NUMERIC(38, 18),
+    ALTER COLUMN total_amount TYPE NUMERIC(38, 18),
+    ALTER COLUMN delivery_price TYPE NUMERIC(38, 18),
+    ALTER COLUMN tax_amount TYPE NUMERIC(38, 18) USING round(tax_amount, 18),
+    ALTER COLUMN refunded_amount TYPE NUMERIC(38, 18) USING round(refunded_amount, 18);
+
+ALTER TABLE order_lines
+    ALTER COLUMN price TYPE NUMERIC(38, 18),
+    ALTER COLUMN coupon_discount TYPE NUMERIC(38, 18),
+    ALTER COLUMN product_discount TYPE NUMERIC(38, 18),
+    ALTER COLUMN delivery_price TYPE NUMERIC(38, 18),
+    ALTER COLUMN total_amount TYPE NUMERIC(38, 18),
+    ALTER COLUMN tax_amount TYPE NUMERIC(38, 18) USING round(tax_amount, 18);
+
+ALTER TABLE order_tax_lines
+    ALTER COLUMN taxable_amount TYPE NUMERIC(38, 18) USING round(taxable_amount, 18),
+    ALTER COLUMN tax_amount TYPE NUMERIC(38, 18) USING round(tax_amount, 18);
+
+ALTER TABLE refunds ALTER COLUMN amount TYPE NUMERIC(38, 18) USING round(amount, 18);
+
+ALTER TABLE coupon_rules ALTER COLUMN fixed_amount TYPE NUMERIC(38, 18) USING round(fixed_amount, 18);
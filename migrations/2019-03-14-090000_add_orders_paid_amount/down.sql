@@ -0,0 +1,3 @@
+DROP TRIGGER IF EXISTS record_paid_amount ON orders;
+DROP FUNCTION IF EXISTS orders_record_paid_amount();
+ALTER TABLE orders DROP COLUMN IF EXISTS paid_amount;
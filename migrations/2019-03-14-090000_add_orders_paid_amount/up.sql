@@ -0,0 +1,17 @@
+-- Total amount of the order at the moment it was paid, refunds never exceed it even if lines are cancelled later
+ALTER TABLE orders ADD COLUMN paid_amount NUMERIC(38, 18);
+
+CREATE OR REPLACE FUNCTION orders_record_paid_amount() RETURNS TRIGGER AS $$
+BEGIN
+    IF NEW.state = 'paid' AND NEW.paid_amount IS NULL THEN
+        NEW.paid_amount := NEW.total_amount;
+    END IF;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER record_paid_amount BEFORE INSERT OR UPDATE ON orders
+    FOR EACH ROW EXECUTE PROCEDURE orders_record_paid_amount();
+
+UPDATE orders o SET paid_amount = o.total_amount
+WHERE EXISTS (SELECT 1 FROM order_diffs d WHERE d.parent = o.id AND d.state = 'paid');
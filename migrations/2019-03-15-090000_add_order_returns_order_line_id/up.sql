@@ -0,0 +1,13 @@
+-- Units are returned from one line of the order, its price and delivery determine the refund.
+ALTER TABLE order_returns ADD COLUMN order_line_id UUID REFERENCES order_lines (id) ON DELETE CASCADE;
+
+-- Returns of single-line orders are returns of that line
+UPDATE order_returns r
+SET order_line_id = l.id
+FROM order_lines l
+WHERE l.order_id = r.order_id
+  AND l.cancelled_at IS NULL
+  AND NOT EXISTS (
+      SELECT 1 FROM order_lines o
+      WHERE o.order_id = r.order_id AND o.cancelled_at IS NULL AND o.id <> l.id
+  );
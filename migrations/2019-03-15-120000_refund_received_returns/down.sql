@@ -0,0 +1,2 @@
+-- Refunds of received returns are kept, Saga may have paid them back already
+SELECT 1;
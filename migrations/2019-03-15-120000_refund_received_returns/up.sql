@@ -0,0 +1,80 @@
+-- Refund requests of received returns were dropped along with their Saga notifications when refunds were added.
+-- Received returns without a refund are refunded with the products amount of their returned units,
+-- delivery charged by the lines is not refunded. Returns opened before returns were made per line
+-- are refunded from all active lines of the order.
+WITH products AS (
+    SELECT
+        l.id AS order_line_id,
+        l.order_id,
+        l.quantity,
+        l.total_amount - CASE
+            WHEN l.delivery_pricing = 'per_unit' THEN l.delivery_price * l.quantity
+            WHEN l.quantity > 0 AND NOT EXISTS (
+                SELECT 1 FROM order_lines e
+                WHERE e.order_id = l.order_id
+                  AND e.cancelled_at IS NULL
+                  AND e.quantity > 0
+                  AND e.position < l.position
+                  AND e.delivery_pricing = 'per_package'
+                  AND e.company_package_id = l.company_package_id
+            ) THEN l.delivery_price
+            ELSE 0
+        END AS amount
+    FROM order_lines l
+    WHERE l.cancelled_at IS NULL
+),
+returned AS (
+    SELECT
+        r.id AS return_id,
+        r.order_id,
+        o.customer,
+        o.store,
+        o.currency,
+        o.currency_type,
+        r.reason,
+        LEAST(
+            SUM(p.amount) * r.quantity / SUM(p.quantity),
+            COALESCE(o.paid_amount, o.total_amount) - o.refunded_amount
+        ) AS amount
+    FROM order_returns r
+    JOIN orders o ON o.id = r.order_id
+    JOIN products p ON p.order_id = r.order_id AND (r.order_line_id IS NULL OR p.order_line_id = r.order_line_id)
+    WHERE r.state = 'received'
+      AND NOT EXISTS (SELECT 1 FROM refunds f WHERE f.return_id = r.id)
+    GROUP BY r.id, o.id
+    HAVING SUM(p.quantity) > 0
+),
+requested AS (
+    SELECT
+        returned.*,
+        CASE WHEN currency_type = 'fiat' THEN round(amount, 2) ELSE amount END AS refund_amount,
+        d.committer,
+        d.committer_role,
+        d.committed_at
+    FROM returned
+    JOIN LATERAL (
+        SELECT committer, committer_role, committed_at FROM order_return_diffs
+        WHERE parent = returned.return_id AND state = 'received'
+        ORDER BY committed_at DESC
+        LIMIT 1
+    ) d ON TRUE
+),
+inserted AS (
+    INSERT INTO refunds (
+        id, order_id, customer, store, return_id, amount, currency, reason, committer, committer_role, created_at, updated_at
+    )
+    SELECT
+        uuid_generate_v4(), order_id, customer, store, return_id, refund_amount,
+        currency, 'Return ' || return_id || ': ' || reason, committer, committer_role, committed_at, committed_at
+    FROM requested
+    WHERE refund_amount > 0
+    RETURNING id, order_id, amount
+),
+refunded AS (
+    UPDATE orders o
+    SET refunded_amount = o.refunded_amount + f.amount
+    FROM (SELECT order_id, SUM(amount) AS amount FROM inserted GROUP BY order_id) f
+    WHERE f.order_id = o.id
+)
+INSERT INTO saga_notifications (order_id, kind, refund_id)
+SELECT order_id, 'refund', id FROM inserted;
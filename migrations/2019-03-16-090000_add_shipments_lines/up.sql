@@ -0,0 +1,13 @@
+-- Units of every order line in the box, the order is delivered once every unit of its active lines is.
+ALTER TABLE shipments ADD COLUMN lines JSONB NOT NULL DEFAULT '[]';
+
+-- Shipments of single-line orders hold units of that line
+UPDATE shipments s
+SET lines = jsonb_build_array(jsonb_build_object('order_line_id', l.id, 'quantity', s.quantity))
+FROM order_lines l
+WHERE l.order_id = s.order_id
+  AND l.cancelled_at IS NULL
+  AND NOT EXISTS (
+      SELECT 1 FROM order_lines o
+      WHERE o.order_id = s.order_id AND o.cancelled_at IS NULL AND o.id <> l.id
+  );
@@ -1,6 +1,9 @@
 use failure::Error as FailureError;
 use future::{self, Future};
 use stq_acl::{AclEngine, Verdict};
+use stq_types::{StoreId, UserId};
+
+use models::*;
 
 pub struct OrdersAcl<F>(pub F);
 
@@ -24,3 +27,110 @@ where
         Box::new(future::ok((allowed, ctx)))
     }
 }
+
+/// Entry of an order, its store and customer repeat the ones of the order
+pub trait OrderScoped {
+    fn store(&self) -> StoreId;
+    fn customer(&self) -> UserId;
+}
+
+/// Superadmins may do anything with orders and their entries. Managers of the store and the customer
+/// may do only what is `permitted` to them.
+pub fn check_order_acl<T: OrderScoped>(login: &UserLogin, entry: &T, permitted: bool) -> bool {
+    use models::UserRole::*;
+
+    if let RepoLogin::User {
+        ref caller_roles,
+        caller_id,
+    } = *login
+    {
+        for role_entry in caller_roles {
+            match role_entry.role {
+                Superadmin => {
+                    return true;
+                }
+                StoreManager(managed_store) => {
+                    if managed_store == entry.store() {
+                        return permitted;
+                    }
+                }
+            }
+        }
+
+        if caller_id == entry.customer() {
+            return permitted;
+        }
+    }
+
+    false
+}
+
+impl OrderScoped for DbOrder {
+    fn store(&self) -> StoreId {
+        self.0.store
+    }
+
+    fn customer(&self) -> UserId {
+        self.0.customer
+    }
+}
+
+impl OrderScoped for OrderLine {
+    fn store(&self) -> StoreId {
+        self.store
+    }
+
+    fn customer(&self) -> UserId {
+        self.customer
+    }
+}
+
+impl OrderScoped for OrderTaxLine {
+    fn store(&self) -> StoreId {
+        self.store
+    }
+
+    fn customer(&self) -> UserId {
+        self.customer
+    }
+}
+
+impl OrderScoped for OrderReturn {
+    fn store(&self) -> StoreId {
+        self.store
+    }
+
+    fn customer(&self) -> UserId {
+        self.customer
+    }
+}
+
+impl OrderScoped for Refund {
+    fn store(&self) -> StoreId {
+        self.store
+    }
+
+    fn customer(&self) -> UserId {
+        self.customer
+    }
+}
+
+impl OrderScoped for Shipment {
+    fn store(&self) -> StoreId {
+        self.store
+    }
+
+    fn customer(&self) -> UserId {
+        self.customer
+    }
+}
+
+impl OrderScoped for TrackingEvent {
+    fn store(&self) -> StoreId {
+        self.store
+    }
+
+    fn customer(&self) -> UserId {
+        self.customer
+    }
+}
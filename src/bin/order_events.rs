@@ -0,0 +1,16 @@
+extern crate orders_lib;
+extern crate stq_logging;
+
+use orders_lib::config::LoaderKind;
+
+fn main() {
+    let config = orders_lib::config::Config::new().expect("Can't load app config!");
+
+    // Prepare sentry integration
+    let _sentry = orders_lib::sentry_integration::init(config.sentry.as_ref());
+
+    // Prepare logger
+    stq_logging::init(config.graylog.as_ref());
+
+    orders_lib::loaders::start_loaders(config, &[LoaderKind::OrderEvents]);
+}
@@ -1,6 +1,8 @@
 extern crate orders_lib;
 extern crate stq_logging;
 
+use orders_lib::config::LoaderKind;
+
 fn main() {
     let config = orders_lib::config::Config::new().expect("Can't load app config!");
 
@@ -10,5 +12,5 @@ fn main() {
     // Prepare logger
     stq_logging::init(config.graylog.as_ref());
 
-    orders_lib::loaders::start_paid_delivered_reporting(config);
+    orders_lib::loaders::start_loaders(config, &[LoaderKind::PaidDeliveredReport]);
 }
@@ -1,9 +1,17 @@
+use failure;
+use std::collections::HashMap;
 use std::env;
 use std::net::IpAddr;
 use stq_logging;
 
 use config_crate::{Config as RawConfig, ConfigError, Environment, File};
+use models::{is_transition_allowed, DeliveryRule};
 use sentry_integration::SentryConfig;
+use stq_static_resources::{CommitterRole, OrderState};
+use stq_types::CompanyPackageId;
+
+/// Carrier of orders without delivery company, they were tracked by UPS only before other carriers were added
+pub const DEFAULT_CARRIER: &str = "ups";
 
 /// Service configuration
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -35,6 +43,10 @@ pub struct Config {
     pub s3: Option<S3>,
     /// Paid and delivered report settings
     pub paid_delivered_report: Option<PaidDeliveredReports>,
+    /// Order events dispatcher settings
+    pub order_events: Option<OrderEvents>,
+    /// Background worker settings
+    pub worker: Option<Worker>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -47,6 +59,133 @@ pub struct SentOrders {
     pub ups_api_url: String,
     /// How long in days order can be in sent state to be processed
     pub sent_state_duration_days: i64,
+    /// DHL tracking, orders sent with DHL are not tracked if not set
+    pub dhl: Option<DhlTracking>,
+    /// Carriers tracked with the generic HTTP tracker
+    #[serde(default)]
+    pub http_carriers: Vec<HttpCarrier>,
+    /// Routes from delivery companies and packages of orders to carriers
+    #[serde(default)]
+    pub carrier_routes: Vec<CarrierRoute>,
+    /// What is done with sent orders once their carriers report delivery problems
+    #[serde(default)]
+    pub delivery_rules: Vec<DeliveryRule>,
+    /// Secrets carriers sign pushed tracking updates with by carrier name, pushes of other carriers are rejected
+    #[serde(default)]
+    pub push_secrets: HashMap<String, String>,
+    /// Sent orders past the tracking window are escalated, they are moved to another state if set
+    pub stale_move: Option<StaleSentMove>,
+}
+
+impl SentOrders {
+    /// Invalid rules are skipped, so the rest of them still apply
+    pub fn valid_delivery_rules(&self) -> Vec<DeliveryRule> {
+        self.delivery_rules
+            .iter()
+            .filter(|rule| match rule.validate() {
+                Ok(()) => true,
+                Err(e) => {
+                    warn!("Delivery rule {:?} is skipped: {}", rule, e);
+                    false
+                }
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StaleSentMove {
+    /// Days in sent state after which the order is moved
+    pub after_days: i64,
+    /// State the order is moved to, it has to be reachable from sent state by the system
+    pub state: OrderState,
+}
+
+impl StaleSentMove {
+    /// Stale orders are moved by the system, so the state has to be reachable from sent state by it
+    pub fn validate(&self) -> Result<(), failure::Error> {
+        if !is_transition_allowed(OrderState::Sent, self.state, CommitterRole::System) {
+            bail!("Orders can not be moved from sent state to {} by the system", self.state);
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DhlTracking {
+    /// DHL shipment tracking api url
+    pub api_url: String,
+    /// DHL api key
+    pub api_key: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HttpCarrier {
+    /// Carrier name routes and shipments refer to
+    pub name: String,
+    /// Tracking url, `{tracking_number}` is replaced with the tracking number
+    pub url: String,
+    /// Headers sent with every request, e.g. api keys
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// JSON pointer to the status in the response, e.g. `/shipments/0/status`
+    pub status_pointer: String,
+    /// Statuses meaning that the parcel is delivered, compared case-insensitively
+    pub delivered_statuses: Vec<String>,
+    /// Statuses meaning that the carrier can not deliver the parcel as planned
+    #[serde(default)]
+    pub exception_statuses: Vec<String>,
+    /// Statuses meaning that the parcel is on its way back to the seller
+    #[serde(default)]
+    pub returned_statuses: Vec<String>,
+    /// Statuses meaning that the receiver was not found
+    #[serde(default)]
+    pub attempted_statuses: Vec<String>,
+    /// JSON pointer to the list of activities in the response, each one with `status_code`, `description`,
+    /// `location` and RFC 3339 `occurred_at` fields. Activities are not recorded if not set
+    pub events_pointer: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CarrierRoute {
+    /// Name of the carrier tracking matching orders
+    pub carrier: String,
+    /// Delivery companies of orders, compared case-insensitively
+    #[serde(default)]
+    pub delivery_companies: Vec<String>,
+    /// Company packages of orders, take precedence over delivery companies
+    #[serde(default)]
+    pub company_package_ids: Vec<i32>,
+}
+
+/// Carrier of the order by its company package first, then by its delivery company.
+/// Delivery companies named after a carrier need no route.
+pub fn route_carrier<'a>(
+    routes: &'a [CarrierRoute],
+    delivery_company: Option<&'a str>,
+    company_package_id: Option<CompanyPackageId>,
+) -> &'a str {
+    company_package_id
+        .and_then(|company_package_id| {
+            routes
+                .iter()
+                .find(|route| route.company_package_ids.contains(&company_package_id.0))
+        })
+        .or_else(|| {
+            delivery_company.and_then(|delivery_company| {
+                routes.iter().find(|route| {
+                    route
+                        .delivery_companies
+                        .iter()
+                        .any(|company| company.eq_ignore_ascii_case(delivery_company))
+                })
+            })
+        })
+        .map(|route| route.carrier.as_str())
+        .or(delivery_company)
+        .unwrap_or(DEFAULT_CARRIER)
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -64,6 +203,45 @@ pub struct PaidDeliveredReports {
     pub interval_s: u64,
 }
 
+/// Background loaders that can be run by the worker
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoaderKind {
+    DeliveredStateTracking,
+    SentStateTracking,
+    PaidDeliveredReport,
+    OrderEvents,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Worker {
+    /// Loaders run by `orders_worker` on one event loop
+    pub loaders: Vec<LoaderKind>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OrderEvents {
+    /// Dispatch interval in seconds
+    pub interval_s: u64,
+    /// Max number of events delivered in one step
+    pub batch_size: i64,
+    /// Number of failed delivery attempts after which the event is moved to dead letters
+    pub max_attempts: i32,
+    /// Delay before the first retry in seconds, doubled with every next attempt
+    pub retry_base_delay_s: i64,
+    /// Upper bound of the retry delay in seconds
+    pub retry_max_delay_s: i64,
+    /// Endpoints the events are delivered to
+    pub webhooks: Vec<Webhook>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Webhook {
+    pub url: String,
+    /// Key used to sign request bodies with HMAC-SHA256
+    pub secret: String,
+}
+
 /// AWS S3 credentials
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct S3 {
@@ -95,6 +273,15 @@ impl Config {
         // Add in settings from the environment (with a prefix of STQ_ORDERS)
         s.merge(Environment::with_prefix(ENV_PREFIX))?;
 
-        s.try_into()
+        let config: Self = s.try_into()?;
+
+        // Every stale order would fail to move otherwise, so the service does not start with it
+        if let Some(stale_move) = config.sent_orders.as_ref().and_then(|sent_orders| sent_orders.stale_move.as_ref()) {
+            stale_move
+                .validate()
+                .map_err(|e| ConfigError::Message(format!("Invalid sent_orders.stale_move: {}", e)))?;
+        }
+
+        Ok(config)
     }
 }
@@ -1,19 +1,23 @@
 use chrono::prelude::*;
+use chrono::Duration as ChronoDuration;
 use failure::{self, Fallible, ResultExt};
 use futures::{future, prelude::*};
 use hyper::{self, Delete, Get, Headers, Post, Put, Request};
+use serde_json;
+use std::collections::HashMap;
 use std::rc::Rc;
 use stq_api::orders::*;
 use stq_http::{
     controller::{Controller, ControllerFuture},
     errors::ErrorMessageWrapper,
-    request_util::{parse_body, serialize_future},
+    request_util::{parse_body, read_body, serialize_future},
 };
 use stq_roles::{
     routing::Controller as RoleController,
     service::{get_login_data, RoleService, RoleServiceImpl},
 };
-use stq_static_resources::CurrencyType;
+use stq_router::RouteParser;
+use stq_static_resources::{CurrencyType, OrderState};
 use stq_types::*;
 use validator::Validate;
 
@@ -24,22 +28,67 @@ use sentry_integration::log_and_capture_error;
 use services::*;
 use types::*;
 
+pub mod routes;
+
+use self::routes::*;
+
+/// Number of job runs returned when `limit` is not set
+const DEFAULT_JOB_RUNS_LIMIT: i64 = 50;
+/// Age of stale orders when `min_age_days` is not set and the tracking window of sent orders is not configured
+const DEFAULT_STALE_ORDER_AGE_DAYS: i64 = 14;
+
 pub type ServiceFactoryFuture<T> = Box<Future<Item = Box<T>, Error = failure::Error>>;
 
 pub struct ServiceFactory {
     pub role: Rc<Fn(UserLogin) -> Box<RoleService<UserRole>>>,
     pub cart: Rc<Fn(UserLogin) -> Box<CartService>>,
     pub order: Rc<Fn(UserLogin) -> Box<OrderService>>,
+    pub job: Rc<Fn(UserLogin) -> Box<JobService>>,
+    pub order_return: Rc<Fn(UserLogin) -> Box<OrderReturnService>>,
+    pub refund: Rc<Fn(UserLogin) -> Box<RefundService>>,
+    pub shipment: Rc<Fn(UserLogin) -> Box<ShipmentService>>,
+    pub tracking: Rc<Fn(UserLogin) -> Box<TrackingService>>,
+    pub delivery_pricing: Rc<Fn(UserLogin) -> Box<DeliveryPricingService>>,
+    pub tax: Rc<Fn(UserLogin) -> Box<TaxService>>,
+    pub coupon_rule: Rc<Fn(UserLogin) -> Box<CouponRuleService>>,
 }
 
 pub struct ControllerImpl {
     db_pool: DbPool,
+    route_parser: Rc<RouteParser<ServiceRoute>>,
     service_factory: Rc<ServiceFactory>,
+    /// Push secrets by lowercase carrier name, pushes of other carriers are rejected
+    push_secrets: HashMap<String, String>,
+    delivery_rules: Vec<DeliveryRule>,
+    /// Carriers of pushed orders are resolved with them
+    carrier_routes: Vec<CarrierRoute>,
+    /// Sent orders past the tracking window are stale
+    stale_order_age_days: i64,
 }
 
 impl ControllerImpl {
-    pub fn new(db_pool: &DbPool, _config: &Config) -> Self {
+    pub fn new(db_pool: &DbPool, config: &Config) -> Self {
+        let sent_orders = config.sent_orders.as_ref();
+
         ControllerImpl {
+            push_secrets: sent_orders
+                .map(|sent_orders| {
+                    sent_orders
+                        .push_secrets
+                        .iter()
+                        .map(|(carrier, secret)| (carrier.to_lowercase(), secret.clone()))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            delivery_rules: sent_orders
+                .map(|sent_orders| sent_orders.valid_delivery_rules())
+                .unwrap_or_default(),
+            carrier_routes: sent_orders
+                .map(|sent_orders| sent_orders.carrier_routes.clone())
+                .unwrap_or_default(),
+            stale_order_age_days: sent_orders
+                .map(|sent_orders| sent_orders.sent_state_duration_days)
+                .unwrap_or(DEFAULT_STALE_ORDER_AGE_DAYS),
             service_factory: Rc::new(ServiceFactory {
                 role: Rc::new({
                     let db_pool = db_pool.clone();
@@ -53,8 +102,41 @@ impl ControllerImpl {
                     let db_pool = db_pool.clone();
                     move |login_data| Box::new(CartServiceImpl::new(db_pool.clone(), login_data)) as Box<CartService>
                 }),
+                job: Rc::new({
+                    let db_pool = db_pool.clone();
+                    move |login_data| Box::new(JobServiceImpl::new(db_pool.clone(), login_data))
+                }),
+                order_return: Rc::new({
+                    let db_pool = db_pool.clone();
+                    move |login_data| Box::new(OrderReturnServiceImpl::new(db_pool.clone(), login_data))
+                }),
+                refund: Rc::new({
+                    let db_pool = db_pool.clone();
+                    move |login_data| Box::new(RefundServiceImpl::new(db_pool.clone(), login_data))
+                }),
+                shipment: Rc::new({
+                    let db_pool = db_pool.clone();
+                    move |login_data| Box::new(ShipmentServiceImpl::new(db_pool.clone(), login_data))
+                }),
+                tracking: Rc::new({
+                    let db_pool = db_pool.clone();
+                    move |login_data| Box::new(TrackingServiceImpl::new(db_pool.clone(), login_data))
+                }),
+                delivery_pricing: Rc::new({
+                    let db_pool = db_pool.clone();
+                    move |login_data| Box::new(DeliveryPricingServiceImpl::new(db_pool.clone(), login_data))
+                }),
+                tax: Rc::new({
+                    let db_pool = db_pool.clone();
+                    move |login_data| Box::new(TaxServiceImpl::new(db_pool.clone(), login_data))
+                }),
+                coupon_rule: Rc::new({
+                    let db_pool = db_pool.clone();
+                    move |login_data| Box::new(CouponRuleServiceImpl::new(db_pool.clone(), login_data))
+                }),
             }),
             db_pool: db_pool.clone(),
+            route_parser: Rc::new(create_route_parser()),
         }
     }
 }
@@ -87,6 +169,15 @@ impl Controller for ControllerImpl {
         let service_factory = self.service_factory.clone();
 
         let route = Route::from_path(uri.path());
+        let service_route = self.route_parser.test(uri.path());
+        let push_secrets = self.push_secrets.clone();
+        let delivery_rules = self.delivery_rules.clone();
+        let carrier_routes = self.carrier_routes.clone();
+        let stale_order_age_days = self.stale_order_age_days;
+        let push_signature = headers
+            .get_raw(PUSH_SIGNATURE_HEADER)
+            .and_then(|raw| raw.one())
+            .map(|signature| String::from_utf8_lossy(signature).into_owned());
         Box::new(
             future::result(extract_user_id(&headers))
                 .map_err(|e| e.context("Failed to extract user ID").into())
@@ -283,10 +374,19 @@ impl Controller for ControllerImpl {
                                 });
                             }
                             (Get, Some(Route::OrdersByUser { user })) => {
-                                return serialize_future({
-                                    debug!("Received request to get orders for user {}", user);
-                                    (service_factory.order)(login_data).get_orders_for_user(user)
-                                });
+                                let (limit, after) = parse_query!(uri.query().unwrap_or_default(), "limit" => i32, "after" => String);
+                                // Clients that do not paginate keep getting all orders as a plain list
+                                return match OrderPageRequest::from_params(limit, after) {
+                                    Ok(Some(page)) => serialize_future({
+                                        debug!("Received request to get orders for user {}, page {:?}", user, page);
+                                        (service_factory.order)(login_data).get_orders_for_user(user, page)
+                                    }),
+                                    Ok(None) => serialize_future({
+                                        debug!("Received request to get orders for user {}", user);
+                                        (service_factory.order)(login_data).get_all_orders_for_user(user)
+                                    }),
+                                    Err(e) => serialize_future::<String, _, _>(future::err(e)),
+                                };
                             }
                             (Get, Some(Route::Order { order_id })) => {
                                 return serialize_future({
@@ -302,22 +402,48 @@ impl Controller for ControllerImpl {
                             }
                             (Put, Some(Route::OrderStatus { order_id })) => {
                                 return serialize_future({
-                                    parse_body::<UpdateStatePayload>(payload).and_then(move |data| {
+                                    parse_body::<UpdateOrderStatePayload>(payload).and_then(move |payload| {
+                                        let UpdateOrderStatePayload { data, precondition } = payload;
                                         debug!("Received request to set order {:?} status {:?}", order_id, data.state);
-                                        (service_factory.order)(login_data).set_order_state(
+                                        // Committer role of the payload is ignored, the caller may not choose it
+                                        (service_factory.order)(login_data).set_order_state_by_caller(
                                             order_id,
                                             data.state,
                                             data.comment,
                                             data.track_id,
-                                            data.committer_role,
+                                            precondition,
                                         )
                                     })
                                 });
                             }
                             (Post, Some(Route::OrderSearch)) => {
                                 return serialize_future({
-                                    parse_body::<OrderSearchTerms>(payload)
-                                        .and_then(move |terms| (service_factory.order)(login_data).search(terms))
+                                    parse_body::<OrderSearchPayload>(payload).and_then(move |payload| {
+                                        let OrderSearchPayload {
+                                            terms,
+                                            filters,
+                                            limit,
+                                            after,
+                                            sort,
+                                        } = payload;
+                                        let service = (service_factory.order)(login_data);
+                                        // Clients that do not paginate keep getting all found orders as a plain list
+                                        OrderPageRequest::from_params(limit, after)
+                                            .into_future()
+                                            .and_then(move |page| match page {
+                                                Some(page) => Box::new(
+                                                    service
+                                                        .search_page(terms, filters, sort, page)
+                                                        .and_then(|page| serde_json::to_value(page).map_err(From::from)),
+                                                )
+                                                    as ServiceFuture<serde_json::Value>,
+                                                None => Box::new(
+                                                    service
+                                                        .search_all(terms, filters, sort)
+                                                        .and_then(|orders| serde_json::to_value(orders).map_err(From::from)),
+                                                ),
+                                            })
+                                    })
                                 });
                             }
                             (Post, Some(Route::OrderFromCart)) => {
@@ -377,6 +503,267 @@ impl Controller for ControllerImpl {
                             _ => {}
                         };
 
+                        match (method.clone(), service_route) {
+                            (Get, Some(ServiceRoute::SagaNotifications)) => {
+                                let status = parse_query!(uri.query().unwrap_or_default(), "status" => SagaNotificationStatus);
+                                return serialize_future({
+                                    debug!("Received request to get saga notifications with status {:?}", status);
+                                    (service_factory.order)(login_data).get_saga_notifications(status)
+                                });
+                            }
+                            (Post, Some(ServiceRoute::OrderQuote)) => {
+                                return serialize_future({
+                                    parse_body::<QuotePayload>(payload).and_then(move |payload| {
+                                        debug!("Received request to quote checkout");
+                                        let validation = match &payload {
+                                            QuotePayload::Cart(payload) => payload.validate(),
+                                            QuotePayload::BuyNow(payload) => payload.validate(),
+                                        };
+                                        validation
+                                            .map_err(failure::Error::from)
+                                            .context("Failed to validate QuotePayload")
+                                            .context(Error::ParseError)
+                                            .map_err(failure::Error::from)
+                                            .into_future()
+                                            .and_then(move |_| (service_factory.order)(login_data).quote(payload))
+                                    })
+                                });
+                            }
+                            (Get, Some(ServiceRoute::StaleOrders)) => {
+                                let (state, min_age_days) =
+                                    parse_query!(uri.query().unwrap_or_default(), "state" => String, "min_age_days" => i64);
+                                return serialize_future({
+                                    let min_age_days = min_age_days.unwrap_or(stale_order_age_days);
+                                    debug!("Received request to get orders in state {:?} for {} days", state, min_age_days);
+                                    parse_order_state(state).into_future().and_then(move |state| {
+                                        (service_factory.order)(login_data).get_stale_orders(state, ChronoDuration::days(min_age_days))
+                                    })
+                                });
+                            }
+                            (Get, Some(ServiceRoute::Jobs)) => {
+                                return serialize_future({
+                                    debug!("Received request to get jobs");
+                                    (service_factory.job)(login_data).get_jobs()
+                                });
+                            }
+                            (Get, Some(ServiceRoute::JobRuns { job_name })) => {
+                                let limit = parse_query!(uri.query().unwrap_or_default(), "limit" => i64);
+                                return serialize_future({
+                                    debug!("Received request to get runs of job {}, limit {:?}", job_name, limit);
+                                    (service_factory.job)(login_data).get_job_runs(job_name, limit.unwrap_or(DEFAULT_JOB_RUNS_LIMIT))
+                                });
+                            }
+                            (Get, Some(ServiceRoute::OrderLines { order_id })) => {
+                                return serialize_future({
+                                    debug!("Received request to get lines of order {}", order_id);
+                                    (service_factory.order)(login_data).get_order_lines(order_id)
+                                });
+                            }
+                            (Get, Some(ServiceRoute::OrderTaxes { order_id })) => {
+                                return serialize_future({
+                                    debug!("Received request to get taxes of order {}", order_id);
+                                    (service_factory.order)(login_data).get_order_taxes(order_id)
+                                });
+                            }
+                            (Post, Some(ServiceRoute::CancelOrderLine { order_id, line_id })) => {
+                                return serialize_future({
+                                    parse_body::<CancelOrderLinePayload>(payload).and_then(move |payload| {
+                                        debug!("Received request to cancel line {} of order {}", line_id, order_id);
+                                        (service_factory.order)(login_data).cancel_order_line(order_id, line_id, payload.comment)
+                                    })
+                                });
+                            }
+                            (Get, Some(ServiceRoute::OrderReturns { order_id })) => {
+                                return serialize_future({
+                                    debug!("Received request to get returns of order {}", order_id);
+                                    (service_factory.order_return)(login_data).get_returns(order_id)
+                                });
+                            }
+                            (Post, Some(ServiceRoute::OrderReturns { order_id })) => {
+                                return serialize_future({
+                                    parse_body::<NewOrderReturnPayload>(payload).and_then(move |payload| {
+                                        debug!(
+                                            "Received request to return {} units of line {} of order {}",
+                                            payload.quantity, payload.order_line_id, order_id
+                                        );
+                                        (service_factory.order_return)(login_data).create_return(order_id, payload)
+                                    })
+                                });
+                            }
+                            (Get, Some(ServiceRoute::OrderRefunds { order_id })) => {
+                                return serialize_future({
+                                    debug!("Received request to get refunds of order {}", order_id);
+                                    (service_factory.refund)(login_data).get_refunds(order_id)
+                                });
+                            }
+                            (Post, Some(ServiceRoute::OrderRefunds { order_id })) => {
+                                return serialize_future({
+                                    parse_body::<NewRefundPayload>(payload).and_then(move |payload| {
+                                        debug!("Received request to refund {} of order {}", payload.amount, order_id);
+                                        (service_factory.refund)(login_data).create_refund(order_id, payload)
+                                    })
+                                });
+                            }
+                            (Get, Some(ServiceRoute::OrderShipments { order_id })) => {
+                                return serialize_future({
+                                    debug!("Received request to get shipments of order {}", order_id);
+                                    (service_factory.shipment)(login_data).get_shipments(order_id)
+                                });
+                            }
+                            (Post, Some(ServiceRoute::OrderShipments { order_id })) => {
+                                return serialize_future({
+                                    parse_body::<NewShipmentPayload>(payload).and_then(move |payload| {
+                                        debug!(
+                                            "Received request to ship {} units of order {} with {} {}",
+                                            shipped_quantity(&payload.lines),
+                                            order_id,
+                                            payload.carrier,
+                                            payload.tracking_number
+                                        );
+                                        (service_factory.shipment)(login_data).create_shipment(order_id, payload)
+                                    })
+                                });
+                            }
+                            (Put, Some(ServiceRoute::Shipment { shipment_id })) => {
+                                return serialize_future({
+                                    parse_body::<ShipmentUpdatePayload>(payload).and_then(move |payload| {
+                                        debug!("Received request to update shipment {}: {:?}", shipment_id, payload);
+                                        (service_factory.shipment)(login_data).update_shipment(shipment_id, payload)
+                                    })
+                                });
+                            }
+                            (Get, Some(ServiceRoute::OrderTracking { order_id })) => {
+                                return serialize_future({
+                                    debug!("Received request to get tracking of order {}", order_id);
+                                    (service_factory.tracking)(login_data).get_tracking(order_id)
+                                });
+                            }
+                            (Post, Some(ServiceRoute::CarrierTracking)) => {
+                                return serialize_future({
+                                    read_body(payload).map_err(failure::Error::from).and_then(move |body| {
+                                        debug!("Received carrier tracking push");
+                                        serde_json::from_str::<CarrierPush>(&body)
+                                            .map_err(failure::Error::from)
+                                            .context("Failed to parse carrier push")
+                                            .context(Error::ParseError)
+                                            .map_err(failure::Error::from)
+                                            .and_then(|push| {
+                                                // Every carrier signs its pushes with its own secret
+                                                let secret = push_secrets.get(&push.carrier.to_lowercase());
+                                                verify_carrier_push(&push.carrier, secret, push_signature, &body).map(|()| push)
+                                            })
+                                            .into_future()
+                                            .and_then(move |push| {
+                                                // Carriers have no user, the signature authorizes the push
+                                                (service_factory.tracking)(system_login()).push_tracking(
+                                                    push,
+                                                    delivery_rules,
+                                                    carrier_routes,
+                                                )
+                                            })
+                                    })
+                                });
+                            }
+                            (Get, Some(ServiceRoute::OrderReturn { return_id })) => {
+                                return serialize_future({
+                                    debug!("Received request to get return {}", return_id);
+                                    (service_factory.order_return)(login_data).get_return(return_id)
+                                });
+                            }
+                            (Post, Some(ServiceRoute::OrderReturnState { return_id })) => {
+                                return serialize_future({
+                                    parse_body::<SetReturnStatePayload>(payload).and_then(move |payload| {
+                                        debug!("Received request to move return {} to {}", return_id, payload.state);
+                                        (service_factory.order_return)(login_data).set_return_state(return_id, payload)
+                                    })
+                                });
+                            }
+                            (Get, Some(ServiceRoute::OrderReturnDiffs { return_id })) => {
+                                return serialize_future({
+                                    debug!("Received request to get history of return {}", return_id);
+                                    (service_factory.order_return)(login_data).get_return_diffs(return_id)
+                                });
+                            }
+                            (Get, Some(ServiceRoute::DeliveryPricingRules)) => {
+                                return serialize_future({
+                                    debug!("Received request to get delivery pricing rules");
+                                    (service_factory.delivery_pricing)(login_data).get_rules()
+                                });
+                            }
+                            (Post, Some(ServiceRoute::DeliveryPricingRules)) => {
+                                return serialize_future({
+                                    parse_body::<DeliveryPricingRuleInserter>(payload).and_then(move |payload| {
+                                        debug!("Received request to set delivery pricing rule {:?}", payload);
+                                        (service_factory.delivery_pricing)(login_data).set_rule(payload)
+                                    })
+                                });
+                            }
+                            (Delete, Some(ServiceRoute::DeliveryPricingRule { rule_id })) => {
+                                return serialize_future({
+                                    debug!("Received request to delete delivery pricing rule {}", rule_id);
+                                    (service_factory.delivery_pricing)(login_data).delete_rule(rule_id)
+                                });
+                            }
+                            (Get, Some(ServiceRoute::TaxRules)) => {
+                                return serialize_future({
+                                    debug!("Received request to get tax rules");
+                                    (service_factory.tax)(login_data).get_rules()
+                                });
+                            }
+                            (Post, Some(ServiceRoute::TaxRules)) => {
+                                return serialize_future({
+                                    parse_body::<TaxRuleInserter>(payload).and_then(move |payload| {
+                                        debug!("Received request to add tax rule {:?}", payload);
+                                        (service_factory.tax)(login_data).add_rule(payload)
+                                    })
+                                });
+                            }
+                            (Delete, Some(ServiceRoute::TaxRule { rule_id })) => {
+                                return serialize_future({
+                                    debug!("Received request to delete tax rule {}", rule_id);
+                                    (service_factory.tax)(login_data).delete_rule(rule_id)
+                                });
+                            }
+                            (Put, Some(ServiceRoute::ProductTaxCategory { product_id })) => {
+                                return serialize_future({
+                                    parse_body::<SetProductTaxCategoryPayload>(payload).and_then(move |payload| {
+                                        debug!(
+                                            "Received request to set tax category of product {} to {}",
+                                            product_id, payload.tax_category
+                                        );
+                                        (service_factory.tax)(login_data).set_product_category(product_id, payload.tax_category)
+                                    })
+                                });
+                            }
+                            (Delete, Some(ServiceRoute::ProductTaxCategory { product_id })) => {
+                                return serialize_future({
+                                    debug!("Received request to reset tax category of product {}", product_id);
+                                    (service_factory.tax)(login_data).delete_product_category(product_id)
+                                });
+                            }
+                            (Get, Some(ServiceRoute::CouponRules)) => {
+                                return serialize_future({
+                                    debug!("Received request to get coupon rules");
+                                    (service_factory.coupon_rule)(login_data).get_rules()
+                                });
+                            }
+                            (Post, Some(ServiceRoute::CouponRules)) => {
+                                return serialize_future({
+                                    parse_body::<CouponRuleInserter>(payload).and_then(move |payload| {
+                                        debug!("Received request to set coupon rule {:?}", payload);
+                                        (service_factory.coupon_rule)(login_data).set_rule(payload)
+                                    })
+                                });
+                            }
+                            (Delete, Some(ServiceRoute::CouponRule { coupon_id })) => {
+                                return serialize_future({
+                                    debug!("Received request to delete rule of coupon {}", coupon_id);
+                                    (service_factory.coupon_rule)(login_data).delete_rule(coupon_id)
+                                });
+                            }
+                            _ => {}
+                        };
+
                         // Fallback
                         Box::new(future::err(
                             format_err!("Could not route request {} {}", method, uri.path())
@@ -406,3 +793,21 @@ impl Controller for ControllerImpl {
         )
     }
 }
+
+/// Stale orders are the sent ones unless the state is set
+fn parse_order_state(state: Option<String>) -> Fallible<OrderState> {
+    match state {
+        Some(state) => serde_json::from_value(serde_json::Value::String(state.clone()))
+            .map_err(|_| format_err!("Unknown order state {}", state).context(Error::ParseError).into()),
+        None => Ok(OrderState::Sent),
+    }
+}
+
+fn verify_carrier_push(carrier: &str, secret: Option<&String>, signature: Option<String>, body: &str) -> Fallible<()> {
+    let secret = secret.ok_or_else(|| format_err!("Pushes of carrier {} are not configured", carrier).context(Error::Forbidden))?;
+    let signature = signature.ok_or_else(|| format_err!("Missing {} header", PUSH_SIGNATURE_HEADER).context(Error::Forbidden))?;
+
+    verify_push_signature(secret, body, &signature)
+        .context(Error::Forbidden)
+        .map_err(failure::Error::from)
+}
@@ -0,0 +1,185 @@
+use stq_router::RouteParser;
+use stq_types::{CouponId, OrderId, ProductId};
+use uuid::Uuid;
+
+use models::{DeliveryPricingRuleId, OrderLineId, OrderReturnId, ShipmentId, TaxRuleId};
+
+/// Routes served by this service in addition to the ones defined in `stq_api::orders::Route`
+#[derive(Clone, Debug, PartialEq)]
+pub enum ServiceRoute {
+    SagaNotifications,
+    OrderQuote,
+    StaleOrders,
+    Jobs,
+    JobRuns { job_name: String },
+    OrderLines { order_id: OrderId },
+    CancelOrderLine { order_id: OrderId, line_id: OrderLineId },
+    OrderTaxes { order_id: OrderId },
+    OrderReturns { order_id: OrderId },
+    OrderReturn { return_id: OrderReturnId },
+    OrderReturnState { return_id: OrderReturnId },
+    OrderReturnDiffs { return_id: OrderReturnId },
+    OrderRefunds { order_id: OrderId },
+    OrderShipments { order_id: OrderId },
+    Shipment { shipment_id: ShipmentId },
+    OrderTracking { order_id: OrderId },
+    CarrierTracking,
+    DeliveryPricingRules,
+    DeliveryPricingRule { rule_id: DeliveryPricingRuleId },
+    TaxRules,
+    TaxRule { rule_id: TaxRuleId },
+    ProductTaxCategory { product_id: ProductId },
+    CouponRules,
+    CouponRule { coupon_id: CouponId },
+}
+
+pub fn create_route_parser() -> RouteParser<ServiceRoute> {
+    let mut route_parser = RouteParser::default();
+
+    route_parser.add_route(r"^/saga_notifications$", || ServiceRoute::SagaNotifications);
+    route_parser.add_route(r"^/orders/quote$", || ServiceRoute::OrderQuote);
+    route_parser.add_route(r"^/orders/stale$", || ServiceRoute::StaleOrders);
+    route_parser.add_route(r"^/jobs$", || ServiceRoute::Jobs);
+    route_parser.add_route_with_params(r"^/jobs/([a-z_]+)/runs$", |params| {
+        params.get(0).map(|job_name| ServiceRoute::JobRuns {
+            job_name: job_name.to_string(),
+        })
+    });
+
+    route_parser.add_route_with_params(r"^/orders/by-id/([0-9a-fA-F-]{36})/lines$", |params| {
+        params
+            .get(0)
+            .and_then(|order_id| Uuid::parse_str(order_id).ok())
+            .map(|order_id| ServiceRoute::OrderLines {
+                order_id: OrderId(order_id),
+            })
+    });
+    route_parser.add_route_with_params(r"^/orders/by-id/([0-9a-fA-F-]{36})/taxes$", |params| {
+        params
+            .get(0)
+            .and_then(|order_id| Uuid::parse_str(order_id).ok())
+            .map(|order_id| ServiceRoute::OrderTaxes {
+                order_id: OrderId(order_id),
+            })
+    });
+    route_parser.add_route_with_params(r"^/orders/by-id/([0-9a-fA-F-]{36})/returns$", |params| {
+        params
+            .get(0)
+            .and_then(|order_id| Uuid::parse_str(order_id).ok())
+            .map(|order_id| ServiceRoute::OrderReturns {
+                order_id: OrderId(order_id),
+            })
+    });
+    route_parser.add_route_with_params(
+        r"^/orders/by-id/([0-9a-fA-F-]{36})/lines/([0-9a-fA-F-]{36})/cancel$",
+        |params| match (params.get(0), params.get(1)) {
+            (Some(order_id), Some(line_id)) => match (Uuid::parse_str(order_id), Uuid::parse_str(line_id)) {
+                (Ok(order_id), Ok(line_id)) => Some(ServiceRoute::CancelOrderLine {
+                    order_id: OrderId(order_id),
+                    line_id: OrderLineId(line_id),
+                }),
+                _ => None,
+            },
+            _ => None,
+        },
+    );
+
+    route_parser.add_route_with_params(r"^/orders/by-id/([0-9a-fA-F-]{36})/refunds$", |params| {
+        params
+            .get(0)
+            .and_then(|order_id| Uuid::parse_str(order_id).ok())
+            .map(|order_id| ServiceRoute::OrderRefunds {
+                order_id: OrderId(order_id),
+            })
+    });
+    route_parser.add_route_with_params(r"^/orders/by-id/([0-9a-fA-F-]{36})/shipments$", |params| {
+        params
+            .get(0)
+            .and_then(|order_id| Uuid::parse_str(order_id).ok())
+            .map(|order_id| ServiceRoute::OrderShipments {
+                order_id: OrderId(order_id),
+            })
+    });
+    route_parser.add_route_with_params(r"^/orders/by-id/([0-9a-fA-F-]{36})/tracking$", |params| {
+        params
+            .get(0)
+            .and_then(|order_id| Uuid::parse_str(order_id).ok())
+            .map(|order_id| ServiceRoute::OrderTracking {
+                order_id: OrderId(order_id),
+            })
+    });
+    route_parser.add_route(r"^/carrier_tracking$", || ServiceRoute::CarrierTracking);
+    route_parser.add_route_with_params(r"^/returns/([0-9a-fA-F-]{36})$", |params| {
+        params
+            .get(0)
+            .and_then(|return_id| Uuid::parse_str(return_id).ok())
+            .map(|return_id| ServiceRoute::OrderReturn {
+                return_id: OrderReturnId(return_id),
+            })
+    });
+    route_parser.add_route_with_params(r"^/returns/([0-9a-fA-F-]{36})/state$", |params| {
+        params
+            .get(0)
+            .and_then(|return_id| Uuid::parse_str(return_id).ok())
+            .map(|return_id| ServiceRoute::OrderReturnState {
+                return_id: OrderReturnId(return_id),
+            })
+    });
+    route_parser.add_route_with_params(r"^/returns/([0-9a-fA-F-]{36})/diffs$", |params| {
+        params
+            .get(0)
+            .and_then(|return_id| Uuid::parse_str(return_id).ok())
+            .map(|return_id| ServiceRoute::OrderReturnDiffs {
+                return_id: OrderReturnId(return_id),
+            })
+    });
+
+    route_parser.add_route_with_params(r"^/shipments/([0-9a-fA-F-]{36})$", |params| {
+        params
+            .get(0)
+            .and_then(|shipment_id| Uuid::parse_str(shipment_id).ok())
+            .map(|shipment_id| ServiceRoute::Shipment {
+                shipment_id: ShipmentId(shipment_id),
+            })
+    });
+
+    route_parser.add_route(r"^/delivery_pricing_rules$", || ServiceRoute::DeliveryPricingRules);
+    route_parser.add_route_with_params(r"^/delivery_pricing_rules/(\d+)$", |params| {
+        params
+            .get(0)
+            .and_then(|rule_id| rule_id.parse().ok())
+            .map(|rule_id| ServiceRoute::DeliveryPricingRule {
+                rule_id: DeliveryPricingRuleId(rule_id),
+            })
+    });
+
+    route_parser.add_route(r"^/tax_rules$", || ServiceRoute::TaxRules);
+    route_parser.add_route_with_params(r"^/tax_rules/(\d+)$", |params| {
+        params
+            .get(0)
+            .and_then(|rule_id| rule_id.parse().ok())
+            .map(|rule_id| ServiceRoute::TaxRule {
+                rule_id: TaxRuleId(rule_id),
+            })
+    });
+    route_parser.add_route_with_params(r"^/products/(\d+)/tax_category$", |params| {
+        params
+            .get(0)
+            .and_then(|product_id| product_id.parse().ok())
+            .map(|product_id| ServiceRoute::ProductTaxCategory {
+                product_id: ProductId(product_id),
+            })
+    });
+
+    route_parser.add_route(r"^/coupon_rules$", || ServiceRoute::CouponRules);
+    route_parser.add_route_with_params(r"^/coupon_rules/(\d+)$", |params| {
+        params
+            .get(0)
+            .and_then(|coupon_id| coupon_id.parse().ok())
+            .map(|coupon_id| ServiceRoute::CouponRule {
+                coupon_id: CouponId(coupon_id),
+            })
+    });
+
+    route_parser
+}
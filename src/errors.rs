@@ -16,6 +16,12 @@ pub enum Error {
     InvalidRoute,
     #[fail(display = "Server is refusing to fullfil the request")]
     Forbidden,
+    #[fail(display = "Order state transition is not allowed")]
+    ForbiddenStateTransition,
+    #[fail(display = "Order was modified concurrently")]
+    OrderConflict,
+    #[fail(display = "Invalid page cursor")]
+    InvalidCursor,
 }
 
 impl Codeable for Error {
@@ -23,10 +29,11 @@ impl Codeable for Error {
         use self::Error::*;
 
         match self {
-            MissingUserId | UserIdParse | MissingPrice => StatusCode::BadRequest,
+            MissingUserId | UserIdParse | MissingPrice | InvalidCursor => StatusCode::BadRequest,
             ParseError => StatusCode::UnprocessableEntity,
             InvalidRoute => StatusCode::NotFound,
             Forbidden => StatusCode::Forbidden,
+            ForbiddenStateTransition | OrderConflict => StatusCode::Conflict,
         }
     }
 }
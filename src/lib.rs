@@ -10,7 +10,10 @@ extern crate futures;
 extern crate futures_cpupool;
 extern crate futures_state_stream;
 extern crate geo;
+extern crate hex;
+extern crate hmac;
 extern crate hyper;
+extern crate hyper_tls;
 #[macro_use]
 extern crate log as log_crate;
 extern crate postgres;
@@ -19,6 +22,7 @@ extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_json;
+extern crate sha2;
 extern crate stq_acl;
 extern crate stq_api;
 extern crate stq_db;
@@ -62,6 +66,8 @@ pub mod models;
 pub mod repos;
 pub mod sentry_integration;
 pub mod services;
+#[cfg(test)]
+pub mod test_utils;
 pub mod types;
 
 pub use config::*;
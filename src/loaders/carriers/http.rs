@@ -0,0 +1,285 @@
+use failure::Error as FailureError;
+use futures::future;
+use futures::prelude::*;
+use hyper::header::Headers;
+use serde_json::value::Value;
+use tokio_core::reactor::Handle;
+
+use stq_http::client::{Client as HttpClient, ClientHandle as HttpClientHandle, Config as HttpConfig};
+
+use super::{CarrierTracker, DeliveryState, Tracking, TrackingFuture};
+use config::HttpCarrier;
+use models::TrackingActivity;
+
+const TRACKING_NUMBER_PLACEHOLDER: &str = "{tracking_number}";
+
+/// Tracker of carriers with a JSON api answering GET requests, configured in `[[sent_orders.http_carriers]]`
+#[derive(Clone)]
+pub struct HttpTracker {
+    handle: HttpClientHandle,
+    config: HttpCarrier,
+}
+
+impl HttpTracker {
+    pub fn new(handle: &Handle, config: HttpCarrier) -> HttpTracker {
+        let client = HttpClient::new(
+            &HttpConfig {
+                http_client_retries: 3,
+                http_client_buffer_size: 3,
+                timeout_duration_ms: 5000,
+            },
+            handle,
+        );
+        let client_handle = client.handle();
+        handle.spawn(client.stream().for_each(|_| Ok(())));
+        HttpTracker {
+            handle: client_handle,
+            config,
+        }
+    }
+
+    fn headers(&self) -> Headers {
+        let mut headers = Headers::new();
+        for (name, value) in &self.config.headers {
+            headers.set_raw(name.clone(), value.clone());
+        }
+        headers
+    }
+}
+
+impl CarrierTracker for HttpTracker {
+    fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    fn track(&self, tracking_number: String) -> TrackingFuture {
+        let url = match tracking_url(&self.config, &tracking_number) {
+            Ok(url) => url,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let config = self.config.clone();
+
+        Box::new(
+            self.handle
+                .request::<Value>(::hyper::Method::Get, url, None, Some(self.headers()))
+                .map_err(From::from)
+                .map(move |response| Tracking {
+                    state: delivery_state_of(&config, &response),
+                    activities: activities_of(&config, &response),
+                }),
+        )
+    }
+}
+
+fn tracking_url(config: &HttpCarrier, tracking_number: &str) -> Result<String, FailureError> {
+    ensure_plain_tracking_number(tracking_number)?;
+    Ok(config.url.replace(TRACKING_NUMBER_PLACEHOLDER, tracking_number))
+}
+
+/// Looks the status the pointer refers to up in the configured status lists
+fn delivery_state_of(config: &HttpCarrier, response: &Value) -> DeliveryState {
+    let status = match response.pointer(&config.status_pointer) {
+        Some(Value::String(status)) => status.clone(),
+        Some(Value::Null) | None => return DeliveryState::NotDelivered,
+        Some(other) => other.to_string(),
+    };
+
+    let listed = |statuses: &[String]| statuses.iter().any(|listed| listed.eq_ignore_ascii_case(&status));
+
+    if listed(&config.delivered_statuses) {
+        DeliveryState::Delivered
+    } else if listed(&config.returned_statuses) {
+        DeliveryState::ReturnedToSender
+    } else if listed(&config.attempted_statuses) {
+        DeliveryState::DeliveryAttempted
+    } else if listed(&config.exception_statuses) {
+        DeliveryState::Exception
+    } else {
+        DeliveryState::NotDelivered
+    }
+}
+
+/// Activities the events pointer refers to, malformed ones are not recorded
+fn activities_of(config: &HttpCarrier, response: &Value) -> Vec<TrackingActivity> {
+    let events = match config.events_pointer.as_ref().and_then(|pointer| response.pointer(pointer)) {
+        Some(events) => events.clone(),
+        None => return vec![],
+    };
+
+    ::serde_json::from_value(events).unwrap_or_else(|e| {
+        warn!("Failed to read tracking events of carrier {}: {}", config.name, e);
+        vec![]
+    })
+}
+
+/// Tracking numbers are put into urls as is, so they may hold neither separators nor escapes
+pub fn ensure_plain_tracking_number(tracking_number: &str) -> Result<(), FailureError> {
+    if tracking_number.is_empty() || !tracking_number.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        bail!("Tracking number {:?} can not be tracked", tracking_number);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use chrono::prelude::*;
+    use hyper::header::ContentType;
+    use hyper::server::{Http, Request, Response, Service};
+    use hyper::{self, StatusCode};
+    use tokio_core::reactor::Core;
+
+    use super::*;
+
+    const API_KEY_HEADER: &str = "X-Api-Key";
+    const API_KEY: &str = "secret";
+
+    fn carrier(url: String, headers: HashMap<String, String>) -> HttpCarrier {
+        HttpCarrier {
+            name: "stub".to_string(),
+            url,
+            headers,
+            status_pointer: "/shipments/0/status".to_string(),
+            delivered_statuses: vec!["delivered".to_string(), "7".to_string()],
+            exception_statuses: vec!["failure".to_string()],
+            returned_statuses: vec!["returned".to_string()],
+            attempted_statuses: vec![],
+            events_pointer: Some("/shipments/0/events".to_string()),
+        }
+    }
+
+    /// Carrier api answering with JSON to authorized requests for the known tracking number
+    struct StubCarrier;
+
+    impl Service for StubCarrier {
+        type Request = Request;
+        type Response = Response;
+        type Error = hyper::Error;
+        type Future = future::FutureResult<Response, hyper::Error>;
+
+        fn call(&self, request: Request) -> Self::Future {
+            let authorized = request
+                .headers()
+                .get_raw(API_KEY_HEADER)
+                .and_then(|value| value.one())
+                .map(|value| value == API_KEY.as_bytes())
+                .unwrap_or(false);
+
+            let response = match (authorized, request.path()) {
+                (false, _) => Response::new().with_status(StatusCode::Unauthorized),
+                (true, "/track/JD014600003828") => Response::new().with_header(ContentType::json()).with_body(
+                    r#"{"shipments": [{"status": "Delivered", "events": [
+                        {"status_code": "delivered", "description": "Delivered to the receiver", "location": "Berlin, DE",
+                         "occurred_at": "2019-03-02T12:00:00Z"}
+                    ]}]}"#,
+                ),
+                (true, "/track/JD014600003829") => Response::new()
+                    .with_header(ContentType::html())
+                    .with_body("<html>Tracking is under maintenance</html>"),
+                (true, _) => Response::new().with_status(StatusCode::InternalServerError),
+            };
+
+            future::ok(response)
+        }
+    }
+
+    #[test]
+    fn tracks_parcels_with_carrier_api() {
+        let mut core = Core::new().expect("Unexpected error creating event loop core");
+        let handle = core.handle();
+
+        let serve = Http::new()
+            .serve_addr_handle(&"127.0.0.1:0".parse().unwrap(), &handle, || Ok(StubCarrier))
+            .unwrap();
+        let url = format!("http://{}/track/{{tracking_number}}", serve.incoming_ref().local_addr());
+        handle.spawn(
+            serve
+                .for_each({
+                    let handle = handle.clone();
+                    move |conn| {
+                        handle.spawn(conn.map(|_| ()).map_err(|_| ()));
+                        Ok(())
+                    }
+                })
+                .map_err(|_| ()),
+        );
+
+        let mut headers = HashMap::new();
+        headers.insert(API_KEY_HEADER.to_string(), API_KEY.to_string());
+        let tracker = HttpTracker::new(&handle, carrier(url.clone(), headers));
+
+        let tracking = core.run(tracker.track("JD014600003828".to_string())).unwrap();
+        assert_eq!(tracking.state, DeliveryState::Delivered);
+        assert_eq!(
+            tracking.activities,
+            vec![TrackingActivity {
+                status_code: "delivered".to_string(),
+                description: "Delivered to the receiver".to_string(),
+                location: Some("Berlin, DE".to_string()),
+                occurred_at: Some(Utc.ymd(2019, 3, 2).and_hms(12, 0, 0)),
+            }]
+        );
+
+        // Responses that are not JSON fail the tracking
+        assert!(core.run(tracker.track("JD014600003829".to_string())).is_err());
+        // Error statuses of the carrier fail the tracking
+        assert!(core.run(tracker.track("JD014600003830".to_string())).is_err());
+        // Tracking numbers that are not plain are not requested at all
+        assert!(core.run(tracker.track("JD01&lang=de".to_string())).is_err());
+
+        // Configured headers are sent with every request
+        let unauthorized = HttpTracker::new(&handle, carrier(url, HashMap::new()));
+        assert!(core.run(unauthorized.track("JD014600003828".to_string())).is_err());
+    }
+
+    #[test]
+    fn reads_status_of_response() {
+        let config = carrier(
+            "http://localhost:8088/track/{tracking_number}?lang=en".to_string(),
+            Default::default(),
+        );
+
+        assert_eq!(
+            tracking_url(&config, "JD014600003828").unwrap(),
+            "http://localhost:8088/track/JD014600003828?lang=en"
+        );
+        assert!(tracking_url(&config, "JD01&lang=de").is_err());
+        assert!(tracking_url(&config, "").is_err());
+
+        let state = |response: &str| delivery_state_of(&config, &::serde_json::from_str::<Value>(response).unwrap());
+        assert_eq!(state(r#"{"shipments": [{"status": "Delivered"}]}"#), DeliveryState::Delivered);
+        assert_eq!(state(r#"{"shipments": [{"status": 7}]}"#), DeliveryState::Delivered);
+        assert_eq!(state(r#"{"shipments": [{"status": "transit"}]}"#), DeliveryState::NotDelivered);
+        assert_eq!(state(r#"{"shipments": [{"status": "Returned"}]}"#), DeliveryState::ReturnedToSender);
+        assert_eq!(state(r#"{"shipments": [{"status": "failure"}]}"#), DeliveryState::Exception);
+        assert_eq!(state(r#"{"shipments": [{"status": null}]}"#), DeliveryState::NotDelivered);
+        assert_eq!(state(r#"{"shipments": []}"#), DeliveryState::NotDelivered);
+
+        let activities = |response: &str| activities_of(&config, &::serde_json::from_str::<Value>(response).unwrap());
+        assert_eq!(
+            activities(
+                r#"{"shipments": [{"events": [
+                    {"status_code": "transit", "description": "Arrived at the sort facility", "location": "Leipzig, DE",
+                     "occurred_at": "2019-03-01T10:15:00Z"},
+                    {"description": "Label created"}
+                ]}]}"#
+            ),
+            vec![
+                TrackingActivity {
+                    status_code: "transit".to_string(),
+                    description: "Arrived at the sort facility".to_string(),
+                    location: Some("Leipzig, DE".to_string()),
+                    occurred_at: Some(Utc.ymd(2019, 3, 1).and_hms(10, 15, 0)),
+                },
+                TrackingActivity {
+                    description: "Label created".to_string(),
+                    ..Default::default()
+                },
+            ]
+        );
+        assert!(activities(r#"{"shipments": [{"events": "none"}]}"#).is_empty());
+        assert!(activities(r#"{"shipments": []}"#).is_empty());
+    }
+}
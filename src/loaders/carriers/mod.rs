@@ -0,0 +1,156 @@
+//! Carriers tracking sent orders and their shipments
+
+pub mod http;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use failure::Error as FailureError;
+use futures::prelude::*;
+use tokio_core::reactor::Handle;
+
+use config::{route_carrier, CarrierRoute, SentOrders};
+use loaders::dhl::DhlClient;
+use loaders::ups::UpsClient;
+pub use models::DeliveryState;
+use models::TrackingActivity;
+
+use stq_types::CompanyPackageId;
+
+pub use self::http::*;
+
+/// Whether the parcel is delivered along with every activity the carrier reports for it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tracking {
+    pub state: DeliveryState,
+    pub activities: Vec<TrackingActivity>,
+}
+
+impl From<DeliveryState> for Tracking {
+    fn from(state: DeliveryState) -> Self {
+        Self { state, activities: vec![] }
+    }
+}
+
+/// Problem the carrier describes, e.g. in its exception activity
+pub fn problem_state(description: &str) -> DeliveryState {
+    let description = description.to_lowercase();
+    if description.contains("return") {
+        DeliveryState::ReturnedToSender
+    } else if description.contains("attempt") {
+        DeliveryState::DeliveryAttempted
+    } else {
+        DeliveryState::Exception
+    }
+}
+
+pub type TrackingFuture = Box<Future<Item = Tracking, Error = FailureError>>;
+
+/// Carrier api tracking the parcel with the tracking number
+pub trait CarrierTracker {
+    /// Name of the carrier, routes and shipments refer to it
+    fn name(&self) -> &str;
+    fn track(&self, tracking_number: String) -> TrackingFuture;
+}
+
+/// Trackers by carrier name along with the routes of orders to them
+#[derive(Clone)]
+pub struct CarrierRegistry {
+    trackers: HashMap<String, Arc<CarrierTracker>>,
+    routes: Vec<CarrierRoute>,
+}
+
+impl CarrierRegistry {
+    pub fn new(routes: Vec<CarrierRoute>) -> Self {
+        Self {
+            trackers: HashMap::new(),
+            routes,
+        }
+    }
+
+    /// UPS is always registered, other carriers are registered if they are configured
+    pub fn from_config(handle: &Handle, config: Option<&SentOrders>) -> Self {
+        let mut registry = Self::new(config.map(|config| config.carrier_routes.clone()).unwrap_or_default());
+
+        let (access_license_number, url) = config
+            .map(|config| (config.ups_api_access_license_number.clone(), config.ups_api_url.clone()))
+            .unwrap_or_default();
+        registry.register(Arc::new(UpsClient::new(handle, access_license_number, url)));
+
+        if let Some(config) = config {
+            if let Some(ref dhl) = config.dhl {
+                registry.register(Arc::new(DhlClient::new(handle, dhl.api_url.clone(), dhl.api_key.clone())));
+            }
+            for carrier in &config.http_carriers {
+                registry.register(Arc::new(HttpTracker::new(handle, carrier.clone())));
+            }
+        }
+
+        registry
+    }
+
+    /// Replaces the tracker registered with the same name
+    pub fn register(&mut self, tracker: Arc<CarrierTracker>) {
+        self.trackers.insert(tracker.name().to_lowercase(), tracker);
+    }
+
+    pub fn tracker(&self, carrier: &str) -> Option<Arc<CarrierTracker>> {
+        self.trackers.get(&carrier.to_lowercase()).cloned()
+    }
+
+    /// Picks the tracker of the order by its route, see `route_carrier`
+    pub fn tracker_for(&self, delivery_company: Option<&str>, company_package_id: Option<CompanyPackageId>) -> Option<Arc<CarrierTracker>> {
+        self.tracker(route_carrier(&self.routes, delivery_company, company_package_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future;
+
+    use super::*;
+
+    struct FakeTracker(&'static str);
+
+    impl CarrierTracker for FakeTracker {
+        fn name(&self) -> &str {
+            self.0
+        }
+
+        fn track(&self, _tracking_number: String) -> TrackingFuture {
+            Box::new(future::ok(DeliveryState::NotDelivered.into()))
+        }
+    }
+
+    #[test]
+    fn picks_tracker_of_order() {
+        let mut registry = CarrierRegistry::new(vec![
+            CarrierRoute {
+                carrier: "dhl".to_string(),
+                delivery_companies: vec!["DHL Express".to_string()],
+                company_package_ids: vec![4],
+            },
+            CarrierRoute {
+                carrier: "stub".to_string(),
+                delivery_companies: vec![],
+                company_package_ids: vec![5],
+            },
+        ]);
+        registry.register(Arc::new(FakeTracker("ups")));
+        registry.register(Arc::new(FakeTracker("dhl")));
+        registry.register(Arc::new(FakeTracker("stub")));
+
+        let name = |delivery_company: Option<&str>, company_package_id: Option<i32>| {
+            registry
+                .tracker_for(delivery_company, company_package_id.map(CompanyPackageId))
+                .map(|tracker| tracker.name().to_string())
+        };
+
+        assert_eq!(name(None, None), Some("ups".to_string()));
+        assert_eq!(name(Some("UPS"), None), Some("ups".to_string()));
+        assert_eq!(name(Some("dhl express"), None), Some("dhl".to_string()));
+        assert_eq!(name(Some("DHL Express"), Some(5)), Some("stub".to_string()));
+        assert_eq!(name(None, Some(4)), Some("dhl".to_string()));
+        assert_eq!(name(Some("Pony Express"), None), None);
+    }
+}
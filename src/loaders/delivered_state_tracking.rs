@@ -1,28 +1,26 @@
 use std::sync::Arc;
-use std::sync::Mutex;
-use std::time::{Duration, Instant};
+use std::time::Duration;
 
+use chrono::prelude::*;
 use chrono::Duration as ChronoDuration;
 use failure::Error as FailureError;
-use failure::Fail;
 use futures::future;
 use futures::future::Either;
 use futures::prelude::*;
-use tokio::timer::Interval;
 use tokio_core::reactor::Handle;
 
-use super::{SagaClient, SagaService};
+use super::{Loader, LoaderFuture, SagaClient, SagaService, StepStats};
 use config::{self, Config};
-use models::{UserLogin, UserRole};
-use services::{OrderService, OrderServiceImpl};
+use models::*;
+use repos::{self, run_in_transaction};
+use services::{system_login, OrderService, OrderServiceImpl};
 
 use stq_db::pool::Pool as DbPool;
-use stq_roles::models::{RepoLogin, RoleEntry};
-use stq_types::{RoleEntryId, UserId};
+use stq_db::repo::*;
+use stq_types::OrderIdentifier;
 
 #[derive(Clone)]
 pub struct DeliveredStateTracking {
-    busy: Arc<Mutex<bool>>,
     db_pool: DbPool,
     config: Option<config::DeliveredOrders>,
     duration: Duration,
@@ -42,7 +40,6 @@ impl DeliveredStateTracking {
     pub fn new(env: DeliveredStateTrackingEnvironment, handle: Handle) -> DeliveredStateTracking {
         let saga_url = env.config.delivered_orders.clone().map(|o| o.saga_url).unwrap_or_default();
         DeliveredStateTracking {
-            busy: Arc::new(Mutex::new(false)),
             duration: Self::duration(env.config.delivered_orders.as_ref()),
             config: env.config.delivered_orders.clone(),
             db_pool: env.db_pool.clone(),
@@ -50,46 +47,19 @@ impl DeliveredStateTracking {
         }
     }
 
-    pub fn start(self) -> impl Stream<Item = (), Error = FailureError> {
-        info!("DeliveredStateTracking started with config {:?}.", self.config.as_ref());
-        let interval = Interval::new(Instant::now(), self.duration).map_err(|e| e.context("timer creation error").into());
-
-        interval.and_then(move |_| {
-            if let Some(config) = self.config.clone() {
-                let busy = *self.busy.lock().expect("DeliveredStateTracking: poisoned mutex at fetch step");
-                if busy {
-                    warn!("DeliveredStateTracking: tried to ping DeliveredStateTracking, but it was busy");
-                    Either::A(future::ok(()))
-                } else {
-                    Either::B(self.clone().make_step(config))
-                }
-            } else {
-                warn!("DeliveredStateTracking: disabled. Config section [delivered_orders] not set.");
-                Either::A(future::ok(()))
-            }
-        })
-    }
-
-    fn make_step(self, config: config::DeliveredOrders) -> impl Future<Item = (), Error = FailureError> {
-        {
-            let mut busy = self.busy.lock().expect("DeliveredStateTracking: poisoned mutex at fetch step");
-            *busy = true;
-        }
-        let busy = self.busy.clone();
+    fn make_step(self, config: config::DeliveredOrders) -> impl Future<Item = StepStats, Error = FailureError> {
         let saga = self.saga.clone();
+        let db_pool = self.db_pool.clone();
         let max_delivered_state_duration = ChronoDuration::days(config.delivery_state_duration_days);
         let self_clone = self.clone();
-        let service = OrderServiceImpl::new(self_clone.db_pool.clone(), super_user());
+        let service = OrderServiceImpl::new(self_clone.db_pool.clone(), system_login());
+        // Completed orders get Saga notifications that are sent along with the ones failed before
         service
             .track_delivered_orders(max_delivered_state_duration)
-            .map(move |orders| orders.into_iter().map(move |order| saga.set_order_completed(order)))
-            .and_then(::futures::future::join_all)
-            .then(move |res| {
-                let mut busy = busy.lock().expect("DeliveredStateTracking: poisoned mutex at fetch step");
-                *busy = false;
-                res
+            .and_then(move |completed_orders| {
+                let completed = completed_orders.len() as i32;
+                process_saga_notifications(db_pool, saga).map(move |stats| StepStats::new(completed, completed) + stats)
             })
-            .and_then(|_| ::future::ok(()))
     }
 
     fn duration(delivered_orders: Option<&config::DeliveredOrders>) -> Duration {
@@ -100,13 +70,243 @@ impl DeliveredStateTracking {
     }
 }
 
-fn super_user() -> UserLogin {
-    RepoLogin::User {
-        caller_id: UserId(1),
-        caller_roles: vec![RoleEntry {
-            id: RoleEntryId::new(),
-            user_id: UserId(1),
-            role: UserRole::Superadmin,
-        }],
+impl Loader for DeliveredStateTracking {
+    fn name(&self) -> &'static str {
+        "delivered_state_tracking"
+    }
+
+    fn interval(&self) -> Duration {
+        self.duration
+    }
+
+    fn step(&self) -> LoaderFuture {
+        match self.config.clone() {
+            Some(config) => Box::new(self.clone().make_step(config)),
+            None => {
+                warn!("DeliveredStateTracking: disabled. Config section [delivered_orders] not set.");
+                Box::new(future::ok(StepStats::default()))
+            }
+        }
+    }
+}
+
+/// Sends pending notifications to Saga, failed ones are kept pending for the next step
+pub fn process_saga_notifications(db_pool: DbPool, saga: Arc<dyn SagaService>) -> impl Future<Item = StepStats, Error = FailureError> {
+    db_pool
+        .run(move |conn| {
+            repos::saga_notification::make_su_repo().select(
+                conn,
+                SagaNotificationFilter {
+                    do_order: true,
+                    status: Some(SagaNotificationStatus::Pending.into()),
+                    ..Default::default()
+                },
+            )
+        })
+        .map(::futures::stream::iter_ok)
+        .flatten_stream()
+        .and_then(move |notification| notify_saga(db_pool.clone(), saga.clone(), notification))
+        .fold(StepStats::default(), |stats, acknowledged| {
+            future::ok::<_, FailureError>(stats + StepStats::new(1, acknowledged as i32))
+        })
+}
+
+fn notify_saga(
+    db_pool: DbPool,
+    saga: Arc<dyn SagaService>,
+    notification: SagaNotification,
+) -> impl Future<Item = bool, Error = FailureError> {
+    let SagaNotification {
+        id,
+        order_id,
+        kind,
+        refund_id,
+        attempts,
+        ..
+    } = notification;
+    let attempts = attempts + 1;
+    let refund_db_pool = db_pool.clone();
+
+    db_pool
+        .run(move |conn| repos::order::make_su_repo().select(conn, OrderFilter::from(OrderIdentifier::Id(order_id))))
+        .map(|mut orders| orders.pop())
+        .and_then(move |order| -> Box<Future<Item = (), Error = FailureError>> {
+            let order = match order {
+                Some(order) => order.0,
+                None => return Box::new(future::err(format_err!("Order {} not found", order_id))),
+            };
+
+            match (kind, refund_id) {
+                (SagaNotificationKind::SellerPayment, _) => saga.set_order_completed(order),
+                (SagaNotificationKind::Refund, Some(refund_id)) => Box::new(
+                    refund_db_pool
+                        .run(move |conn| {
+                            repos::refund::make_su_repo().select(
+                                conn,
+                                RefundFilter {
+                                    id: Some(refund_id.into()),
+                                    ..Default::default()
+                                },
+                            )
+                        })
+                        .map(|mut refunds| refunds.pop())
+                        .and_then(move |refund| match refund {
+                            Some(refund) => Either::A(saga.set_refund_needed(order, refund)),
+                            None => Either::B(future::err(format_err!("Refund {} not found", refund_id))),
+                        }),
+                ),
+                (SagaNotificationKind::Refund, None) => Box::new(future::err(format_err!("Refund of notification {} is not set", id))),
+            }
+        })
+        .then(move |res| {
+            let acknowledged = res.is_ok();
+            let data = match res {
+                Ok(()) => {
+                    info!("Saga acknowledged {} notification {} of order {}", kind, id, order_id);
+                    SagaNotificationUpdateData {
+                        status: Some(SagaNotificationStatus::Acknowledged),
+                        attempts: Some(attempts),
+                        last_error: Some(None),
+                        acknowledged_at: Some(Utc::now()),
+                    }
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed to send {} notification {} of order {} to Saga, attempt {}: {}",
+                        kind, id, order_id, attempts, e
+                    );
+                    SagaNotificationUpdateData {
+                        attempts: Some(attempts),
+                        last_error: Some(Some(e.to_string())),
+                        ..Default::default()
+                    }
+                }
+            };
+
+            db_pool
+                .run(move |conn| {
+                    run_in_transaction(conn, move |conn| {
+                        repos::saga_notification::make_su_repo()
+                            .update(
+                                conn,
+                                SagaNotificationUpdater {
+                                    mask: SagaNotificationFilter {
+                                        id: Some(id.into()),
+                                        ..Default::default()
+                                    },
+                                    data,
+                                },
+                            )
+                            // Refund keeps the state of its request to Saga
+                            .and_then(move |(_, conn)| -> RepoConnectionFuture<()> {
+                                match refund_id {
+                                    Some(refund_id) if acknowledged => Box::new(
+                                        repos::refund::make_su_repo()
+                                            .update(
+                                                conn,
+                                                RefundUpdater {
+                                                    mask: RefundFilter {
+                                                        id: Some(refund_id.into()),
+                                                        ..Default::default()
+                                                    },
+                                                    data: RefundUpdateData {
+                                                        saga_status: Some(SagaNotificationStatus::Acknowledged),
+                                                    },
+                                                },
+                                            )
+                                            .map(|(_, conn)| ((), conn)),
+                                    ),
+                                    _ => Box::new(future::ok(((), conn))),
+                                }
+                            })
+                    })
+                })
+                .map(move |_| acknowledged)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use stq_api::orders::Order;
+    use stq_types::OrderId;
+    use tokio_core::reactor::Core;
+
+    use test_utils::*;
+
+    /// Saga that fails the first `failures` calls and records acknowledged orders
+    struct FakeSaga {
+        failures: Mutex<u32>,
+        completed: Mutex<Vec<OrderId>>,
+    }
+
+    impl SagaService for FakeSaga {
+        fn set_order_completed(&self, order: Order) -> Box<Future<Item = (), Error = FailureError>> {
+            let mut failures = self.failures.lock().unwrap();
+            if *failures > 0 {
+                *failures -= 1;
+                return Box::new(future::err(format_err!("Saga is unavailable")));
+            }
+
+            self.completed.lock().unwrap().push(order.id);
+            Box::new(future::ok(()))
+        }
+
+        fn set_refund_needed(&self, _order: Order, _refund: Refund) -> Box<Future<Item = (), Error = FailureError>> {
+            Box::new(future::err(format_err!("Refunds are not expected")))
+        }
+    }
+
+    #[test]
+    fn retries_saga_notification_until_acknowledged() {
+        let mut core = Core::new().expect("Unexpected error creating event loop core");
+        let db_pool = create_db_pool(&mut core);
+
+        let order = core
+            .run(db_pool.run(|conn| repos::order::make_su_repo().insert_exactly_one(conn, order_fixture())))
+            .unwrap()
+            .0;
+        let order_id = order.id;
+        core.run(db_pool.run(move |conn| {
+            repos::saga_notification::make_su_repo().insert_exactly_one(conn, SagaNotificationInserter::seller_payment(order_id))
+        }))
+        .unwrap();
+
+        let fake_saga = Arc::new(FakeSaga {
+            failures: Mutex::new(1),
+            completed: Mutex::new(vec![]),
+        });
+        let get_notification = |core: &mut Core| {
+            core.run(db_pool.run(move |conn| {
+                repos::saga_notification::make_su_repo().select(
+                    conn,
+                    SagaNotificationFilter {
+                        order_id: Some(order_id.into()),
+                        ..Default::default()
+                    },
+                )
+            }))
+            .unwrap()
+            .pop()
+            .unwrap()
+        };
+
+        core.run(process_saga_notifications(db_pool.clone(), fake_saga.clone())).unwrap();
+        let notification = get_notification(&mut core);
+        assert_eq!(notification.status, SagaNotificationStatus::Pending);
+        assert_eq!(notification.attempts, 1);
+        assert!(notification.last_error.is_some());
+
+        core.run(process_saga_notifications(db_pool.clone(), fake_saga.clone())).unwrap();
+        let notification = get_notification(&mut core);
+        assert_eq!(notification.status, SagaNotificationStatus::Acknowledged);
+        assert_eq!(notification.attempts, 2);
+        assert!(fake_saga.completed.lock().unwrap().contains(&order_id));
+
+        core.run(db_pool.run(move |conn| repos::order::make_su_repo().delete(conn, OrderFilter::from(OrderIdentifier::Id(order_id)))))
+            .unwrap();
     }
 }
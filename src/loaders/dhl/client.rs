@@ -0,0 +1,132 @@
+use chrono::prelude::*;
+use failure::Error as FailureError;
+use futures::future;
+use futures::prelude::*;
+use hyper::header::Headers;
+use tokio_core::reactor::Handle;
+
+use stq_http::client::{Client as HttpClient, ClientHandle as HttpClientHandle, Config as HttpConfig};
+
+use super::model::*;
+use loaders::carriers::{ensure_plain_tracking_number, problem_state, CarrierTracker, DeliveryState, Tracking, TrackingFuture};
+use models::TrackingActivity;
+
+const CARRIER_NAME: &str = "dhl";
+const API_KEY_HEADER: &str = "DHL-API-Key";
+const DELIVERED_STATUS_CODE: &str = "delivered";
+const FAILURE_STATUS_CODE: &str = "failure";
+
+/// Client of the DHL shipment tracking api
+#[derive(Clone)]
+pub struct DhlClient {
+    handle: HttpClientHandle,
+    url: String,
+    api_key: String,
+}
+
+impl DhlClient {
+    pub fn new(handle: &Handle, url: String, api_key: String) -> DhlClient {
+        let client = HttpClient::new(
+            &HttpConfig {
+                http_client_retries: 3,
+                http_client_buffer_size: 3,
+                timeout_duration_ms: 5000,
+            },
+            handle,
+        );
+        let client_handle = client.handle();
+        handle.spawn(client.stream().for_each(|_| Ok(())));
+        DhlClient {
+            handle: client_handle,
+            url,
+            api_key,
+        }
+    }
+
+    fn headers(&self) -> Headers {
+        let mut headers = Headers::new();
+        headers.set_raw(API_KEY_HEADER, self.api_key.clone());
+        headers
+    }
+}
+
+impl CarrierTracker for DhlClient {
+    fn name(&self) -> &str {
+        CARRIER_NAME
+    }
+
+    fn track(&self, tracking_number: String) -> TrackingFuture {
+        if let Err(e) = ensure_plain_tracking_number(&tracking_number) {
+            return Box::new(future::err(e));
+        }
+
+        Box::new(
+            self.handle
+                .request::<DhlResponse>(
+                    ::hyper::Method::Get,
+                    format!("{}?trackingNumber={}", self.url, tracking_number),
+                    None,
+                    Some(self.headers()),
+                )
+                .map_err(FailureError::from)
+                .map(Tracking::from),
+        )
+    }
+}
+
+impl From<DhlResponse> for Tracking {
+    /// Parcel with several shipments found is delivered once all of them are delivered,
+    /// failure of any of them is the problem of the whole parcel
+    fn from(response: DhlResponse) -> Self {
+        let delivered = !response.shipments.is_empty()
+            && response.shipments.iter().all(|shipment| {
+                shipment
+                    .status
+                    .as_ref()
+                    .and_then(|status| status.status_code.as_ref())
+                    .map(|status_code| status_code == DELIVERED_STATUS_CODE)
+                    .unwrap_or(false)
+            });
+        let failure = response
+            .shipments
+            .iter()
+            .filter_map(|shipment| shipment.status.as_ref())
+            .find(|status| {
+                status
+                    .status_code
+                    .as_ref()
+                    .map(|status_code| status_code == FAILURE_STATUS_CODE)
+                    .unwrap_or(false)
+            });
+        let state = match failure {
+            _ if delivered => DeliveryState::Delivered,
+            Some(status) => problem_state(status.description.as_ref().map(|description| description.as_str()).unwrap_or("")),
+            None => DeliveryState::NotDelivered,
+        };
+
+        let activities = response
+            .shipments
+            .into_iter()
+            .flat_map(|shipment| shipment.events.into_iter().rev())
+            .map(|event| TrackingActivity {
+                status_code: event.status_code.unwrap_or_default(),
+                description: event.description.unwrap_or_default(),
+                location: event
+                    .location
+                    .and_then(|location| location.address)
+                    .and_then(|address| address.address_locality),
+                occurred_at: event.timestamp.and_then(|timestamp| parse_timestamp(&timestamp)),
+            })
+            .collect();
+
+        Tracking { state, activities }
+    }
+}
+
+/// Timestamps without the offset are taken as UTC
+fn parse_timestamp(timestamp: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(timestamp)
+        .map(|timestamp| timestamp.with_timezone(&Utc))
+        .or_else(|_| NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%dT%H:%M:%S").map(|timestamp| DateTime::from_utc(timestamp, Utc)))
+        .ok()
+}
@@ -0,0 +1,4 @@
+pub mod client;
+pub mod model;
+
+pub use self::client::*;
@@ -0,0 +1,37 @@
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DhlResponse {
+    #[serde(default)]
+    pub shipments: Vec<DhlShipment>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DhlShipment {
+    pub id: String,
+    pub status: Option<DhlEvent>,
+    /// Latest events first
+    #[serde(default)]
+    pub events: Vec<DhlEvent>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DhlEvent {
+    /// Local time of the event, with or without the offset
+    pub timestamp: Option<String>,
+    pub location: Option<DhlLocation>,
+    /// One of `pre-transit`, `transit`, `delivered`, `failure` or `unknown`
+    pub status_code: Option<String>,
+    pub description: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DhlLocation {
+    pub address: Option<DhlAddress>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DhlAddress {
+    pub address_locality: Option<String>,
+    pub country_code: Option<String>,
+}
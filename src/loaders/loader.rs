@@ -0,0 +1,283 @@
+use std::ops::Add;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use chrono::prelude::*;
+use failure::Error as FailureError;
+use failure::Fail;
+use futures::future;
+use futures::future::Either;
+use futures::prelude::*;
+use tokio::timer::Interval;
+
+use models::*;
+use repos;
+use repos::raw;
+use sentry_integration::log_and_capture_error;
+
+use stq_db::pool::Pool as DbPool;
+use stq_db::repo::*;
+
+pub type LoaderFuture = Box<Future<Item = StepStats, Error = FailureError>>;
+
+/// Items handled by one step of the loader, recorded in its job run
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StepStats {
+    /// Items looked at
+    pub processed: i32,
+    /// Items actually modified or delivered
+    pub changed: i32,
+}
+
+impl StepStats {
+    pub fn new(processed: i32, changed: i32) -> Self {
+        Self { processed, changed }
+    }
+}
+
+impl Add for StepStats {
+    type Output = StepStats;
+
+    fn add(self, other: StepStats) -> StepStats {
+        StepStats {
+            processed: self.processed + other.processed,
+            changed: self.changed + other.changed,
+        }
+    }
+}
+
+/// Background job run periodically by the worker
+pub trait Loader {
+    /// Unique name of the loader, also names its advisory lock
+    fn name(&self) -> &'static str;
+    /// Time between steps
+    fn interval(&self) -> Duration;
+    /// One iteration of the job
+    fn step(&self) -> LoaderFuture;
+}
+
+/// Sums stats of the items handled independently by the step. A failed item is reported to Sentry
+/// and counted as processed, the step fails once all items are handled if any of them failed.
+pub fn collect_stats<S>(items: S) -> impl Future<Item = StepStats, Error = FailureError>
+where
+    S: Stream<Item = StepStats, Error = FailureError>,
+{
+    items
+        .then(|res| future::ok::<_, FailureError>(res))
+        .fold((StepStats::default(), 0, None), |(stats, failures, first_error), res| {
+            future::ok::<_, FailureError>(match res {
+                Ok(next) => (stats + next, failures, first_error),
+                Err(e) => {
+                    log_and_capture_error(&e);
+                    (stats + StepStats::new(1, 0), failures + 1, first_error.or(Some(e)))
+                }
+            })
+        })
+        .and_then(
+            |(stats, failures, first_error): (StepStats, i32, Option<FailureError>)| match first_error {
+                None => Ok(stats),
+                Some(e) => Err(e.context(format!("{} of {} items failed", failures, stats.processed)).into()),
+            },
+        )
+}
+
+/// Runs steps of the loader on its interval until the event loop stops.
+/// The next step waits for the previous one, failed steps are logged and do not stop the loader.
+/// Every step that is not skipped is recorded in `job_runs`.
+pub fn run_loader(db_pool: DbPool, loader: Box<Loader>) -> impl Future<Item = (), Error = ()> {
+    let name = loader.name();
+    info!("{} started with interval {:?}.", name, loader.interval());
+    let loader: Rc<Loader> = Rc::from(loader);
+
+    Interval::new(Instant::now(), loader.interval())
+        .map_err(|e| e.context("timer creation error").into())
+        .and_then(move |_| {
+            let step = run_step(db_pool.clone(), loader.clone());
+            run_exclusively(&db_pool, name, move || step)
+        })
+        .or_else(move |e| {
+            error!("Error in {}: {:?}.", name, e);
+            future::ok(())
+        })
+        .for_each(|_| future::ok(()))
+}
+
+/// Inserts the job run, makes the step and records its outcome
+fn run_step(db_pool: DbPool, loader: Rc<Loader>) -> impl Future<Item = (), Error = FailureError> {
+    let name = loader.name();
+
+    db_pool
+        .run(move |conn| {
+            repos::job_run::make_su_repo().insert_exactly_one(
+                conn,
+                JobRunInserter {
+                    job_name: name.to_string(),
+                },
+            )
+        })
+        .and_then(move |run| {
+            loader.step().then(move |res| {
+                let data = match res {
+                    Ok(ref stats) => JobRunUpdateData {
+                        finished_at: Some(Utc::now()),
+                        status: Some(JobRunStatus::Succeeded),
+                        items_processed: Some(stats.processed),
+                        items_changed: Some(stats.changed),
+                        ..Default::default()
+                    },
+                    Err(ref e) => JobRunUpdateData {
+                        finished_at: Some(Utc::now()),
+                        status: Some(JobRunStatus::Failed),
+                        error: Some(Some(e.to_string())),
+                        ..Default::default()
+                    },
+                };
+
+                db_pool
+                    .run(move |conn| {
+                        repos::job_run::make_su_repo().update(
+                            conn,
+                            JobRunUpdater {
+                                mask: JobRunFilter {
+                                    id: Some(run.id.into()),
+                                    ..Default::default()
+                                },
+                                data,
+                            },
+                        )
+                    })
+                    .then(move |update_res| {
+                        if let Err(e) = update_res {
+                            error!("{}: failed to record job run {}: {:?}", name, run.id, e);
+                        }
+                        res.map(|_| ())
+                    })
+            })
+        })
+}
+
+/// Runs `f` only if this instance acquires the advisory lock of the loader, skips the step otherwise.
+/// The lock is held by a dedicated connection until `f` completes. If the connection is lost
+/// or the process exits, Postgres releases the lock along with the session.
+/// If the lock can not be released, the session is terminated so the pool does not reuse a connection holding it.
+pub fn run_exclusively<F, U>(db_pool: &DbPool, name: &'static str, f: F) -> impl Future<Item = (), Error = FailureError>
+where
+    F: FnOnce() -> U + 'static,
+    U: IntoFuture<Item = (), Error = FailureError> + 'static,
+{
+    let key = lock_key(name);
+
+    db_pool.run(move |conn| {
+        raw::query(conn, "SELECT pg_try_advisory_lock($1)", vec![Box::new(key)]).and_then(move |(rows, conn)| {
+            let acquired: bool = rows.first().map(|row| row.get(0)).unwrap_or(false);
+            if !acquired {
+                info!("{}: lock is held by another instance, skipping step", name);
+                return Either::A(future::ok(((), conn)));
+            }
+
+            Either::B(f().into_future().then(move |res| {
+                raw::query(conn, "SELECT pg_advisory_unlock($1)", vec![Box::new(key)]).then(move |unlock_res| match unlock_res {
+                    Ok((_, conn)) => Either::A(future::result(match res {
+                        Ok(()) => Ok(((), conn)),
+                        Err(e) => Err((e, conn)),
+                    })),
+                    Err((unlock_error, conn)) => {
+                        error!("{}: failed to release lock, terminating its session: {:?}", name, unlock_error);
+                        let e = match res {
+                            Ok(()) => unlock_error,
+                            Err(e) => e,
+                        };
+                        Either::B(terminate_session(conn).and_then(move |conn| Err((e, conn))))
+                    }
+                })
+            }))
+        })
+    })
+}
+
+/// Closes the server side of the connection, which releases its session locks.
+/// The pool discards the connection once it fails the check on the next checkout.
+fn terminate_session(conn: RepoConnection) -> impl Future<Item = RepoConnection, Error = (RepoError, RepoConnection)> {
+    raw::execute(conn, "SELECT pg_terminate_backend(pg_backend_pid())").or_else(|(e, conn)| {
+        // The server closes the connection while answering, so the query is expected to fail
+        debug!("Session terminated: {:?}", e);
+        Ok(conn)
+    })
+}
+
+/// Advisory lock key of the loader: 64-bit FNV-1a hash of its name, stable across builds and instances
+fn lock_key(name: &str) -> i64 {
+    let hash = name.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    });
+    hash as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use tokio_core::reactor::Core;
+
+    use super::*;
+    use test_utils::*;
+
+    #[test]
+    fn lock_key_is_stable() {
+        assert_eq!(lock_key(""), 0xcbf2_9ce4_8422_2325u64 as i64);
+        assert_eq!(lock_key("a"), 0xaf63_dc4c_8601_ec8cu64 as i64);
+        assert_ne!(lock_key("sent_state_tracking"), lock_key("delivered_state_tracking"));
+    }
+
+    #[test]
+    fn skips_step_while_lock_is_held() {
+        let mut core = Core::new().expect("Unexpected error creating event loop core");
+        let db_pool = create_db_pool(&mut core);
+        let inner_ran = Rc::new(Cell::new(false));
+
+        core.run(run_exclusively(&db_pool, "test_exclusive_loader", {
+            let db_pool = db_pool.clone();
+            let inner_ran = inner_ran.clone();
+            move || {
+                run_exclusively(&db_pool, "test_exclusive_loader", move || {
+                    inner_ran.set(true);
+                    Ok(())
+                })
+            }
+        }))
+        .unwrap();
+        assert!(!inner_ran.get());
+
+        // Lock is released once the step completes
+        let ran = Rc::new(Cell::new(false));
+        core.run(run_exclusively(&db_pool, "test_exclusive_loader", {
+            let ran = ran.clone();
+            move || {
+                ran.set(true);
+                Ok(())
+            }
+        }))
+        .unwrap();
+        assert!(ran.get());
+    }
+
+    #[test]
+    fn releases_lock_after_failed_step() {
+        let mut core = Core::new().expect("Unexpected error creating event loop core");
+        let db_pool = create_db_pool(&mut core);
+
+        let res = core.run(run_exclusively(&db_pool, "test_failing_loader", || Err(format_err!("Step failed"))));
+        assert!(res.is_err());
+
+        let ran = Rc::new(Cell::new(false));
+        core.run(run_exclusively(&db_pool, "test_failing_loader", {
+            let ran = ran.clone();
+            move || {
+                ran.set(true);
+                Ok(())
+            }
+        }))
+        .unwrap();
+        assert!(ran.get());
+    }
+}
@@ -2,8 +2,7 @@ use std::sync::Arc;
 
 use bb8;
 use bb8_postgres::PostgresConnectionManager;
-use futures;
-use futures::future;
+use failure::Error as FailureError;
 use futures::prelude::*;
 use tokio_core::reactor::Core;
 use tokio_core::reactor::Handle;
@@ -12,7 +11,11 @@ use tokio_postgres::TlsMode;
 use config::*;
 use types::*;
 
+mod carriers;
 mod delivered_state_tracking;
+mod dhl;
+mod loader;
+mod order_events;
 mod paid_delivered_report;
 mod s3;
 mod saga;
@@ -20,74 +23,33 @@ mod sent_state_tracking;
 mod ups;
 
 use self::delivered_state_tracking::*;
+pub use self::loader::*;
+pub use self::order_events::*;
 use self::paid_delivered_report::*;
 pub use self::saga::*;
 use self::sent_state_tracking::*;
 
-pub fn start_delivered_state_tracking(config: Config) {
-    let mut core = Core::new().expect("Unexpected error creating event loop core");
-    let handle = core.handle();
-
-    let db_pool = {
-        let manager = PostgresConnectionManager::new(config.db.dsn.clone(), || TlsMode::None).unwrap();
-        let remote = core.remote();
-        DbPool::from(
-            core.run(
-                bb8::Pool::builder()
-                    .min_idle(Some(1))
-                    .build(manager, remote)
-                    .map_err(|e| format_err!("{}", e)),
-            )
-            .expect("Failed to create connection pool"),
-        )
-    };
-    let env = DeliveredStateTrackingEnvironment {
-        db_pool,
-        config: Arc::new(config),
+const ALL_LOADERS: &[LoaderKind] = &[
+    LoaderKind::DeliveredStateTracking,
+    LoaderKind::SentStateTracking,
+    LoaderKind::PaidDeliveredReport,
+    LoaderKind::OrderEvents,
+];
+
+/// Runs loaders listed in the `[worker]` config section, all of them if the section is not set
+pub fn start_worker(config: Config) {
+    let loaders = match config.worker {
+        Some(ref worker) => worker.loaders.clone(),
+        None => ALL_LOADERS.to_vec(),
     };
-    handle.spawn(create_delivered_state_tracking_loader(env, handle.clone()));
 
-    core.run(tokio_signal::ctrl_c().flatten_stream().take(1u64).for_each(|()| {
-        info!("Ctrl+C received. Exit");
-        Ok(())
-    }))
-    .unwrap();
+    start_loaders(config, &loaders);
 }
 
-pub fn start_sent_state_tracking(config: Config) {
+/// Runs the loaders on one event loop sharing one connection pool until Ctrl+C is received
+pub fn start_loaders(config: Config, loaders: &[LoaderKind]) {
     let mut core = Core::new().expect("Unexpected error creating event loop core");
-    let handle = Arc::new(core.handle());
-
-    let db_pool = {
-        let manager = PostgresConnectionManager::new(config.db.dsn.clone(), || TlsMode::None).unwrap();
-        let remote = core.remote();
-        DbPool::from(
-            core.run(
-                bb8::Pool::builder()
-                    .min_idle(Some(1))
-                    .build(manager, remote)
-                    .map_err(|e| format_err!("{}", e)),
-            )
-            .expect("Failed to create connection pool"),
-        )
-    };
-    let env = SentStateTrackingEnvironment {
-        db_pool,
-        config: Arc::new(config),
-        handle: handle.clone(),
-    };
-    handle.spawn(create_sent_state_tracking_loader(env));
-
-    core.run(tokio_signal::ctrl_c().flatten_stream().take(1u64).for_each(|()| {
-        info!("Ctrl+C received. Exit");
-        Ok(())
-    }))
-    .unwrap();
-}
-
-pub fn start_paid_delivered_reporting(config: Config) {
-    let mut core = Core::new().expect("Unexpected error creating event loop core");
-    let handle = Arc::new(core.handle());
+    let handle = core.handle();
 
     let db_pool = {
         let manager = PostgresConnectionManager::new(config.db.dsn.clone(), || TlsMode::None).unwrap();
@@ -102,13 +64,14 @@ pub fn start_paid_delivered_reporting(config: Config) {
             .expect("Failed to create connection pool"),
         )
     };
-    let env = PaidDeliveredReportEnvironment {
-        db_pool,
-        config: Arc::new(config),
-        handle: handle.clone(),
-    };
+    let config = Arc::new(config);
 
-    handle.spawn(create_paid_delivered_report(env));
+    for kind in loaders {
+        match create_loader(*kind, db_pool.clone(), config.clone(), &handle) {
+            Ok(loader) => handle.spawn(run_loader(db_pool.clone(), loader)),
+            Err(e) => error!("Failed to create loader {:?}, it is not started: {:?}.", kind, e),
+        }
+    }
 
     core.run(tokio_signal::ctrl_c().flatten_stream().take(1u64).for_each(|()| {
         info!("Ctrl+C received. Exit");
@@ -117,37 +80,25 @@ pub fn start_paid_delivered_reporting(config: Config) {
     .unwrap();
 }
 
-fn create_paid_delivered_report(env: PaidDeliveredReportEnvironment) -> impl Future<Item = (), Error = ()> {
-    future::result(PaidDeliveredReport::new(env))
-        .map(|loader| loader.start())
-        .flatten_stream()
-        .or_else(|e| {
-            error!("Error in paid delivered report: {:?}.", e);
-            futures::future::ok(())
-        })
-        .for_each(|_| futures::future::ok(()))
-}
-
-fn create_delivered_state_tracking_loader(env: DeliveredStateTrackingEnvironment, handle: Handle) -> impl Future<Item = (), Error = ()> {
-    let loader = DeliveredStateTracking::new(env, handle);
-
-    let stream = loader.start();
-    stream
-        .or_else(|e| {
-            error!("Error in delivered state tracking loader: {:?}.", e);
-            futures::future::ok(())
-        })
-        .for_each(|_| futures::future::ok(()))
-}
-
-fn create_sent_state_tracking_loader(env: SentStateTrackingEnvironment) -> impl Future<Item = (), Error = ()> {
-    let loader = SentStateTracking::new(env);
-
-    let stream = loader.start();
-    stream
-        .or_else(|e| {
-            error!("Error in sent state tracking loader: {:?}.", e);
-            futures::future::ok(())
-        })
-        .for_each(|_| futures::future::ok(()))
+fn create_loader(kind: LoaderKind, db_pool: DbPool, config: Arc<Config>, handle: &Handle) -> Result<Box<Loader>, FailureError> {
+    Ok(match kind {
+        LoaderKind::DeliveredStateTracking => Box::new(DeliveredStateTracking::new(
+            DeliveredStateTrackingEnvironment { db_pool, config },
+            handle.clone(),
+        )),
+        LoaderKind::SentStateTracking => Box::new(SentStateTracking::new(SentStateTrackingEnvironment {
+            db_pool,
+            config,
+            handle: Arc::new(handle.clone()),
+        })),
+        LoaderKind::PaidDeliveredReport => Box::new(PaidDeliveredReport::new(PaidDeliveredReportEnvironment {
+            db_pool,
+            config,
+            handle: Arc::new(handle.clone()),
+        })?),
+        LoaderKind::OrderEvents => Box::new(OrderEventsDispatcher::new(
+            OrderEventsDispatcherEnvironment { db_pool, config },
+            handle.clone(),
+        )),
+    })
 }
@@ -0,0 +1,286 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::prelude::*;
+use chrono::Duration as ChronoDuration;
+use failure::Error as FailureError;
+use failure::Fail;
+use futures::future;
+use futures::prelude::*;
+use hex;
+use hmac::{Hmac, Mac};
+use hyper::client::HttpConnector;
+use hyper::header::{self, Headers};
+use hyper::{Client as HyperClient, Method, Request, Uri};
+use hyper_tls::HttpsConnector;
+use serde_json;
+use sha2::Sha256;
+use tokio_core::reactor::{Handle, Timeout};
+
+use config::{self, Config};
+use loaders::{collect_stats, Loader, LoaderFuture, StepStats};
+use models::*;
+use repos;
+use sentry_integration::log_and_capture_error;
+
+use stq_db::pool::Pool as DbPool;
+use stq_db::repo::*;
+
+/// Hex encoded HMAC-SHA256 of the request body, prefixed with `sha256=`
+pub const SIGNATURE_HEADER: &str = "X-Orders-Signature";
+/// Event id, the same for all delivery attempts of the event
+pub const EVENT_ID_HEADER: &str = "X-Orders-Event-Id";
+
+#[derive(Clone)]
+pub struct OrderEventsDispatcher {
+    db_pool: DbPool,
+    config: Option<config::OrderEvents>,
+    duration: Duration,
+    http_client: HyperClient<HttpsConnector<HttpConnector>>,
+    handle: Handle,
+}
+
+#[derive(Clone)]
+pub struct OrderEventsDispatcherEnvironment {
+    pub db_pool: DbPool,
+    pub config: Arc<Config>,
+}
+
+impl OrderEventsDispatcher {
+    /// Ten seconds
+    const DEFAULT_DURATION: u64 = 10;
+    /// Five seconds
+    const REQUEST_TIMEOUT_MS: u64 = 5000;
+
+    pub fn new(env: OrderEventsDispatcherEnvironment, handle: Handle) -> OrderEventsDispatcher {
+        // Failed deliveries are retried with backoff by the dispatcher itself, so requests are sent once
+        let connector = HttpsConnector::new(4, &handle).expect("Failed to create TLS connector for webhooks");
+        let http_client = HyperClient::configure().connector(connector).build(&handle);
+
+        OrderEventsDispatcher {
+            duration: Self::duration(env.config.order_events.as_ref()),
+            config: env.config.order_events.clone(),
+            db_pool: env.db_pool.clone(),
+            http_client,
+            handle,
+        }
+    }
+
+    fn make_step(self, config: config::OrderEvents) -> impl Future<Item = StepStats, Error = FailureError> {
+        let db_pool = self.db_pool.clone();
+        let batch_size = config.batch_size;
+
+        // Events are dispatched one by one to keep their order for the webhooks
+        let events = db_pool
+            .run(move |conn| repos::order_event::select_due_events(conn, batch_size))
+            .map(::futures::stream::iter_ok)
+            .flatten_stream()
+            .and_then(move |event| self.dispatch(config.clone(), event))
+            .map(|delivered| StepStats::new(1, delivered as i32));
+
+        collect_stats(events)
+    }
+
+    /// Delivers the event and records the result of the attempt, resolves to `true` if the event is delivered
+    fn dispatch(&self, config: config::OrderEvents, event: OrderEvent) -> impl Future<Item = bool, Error = FailureError> {
+        let db_pool = self.db_pool.clone();
+        let event_id = event.id;
+        let attempts = event.attempts + 1;
+        let mut delivered_to = event.delivered_to.clone();
+
+        self.deliver(&config.webhooks, event).then(move |res| {
+            let errors = match res {
+                Ok(results) => {
+                    let mut errors = vec![];
+                    for (url, result) in results {
+                        match result {
+                            Ok(()) => delivered_to.push(url),
+                            Err(e) => errors.push(e),
+                        }
+                    }
+                    errors
+                }
+                Err(e) => vec![e],
+            };
+
+            let delivered = errors.is_empty();
+            let data = if delivered {
+                info!("Order event {} delivered", event_id);
+                OrderEventUpdateData {
+                    status: Some(OrderEventStatus::Delivered),
+                    attempts: Some(attempts),
+                    last_error: Some(None),
+                    delivered_to: Some(delivered_to),
+                    ..Default::default()
+                }
+            } else {
+                let error = errors.iter().map(|e| e.to_string()).collect::<Vec<_>>().join("; ");
+                let status = if attempts >= config.max_attempts {
+                    for e in &errors {
+                        log_and_capture_error(e);
+                    }
+                    error!("Order event {} is moved to dead letters after {} attempts", event_id, attempts);
+                    OrderEventStatus::Failed
+                } else {
+                    warn!("Failed to deliver order event {}, attempt {}: {}", event_id, attempts, error);
+                    OrderEventStatus::Pending
+                };
+
+                OrderEventUpdateData {
+                    status: Some(status),
+                    attempts: Some(attempts),
+                    next_attempt_at: Some(Utc::now() + retry_delay(config.retry_base_delay_s, config.retry_max_delay_s, attempts)),
+                    last_error: Some(Some(error)),
+                    delivered_to: Some(delivered_to),
+                }
+            };
+
+            db_pool
+                .run(move |conn| {
+                    repos::order_event::make_su_repo().update(
+                        conn,
+                        OrderEventUpdater {
+                            mask: OrderEventFilter {
+                                id: Some(event_id.into()),
+                                ..Default::default()
+                            },
+                            data,
+                        },
+                    )
+                })
+                .map(move |_| delivered)
+        })
+    }
+
+    /// Sends the event to every webhook that has not accepted it yet, resolves to the result of every request by webhook URL
+    fn deliver(
+        &self,
+        webhooks: &[config::Webhook],
+        event: OrderEvent,
+    ) -> Box<Future<Item = Vec<(String, Result<(), FailureError>)>, Error = FailureError>> {
+        let event_id = event.id;
+        let delivered_to = event.delivered_to.clone();
+        let body = match serde_json::to_string(&OrderEventMessage::from(event)) {
+            Ok(body) => body,
+            Err(e) => return Box::new(future::err(e.into())),
+        };
+
+        let mut requests = vec![];
+        for webhook in webhooks.iter().filter(|webhook| !delivered_to.contains(&webhook.url)) {
+            let signature = match sign(&webhook.secret, &body) {
+                Ok(signature) => signature,
+                Err(e) => return Box::new(future::err(e)),
+            };
+            let url = webhook.url.clone();
+            requests.push(
+                self.post(&webhook.url, body.clone(), webhook_headers(event_id, signature))
+                    .then(move |res| {
+                        let res: Result<(), FailureError> = res.map_err(|e| e.context(format!("Webhook {} failed", url)).into());
+                        Ok::<_, FailureError>((url, res))
+                    }),
+            );
+        }
+
+        Box::new(future::join_all(requests))
+    }
+
+    /// Posts the body to the webhook, any 2xx answer is an acknowledgement and its body is ignored
+    fn post(&self, url: &str, body: String, headers: Headers) -> Box<Future<Item = (), Error = FailureError>> {
+        let uri = match url.parse::<Uri>() {
+            Ok(uri) => uri,
+            Err(e) => return Box::new(future::err(e.into())),
+        };
+        let mut request = Request::new(Method::Post, uri);
+        *request.headers_mut() = headers;
+        request.set_body(body);
+
+        let timeout = match Timeout::new(Duration::from_millis(Self::REQUEST_TIMEOUT_MS), &self.handle) {
+            Ok(timeout) => timeout,
+            Err(e) => return Box::new(future::err(e.into())),
+        };
+        let response = self.http_client.request(request).map_err(FailureError::from).and_then(|response| {
+            let status = response.status();
+            if status.is_success() {
+                Ok(())
+            } else {
+                Err(format_err!("Unexpected response status {}", status))
+            }
+        });
+        let timeout = timeout
+            .map_err(FailureError::from)
+            .and_then(|()| -> Result<(), FailureError> { Err(format_err!("No response in {} ms", Self::REQUEST_TIMEOUT_MS)) });
+
+        Box::new(response.select(timeout).map(|(res, _)| res).map_err(|(e, _)| e))
+    }
+
+    fn duration(order_events: Option<&config::OrderEvents>) -> Duration {
+        match order_events {
+            Some(config) => Duration::from_secs(config.interval_s),
+            None => Duration::from_secs(OrderEventsDispatcher::DEFAULT_DURATION),
+        }
+    }
+}
+
+impl Loader for OrderEventsDispatcher {
+    fn name(&self) -> &'static str {
+        "order_events"
+    }
+
+    fn interval(&self) -> Duration {
+        self.duration
+    }
+
+    fn step(&self) -> LoaderFuture {
+        match self.config.clone() {
+            Some(config) => Box::new(self.clone().make_step(config)),
+            None => {
+                warn!("OrderEventsDispatcher: disabled. Config section [order_events] not set.");
+                Box::new(future::ok(StepStats::default()))
+            }
+        }
+    }
+}
+
+/// Hex encoded HMAC-SHA256 of the body
+pub fn sign(secret: &str, body: &str) -> Result<String, FailureError> {
+    let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes()).map_err(|_| format_err!("Invalid webhook secret"))?;
+    mac.input(body.as_bytes());
+    Ok(hex::encode(mac.result().code()))
+}
+
+/// Exponential backoff: `base_delay_s` after the first failed attempt, doubled after every next one
+pub fn retry_delay(base_delay_s: i64, max_delay_s: i64, attempts: i32) -> ChronoDuration {
+    let exponent = (attempts - 1).max(0).min(32) as u32;
+    let delay = base_delay_s.saturating_mul(1i64 << exponent).min(max_delay_s);
+    ChronoDuration::seconds(delay)
+}
+
+fn webhook_headers(event_id: OrderEventId, signature: String) -> Headers {
+    let mut headers = Headers::new();
+    headers.set(header::ContentType::json());
+    headers.set_raw(EVENT_ID_HEADER, event_id.to_string());
+    headers.set_raw(SIGNATURE_HEADER, format!("sha256={}", signature));
+    headers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signs_body_with_hmac_sha256() {
+        assert_eq!(
+            sign("secret", r#"{"id":1}"#).unwrap(),
+            "03def589620c813f198fd03d7967e292b163ef0435ebf43071ce0e9519763cb7"
+        );
+    }
+
+    #[test]
+    fn doubles_retry_delay_up_to_max() {
+        assert_eq!(retry_delay(30, 3600, 1), ChronoDuration::seconds(30));
+        assert_eq!(retry_delay(30, 3600, 2), ChronoDuration::seconds(60));
+        assert_eq!(retry_delay(30, 3600, 4), ChronoDuration::seconds(240));
+        assert_eq!(retry_delay(30, 3600, 10), ChronoDuration::seconds(3600));
+        assert_eq!(retry_delay(30, 3600, 1000), ChronoDuration::seconds(3600));
+    }
+}
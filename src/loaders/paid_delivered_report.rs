@@ -1,33 +1,28 @@
 use std::sync::Arc;
-use std::sync::Mutex;
-use std::time::{Duration, Instant};
+use std::time::Duration;
 
 use chrono::prelude::*;
 use csv::Writer;
 use failure::Error as FailureError;
-use failure::Fail;
 use futures::future;
-use futures::future::Either;
 use futures::prelude::*;
 use futures::stream;
-use tokio::timer::Interval;
 use tokio_core::reactor::Handle;
 
 use config::{self, Config};
 use loaders::s3::S3Client;
-use models::{OrderDiffFilter, UserLogin, UserRole};
-use sentry_integration::log_and_capture_error;
-use services::{OrderService, OrderServiceImpl, ServiceFuture};
+use loaders::{collect_stats, Loader, LoaderFuture, StepStats};
+use models::{
+    DeliveryPricing, Money, OrderDiffFilter, OrderLine, OrderLineId, OrderReturnId, OrderWithMeta, Refund, RefundId, SagaNotificationStatus,
+};
+use services::{system_login, OrderService, OrderServiceImpl, RefundService, RefundServiceImpl, ServiceFuture};
 
-use stq_api::orders::Order;
 use stq_db::pool::Pool as DbPool;
-use stq_roles::models::{RepoLogin, RoleEntry};
-use stq_static_resources::{Currency, OrderState};
+use stq_static_resources::{CommitterRole, Currency, OrderState};
 use stq_types::*;
 
 #[derive(Clone)]
 pub struct PaidDeliveredReport {
-    busy: Arc<Mutex<bool>>,
     s3: S3Client,
     db_pool: DbPool,
     config: Option<config::PaidDeliveredReports>,
@@ -41,37 +36,63 @@ pub struct PaidDeliveredReportEnvironment {
     pub handle: Arc<Handle>,
 }
 
+/// Entries uploaded to s3 as a single CSV file
+trait CsvReport {
+    fn file_name(&self) -> String;
+    fn name(&self) -> String;
+    fn len(&self) -> usize;
+    fn into_csv(self) -> Result<Vec<u8>, FailureError>;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
 struct UploadData {
     now: DateTime<Utc>,
     state: OrderState,
-    orders: Vec<Order>,
+    orders: Vec<(OrderWithMeta, Vec<OrderLine>)>,
 }
 
+struct RefundUploadData {
+    now: DateTime<Utc>,
+    refunds: Vec<Refund>,
+}
+
+/// Row of the report, one per active line of the order. Order fields are repeated for every line of it.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct CsvOrder {
     id: OrderId,
-    created_from: CartItemId,
-    conversion_id: ConversionId,
     slug: OrderSlug,
     customer: UserId,
     store: StoreId,
-    product: ProductId,
-    price: ProductPrice,
     currency: Currency,
-    quantity: Quantity,
     receiver_name: String,
     receiver_phone: String,
     receiver_email: String,
     state: OrderState,
-    delivery_company: Option<String>,
     track_id: Option<String>,
+    order_tax_amount: Money,
+    order_total_amount: Money,
+    order_refunded_amount: Money,
+    //line
+    line_id: OrderLineId,
+    line_position: i32,
+    created_from: Option<CartItemId>,
+    product: ProductId,
+    price: Money,
+    quantity: Quantity,
     pre_order: bool,
     pre_order_days: i32,
     coupon_id: Option<CouponId>,
     coupon_percent: Option<i32>,
-    coupon_discount: Option<ProductPrice>,
-    product_discount: Option<ProductPrice>,
-    total_amount: ProductPrice,
+    coupon_discount: Option<Money>,
+    product_discount: Option<Money>,
+    delivery_company: Option<String>,
+    delivery_price: Money,
+    delivery_pricing: DeliveryPricing,
+    tax_amount: Money,
+    total_amount: Money,
     //address
     administrative_area_level_1: Option<String>,
     administrative_area_level_2: Option<String>,
@@ -85,6 +106,22 @@ struct CsvOrder {
     place_id: Option<String>,
 }
 
+#[derive(Debug, Clone, Serialize)]
+struct CsvRefund {
+    id: RefundId,
+    order_id: OrderId,
+    return_id: Option<OrderReturnId>,
+    customer: UserId,
+    store: StoreId,
+    amount: Money,
+    currency: Currency,
+    reason: String,
+    committer: UserId,
+    committer_role: CommitterRole,
+    saga_status: SagaNotificationStatus,
+    created_at: DateTime<Utc>,
+}
+
 impl PaidDeliveredReport {
     /// One hour
     const DEFAULT_DURATION: u64 = 60 * 60;
@@ -99,40 +136,14 @@ impl PaidDeliveredReport {
         };
 
         Ok(PaidDeliveredReport {
-            busy: Arc::new(Mutex::new(false)),
             s3,
-            interval: Self::interval(env.config.paid_delivered_report.as_ref()),
+            interval: Self::duration(env.config.paid_delivered_report.as_ref()),
             config: config.clone(),
             db_pool: env.db_pool.clone(),
         })
     }
 
-    pub fn start(self) -> impl Stream<Item = (), Error = FailureError> {
-        info!("PaidDeliveredReport started with config {:?}", self.config);
-        let interval = Interval::new(Instant::now(), self.interval).map_err(|e| e.context("timer creation error").into());
-
-        interval.and_then(move |_| {
-            if let Some(config) = self.config.clone() {
-                let busy = *self.busy.lock().expect("PaidDeliveredReport: poisoned mutex at fetch step");
-                if busy {
-                    warn!("PaidDeliveredReport: tried to ping PaidDeliveredReport, but it was busy");
-                    Either::A(future::ok(()))
-                } else {
-                    Either::B(self.clone().make_step(config))
-                }
-            } else {
-                warn!("PaidDeliveredReport: disabled. Config section [paid_delivered_report] not set.");
-                Either::A(future::ok(()))
-            }
-        })
-    }
-
-    fn make_step(self, _config: config::PaidDeliveredReports) -> impl Future<Item = (), Error = FailureError> {
-        {
-            let mut busy = self.busy.lock().expect("PaidDeliveredReport: poisoned mutex at fetch step");
-            *busy = true;
-        }
-        let busy = self.busy.clone();
+    fn make_step(self, _config: config::PaidDeliveredReports) -> impl Future<Item = StepStats, Error = FailureError> {
         let now = ::chrono::offset::Utc::now();
         let start_of_yesterday = now.date().pred().and_hms(0, 0, 0);
         let paid_diffs = OrderDiffFilter {
@@ -163,45 +174,40 @@ impl PaidDeliveredReport {
             })
         });
 
-        stream::futures_unordered(vec![
-            Box::new(paid_order_report) as ServiceFuture<()>,
-            Box::new(delivered_order_report) as ServiceFuture<()>,
-        ])
-        .then(|result| match result {
-            Ok(_) => ::future::ok(()),
-            Err(error) => {
-                log_and_capture_error(&error);
-                ::future::ok(())
-            }
-        })
-        .fold((), fold_ok)
-        .then(move |res| {
-            let mut busy = busy.lock().expect("PaidDeliveredReport: poisoned mutex at fetch step");
-            *busy = false;
-            res
-        })
-        .and_then(|_| ::future::ok(()))
+        let self_clone = self.clone();
+        let refund_report = RefundServiceImpl::new(self.db_pool.clone(), system_login())
+            .get_refunds_since(start_of_yesterday)
+            .and_then(move |refunds| self_clone.upload(RefundUploadData { now, refunds }));
+
+        collect_stats(stream::futures_unordered(vec![
+            Box::new(paid_order_report) as ServiceFuture<StepStats>,
+            Box::new(delivered_order_report) as ServiceFuture<StepStats>,
+            Box::new(refund_report) as ServiceFuture<StepStats>,
+        ]))
     }
 
-    fn upload(&self, upload: UploadData) -> impl Future<Item = (), Error = FailureError> {
+    /// Uploads the report if it is not empty
+    fn upload<R: CsvReport>(&self, upload: R) -> impl Future<Item = StepStats, Error = FailureError> {
         let filename = upload.file_name();
         let upload_name = upload.name();
         if upload.is_empty() {
             info!("{} - no entries", upload_name);
-            future::Either::A(future::ok(()))
+            future::Either::A(future::ok(StepStats::new(1, 0)))
         } else {
-            info!("{} - uploading {} entries to s3", upload_name, upload.orders.len());
+            info!("{} - uploading {} entries to s3", upload_name, upload.len());
             let s3 = self.s3.clone();
-            let upload_res = future::result(upload.into_csv()).and_then(move |csv| s3.upload(&filename, csv));
+            let upload_res = future::result(upload.into_csv())
+                .and_then(move |csv| s3.upload(&filename, csv))
+                .map(|_| StepStats::new(1, 1));
             future::Either::B(upload_res)
         }
     }
 
     fn create_service(&self) -> OrderServiceImpl {
-        OrderServiceImpl::new(self.db_pool.clone(), super_user())
+        OrderServiceImpl::new(self.db_pool.clone(), system_login())
     }
 
-    fn interval(config: Option<&config::PaidDeliveredReports>) -> Duration {
+    fn duration(config: Option<&config::PaidDeliveredReports>) -> Duration {
         match config {
             Some(config) => Duration::from_secs(config.interval_s),
             None => Duration::from_secs(Self::DEFAULT_DURATION),
@@ -209,7 +215,27 @@ impl PaidDeliveredReport {
     }
 }
 
-impl UploadData {
+impl Loader for PaidDeliveredReport {
+    fn name(&self) -> &'static str {
+        "paid_delivered_report"
+    }
+
+    fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    fn step(&self) -> LoaderFuture {
+        match self.config.clone() {
+            Some(config) => Box::new(self.clone().make_step(config)),
+            None => {
+                warn!("PaidDeliveredReport: disabled. Config section [paid_delivered_report] not set.");
+                Box::new(future::ok(StepStats::default()))
+            }
+        }
+    }
+}
+
+impl CsvReport for UploadData {
     fn file_name(&self) -> String {
         let start_of_yesterday = self.now.date().pred().and_hms(0, 0, 0);
         let format_string = "%FT%T"; //2018-11-02T07:14:48
@@ -234,70 +260,120 @@ impl UploadData {
 
     fn into_csv(self) -> Result<Vec<u8>, FailureError> {
         let mut writer = Writer::from_writer(Vec::new());
-        for order in self.orders {
-            let csv_order = CsvOrder::from(order);
-            writer.serialize(csv_order)?;
+        for (order, lines) in self.orders {
+            for line in lines {
+                writer.serialize(CsvOrder::new(&order, line))?;
+            }
         }
         let res = writer.into_inner()?;
         Ok(res)
     }
 
-    fn is_empty(&self) -> bool {
-        self.orders.is_empty()
+    fn len(&self) -> usize {
+        self.orders.iter().map(|(_, lines)| lines.len()).sum()
+    }
+}
+
+impl CsvReport for RefundUploadData {
+    fn file_name(&self) -> String {
+        let start_of_yesterday = self.now.date().pred().and_hms(0, 0, 0);
+        let format_string = "%FT%T"; //2018-11-02T07:14:48
+        format!(
+            "refunds_{}_-_{}.csv",
+            start_of_yesterday.format(format_string),
+            self.now.format(format_string),
+        )
+    }
+
+    fn name(&self) -> String {
+        let start_of_yesterday = self.now.date().pred().and_hms(0, 0, 0);
+        let format_string = "%FT%T"; //2018-11-02T07:14:48
+        format!(
+            "refunds {} - {}",
+            start_of_yesterday.format(format_string),
+            self.now.format(format_string),
+        )
+    }
+
+    fn into_csv(self) -> Result<Vec<u8>, FailureError> {
+        let mut writer = Writer::from_writer(Vec::new());
+        for refund in self.refunds {
+            writer.serialize(CsvRefund::from(refund))?;
+        }
+        let res = writer.into_inner()?;
+        Ok(res)
+    }
+
+    fn len(&self) -> usize {
+        self.refunds.len()
     }
 }
 
-impl From<Order> for CsvOrder {
-    fn from(order: Order) -> CsvOrder {
+impl CsvOrder {
+    fn new(order: &OrderWithMeta, line: OrderLine) -> CsvOrder {
+        let OrderWithMeta { ref order, ref meta } = *order;
+        let currency = order.currency;
+        let money = |v: Money| v.round_to(currency);
         CsvOrder {
             id: order.id,
-            created_from: order.created_from,
-            conversion_id: order.conversion_id,
             slug: order.slug,
             customer: order.customer,
             store: order.store,
-            product: order.product,
-            price: order.price,
-            currency: order.currency,
-            quantity: order.quantity,
-            receiver_name: order.receiver_name,
-            receiver_phone: order.receiver_phone,
-            receiver_email: order.receiver_email,
+            currency,
+            receiver_name: order.receiver_name.clone(),
+            receiver_phone: order.receiver_phone.clone(),
+            receiver_email: order.receiver_email.clone(),
             state: order.state,
-            delivery_company: order.delivery_company,
-            track_id: order.track_id,
-            pre_order: order.pre_order,
-            pre_order_days: order.pre_order_days,
-            coupon_id: order.coupon_id,
-            coupon_percent: order.coupon_percent,
-            coupon_discount: order.coupon_discount,
-            product_discount: order.product_discount,
-            total_amount: order.total_amount,
-            administrative_area_level_1: order.address.administrative_area_level_1,
-            administrative_area_level_2: order.address.administrative_area_level_2,
-            country: order.address.country,
-            locality: order.address.locality,
-            political: order.address.political,
-            postal_code: order.address.postal_code,
-            route: order.address.route,
-            street_number: order.address.street_number,
-            address: order.address.address,
-            place_id: order.address.place_id,
+            track_id: order.track_id.clone(),
+            order_tax_amount: money(meta.tax_amount),
+            order_total_amount: money(Money::from(order.total_amount)),
+            order_refunded_amount: money(meta.refunded_amount),
+            line_id: line.id,
+            line_position: line.position,
+            created_from: line.created_from,
+            product: line.product,
+            price: money(line.price),
+            quantity: line.quantity,
+            pre_order: line.pre_order,
+            pre_order_days: line.pre_order_days,
+            coupon_id: line.coupon_id,
+            coupon_percent: line.coupon_percent,
+            coupon_discount: line.coupon_discount.map(money),
+            product_discount: line.product_discount.map(money),
+            delivery_company: line.delivery_company,
+            delivery_price: money(line.delivery_price),
+            delivery_pricing: line.delivery_pricing,
+            tax_amount: money(line.tax_amount),
+            total_amount: money(line.total_amount),
+            administrative_area_level_1: order.address.administrative_area_level_1.clone(),
+            administrative_area_level_2: order.address.administrative_area_level_2.clone(),
+            country: order.address.country.clone(),
+            locality: order.address.locality.clone(),
+            political: order.address.political.clone(),
+            postal_code: order.address.postal_code.clone(),
+            route: order.address.route.clone(),
+            street_number: order.address.street_number.clone(),
+            address: order.address.address.clone(),
+            place_id: order.address.place_id.clone(),
         }
     }
 }
 
-fn super_user() -> UserLogin {
-    RepoLogin::User {
-        caller_id: UserId(1),
-        caller_roles: vec![RoleEntry {
-            id: RoleEntryId::new(),
-            user_id: UserId(1),
-            role: UserRole::Superadmin,
-        }],
+impl From<Refund> for CsvRefund {
+    fn from(refund: Refund) -> CsvRefund {
+        CsvRefund {
+            id: refund.id,
+            order_id: refund.order_id,
+            return_id: refund.return_id,
+            customer: refund.customer,
+            store: refund.store,
+            amount: refund.amount.round_to(refund.currency),
+            currency: refund.currency,
+            reason: refund.reason,
+            committer: refund.committer,
+            committer_role: refund.committer_role,
+            saga_status: refund.saga_status,
+            created_at: refund.created_at,
+        }
     }
 }
-
-fn fold_ok(_acc: (), _next: ()) -> impl Future<Item = (), Error = FailureError> {
-    ::future::ok(())
-}
@@ -4,6 +4,7 @@ use serde_json;
 use tokio_core::reactor::Handle;
 
 use super::*;
+use models::Refund;
 use stq_api::orders::*;
 use stq_http::client::{Client as HttpClient, ClientHandle as HttpClientHandle, Config as HttpConfig};
 
@@ -15,6 +16,8 @@ pub struct SagaClient {
 
 pub trait SagaService {
     fn set_order_completed(&self, order: Order) -> Box<Future<Item = (), Error = FailureError>>;
+    /// Asks Saga to give the refund back to the customer
+    fn set_refund_needed(&self, order: Order, refund: Refund) -> Box<Future<Item = (), Error = FailureError>>;
 }
 
 impl SagaClient {
@@ -42,15 +45,10 @@ impl SagaClient {
     fn request_url(&self, request: &str) -> String {
         format!("{}/{}", self.base_url(), request)
     }
-}
 
-impl SagaService for SagaClient {
-    fn set_order_completed(&self, order: Order) -> Box<Future<Item = (), Error = FailureError>> {
+    fn set_payment_state(&self, order: Order, payload: OrderPaymentStateRequest) -> Box<Future<Item = (), Error = FailureError>> {
         let request_path = format!("orders/{}/set_payment_state", order.id);
         let url = self.request_url(&request_path);
-        let payload = OrderPaymentStateRequest {
-            state: PaymentState::PaymentToSellerNeeded,
-        };
         let self_clone = self.clone();
         Box::new(
             serde_json::to_string(&payload)
@@ -66,3 +64,29 @@ impl SagaService for SagaClient {
         )
     }
 }
+
+impl SagaService for SagaClient {
+    fn set_order_completed(&self, order: Order) -> Box<Future<Item = (), Error = FailureError>> {
+        self.set_payment_state(
+            order,
+            OrderPaymentStateRequest {
+                state: PaymentState::PaymentToSellerNeeded,
+                refund: None,
+            },
+        )
+    }
+
+    fn set_refund_needed(&self, order: Order, refund: Refund) -> Box<Future<Item = (), Error = FailureError>> {
+        self.set_payment_state(
+            order,
+            OrderPaymentStateRequest {
+                state: PaymentState::RefundNeeded,
+                refund: Some(RefundRequest {
+                    id: refund.id,
+                    amount: refund.amount,
+                    currency: refund.currency,
+                }),
+            },
+        )
+    }
+}
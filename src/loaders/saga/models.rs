@@ -1,6 +1,20 @@
-#[derive(Debug, Serialize, Clone, Copy, Eq, PartialEq, Hash)]
+use stq_static_resources::Currency;
+
+use models::{Money, RefundId};
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
 pub struct OrderPaymentStateRequest {
     pub state: PaymentState,
+    /// Set when the customer has to be refunded, the refund may be partial
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refund: Option<RefundRequest>,
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+pub struct RefundRequest {
+    pub id: RefundId,
+    pub amount: Money,
+    pub currency: Currency,
 }
 
 #[derive(Debug, Serialize, Clone, Copy, Eq, PartialEq, Hash)]
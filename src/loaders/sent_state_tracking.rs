@@ -1,31 +1,27 @@
 use std::sync::Arc;
-use std::sync::Mutex;
-use std::time::{Duration, Instant};
+use std::time::Duration;
 
 use chrono::Duration as ChronoDuration;
 use failure::Error as FailureError;
-use failure::Fail;
 use futures::future;
-use futures::future::Either;
 use futures::prelude::*;
-use tokio::timer::Interval;
 use tokio_core::reactor::Handle;
 
 use config::{self, Config};
-use loaders::ups::{DeliveryState, UpsClient};
-use models::{UserLogin, UserRole};
-use sentry_integration::log_and_capture_error;
-use services::{OrderService, OrderServiceImpl};
+use loaders::carriers::{CarrierRegistry, CarrierTracker, Tracking};
+use loaders::{collect_stats, Loader, LoaderFuture, StepStats};
+use models::{DeliveryRule, OrderUpdatePrecondition, Shipment, ShipmentStatus, StaleOrder, TrackedParcel};
+use services::{system_login, OrderService, OrderServiceImpl, ShipmentService, ShipmentServiceImpl, TrackingService, TrackingServiceImpl};
 
+use stq_api::orders::Order;
 use stq_db::pool::Pool as DbPool;
-use stq_roles::models::{RepoLogin, RoleEntry};
 use stq_static_resources::{CommitterRole, OrderState};
-use stq_types::{OrderId, OrderIdentifier, RoleEntryId, UserId};
+use stq_types::OrderIdentifier;
 
 #[derive(Clone)]
 pub struct SentStateTracking {
-    ups_client: UpsClient,
-    busy: Arc<Mutex<bool>>,
+    carriers: CarrierRegistry,
+    rules: Vec<DeliveryRule>,
     db_pool: DbPool,
     config: Option<config::SentOrders>,
     duration: Duration,
@@ -44,112 +40,190 @@ impl SentStateTracking {
 
     pub fn new(env: SentStateTrackingEnvironment) -> SentStateTracking {
         let sent_config = &env.config.sent_orders;
-        let access_license_number = sent_config
-            .as_ref()
-            .map(|config| config.ups_api_access_license_number.as_ref())
-            .unwrap_or("")
-            .to_string();
-        let url = sent_config
-            .as_ref()
-            .map(|config| config.ups_api_url.as_ref())
-            .unwrap_or("")
-            .to_string();
         SentStateTracking {
-            busy: Arc::new(Mutex::new(false)),
             duration: Self::duration(env.config.sent_orders.as_ref()),
             config: sent_config.clone(),
             db_pool: env.db_pool.clone(),
-            ups_client: UpsClient::new(&env.handle, access_license_number, url),
+            carriers: CarrierRegistry::from_config(&env.handle, sent_config.as_ref()),
+            rules: sent_config.as_ref().map(|config| config.valid_delivery_rules()).unwrap_or_default(),
         }
     }
 
-    pub fn start(self) -> impl Stream<Item = (), Error = FailureError> {
-        info!("SentStateTracking started");
-        let interval = Interval::new(Instant::now(), self.duration).map_err(|e| e.context("timer creation error").into());
-
-        interval.and_then(move |_| {
-            if let Some(config) = self.config.clone() {
-                let busy = *self
-                    .busy
-                    .lock()
-                    .expect("SentStateTrackingEnvironment: poisoned mutex at fetch step");
-                if busy {
-                    warn!("SentStateTrackingEnvironment: tried to ping SentStateTrackingEnvironment, but it was busy");
-                    Either::A(future::ok(()))
-                } else {
-                    Either::B(self.clone().make_step(config))
-                }
-            } else {
-                warn!("SentStateTrackingEnvironment: disabled. Config section [sent_orders] not set.");
-                Either::A(future::ok(()))
-            }
-        })
-    }
-
-    fn make_step(self, config: config::SentOrders) -> impl Future<Item = (), Error = FailureError> {
-        {
-            let mut busy = self.busy.lock().expect("SentStateTracking: poisoned mutex at fetch step");
-            *busy = true;
-        }
-        let busy = self.busy.clone();
-
+    fn make_step(self, config: config::SentOrders) -> impl Future<Item = StepStats, Error = FailureError> {
         let service = self.create_service();
-        let ups_client = self.ups_client.clone();
         let now = ::chrono::offset::Utc::now();
-        let from_date = now - ChronoDuration::days(config.sent_state_duration_days);
-        service
+        let tracking_window = ChronoDuration::days(config.sent_state_duration_days);
+        let from_date = now - tracking_window;
+        let loader = self.clone();
+        let orders = service
             .get_orders_with_state(OrderState::Sent, from_date)
             .map(::futures::stream::iter_ok)
             .flatten_stream()
             .inspect(|order| {
                 info!("Process order {} with track_id {:?}", order.id, order.track_id);
             })
-            .filter_map(|order| match order.track_id {
-                Some(track_id) => Some((order.id, track_id)),
-                None => None,
-            })
-            .and_then(move |(order_id, track_id)| Self::delivery_state(ups_client.clone(), order_id, track_id))
-            .filter(|(_order_id, state)| state == &DeliveryState::Delivered)
-            .and_then(move |(order_id, state)| {
-                info!("Change order {} with state {}", order_id, state);
-                let service = self.create_service();
-                service.set_order_state(
-                    OrderIdentifier::Id(order_id),
-                    OrderState::Delivered,
-                    None,
-                    None,
-                    CommitterRole::System,
+            .and_then(move |order| {
+                let self_clone = self.clone();
+                self.create_shipment_service().get_shipments(order.id).and_then(
+                    move |shipments| -> Box<Future<Item = bool, Error = FailureError>> {
+                        if !shipments.is_empty() {
+                            return Box::new(self_clone.track_shipments(shipments));
+                        }
+
+                        // Orders sent in a single box are tracked by the track id of the order
+                        self_clone.track_order(order)
+                    },
                 )
             })
-            .then(|result| match result {
-                Ok(_) => ::future::ok(()),
-                Err(error) => {
-                    log_and_capture_error(&error);
-                    ::future::ok(())
+            .map(|changed| StepStats::new(1, changed as i32));
+
+        // Orders that left the tracking window are not polled anymore, so they are escalated instead
+        let stale_orders = service
+            .get_stale_orders(OrderState::Sent, tracking_window)
+            .map(::futures::stream::iter_ok)
+            .flatten_stream()
+            .and_then(move |stale_order| loader.clone().handle_stale_order(stale_order, config.stale_move.clone()))
+            .map(|changed| StepStats::new(1, changed as i32));
+
+        collect_stats(orders.chain(stale_orders))
+    }
+
+    /// Stale orders are moved once they stay in sent state long enough if it is configured, escalated otherwise
+    fn handle_stale_order(
+        self,
+        stale_order: StaleOrder,
+        stale_move: Option<config::StaleSentMove>,
+    ) -> Box<Future<Item = bool, Error = FailureError>> {
+        let order_id = stale_order.order.id;
+        let age_days = stale_order.age_days;
+
+        match stale_move {
+            Some(ref stale_move) if age_days >= stale_move.after_days => {
+                info!(
+                    "Move order {} staying in sent state for {} days to {:?}",
+                    order_id, age_days, stale_move.state
+                );
+                Box::new(
+                    self.create_service()
+                        .set_order_state(
+                            OrderIdentifier::Id(order_id),
+                            stale_move.state,
+                            Some(format!("Order was in sent state for {} days", age_days)),
+                            None,
+                            CommitterRole::System,
+                            OrderUpdatePrecondition::with_expected_state(OrderState::Sent),
+                        )
+                        .map(|order| order.is_some()),
+                )
+            }
+            _ => Box::new(
+                self.create_tracking_service()
+                    .escalate_stale_order(order_id, stale_order.state_since)
+                    .inspect(move |escalated| {
+                        if *escalated {
+                            warn!("Order {} is in sent state for {} days, it is escalated", order_id, age_days);
+                        }
+                    }),
+            ),
+        }
+    }
+
+    fn track_order(self, order: Order) -> Box<Future<Item = bool, Error = FailureError>> {
+        let track_id = match order.track_id {
+            Some(track_id) => track_id,
+            None => return Box::new(future::ok(false)),
+        };
+        let tracker = self.carriers.tracker_for(
+            order.delivery_company.as_ref().map(|company| company.as_str()),
+            order.company_package_id,
+        );
+        let tracker = match tracker {
+            Some(tracker) => tracker,
+            None => {
+                warn!(
+                    "Order {} sent with {:?} is not tracked, no carrier is configured for it",
+                    order.id, order.delivery_company
+                );
+                return Box::new(future::ok(false));
+            }
+        };
+
+        let parcel = TrackedParcel {
+            order_id: order.id,
+            shipment_id: None,
+            customer: order.customer,
+            store: order.store,
+            carrier: tracker.name().to_string(),
+            tracking_number: track_id,
+        };
+
+        Box::new(self.track(tracker, parcel))
+    }
+
+    /// Marks delivered shipments, the order is delivered along with the last one of them
+    fn track_shipments(self, shipments: Vec<Shipment>) -> impl Future<Item = bool, Error = FailureError> {
+        let carriers = self.carriers.clone();
+        ::futures::stream::iter_ok(
+            shipments
+                .into_iter()
+                .filter(|shipment| shipment.status != ShipmentStatus::Delivered),
+        )
+        .filter_map(move |shipment| {
+            let tracker = carriers.tracker_for(Some(shipment.carrier.as_str()), None);
+            match tracker {
+                Some(tracker) => Some((tracker, shipment)),
+                None => {
+                    warn!(
+                        "Shipment {} of order {} is not tracked, carrier {} is not configured",
+                        shipment.id, shipment.order_id, shipment.carrier
+                    );
+                    None
                 }
-            })
-            .fold((), fold_ok)
-            .then(move |res| {
-                let mut busy = busy.lock().expect("SentStateTracking: poisoned mutex at fetch step");
-                *busy = false;
-                res
-            })
-            .and_then(|_| ::future::ok(()))
+            }
+        })
+        .and_then(move |(tracker, shipment)| {
+            let parcel = TrackedParcel {
+                order_id: shipment.order_id,
+                shipment_id: Some(shipment.id),
+                customer: shipment.customer,
+                store: shipment.store,
+                carrier: shipment.carrier,
+                tracking_number: shipment.tracking_number,
+            };
+            self.clone().track(tracker, parcel)
+        })
+        .fold(false, |changed, shipment_changed| {
+            future::ok::<_, FailureError>(changed || shipment_changed)
+        })
     }
 
-    fn delivery_state(
-        ups_client: UpsClient,
-        order_id: OrderId,
-        track_id: String,
-    ) -> impl Future<Item = (OrderId, DeliveryState), Error = FailureError> {
-        ups_client
-            .delivery_status(track_id)
-            .map_err(From::from)
-            .map(move |delivery_state| (order_id, delivery_state))
+    /// Records the activities the carrier reports for the parcel along the way, then applies its delivery state
+    fn track(self, tracker: Arc<CarrierTracker>, parcel: TrackedParcel) -> impl Future<Item = bool, Error = FailureError> {
+        tracker
+            .track(parcel.tracking_number.clone())
+            .and_then(move |Tracking { state, activities }| {
+                self.create_tracking_service()
+                    .add_tracking_events(parcel.clone(), activities)
+                    .and_then(move |events| {
+                        if !events.is_empty() {
+                            info!("Recorded {} tracking events of order {}", events.len(), events[0].order_id);
+                        }
+                        self.create_tracking_service()
+                            .apply_delivery_state(parcel, state, self.rules.clone())
+                    })
+            })
     }
 
     fn create_service(&self) -> OrderServiceImpl {
-        OrderServiceImpl::new(self.db_pool.clone(), super_user())
+        OrderServiceImpl::new(self.db_pool.clone(), system_login())
+    }
+
+    fn create_shipment_service(&self) -> ShipmentServiceImpl {
+        ShipmentServiceImpl::new(self.db_pool.clone(), system_login())
+    }
+
+    fn create_tracking_service(&self) -> TrackingServiceImpl {
+        TrackingServiceImpl::new(self.db_pool.clone(), system_login())
     }
 
     fn duration(delivered_orders: Option<&config::SentOrders>) -> Duration {
@@ -160,17 +234,22 @@ impl SentStateTracking {
     }
 }
 
-fn fold_ok(_acc: (), _next: ()) -> impl Future<Item = (), Error = FailureError> {
-    ::future::ok(())
-}
+impl Loader for SentStateTracking {
+    fn name(&self) -> &'static str {
+        "sent_state_tracking"
+    }
 
-fn super_user() -> UserLogin {
-    RepoLogin::User {
-        caller_id: UserId(1),
-        caller_roles: vec![RoleEntry {
-            id: RoleEntryId::new(),
-            user_id: UserId(1),
-            role: UserRole::Superadmin,
-        }],
+    fn interval(&self) -> Duration {
+        self.duration
+    }
+
+    fn step(&self) -> LoaderFuture {
+        match self.config.clone() {
+            Some(config) => Box::new(self.clone().make_step(config)),
+            None => {
+                warn!("SentStateTracking: disabled. Config section [sent_orders] not set.");
+                Box::new(future::ok(StepStats::default()))
+            }
+        }
     }
 }
@@ -1,5 +1,6 @@
 use std::fmt;
 
+use chrono::prelude::*;
 use failure::Error as FailureError;
 use futures::future::IntoFuture;
 use futures::prelude::*;
@@ -11,10 +12,15 @@ use tokio_core::reactor::Handle;
 use stq_http::client::{Client as HttpClient, ClientHandle as HttpClientHandle, Config as HttpConfig};
 
 use super::model::*;
+use loaders::carriers::{problem_state, CarrierTracker, DeliveryState, Tracking, TrackingFuture};
+use models::TrackingActivity;
 
+const CARRIER_NAME: &str = "ups";
 const REQUEST_OPTION: &str = "1";
 const CUSTOMER_CONTEXT: &str = "Storiqa";
 const DELIVERED_ACTIVITY_STATE: &str = "D";
+const EXCEPTION_ACTIVITY_STATE: &str = "X";
+const RETURNED_TO_SHIPPER_ACTIVITY_STATE: &str = "RS";
 
 #[derive(Clone)]
 pub struct UpsClient {
@@ -23,12 +29,6 @@ pub struct UpsClient {
     url: String,
 }
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
-pub enum DeliveryState {
-    NotDelivered,
-    Delivered,
-}
-
 #[derive(Debug, Clone)]
 pub struct Error {
     track_id: String,
@@ -54,7 +54,7 @@ impl UpsClient {
         }
     }
 
-    pub fn delivery_status(&self, track_id: String) -> impl Future<Item = DeliveryState, Error = FailureError> {
+    pub fn tracking(&self, track_id: String) -> impl Future<Item = Tracking, Error = FailureError> {
         let self_clone = self.clone();
         request_body(self.access_license_number.clone(), track_id.clone())
             .into_future()
@@ -64,13 +64,23 @@ impl UpsClient {
                     .request::<UpsResponse>(::hyper::Method::Post, self_clone.url.clone(), Some(body), Some(ups_headers()))
                     .map_err(From::from)
             })
-            .and_then(move |res| DeliveryState::try_from_response(track_id, res))
+            .and_then(move |res| Tracking::try_from_ups_response(track_id, res))
             .map_err(From::from)
     }
 }
 
-impl DeliveryState {
-    fn try_from_response(track_id: String, response: UpsResponse) -> Result<Self, FailureError> {
+impl CarrierTracker for UpsClient {
+    fn name(&self) -> &str {
+        CARRIER_NAME
+    }
+
+    fn track(&self, tracking_number: String) -> TrackingFuture {
+        Box::new(self.tracking(tracking_number))
+    }
+}
+
+impl Tracking {
+    fn try_from_ups_response(track_id: String, response: UpsResponse) -> Result<Self, FailureError> {
         if let Some(fault) = response.Fault {
             bail!(Error { track_id, fault });
         }
@@ -80,18 +90,19 @@ impl DeliveryState {
         {
             Some(Value::Array(ser_activities)) => {
                 let activities: Vec<Activity> = ::serde_json::from_value(Value::Array(ser_activities))?;
-                Ok(Self::from_activities(&activities))
+                Ok(Self::from_ups_activities(&activities))
             }
             Some(Value::Object(ser_single_activity)) => {
                 let single_activity: Activity = ::serde_json::from_value(Value::Object(ser_single_activity))?;
-                Ok(Self::from_activities(&[single_activity]))
+                Ok(Self::from_ups_activities(&[single_activity]))
             }
-            _ => Ok(DeliveryState::NotDelivered),
+            _ => Ok(DeliveryState::NotDelivered.into()),
         }
     }
 
-    fn from_activities(activities: &[Activity]) -> DeliveryState {
-        if activities
+    /// UPS lists activities latest first
+    fn from_ups_activities(activities: &[Activity]) -> Tracking {
+        let state = if activities
             .iter()
             .filter_map(|activity| activity.Status.as_ref())
             .filter_map(|status| status.Type.as_ref())
@@ -99,7 +110,60 @@ impl DeliveryState {
         {
             DeliveryState::Delivered
         } else {
-            DeliveryState::NotDelivered
+            // The latest activity tells whether the parcel is still on its way
+            match activities.first().and_then(|activity| activity.Status.as_ref()) {
+                Some(status) => match status.Type.as_ref().map(|type_| type_.as_str()) {
+                    Some(RETURNED_TO_SHIPPER_ACTIVITY_STATE) => DeliveryState::ReturnedToSender,
+                    Some(EXCEPTION_ACTIVITY_STATE) => problem_state(&status.Description),
+                    _ => DeliveryState::NotDelivered,
+                },
+                None => DeliveryState::NotDelivered,
+            }
+        };
+
+        let activities = activities
+            .iter()
+            .rev()
+            .filter_map(|activity| {
+                activity.Status.as_ref().map(|status| TrackingActivity {
+                    status_code: status.Code.clone(),
+                    description: status.Description.clone(),
+                    location: activity.ActivityLocation.as_ref().and_then(ActivityLocation::to_location),
+                    occurred_at: activity.occurred_at(),
+                })
+            })
+            .collect();
+
+        Tracking { state, activities }
+    }
+}
+
+impl ActivityLocation {
+    /// City, region and country that are known, e.g. `ATLANTA, GA, US`
+    fn to_location(&self) -> Option<String> {
+        let address = self.Address.as_ref()?;
+        let parts: Vec<&str> = vec![&address.City, &address.StateProvinceCode, &address.CountryCode]
+            .into_iter()
+            .filter_map(|part| part.as_ref().map(|part| part.trim()))
+            .filter(|part| !part.is_empty())
+            .collect();
+
+        if parts.is_empty() {
+            None
+        } else {
+            Some(parts.join(", "))
+        }
+    }
+}
+
+impl Activity {
+    /// UPS reports local time of the activity without the offset, it is taken as UTC
+    fn occurred_at(&self) -> Option<DateTime<Utc>> {
+        match (self.Date.as_ref(), self.Time.as_ref()) {
+            (Some(date), Some(time)) => NaiveDateTime::parse_from_str(&format!("{}{}", date, time), "%Y%m%d%H%M%S")
+                .ok()
+                .map(|occurred_at| DateTime::from_utc(occurred_at, Utc)),
+            _ => None,
         }
     }
 }
@@ -133,17 +197,88 @@ fn ups_headers() -> Headers {
     headers
 }
 
-impl fmt::Display for DeliveryState {
+impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        match self {
-            DeliveryState::NotDelivered => write!(f, "not delivered"),
-            DeliveryState::Delivered => write!(f, "delivered"),
-        }
+        write!(f, "Api error for {}: {}", self.track_id, self.fault)
     }
 }
 
-impl fmt::Display for Error {
-    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        write!(f, "Api error for {}: {}", self.track_id, self.fault)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_activities_of_response() {
+        let response: UpsResponse = ::serde_json::from_str(
+            r#"{"TrackResponse": {
+                "Response": {
+                    "ResponseStatus": {"Code": "1", "Description": "Success"},
+                    "TransactionReference": {"CustomerContext": "Storiqa"}
+                },
+                "Shipment": {"Package": {"Activity": [
+                    {
+                        "ActivityLocation": {"Address": {"City": "ATLANTA", "StateProvinceCode": "GA", "CountryCode": "US"}},
+                        "Status": {"Type": "D", "Description": "DELIVERED", "Code": "KB"},
+                        "Date": "20190302",
+                        "Time": "143000"
+                    },
+                    {
+                        "Status": {"Type": "M", "Description": "Order Processed: Ready for UPS", "Code": "MP"},
+                        "Date": "20190301"
+                    }
+                ]}}
+            }}"#,
+        )
+        .unwrap();
+
+        let tracking = Tracking::try_from_ups_response("1Z12345E0205271688".to_string(), response).unwrap();
+        assert_eq!(tracking.state, DeliveryState::Delivered);
+        assert_eq!(
+            tracking.activities,
+            vec![
+                TrackingActivity {
+                    status_code: "MP".to_string(),
+                    description: "Order Processed: Ready for UPS".to_string(),
+                    location: None,
+                    occurred_at: None,
+                },
+                TrackingActivity {
+                    status_code: "KB".to_string(),
+                    description: "DELIVERED".to_string(),
+                    location: Some("ATLANTA, GA, US".to_string()),
+                    occurred_at: Some(Utc.ymd(2019, 3, 2).and_hms(14, 30, 0)),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn maps_latest_activity_to_delivery_state() {
+        let state = |type_: &str, description: &str| {
+            let activity: Activity = ::serde_json::from_value(json_activity(type_, description)).unwrap();
+            Tracking::from_ups_activities(&[activity]).state
+        };
+
+        assert_eq!(state("I", "ARRIVAL SCAN"), DeliveryState::NotDelivered);
+        assert_eq!(state("RS", "RETURNED TO SHIPPER"), DeliveryState::ReturnedToSender);
+        assert_eq!(
+            state(
+                "X",
+                "THE RECEIVER WAS NOT AVAILABLE FOR DELIVERY. WE'LL MAKE A SECOND ATTEMPT THE NEXT BUSINESS DAY"
+            ),
+            DeliveryState::DeliveryAttempted
+        );
+        assert_eq!(state("X", "THE ADDRESS HAS BEEN CORRECTED"), DeliveryState::Exception);
+    }
+
+    fn json_activity(type_: &str, description: &str) -> Value {
+        let mut status = ::serde_json::Map::new();
+        status.insert("Type".to_string(), Value::String(type_.to_string()));
+        status.insert("Description".to_string(), Value::String(description.to_string()));
+        status.insert("Code".to_string(), Value::String("XX".to_string()));
+
+        let mut activity = ::serde_json::Map::new();
+        activity.insert("Status".to_string(), Value::Object(status));
+        Value::Object(activity)
     }
 }
@@ -46,7 +46,26 @@ pub struct Package {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[allow(non_snake_case)]
 pub struct Activity {
+    pub ActivityLocation: Option<ActivityLocation>,
     pub Status: Option<Status>,
+    /// `YYYYMMDD`
+    pub Date: Option<String>,
+    /// `HHMMSS`
+    pub Time: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct ActivityLocation {
+    pub Address: Option<Address>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct Address {
+    pub City: Option<String>,
+    pub StateProvinceCode: Option<String>,
+    pub CountryCode: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
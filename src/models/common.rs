@@ -1,4 +1,3 @@
-use chrono::prelude::*;
 use stq_db::statement::*;
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
@@ -17,7 +16,7 @@ impl<T> From<T> for ValueContainer<T> {
     }
 }
 
-pub fn into_range(from: Option<DateTime<Utc>>, to: Option<DateTime<Utc>>) -> Option<ValueContainer<Range<DateTime<Utc>>>> {
+pub fn into_range<T>(from: Option<T>, to: Option<T>) -> Option<ValueContainer<Range<T>>> {
     if let (Some(from), Some(to)) = (from, to) {
         Some(
             Range::Between((
@@ -44,3 +43,64 @@ pub fn into_range(from: Option<DateTime<Utc>>, to: Option<DateTime<Utc>>) -> Opt
         None
     }
 }
+
+fn split_range<T>(range: Option<ValueContainer<Range<T>>>) -> (Option<RangeLimit<T>>, Option<RangeLimit<T>>) {
+    match range.map(|range| range.value) {
+        None => (None, None),
+        Some(Range::From(from)) => (Some(from), None),
+        Some(Range::To(to)) => (None, Some(to)),
+        Some(Range::Between((from, to))) => (Some(from), Some(to)),
+    }
+}
+
+fn join_range<T>(from: Option<RangeLimit<T>>, to: Option<RangeLimit<T>>) -> Option<ValueContainer<Range<T>>> {
+    match (from, to) {
+        (None, None) => None,
+        (Some(from), None) => Some(Range::From(from).into()),
+        (None, Some(to)) => Some(Range::To(to).into()),
+        (Some(from), Some(to)) => Some(Range::Between((from, to)).into()),
+    }
+}
+
+/// Narrows the range so that it ends at `limit` or before it
+pub fn with_upper_limit<T: PartialOrd>(range: Option<ValueContainer<Range<T>>>, limit: RangeLimit<T>) -> Option<ValueContainer<Range<T>>> {
+    let (from, to) = split_range(range);
+    let to = match to {
+        Some(to) => {
+            if to.value < limit.value || (to.value == limit.value && !to.inclusive) {
+                to
+            } else {
+                limit
+            }
+        }
+        None => limit,
+    };
+    join_range(from, Some(to))
+}
+
+/// Narrows the range so that it starts at `limit` or after it
+pub fn with_lower_limit<T: PartialOrd>(range: Option<ValueContainer<Range<T>>>, limit: RangeLimit<T>) -> Option<ValueContainer<Range<T>>> {
+    let (from, to) = split_range(range);
+    let from = match from {
+        Some(from) => {
+            if from.value > limit.value || (from.value == limit.value && !from.inclusive) {
+                from
+            } else {
+                limit
+            }
+        }
+        None => limit,
+    };
+    join_range(Some(from), to)
+}
+
+/// Range containing the only value
+pub fn exact_range<T: Clone>(value: T) -> Option<ValueContainer<Range<T>>> {
+    join_range(
+        Some(RangeLimit {
+            value: value.clone(),
+            inclusive: true,
+        }),
+        Some(RangeLimit { value, inclusive: true }),
+    )
+}
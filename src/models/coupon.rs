@@ -0,0 +1,224 @@
+use std::cmp;
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::prelude::*;
+use failure;
+use tokio_postgres::rows::Row;
+
+use stq_db::statement::*;
+use stq_static_resources::Currency;
+use stq_types::*;
+
+use super::*;
+
+const COUPON_ID_COLUMN: &str = "coupon_id";
+const SCOPE_COLUMN: &str = "scope";
+const FIXED_AMOUNT_COLUMN: &str = "fixed_amount";
+const CURRENCY_COLUMN: &str = "currency";
+const CREATED_AT_COLUMN: &str = "created_at";
+
+/// Products the coupon discount applies to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CouponScope {
+    /// One unit of the product the coupon is attached to
+    Unit,
+    /// All units of the product the coupon is attached to
+    Product,
+    /// All products of the store the coupon is attached to a product of
+    Store,
+    /// All products of the cart in the currency of the product the coupon is attached to
+    Cart,
+}
+
+impl Default for CouponScope {
+    fn default() -> Self {
+        CouponScope::Unit
+    }
+}
+
+impl fmt::Display for CouponScope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::CouponScope::*;
+
+        write!(
+            f,
+            "{}",
+            match self {
+                Unit => "unit",
+                Product => "product",
+                Store => "store",
+                Cart => "cart",
+            }
+        )
+    }
+}
+
+impl FromStr for CouponScope {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use self::CouponScope::*;
+
+        Ok(match s {
+            "unit" => Unit,
+            "product" => Product,
+            "store" => Store,
+            "cart" => Cart,
+            other => bail!("Unknown coupon scope: {}", other),
+        })
+    }
+}
+
+/// Scope and fixed amount of the coupon, the coupon gives its percent discount when the amount is not set
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CouponRule {
+    pub coupon_id: CouponId,
+    pub scope: CouponScope,
+    pub fixed_amount: Option<Money>,
+    /// Currency of the fixed amount
+    pub currency: Option<Currency>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<Row> for CouponRule {
+    fn from(row: Row) -> Self {
+        Self {
+            coupon_id: CouponId(row.get(COUPON_ID_COLUMN)),
+            scope: CouponScope::from_str(row.get(SCOPE_COLUMN)).unwrap(),
+            fixed_amount: row.get(FIXED_AMOUNT_COLUMN),
+            currency: row
+                .get::<Option<String>, _>(CURRENCY_COLUMN)
+                .map(|currency| Currency::from_str(&currency).unwrap()),
+            created_at: row.get(CREATED_AT_COLUMN),
+        }
+    }
+}
+
+/// Rules of the coupons being used
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CouponRules(pub Vec<CouponRule>);
+
+impl CouponRules {
+    fn rule_for(&self, coupon_id: CouponId) -> Option<&CouponRule> {
+        self.0.iter().find(|rule| rule.coupon_id == coupon_id)
+    }
+
+    /// Coupons without rules apply to one unit of the product
+    pub fn scope_of(&self, coupon_id: CouponId) -> CouponScope {
+        self.rule_for(coupon_id).map(|rule| rule.scope).unwrap_or_default()
+    }
+
+    /// Discount the coupon gives on `amount` in minor units of the currency.
+    /// Fixed discounts apply in their own currency only and never exceed the amount.
+    pub fn discount(&self, coupon_id: CouponId, percent: i32, amount: Money, currency: Currency) -> Money {
+        match self.rule_for(coupon_id) {
+            Some(CouponRule {
+                fixed_amount: Some(fixed_amount),
+                currency: fixed_currency,
+                ..
+            }) => {
+                if *fixed_currency == Some(currency) {
+                    cmp::min(fixed_amount.round_to(currency), amount)
+                } else {
+                    Money::zero()
+                }
+            }
+            _ => amount.percent(percent).round_to(currency),
+        }
+    }
+}
+
+/// Splits the discount between the amounts in proportion to them. Shares are rounded to minor units of the currency
+/// by the largest remainder, so the shares always sum up to the discount.
+pub fn allocate_discount(discount: Money, amounts: &[Money], currency: Currency) -> Vec<Money> {
+    discount.allocate(amounts, minor_units(currency))
+}
+
+/// Sets the scope of the coupon, replacing its previous rule
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct CouponRuleInserter {
+    pub coupon_id: CouponId,
+    pub scope: CouponScope,
+    pub fixed_amount: Option<Money>,
+    pub currency: Option<Currency>,
+}
+
+impl CouponRuleInserter {
+    pub fn validate(&self) -> Result<(), failure::Error> {
+        match (self.fixed_amount, self.currency) {
+            (None, None) => Ok(()),
+            (Some(fixed_amount), Some(_)) if fixed_amount > Money::zero() => Ok(()),
+            (Some(fixed_amount), Some(_)) => bail!("Fixed coupon amount has to be positive, got {}", fixed_amount),
+            _ => bail!("Fixed coupon amount has to be set along with its currency"),
+        }
+    }
+}
+
+impl Inserter for CouponRuleInserter {
+    fn into_insert_builder(self, table: &'static str) -> InsertBuilder {
+        InsertBuilder::new(table)
+            .with_arg(COUPON_ID_COLUMN, self.coupon_id.0)
+            .with_arg(SCOPE_COLUMN, self.scope.to_string())
+            .with_arg(FIXED_AMOUNT_COLUMN, self.fixed_amount)
+            .with_arg(CURRENCY_COLUMN, self.currency.map(|v| v.to_string()))
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CouponRuleFilter {
+    pub coupon_id: Option<ValueContainer<CouponId>>,
+}
+
+impl Filter for CouponRuleFilter {
+    fn into_filtered_operation_builder(self, table: &'static str) -> FilteredOperationBuilder {
+        let mut b = FilteredOperationBuilder::new(table);
+
+        if let Some(v) = self.coupon_id {
+            b = b.with_filter(COUPON_ID_COLUMN, v.value.0);
+        }
+
+        b
+    }
+}
+
+pub struct DummyCouponRuleUpdater {}
+impl Updater for DummyCouponRuleUpdater {
+    fn into_update_builder(self, _table: &'static str) -> UpdateBuilder {
+        unreachable!()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixed_discount_is_allocated_in_proportion() {
+        let money = |v: &str| v.parse::<Money>().unwrap();
+        let rules = CouponRules(vec![CouponRule {
+            coupon_id: CouponId(1),
+            scope: CouponScope::Store,
+            fixed_amount: Some(money("10")),
+            currency: Some(Currency::RUB),
+            created_at: Utc::now(),
+        }]);
+
+        assert_eq!(rules.scope_of(CouponId(1)), CouponScope::Store);
+        assert_eq!(rules.scope_of(CouponId(2)), CouponScope::Unit);
+        assert_eq!(rules.discount(CouponId(1), 50, money("300"), Currency::RUB), money("10"));
+        assert_eq!(rules.discount(CouponId(1), 50, money("4"), Currency::RUB), money("4"));
+        assert_eq!(rules.discount(CouponId(1), 50, money("300"), Currency::USD), Money::zero());
+        assert_eq!(rules.discount(CouponId(2), 50, money("0.05"), Currency::RUB), money("0.03"));
+
+        let shares = allocate_discount(money("10"), &[money("100"), money("100"), money("100")], Currency::RUB);
+        assert_eq!(shares, vec![money("3.34"), money("3.33"), money("3.33")]);
+
+        let shares = allocate_discount(money("10"), &[money("50"), money("150")], Currency::RUB);
+        assert_eq!(shares, vec![money("2.5"), money("7.5")]);
+
+        let shares = allocate_discount(money("10"), &[Money::zero()], Currency::RUB);
+        assert_eq!(shares, vec![Money::zero()]);
+    }
+}
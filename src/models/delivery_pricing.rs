@@ -0,0 +1,230 @@
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::prelude::*;
+use failure;
+use tokio_postgres::rows::Row;
+
+use stq_db::statement::*;
+use stq_types::*;
+
+use super::Money;
+
+const ID_COLUMN: &str = "id";
+const STORE_COLUMN: &str = "store";
+const COMPANY_PACKAGE_ID_COLUMN: &str = "company_package_id";
+const PRICING_COLUMN: &str = "pricing";
+const CREATED_AT_COLUMN: &str = "created_at";
+
+/// How the delivery price of the product is charged
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryPricing {
+    /// For every unit of the product
+    PerUnit,
+    /// Once for all products of the order shipped in the same package
+    PerPackage,
+}
+
+impl Default for DeliveryPricing {
+    fn default() -> Self {
+        DeliveryPricing::PerUnit
+    }
+}
+
+impl fmt::Display for DeliveryPricing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::DeliveryPricing::*;
+
+        write!(
+            f,
+            "{}",
+            match self {
+                PerUnit => "per_unit",
+                PerPackage => "per_package",
+            }
+        )
+    }
+}
+
+impl FromStr for DeliveryPricing {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use self::DeliveryPricing::*;
+
+        Ok(match s {
+            "per_unit" => PerUnit,
+            "per_package" => PerPackage,
+            other => bail!("Unknown delivery pricing: {}", other),
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct DeliveryPricingRuleId(pub i32);
+
+impl fmt::Display for DeliveryPricingRuleId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Delivery pricing of either the store or the package
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DeliveryPricingRule {
+    pub id: DeliveryPricingRuleId,
+    pub store: Option<StoreId>,
+    pub company_package_id: Option<CompanyPackageId>,
+    pub pricing: DeliveryPricing,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<Row> for DeliveryPricingRule {
+    fn from(row: Row) -> Self {
+        Self {
+            id: DeliveryPricingRuleId(row.get(ID_COLUMN)),
+            store: row.get::<Option<i32>, _>(STORE_COLUMN).map(StoreId),
+            company_package_id: row.get::<Option<i32>, _>(COMPANY_PACKAGE_ID_COLUMN).map(CompanyPackageId),
+            pricing: DeliveryPricing::from_str(row.get(PRICING_COLUMN)).unwrap(),
+            created_at: row.get(CREATED_AT_COLUMN),
+        }
+    }
+}
+
+/// Rules applicable to the products being ordered
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DeliveryPricingRules(pub Vec<DeliveryPricingRule>);
+
+impl DeliveryPricingRules {
+    /// Rule of the package takes precedence over the rule of the store, delivery is charged per unit by default
+    pub fn pricing_for(&self, store: StoreId, company_package_id: Option<CompanyPackageId>) -> DeliveryPricing {
+        let package_rule = company_package_id
+            .and_then(|company_package_id| self.0.iter().find(|rule| rule.company_package_id == Some(company_package_id)));
+        let store_rule = || self.0.iter().find(|rule| rule.store == Some(store));
+
+        package_rule.or_else(store_rule).map(|rule| rule.pricing).unwrap_or_default()
+    }
+}
+
+/// Delivery charged for the product unless it is shared with another product of the order
+pub fn delivery_amount(quantity: Quantity, delivery_price: Money, delivery_pricing: DeliveryPricing) -> Money {
+    match delivery_pricing {
+        DeliveryPricing::PerUnit => delivery_price * quantity.0,
+        DeliveryPricing::PerPackage if quantity.0 > 0 => delivery_price,
+        DeliveryPricing::PerPackage => Money::zero(),
+    }
+}
+
+/// Lines charged for delivery per package share the charge if they are shipped in the same package
+pub fn share_delivery(
+    pricing: DeliveryPricing,
+    company_package_id: Option<CompanyPackageId>,
+    other_pricing: DeliveryPricing,
+    other_company_package_id: Option<CompanyPackageId>,
+) -> bool {
+    pricing == DeliveryPricing::PerPackage
+        && other_pricing == DeliveryPricing::PerPackage
+        && company_package_id.is_some()
+        && company_package_id == other_company_package_id
+}
+
+/// Sets delivery pricing of either the store or the package, replacing the previous rule
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct DeliveryPricingRuleInserter {
+    pub store: Option<StoreId>,
+    pub company_package_id: Option<CompanyPackageId>,
+    pub pricing: DeliveryPricing,
+}
+
+impl DeliveryPricingRuleInserter {
+    /// Filter matching the rule this one replaces
+    pub fn replaced_rule_filter(&self) -> Result<DeliveryPricingRuleFilter, failure::Error> {
+        match (self.store, self.company_package_id) {
+            (Some(store), None) => Ok(DeliveryPricingRuleFilter {
+                store: Some(store.into()),
+                ..Default::default()
+            }),
+            (None, Some(company_package_id)) => Ok(DeliveryPricingRuleFilter {
+                company_package_id: Some(company_package_id.into()),
+                ..Default::default()
+            }),
+            _ => bail!("Delivery pricing rule has to be set for either the store or the package"),
+        }
+    }
+}
+
+impl Inserter for DeliveryPricingRuleInserter {
+    fn into_insert_builder(self, table: &'static str) -> InsertBuilder {
+        InsertBuilder::new(table)
+            .with_arg(STORE_COLUMN, self.store.map(|v| v.0))
+            .with_arg(COMPANY_PACKAGE_ID_COLUMN, self.company_package_id.map(|v| v.0))
+            .with_arg(PRICING_COLUMN, self.pricing.to_string())
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct DeliveryPricingRuleFilter {
+    pub id: Option<ValueContainer<DeliveryPricingRuleId>>,
+    pub store: Option<ValueContainer<StoreId>>,
+    pub company_package_id: Option<ValueContainer<CompanyPackageId>>,
+}
+
+impl Filter for DeliveryPricingRuleFilter {
+    fn into_filtered_operation_builder(self, table: &'static str) -> FilteredOperationBuilder {
+        let mut b = FilteredOperationBuilder::new(table);
+
+        if let Some(v) = self.id {
+            b = b.with_filter(ID_COLUMN, v.value.0);
+        }
+
+        if let Some(v) = self.store {
+            b = b.with_filter(STORE_COLUMN, v.value.0);
+        }
+
+        if let Some(v) = self.company_package_id {
+            b = b.with_filter(COMPANY_PACKAGE_ID_COLUMN, v.value.0);
+        }
+
+        b
+    }
+}
+
+pub struct DummyDeliveryPricingRuleUpdater {}
+impl Updater for DummyDeliveryPricingRuleUpdater {
+    fn into_update_builder(self, _table: &'static str) -> UpdateBuilder {
+        unreachable!()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(store: Option<StoreId>, company_package_id: Option<CompanyPackageId>, pricing: DeliveryPricing) -> DeliveryPricingRule {
+        DeliveryPricingRule {
+            id: DeliveryPricingRuleId(1),
+            store,
+            company_package_id,
+            pricing,
+            created_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn package_rule_takes_precedence() {
+        let rules = DeliveryPricingRules(vec![
+            rule(Some(StoreId(1)), None, DeliveryPricing::PerPackage),
+            rule(None, Some(CompanyPackageId(10)), DeliveryPricing::PerUnit),
+            rule(None, Some(CompanyPackageId(20)), DeliveryPricing::PerPackage),
+        ]);
+
+        assert_eq!(rules.pricing_for(StoreId(1), None), DeliveryPricing::PerPackage);
+        assert_eq!(rules.pricing_for(StoreId(1), Some(CompanyPackageId(10))), DeliveryPricing::PerUnit);
+        assert_eq!(
+            rules.pricing_for(StoreId(2), Some(CompanyPackageId(20))),
+            DeliveryPricing::PerPackage
+        );
+        assert_eq!(rules.pricing_for(StoreId(2), Some(CompanyPackageId(30))), DeliveryPricing::PerUnit);
+    }
+}
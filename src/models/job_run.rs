@@ -0,0 +1,185 @@
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::prelude::*;
+use failure;
+use tokio_postgres::rows::Row;
+
+use stq_db::statement::*;
+
+const ID_COLUMN: &str = "id";
+const JOB_NAME_COLUMN: &str = "job_name";
+const STARTED_AT_COLUMN: &str = "started_at";
+const FINISHED_AT_COLUMN: &str = "finished_at";
+const STATUS_COLUMN: &str = "status";
+const ITEMS_PROCESSED_COLUMN: &str = "items_processed";
+const ITEMS_CHANGED_COLUMN: &str = "items_changed";
+const ERROR_COLUMN: &str = "error";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct JobRunId(pub i64);
+
+impl fmt::Display for JobRunId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobRunStatus {
+    Running,
+    Succeeded,
+    Failed,
+}
+
+impl fmt::Display for JobRunStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::JobRunStatus::*;
+
+        write!(
+            f,
+            "{}",
+            match self {
+                Running => "running",
+                Succeeded => "succeeded",
+                Failed => "failed",
+            }
+        )
+    }
+}
+
+impl FromStr for JobRunStatus {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use self::JobRunStatus::*;
+
+        Ok(match s {
+            "running" => Running,
+            "succeeded" => Succeeded,
+            "failed" => Failed,
+            other => bail!("Unknown job run status: {}", other),
+        })
+    }
+}
+
+/// One step of a background loader
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct JobRun {
+    pub id: JobRunId,
+    pub job_name: String,
+    pub started_at: DateTime<Utc>,
+    /// Not set while the step is running
+    pub finished_at: Option<DateTime<Utc>>,
+    pub status: JobRunStatus,
+    pub items_processed: i32,
+    pub items_changed: i32,
+    pub error: Option<String>,
+}
+
+impl From<Row> for JobRun {
+    fn from(row: Row) -> Self {
+        Self {
+            id: JobRunId(row.get(ID_COLUMN)),
+            job_name: row.get(JOB_NAME_COLUMN),
+            started_at: row.get(STARTED_AT_COLUMN),
+            finished_at: row.get(FINISHED_AT_COLUMN),
+            status: JobRunStatus::from_str(row.get(STATUS_COLUMN)).unwrap(),
+            items_processed: row.get(ITEMS_PROCESSED_COLUMN),
+            items_changed: row.get(ITEMS_CHANGED_COLUMN),
+            error: row.get(ERROR_COLUMN),
+        }
+    }
+}
+
+/// Background job with its latest runs
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Job {
+    pub name: String,
+    pub last_run: JobRun,
+    /// `None` if the job has never succeeded
+    pub last_success: Option<JobRun>,
+}
+
+pub struct JobRunInserter {
+    pub job_name: String,
+}
+
+impl Inserter for JobRunInserter {
+    fn into_insert_builder(self, table: &'static str) -> InsertBuilder {
+        InsertBuilder::new(table).with_arg(JOB_NAME_COLUMN, self.job_name)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct JobRunFilter {
+    pub id: Option<ValueContainer<JobRunId>>,
+    pub job_name: Option<ValueContainer<String>>,
+    pub status: Option<ValueContainer<JobRunStatus>>,
+}
+
+impl Filter for JobRunFilter {
+    fn into_filtered_operation_builder(self, table: &'static str) -> FilteredOperationBuilder {
+        let mut b = FilteredOperationBuilder::new(table);
+
+        if let Some(v) = self.id {
+            b = b.with_filter(ID_COLUMN, v.value.0);
+        }
+
+        if let Some(v) = self.job_name {
+            b = b.with_filter(JOB_NAME_COLUMN, v.value);
+        }
+
+        if let Some(v) = self.status {
+            b = b.with_filter(STATUS_COLUMN, v.value.to_string());
+        }
+
+        b
+    }
+}
+
+/// Outcome of the finished step
+#[derive(Clone, Debug, Default)]
+pub struct JobRunUpdateData {
+    pub finished_at: Option<DateTime<Utc>>,
+    pub status: Option<JobRunStatus>,
+    pub items_processed: Option<i32>,
+    pub items_changed: Option<i32>,
+    pub error: Option<Option<String>>,
+}
+
+pub struct JobRunUpdater {
+    pub mask: JobRunFilter,
+    pub data: JobRunUpdateData,
+}
+
+impl Updater for JobRunUpdater {
+    fn into_update_builder(self, table: &'static str) -> UpdateBuilder {
+        let JobRunUpdater { mask, data } = self;
+
+        let mut b = UpdateBuilder::from(mask.into_filtered_operation_builder(table));
+
+        if let Some(finished_at) = data.finished_at {
+            b = b.with_value(FINISHED_AT_COLUMN, finished_at);
+        }
+
+        if let Some(status) = data.status {
+            b = b.with_value(STATUS_COLUMN, status.to_string());
+        }
+
+        if let Some(items_processed) = data.items_processed {
+            b = b.with_value(ITEMS_PROCESSED_COLUMN, items_processed);
+        }
+
+        if let Some(items_changed) = data.items_changed {
+            b = b.with_value(ITEMS_CHANGED_COLUMN, items_changed);
+        }
+
+        if let Some(error) = data.error {
+            b = b.with_value(ERROR_COLUMN, error);
+        }
+
+        b
+    }
+}
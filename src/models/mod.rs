@@ -4,11 +4,62 @@ pub use self::common::*;
 pub mod cart_item;
 pub use self::cart_item::*;
 
+pub mod money;
+pub use self::money::*;
+
+pub mod coupon;
+pub use self::coupon::*;
+
+pub mod delivery_pricing;
+pub use self::delivery_pricing::*;
+
+pub mod tax;
+pub use self::tax::*;
+
 pub mod order;
 pub use self::order::*;
 
+pub mod order_page;
+pub use self::order_page::*;
+
 pub mod order_diff;
 pub use self::order_diff::*;
 
+pub mod order_line;
+pub use self::order_line::*;
+
+pub mod order_return;
+pub use self::order_return::*;
+
+pub mod refund;
+pub use self::refund::*;
+
+pub mod shipment;
+pub use self::shipment::*;
+
+pub mod tracking_event;
+pub use self::tracking_event::*;
+
+pub mod stale_order;
+pub use self::stale_order::*;
+
+pub mod quote;
+pub use self::quote::*;
+
 pub mod roles;
 pub use self::roles::*;
+
+pub mod order_state;
+pub use self::order_state::*;
+
+pub mod rejected_order_transition;
+pub use self::rejected_order_transition::*;
+
+pub mod order_event;
+pub use self::order_event::*;
+
+pub mod saga_notification;
+pub use self::saga_notification::*;
+
+pub mod job_run;
+pub use self::job_run::*;
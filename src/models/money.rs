@@ -0,0 +1,479 @@
+use std::error::Error as StdError;
+use std::fmt;
+use std::ops::{Add, Mul, Sub};
+use std::str::FromStr;
+
+use failure;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tokio_postgres::types::{FromSql, IsNull, ToSql, Type};
+
+use stq_static_resources::{Currency, CurrencyType};
+use stq_types::ProductPrice;
+
+/// Fixed-point amount of money in 10^-18 of the currency unit, enough for the smallest units of crypto currencies.
+/// Serialized as a JSON number like `ProductPrice`, stored as `NUMERIC(38, 18)`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Money(i128);
+
+/// Decimal places of the currency used for prices and totals
+pub fn minor_units(currency: Currency) -> u32 {
+    match currency.currency_type() {
+        CurrencyType::Fiat => 2,
+        CurrencyType::Crypto => Money::SCALE,
+    }
+}
+
+/// Divides rounding half away from zero
+fn div_round(value: i128, divisor: i128) -> i128 {
+    let quotient = value / divisor;
+    let remainder = value % divisor;
+    if remainder.abs() * 2 >= divisor.abs() {
+        quotient + value.signum() * divisor.signum()
+    } else {
+        quotient
+    }
+}
+
+/// `a * b / d` rounded down along with the remainder. The product is kept in 256 bits,
+/// the quotient is expected to fit in 128 bits.
+fn mul_div(a: u128, b: u128, d: u128) -> (u128, u128) {
+    const LOW: u128 = 0xFFFF_FFFF_FFFF_FFFF;
+
+    let (a_high, a_low) = (a >> 64, a & LOW);
+    let (b_high, b_low) = (b >> 64, b & LOW);
+    let low_low = a_low * b_low;
+    let high_low = a_high * b_low;
+    let low_high = a_low * b_high;
+    let cross = (low_low >> 64) + (high_low & LOW) + (low_high & LOW);
+    let low = (cross << 64) | (low_low & LOW);
+    let high = a_high * b_high + (high_low >> 64) + (low_high >> 64) + (cross >> 64);
+
+    let mut quotient = 0u128;
+    let mut remainder = 0u128;
+    for position in (0..256).rev() {
+        let bit = if position >= 128 {
+            (high >> (position - 128)) & 1
+        } else {
+            (low >> position) & 1
+        };
+        // Remainder is less than `d`, its bit shifted out still counts
+        let carry = remainder >> 127;
+        remainder = (remainder << 1) | bit;
+        quotient <<= 1;
+        if carry == 1 || remainder >= d {
+            remainder = remainder.wrapping_sub(d);
+            quotient |= 1;
+        }
+    }
+
+    (quotient, remainder)
+}
+
+/// Parses the decimal into units, digits below `SCALE` are rounded if `round` is set and rejected otherwise
+fn parse_units(s: &str, round: bool) -> Result<i128, failure::Error> {
+    let (negative, digits) = match s.chars().next() {
+        Some('-') => (true, &s[1..]),
+        _ => (false, s),
+    };
+    let mut parts = digits.splitn(2, '.');
+    let whole = parts.next().unwrap_or_default();
+    let fraction = parts.next().unwrap_or_default();
+    if whole.is_empty() || !whole.chars().chain(fraction.chars()).all(|c| c.is_ascii_digit()) {
+        bail!("Invalid amount of money: {}", s);
+    }
+
+    let scale = Money::SCALE as usize;
+    let (kept, rest) = if fraction.len() > scale {
+        if !round {
+            bail!("Amount of money has more than {} decimal places: {}", Money::SCALE, s);
+        }
+        fraction.split_at(scale)
+    } else {
+        (fraction, "")
+    };
+
+    let overflow = || format_err!("Amount of money is too large: {}", s);
+    let mut units = whole
+        .parse::<i128>()?
+        .checked_mul(Money::ONE)
+        .and_then(|units| units.checked_add(format!("{:0<18}", kept).parse::<i128>().ok()?))
+        .ok_or_else(overflow)?;
+    if rest.chars().next().map(|digit| digit >= '5').unwrap_or(false) {
+        units = units.checked_add(1).ok_or_else(overflow)?;
+    }
+
+    Ok(if negative { -units } else { units })
+}
+
+impl Money {
+    /// Decimal places kept by the amount
+    pub const SCALE: u32 = 18;
+    const ONE: i128 = 1_000_000_000_000_000_000;
+    /// Decimal places of the rates amounts are multiplied by
+    const RATE_SCALE: u32 = 9;
+
+    pub fn zero() -> Self {
+        Money(0)
+    }
+
+    /// Amount in 10^-18 of the currency unit
+    pub fn from_units(units: i128) -> Self {
+        Money(units)
+    }
+
+    pub fn units(self) -> i128 {
+        self.0
+    }
+
+    /// Takes the shortest decimal representation of the float, inputs are expected to be prices written in decimal
+    pub fn from_f64(value: f64) -> Self {
+        if !value.is_finite() {
+            return Money::zero();
+        }
+        Money(parse_units(&value.to_string(), true).unwrap_or_default())
+    }
+
+    pub fn to_f64(self) -> f64 {
+        self.to_string().parse().unwrap_or_default()
+    }
+
+    pub fn is_zero(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Rounds half away from zero to `decimals` places
+    pub fn round_dp(self, decimals: u32) -> Self {
+        if decimals >= Self::SCALE {
+            return self;
+        }
+        let step = 10i128.pow(Self::SCALE - decimals);
+        Money(div_round(self.0, step) * step)
+    }
+
+    /// Rounds half away from zero to the minor units of the currency
+    pub fn round_to(self, currency: Currency) -> Self {
+        self.round_dp(minor_units(currency))
+    }
+
+    /// Amount multiplied by the rate, e.g. an exchange rate, the rate is taken with 9 decimal places.
+    /// Rounded half away from zero to `SCALE` decimal places.
+    pub fn share(self, rate: f64) -> Self {
+        let one = 10i128.pow(Self::RATE_SCALE);
+        let rate = (rate * one as f64).round() as i128;
+        Money(div_round(self.0 * rate, one))
+    }
+
+    /// Share of the amount, `percent` of 100, rounded half away from zero to `SCALE` decimal places
+    pub fn percent(self, percent: i32) -> Self {
+        Money(div_round(self.0 * i128::from(percent), 100))
+    }
+
+    /// Splits the amount rounded to `decimals` places into parts proportional to the weights, which must not be negative.
+    /// Every part gets its share rounded down to `decimals` places, the smallest units left are given one by one
+    /// to the parts with the largest remainders, earlier parts first. So the parts always sum up to the rounded amount.
+    /// All parts are zero if the weights are.
+    pub fn allocate(self, weights: &[Money], decimals: u32) -> Vec<Money> {
+        let step = 10i128.pow(Self::SCALE - decimals.min(Self::SCALE));
+        let total = self.round_dp(decimals).0 / step;
+        let weight_sum = weights.iter().map(|weight| weight.0.max(0) as u128).sum::<u128>();
+        if weight_sum == 0 {
+            return weights.iter().map(|_| Money::zero()).collect();
+        }
+
+        let mut parts = weights
+            .iter()
+            .map(|weight| mul_div(total.abs() as u128, weight.0.max(0) as u128, weight_sum))
+            .collect::<Vec<_>>();
+        let mut left = total.abs() as u128 - parts.iter().map(|(quotient, _)| quotient).sum::<u128>();
+
+        let mut by_remainder = (0..parts.len()).collect::<Vec<_>>();
+        by_remainder.sort_by(|a, b| parts[*b].1.cmp(&parts[*a].1).then(a.cmp(b)));
+        for index in by_remainder {
+            if left == 0 {
+                break;
+            }
+            parts[index].0 += 1;
+            left -= 1;
+        }
+
+        parts
+            .into_iter()
+            .map(|(quotient, _)| Money(total.signum() * quotient as i128 * step))
+            .collect()
+    }
+}
+
+impl Add for Money {
+    type Output = Money;
+
+    fn add(self, other: Money) -> Money {
+        Money(self.0 + other.0)
+    }
+}
+
+impl Sub for Money {
+    type Output = Money;
+
+    fn sub(self, other: Money) -> Money {
+        Money(self.0 - other.0)
+    }
+}
+
+impl Mul<i32> for Money {
+    type Output = Money;
+
+    fn mul(self, quantity: i32) -> Money {
+        Money(self.0 * i128::from(quantity))
+    }
+}
+
+impl From<ProductPrice> for Money {
+    fn from(v: ProductPrice) -> Self {
+        Money::from_f64(v.0)
+    }
+}
+
+impl From<Money> for ProductPrice {
+    fn from(v: Money) -> Self {
+        ProductPrice(v.to_f64())
+    }
+}
+
+/// Shortest decimal representation, e.g. `12.3` or `-0.005`
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let abs = self.0.abs();
+        let fraction = format!("{:018}", abs % Self::ONE);
+        let fraction = fraction.trim_end_matches('0');
+        if fraction.is_empty() {
+            write!(f, "{}{}", sign, abs / Self::ONE)
+        } else {
+            write!(f, "{}{}.{}", sign, abs / Self::ONE, fraction)
+        }
+    }
+}
+
+impl FromStr for Money {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_units(s, false).map(Money)
+    }
+}
+
+impl Serialize for Money {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_f64(self.to_f64())
+    }
+}
+
+impl<'de> Deserialize<'de> for Money {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        f64::deserialize(deserializer).map(Money::from_f64)
+    }
+}
+
+/// `NUMERIC` binary format: digit count, weight of the first digit, sign, display scale
+/// and base 10000 digits, most significant first
+const NUMERIC_NEGATIVE: u16 = 0x4000;
+const NUMERIC_NAN: u16 = 0xC000;
+const NUMERIC_BASE: i128 = 10_000;
+
+fn read_u16(raw: &[u8], position: usize) -> Result<u16, Box<StdError + Sync + Send>> {
+    match raw.get(position..position + 2) {
+        Some(bytes) => Ok((u16::from(bytes[0]) << 8) | u16::from(bytes[1])),
+        None => Err("NUMERIC value is truncated".into()),
+    }
+}
+
+fn write_u16(out: &mut Vec<u8>, v: u16) {
+    out.push((v >> 8) as u8);
+    out.push(v as u8);
+}
+
+fn numeric_from_sql(raw: &[u8]) -> Result<Money, Box<StdError + Sync + Send>> {
+    let ndigits = read_u16(raw, 0)? as usize;
+    let weight = i32::from(read_u16(raw, 2)? as i16);
+    let sign = read_u16(raw, 4)?;
+    if sign == NUMERIC_NAN {
+        return Err("NaN is not an amount of money".into());
+    }
+
+    // Units are 10^SCALE times the value, the digit of weight `w` stands for 10000^w
+    let too_large = || -> Box<StdError + Sync + Send> { "NUMERIC value is too large for an amount of money".into() };
+    let mut units = 0i128;
+    for i in 0..ndigits {
+        let digit = i128::from(read_u16(raw, 8 + 2 * i)?);
+        let exponent = 4 * (weight - i as i32) + Money::SCALE as i32;
+        let value = if exponent >= 0 {
+            if digit != 0 && exponent > 38 {
+                return Err(too_large());
+            }
+            digit.checked_mul(10i128.pow(exponent.min(38) as u32)).ok_or_else(too_large)?
+        } else if exponent > -4 {
+            // The digit below the scale rounds the last kept one, the following ones can not change it
+            div_round(digit, 10i128.pow(-exponent as u32))
+        } else {
+            0
+        };
+        units = units.checked_add(value).ok_or_else(too_large)?;
+    }
+
+    Ok(Money(if sign == NUMERIC_NEGATIVE { -units } else { units }))
+}
+
+fn numeric_to_sql(money: Money, out: &mut Vec<u8>) {
+    let abs = money.0.abs();
+
+    let mut digits = vec![];
+    let mut whole = abs / Money::ONE;
+    while whole > 0 {
+        digits.insert(0, (whole % NUMERIC_BASE) as u16);
+        whole /= NUMERIC_BASE;
+    }
+    let mut weight = digits.len() as i16 - 1;
+    // 18 decimal places padded to 20 make five base 10000 digits
+    let mut fraction = (abs % Money::ONE) * 100;
+    let mut fraction_digits = vec![];
+    for _ in 0..5 {
+        fraction_digits.insert(0, (fraction % NUMERIC_BASE) as u16);
+        fraction /= NUMERIC_BASE;
+    }
+    digits.extend(fraction_digits);
+
+    while digits.last() == Some(&0) {
+        digits.pop();
+    }
+    while digits.first() == Some(&0) {
+        digits.remove(0);
+        weight -= 1;
+    }
+    if digits.is_empty() {
+        weight = 0;
+    }
+
+    write_u16(out, digits.len() as u16);
+    write_u16(out, weight as u16);
+    write_u16(out, if money.0 < 0 { NUMERIC_NEGATIVE } else { 0 });
+    write_u16(out, Money::SCALE as u16);
+    for digit in digits {
+        write_u16(out, digit);
+    }
+}
+
+impl<'a> FromSql<'a> for Money {
+    fn from_sql(_: &Type, raw: &'a [u8]) -> Result<Self, Box<StdError + Sync + Send>> {
+        numeric_from_sql(raw)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        ty.name() == "numeric"
+    }
+}
+
+impl ToSql for Money {
+    fn to_sql(&self, _: &Type, out: &mut Vec<u8>) -> Result<IsNull, Box<StdError + Sync + Send>> {
+        numeric_to_sql(*self, out);
+        Ok(IsNull::No)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        ty.name() == "numeric"
+    }
+
+    fn to_sql_checked(&self, ty: &Type, out: &mut Vec<u8>) -> Result<IsNull, Box<StdError + Sync + Send>> {
+        if !<Self as ToSql>::accepts(ty) {
+            return Err(format!("cannot convert Money to {}", ty.name()).into());
+        }
+        self.to_sql(ty, out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn money(s: &str) -> Money {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn rounds_half_away_from_zero() {
+        assert_eq!(Money::from_f64(0.125).round_dp(2), Money::from_f64(0.13));
+        assert_eq!(Money::from_f64(0.124).round_dp(2), Money::from_f64(0.12));
+        assert_eq!(Money::from_f64(-0.125).round_dp(2), Money::from_f64(-0.13));
+        assert_eq!(Money::from_f64(10.0).percent(33), Money::from_f64(3.3));
+        assert_eq!(Money::from_f64(0.1).share(0.5), Money::from_f64(0.05));
+        assert_eq!(Money::from_f64(0.1) + Money::from_f64(0.2), Money::from_f64(0.3));
+    }
+
+    #[test]
+    fn string_roundtrip() {
+        for s in &[
+            "0",
+            "12.3",
+            "-0.005",
+            "99.99",
+            "0.000000000000000001",
+            "123456789.123456789012345678",
+        ] {
+            assert_eq!(money(s).to_string(), *s);
+        }
+        assert!("1.0000000000000000001".parse::<Money>().is_err());
+        assert!("1e5".parse::<Money>().is_err());
+        assert!("".parse::<Money>().is_err());
+        assert!("170141183460469231732".parse::<Money>().is_err());
+        assert!("99999999999999999999999999999999999999999".parse::<Money>().is_err());
+    }
+
+    #[test]
+    fn allocates_by_largest_remainder() {
+        assert_eq!(
+            money("10").allocate(&[money("1"), money("1"), money("1")], 2),
+            vec![money("3.34"), money("3.33"), money("3.33")]
+        );
+        assert_eq!(
+            money("10").allocate(&[money("1"), money("2"), money("2")], 2),
+            vec![money("2"), money("4"), money("4")]
+        );
+        assert_eq!(
+            money("-0.05").allocate(&[money("1"), money("1")], 2),
+            vec![money("-0.03"), money("-0.02")]
+        );
+        assert_eq!(
+            money("10").allocate(&[Money::zero(), Money::zero()], 2),
+            vec![Money::zero(), Money::zero()]
+        );
+
+        // Crypto amounts are split down to 10^-18 without overflow
+        let parts = money("1000000").allocate(&[money("1000000"), money("2000000"), money("0.000000000000000001")], 18);
+        assert_eq!(parts.iter().fold(Money::zero(), |sum, part| sum + *part), money("1000000"));
+        assert_eq!(parts[0], money("333333.333333333333333333"));
+    }
+
+    #[test]
+    fn numeric_roundtrip() {
+        for s in &[
+            "0",
+            "12.3",
+            "-0.005",
+            "10000",
+            "99.99",
+            "0.000000000000000001",
+            "-123456789.123456789012345678",
+        ] {
+            let money = money(s);
+            let mut raw = vec![];
+            numeric_to_sql(money, &mut raw);
+            assert_eq!(numeric_from_sql(&raw).unwrap(), money);
+        }
+
+        // 1.234567895 with the display scale of 9
+        let raw = [0, 4, 0, 0, 0, 0, 0, 9, 0, 1, 9, 41, 26, 133, 19, 136];
+        assert_eq!(numeric_from_sql(&raw).unwrap(), money("1.234567895"));
+        // 0.0000000000000000015 with the display scale of 19 is rounded to 18 decimal places
+        let raw = [0, 1, 255, 251, 0, 0, 0, 19, 0, 150];
+        assert_eq!(numeric_from_sql(&raw).unwrap(), money("0.000000000000000002"));
+    }
+}
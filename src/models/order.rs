@@ -1,5 +1,6 @@
 use chrono::prelude::*;
 use failure::Fallible;
+use std::fmt;
 use std::str::FromStr;
 use stq_api::orders::*;
 use stq_db::statement::*;
@@ -55,6 +56,14 @@ const DELIVERY_PRICE_COLUMN: &str = "delivery_price";
 const SHIPPING_ID_COLUMN: &str = "shipping_id";
 const PRODUCT_CASHBACK_COLUMN: &str = "product_cashback";
 const CURRENCY_TYPE_COLUMN: &str = "currency_type";
+const VERSION_COLUMN: &str = "version";
+const DELIVERY_PRICING_COLUMN: &str = "delivery_pricing";
+const TAX_AMOUNT_COLUMN: &str = "tax_amount";
+const REFUNDED_AMOUNT_COLUMN: &str = "refunded_amount";
+const PAID_AMOUNT_COLUMN: &str = "paid_amount";
+const DELIVERY_STATE_COLUMN: &str = "delivery_state";
+const ATTENTION_REASON_COLUMN: &str = "attention_reason";
+const ESCALATED_AT_COLUMN: &str = "escalated_at";
 
 const UUID_COLUMN: &str = "uuid";
 
@@ -108,12 +117,94 @@ pub fn address_from_row(row: &Row) -> AddressFull {
     }
 }
 
+/// Order revision, incremented on every update of the order
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct OrderVersion(pub i32);
+
+impl OrderVersion {
+    pub fn next(self) -> Self {
+        OrderVersion(self.0 + 1)
+    }
+}
+
+impl fmt::Display for OrderVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Order attributes that are stored in the database but are not part of `Order`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OrderMeta {
+    pub version: OrderVersion,
+    /// How `delivery_price` was charged
+    pub delivery_pricing: DeliveryPricing,
+    /// Taxes of the active lines, exclusive ones are included into `total_amount`
+    pub tax_amount: Money,
+    /// Sum of the refunds made for the order, never exceeds `paid_amount`
+    pub refunded_amount: Money,
+    /// `total_amount` at the moment the order was paid, not set for unpaid orders
+    pub paid_amount: Option<Money>,
+    /// Latest state reported by the carrier of the sent order
+    pub delivery_state: Option<DeliveryState>,
+    /// Why the seller has to look at the order, set by delivery rules
+    pub attention_reason: Option<String>,
+    /// When the order staying in sent state past the tracking window was escalated
+    pub escalated_at: Option<DateTime<Utc>>,
+}
+
+/// `Order` extended with its meta, as returned to API clients
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OrderWithMeta {
+    #[serde(flatten)]
+    pub order: Order,
+    #[serde(flatten)]
+    pub meta: OrderMeta,
+}
+
+impl From<DbOrder> for OrderWithMeta {
+    fn from(v: DbOrder) -> Self {
+        let DbOrder(order, meta) = v;
+        Self { order, meta }
+    }
+}
+
+/// Preconditions that must hold for the order to be updated
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct OrderUpdatePrecondition {
+    pub expected_state: Option<OrderState>,
+    pub expected_version: Option<OrderVersion>,
+}
+
+impl OrderUpdatePrecondition {
+    pub fn with_expected_state(state: OrderState) -> Self {
+        Self {
+            expected_state: Some(state),
+            ..Default::default()
+        }
+    }
+
+    pub fn holds_for(&self, order: &DbOrder) -> bool {
+        self.expected_state.map(|state| state == order.0.state).unwrap_or(true)
+            && self.expected_version.map(|version| version == order.1.version).unwrap_or(true)
+    }
+}
+
+/// `UpdateStatePayload` with optional optimistic concurrency preconditions
+#[derive(Deserialize)]
+pub struct UpdateOrderStatePayload {
+    #[serde(flatten)]
+    pub data: UpdateStatePayload,
+    #[serde(flatten)]
+    pub precondition: OrderUpdatePrecondition,
+}
+
 #[derive(Clone, Debug, PartialEq)]
-pub struct DbOrder(pub Order);
+pub struct DbOrder(pub Order, pub OrderMeta);
 
 impl From<Row> for DbOrder {
     fn from(row: Row) -> Self {
-        DbOrder(Order {
+        let order = Order {
             id: OrderId(row.get(ID_COLUMN)),
             created_from: CartItemId(row.get(CREATED_FROM_COLUMN)),
             conversion_id: ConversionId(row.get(CONVERSION_ID_COLUMN)),
@@ -121,7 +212,7 @@ impl From<Row> for DbOrder {
             customer: UserId(row.get(CUSTOMER_COLUMN)),
             store: StoreId(row.get(STORE_COLUMN)),
             product: ProductId(row.get(PRODUCT_COLUMN)),
-            price: ProductPrice(row.get(PRICE_COLUMN)),
+            price: row.get::<Money, _>(PRICE_COLUMN).into(),
             currency: Currency::from_str(row.get(CURRENCY_COLUMN)).unwrap(),
             quantity: Quantity(row.get(QUANTITY_COLUMN)),
             address: address_from_row(&row),
@@ -138,15 +229,29 @@ impl From<Row> for DbOrder {
             pre_order_days: row.get(PRE_ORDER_DAYS_COLUMN),
             coupon_id: row.get::<Option<i32>, _>(COUPON_ID_COLUMN).map(CouponId),
             coupon_percent: row.get(COUPON_PERCENT_COLUMN),
-            coupon_discount: row.get::<Option<f64>, _>(COUPON_DISCOUNT_COLUMN).map(ProductPrice),
-            product_discount: row.get::<Option<f64>, _>(PRODUCT_DISCOUNT_COLUMN).map(ProductPrice),
-            total_amount: ProductPrice(row.get(TOTAL_AMOUNT_COLUMN)),
+            coupon_discount: row.get::<Option<Money>, _>(COUPON_DISCOUNT_COLUMN).map(From::from),
+            product_discount: row.get::<Option<Money>, _>(PRODUCT_DISCOUNT_COLUMN).map(From::from),
+            total_amount: row.get::<Money, _>(TOTAL_AMOUNT_COLUMN).into(),
             company_package_id: row.get::<Option<i32>, _>(COMPANY_PACKAGE_ID_COLUMN).map(CompanyPackageId),
-            delivery_price: row.get(DELIVERY_PRICE_COLUMN),
+            delivery_price: row.get::<Money, _>(DELIVERY_PRICE_COLUMN).to_f64(),
             shipping_id: row.get::<Option<i32>, _>(SHIPPING_ID_COLUMN).map(ShippingId),
             product_cashback: row.get::<Option<f64>, _>(PRODUCT_CASHBACK_COLUMN).map(CashbackPercent),
             currency_type: row.get(CURRENCY_TYPE_COLUMN),
-        })
+        };
+        let meta = OrderMeta {
+            version: OrderVersion(row.get(VERSION_COLUMN)),
+            delivery_pricing: DeliveryPricing::from_str(row.get(DELIVERY_PRICING_COLUMN)).unwrap(),
+            tax_amount: row.get(TAX_AMOUNT_COLUMN),
+            refunded_amount: row.get(REFUNDED_AMOUNT_COLUMN),
+            paid_amount: row.get(PAID_AMOUNT_COLUMN),
+            delivery_state: row
+                .get::<Option<String>, _>(DELIVERY_STATE_COLUMN)
+                .map(|delivery_state| DeliveryState::from_str(&delivery_state).unwrap()),
+            attention_reason: row.get(ATTENTION_REASON_COLUMN),
+            escalated_at: row.get(ESCALATED_AT_COLUMN),
+        };
+
+        DbOrder(order, meta)
     }
 }
 
@@ -158,7 +263,7 @@ pub struct OrderInserter {
     pub customer: UserId,
     pub store: StoreId,
     pub product: ProductId,
-    pub price: ProductPrice,
+    pub price: Money,
     pub currency: Currency,
     pub quantity: Quantity,
     pub address: AddressFull,
@@ -172,11 +277,13 @@ pub struct OrderInserter {
     pub pre_order_days: i32,
     pub coupon_id: Option<CouponId>,
     pub coupon_percent: Option<i32>,
-    pub coupon_discount: Option<ProductPrice>,
-    pub product_discount: Option<ProductPrice>,
-    pub total_amount: ProductPrice,
+    pub coupon_discount: Option<Money>,
+    pub product_discount: Option<Money>,
+    pub total_amount: Money,
     pub company_package_id: Option<CompanyPackageId>,
-    pub delivery_price: f64,
+    pub delivery_price: Money,
+    pub delivery_pricing: DeliveryPricing,
+    pub tax_amount: Money,
     pub shipping_id: Option<ShippingId>,
     pub product_cashback: Option<CashbackPercent>,
     pub uuid: Uuid,
@@ -192,14 +299,16 @@ impl Inserter for OrderInserter {
             .with_arg(RECEIVER_NAME_COLUMN, self.receiver_name)
             .with_arg(RECEIVER_PHONE_COLUMN, self.receiver_phone)
             .with_arg(RECEIVER_EMAIL_COLUMN, self.receiver_email)
-            .with_arg(PRICE_COLUMN, self.price.0)
+            .with_arg(PRICE_COLUMN, self.price)
             .with_arg(CURRENCY_COLUMN, self.currency.to_string())
             .with_arg(QUANTITY_COLUMN, self.quantity.0)
             .with_arg(STATE_COLUMN, self.state)
             .with_arg(PRE_ORDER_COLUMN, self.pre_order)
             .with_arg(PRE_ORDER_DAYS_COLUMN, self.pre_order_days)
-            .with_arg(TOTAL_AMOUNT_COLUMN, self.total_amount.0)
+            .with_arg(TOTAL_AMOUNT_COLUMN, self.total_amount)
             .with_arg(DELIVERY_PRICE_COLUMN, self.delivery_price)
+            .with_arg(DELIVERY_PRICING_COLUMN, self.delivery_pricing.to_string())
+            .with_arg(TAX_AMOUNT_COLUMN, self.tax_amount)
             .with_arg(UUID_COLUMN, self.uuid)
             .with_arg(CURRENCY_TYPE_COLUMN, self.currency_type);
 
@@ -230,11 +339,11 @@ impl Inserter for OrderInserter {
         }
 
         if let Some(v) = self.coupon_discount {
-            b = b.with_arg(COUPON_DISCOUNT_COLUMN, v.0);
+            b = b.with_arg(COUPON_DISCOUNT_COLUMN, v);
         }
 
         if let Some(v) = self.product_discount {
-            b = b.with_arg(PRODUCT_DISCOUNT_COLUMN, v.0);
+            b = b.with_arg(PRODUCT_DISCOUNT_COLUMN, v);
         }
 
         if let Some(v) = self.delivery_company {
@@ -264,21 +373,51 @@ pub struct OrderFilter {
     pub do_order: bool,
     pub id: Option<ValueContainer<OrderId>>,
     pub ids: Option<ValueContainer<Vec<OrderId>>>,
+    /// Orders with lesser ids, breaks ties between orders with the same sort key
+    pub id_before: Option<ValueContainer<OrderId>>,
+    /// Orders with greater ids, breaks ties between orders with the same sort key
+    pub id_after: Option<ValueContainer<OrderId>>,
     pub created_from: Option<ValueContainer<CartItemId>>,
     pub conversion_id: Option<ValueContainer<ConversionId>>,
     pub slug: Option<ValueContainer<OrderSlug>>,
+    pub slug_range: Option<ValueContainer<Range<i32>>>,
     pub customer: Option<ValueContainer<UserId>>,
     pub store: Option<ValueContainer<StoreId>>,
-    pub product: Option<ValueContainer<ProductId>>,
     pub created_at: Option<ValueContainer<Range<DateTime<Utc>>>>,
     pub updated_at: Option<ValueContainer<Range<DateTime<Utc>>>>,
     pub state: Option<ValueContainer<OrderState>>,
+    pub states: Option<ValueContainer<Vec<OrderState>>>,
     pub payment_status: Option<ValueContainer<bool>>,
     pub delivery_company: Option<ValueContainer<Option<String>>>,
     pub track_id: Option<ValueContainer<Option<String>>>,
     pub pre_order: Option<ValueContainer<bool>>,
     pub pre_order_days: Option<ValueContainer<i32>>,
     pub currency_type: Option<ValueContainer<CurrencyType>>,
+    pub total_amount: Option<ValueContainer<Range<Money>>>,
+    pub receiver_email: Option<ValueContainer<String>>,
+    pub receiver_phone: Option<ValueContainer<String>>,
+    pub version: Option<ValueContainer<OrderVersion>>,
+    /// Applied only along with `do_order`
+    pub sort: OrderSort,
+}
+
+/// Search terms not covered by `OrderSearchTerms`
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+pub struct OrderSearchFilters {
+    /// Free text matched against receiver, slug, track id and address of the order
+    pub q: Option<String>,
+    /// Orders in any of these states
+    pub states: Option<Vec<OrderState>>,
+    /// Orders having a line of the product
+    pub product: Option<ProductId>,
+    pub delivery_company: Option<String>,
+    pub track_id: Option<String>,
+    pub pre_order: Option<bool>,
+    pub currency_type: Option<CurrencyType>,
+    pub total_amount_from: Option<Money>,
+    pub total_amount_to: Option<Money>,
+    pub receiver_email: Option<String>,
+    pub receiver_phone: Option<String>,
 }
 
 impl From<OrderIdentifier> for OrderFilter {
@@ -320,6 +459,92 @@ impl OrderFilter {
 
         Ok(mask)
     }
+
+    /// Applies the filters except `q` and `product`, which are not column filters of orders.
+    /// Orders with lines of the product are selected by `repos::order::select_ids_with_product`.
+    pub fn with_search_filters(mut self, filters: OrderSearchFilters) -> Self {
+        self.states = filters.states.map(From::from);
+        self.delivery_company = filters.delivery_company.map(|v| Some(v).into());
+        self.track_id = filters.track_id.map(|v| Some(v).into());
+        self.pre_order = filters.pre_order.map(From::from);
+        self.currency_type = filters.currency_type.map(From::from);
+        self.total_amount = super::into_range(filters.total_amount_from, filters.total_amount_to);
+        self.receiver_email = filters.receiver_email.map(From::from);
+        self.receiver_phone = filters.receiver_phone.map(From::from);
+        self
+    }
+
+    pub fn with_sort(mut self, sort: OrderSort) -> Self {
+        self.sort = sort;
+        self
+    }
+
+    /// Narrows the filter to orders with the same sort key as the cursor that follow it in the sort order
+    pub fn at_cursor(self, cursor: OrderCursor) -> Self {
+        let tie_break = Some(cursor.id.into());
+        let mut mask = match self.sort.direction {
+            SortDirection::Asc => OrderFilter {
+                id_after: tie_break,
+                ..self
+            },
+            SortDirection::Desc => OrderFilter {
+                id_before: tie_break,
+                ..self
+            },
+        };
+
+        match cursor.key {
+            OrderSortKey::CreatedAt(v) => mask.created_at = super::exact_range(v),
+            OrderSortKey::UpdatedAt(v) => mask.updated_at = super::exact_range(v),
+            OrderSortKey::TotalAmount(v) => mask.total_amount = super::exact_range(v),
+            OrderSortKey::Slug(v) => mask.slug_range = super::exact_range(v.0),
+        };
+
+        mask
+    }
+
+    /// Narrows the filter to orders with sort keys following the one of the cursor
+    pub fn beyond_cursor(self, cursor: OrderCursor) -> Self {
+        fn narrow<T: PartialOrd>(
+            range: Option<ValueContainer<Range<T>>>,
+            direction: SortDirection,
+            value: T,
+        ) -> Option<ValueContainer<Range<T>>> {
+            let limit = RangeLimit { value, inclusive: false };
+            match direction {
+                SortDirection::Asc => super::with_lower_limit(range, limit),
+                SortDirection::Desc => super::with_upper_limit(range, limit),
+            }
+        }
+
+        let direction = self.sort.direction;
+        let mut mask = self;
+
+        match cursor.key {
+            OrderSortKey::CreatedAt(v) => mask.created_at = narrow(mask.created_at, direction, v),
+            OrderSortKey::UpdatedAt(v) => mask.updated_at = narrow(mask.updated_at, direction, v),
+            OrderSortKey::TotalAmount(v) => mask.total_amount = narrow(mask.total_amount, direction, v),
+            OrderSortKey::Slug(v) => mask.slug_range = narrow(mask.slug_range, direction, v.0),
+        };
+
+        mask
+    }
+}
+
+fn order_by_clause(sort: OrderSort) -> &'static str {
+    use self::OrderSortField::*;
+    use self::SortDirection::*;
+
+    match (sort.field, sort.direction) {
+        (CreatedAt, Asc) => "ORDER BY created_at ASC, id ASC",
+        (CreatedAt, Desc) => "ORDER BY created_at DESC, id DESC",
+        (UpdatedAt, Asc) => "ORDER BY updated_at ASC, id ASC",
+        (UpdatedAt, Desc) => "ORDER BY updated_at DESC, id DESC",
+        (TotalAmount, Asc) => "ORDER BY total_amount ASC, id ASC",
+        (TotalAmount, Desc) => "ORDER BY total_amount DESC, id DESC",
+        (Slug, Asc) => "ORDER BY slug ASC, id ASC",
+        (Slug, Desc) => "ORDER BY slug DESC, id DESC",
+    }
 }
 
 impl Filter for OrderFilter {
@@ -335,6 +560,26 @@ impl Filter for OrderFilter {
             b = b.with_filter::<Uuid, _>(ID_COLUMN, ids);
         }
 
+        if let Some(v) = self.id_before {
+            b = b.with_filter::<Uuid, _>(
+                ID_COLUMN,
+                Range::To(RangeLimit {
+                    value: v.value.0,
+                    inclusive: false,
+                }),
+            );
+        }
+
+        if let Some(v) = self.id_after {
+            b = b.with_filter::<Uuid, _>(
+                ID_COLUMN,
+                Range::From(RangeLimit {
+                    value: v.value.0,
+                    inclusive: false,
+                }),
+            );
+        }
+
         if let Some(v) = self.created_from {
             b = b.with_filter(CREATED_FROM_COLUMN, v.value.0);
         }
@@ -347,6 +592,10 @@ impl Filter for OrderFilter {
             b = b.with_filter(SLUG_COLUMN, v.value.0);
         }
 
+        if let Some(v) = self.slug_range {
+            b = b.with_filter::<i32, _>(SLUG_COLUMN, v.value);
+        }
+
         if let Some(v) = self.customer {
             b = b.with_filter(CUSTOMER_COLUMN, v.value.0);
         }
@@ -355,10 +604,6 @@ impl Filter for OrderFilter {
             b = b.with_filter(STORE_COLUMN, v.value.0);
         }
 
-        if let Some(v) = self.product {
-            b = b.with_filter(PRODUCT_COLUMN, v.value.0);
-        }
-
         if let Some(v) = self.created_at {
             b = b.with_filter::<DateTime<Utc>, _>(CREATED_AT_COLUMN, v.value);
         }
@@ -371,6 +616,10 @@ impl Filter for OrderFilter {
             b = b.with_filter(STATE_COLUMN, v.value);
         }
 
+        if let Some(v) = self.states {
+            b = b.with_filter::<OrderState, _>(STATE_COLUMN, v.value);
+        }
+
         if let Some(v) = self.payment_status {
             b = b.with_filter(PAYMENT_STATUS_COLUMN, v.value);
         }
@@ -395,17 +644,42 @@ impl Filter for OrderFilter {
             b = b.with_filter(CURRENCY_TYPE_COLUMN, v.value);
         }
 
+        if let Some(v) = self.total_amount {
+            b = b.with_filter::<Money, _>(TOTAL_AMOUNT_COLUMN, v.value);
+        }
+
+        if let Some(v) = self.receiver_email {
+            b = b.with_filter(RECEIVER_EMAIL_COLUMN, v.value);
+        }
+
+        if let Some(v) = self.receiver_phone {
+            b = b.with_filter(RECEIVER_PHONE_COLUMN, v.value);
+        }
+
+        if let Some(v) = self.version {
+            b = b.with_filter(VERSION_COLUMN, v.value.0);
+        }
+
         if self.do_order {
-            b = b.with_extra("ORDER BY created_at DESC");
+            b = b.with_extra(order_by_clause(self.sort));
         }
 
         b
     }
 }
 
+#[derive(Default)]
 pub struct OrderUpdateData {
     pub state: Option<OrderState>,
     pub track_id: Option<String>,
+    /// Line the single product fields of the order are copied from
+    pub primary_line: Option<OrderLine>,
+    pub total_amount: Option<Money>,
+    pub tax_amount: Option<Money>,
+    pub refunded_amount: Option<Money>,
+    pub delivery_state: Option<DeliveryState>,
+    pub attention_reason: Option<Option<String>>,
+    pub escalated_at: Option<DateTime<Utc>>,
 }
 
 pub struct OrderUpdater {
@@ -427,6 +701,53 @@ impl Updater for OrderUpdater {
             b = b.with_value(TRACK_ID_COLUMN, track_id);
         }
 
+        if let Some(line) = data.primary_line {
+            if let Some(v) = line.created_from {
+                b = b.with_value(CREATED_FROM_COLUMN, v.0);
+            }
+
+            b = b
+                .with_value(PRODUCT_COLUMN, line.product.0)
+                .with_value(QUANTITY_COLUMN, line.quantity.0)
+                .with_value(PRICE_COLUMN, line.price)
+                .with_value(PRE_ORDER_COLUMN, line.pre_order)
+                .with_value(PRE_ORDER_DAYS_COLUMN, line.pre_order_days)
+                .with_value(COUPON_ID_COLUMN, line.coupon_id.map(|v| v.0))
+                .with_value(COUPON_PERCENT_COLUMN, line.coupon_percent)
+                .with_value(COUPON_DISCOUNT_COLUMN, line.coupon_discount)
+                .with_value(PRODUCT_DISCOUNT_COLUMN, line.product_discount)
+                .with_value(DELIVERY_COMPANY_COLUMN, line.delivery_company)
+                .with_value(COMPANY_PACKAGE_ID_COLUMN, line.company_package_id.map(|v| v.0))
+                .with_value(SHIPPING_ID_COLUMN, line.shipping_id.map(|v| v.0))
+                .with_value(DELIVERY_PRICE_COLUMN, line.delivery_price)
+                .with_value(DELIVERY_PRICING_COLUMN, line.delivery_pricing.to_string())
+                .with_value(PRODUCT_CASHBACK_COLUMN, line.product_cashback.map(|v| v.0));
+        }
+
+        if let Some(total_amount) = data.total_amount {
+            b = b.with_value(TOTAL_AMOUNT_COLUMN, total_amount);
+        }
+
+        if let Some(tax_amount) = data.tax_amount {
+            b = b.with_value(TAX_AMOUNT_COLUMN, tax_amount);
+        }
+
+        if let Some(refunded_amount) = data.refunded_amount {
+            b = b.with_value(REFUNDED_AMOUNT_COLUMN, refunded_amount);
+        }
+
+        if let Some(delivery_state) = data.delivery_state {
+            b = b.with_value(DELIVERY_STATE_COLUMN, delivery_state.to_string());
+        }
+
+        if let Some(attention_reason) = data.attention_reason {
+            b = b.with_value(ATTENTION_REASON_COLUMN, attention_reason);
+        }
+
+        if let Some(escalated_at) = data.escalated_at {
+            b = b.with_value(ESCALATED_AT_COLUMN, escalated_at);
+        }
+
         b
     }
 }
@@ -1,5 +1,6 @@
 use chrono::prelude::*;
 use tokio_postgres::rows::Row;
+use uuid::Uuid;
 
 use stq_api::orders::*;
 use stq_db::statement::*;
@@ -59,6 +60,7 @@ pub struct OrderDiffFilter {
     pub do_order: bool,
     pub id: Option<ValueContainer<OrderDiffId>>,
     pub parent: Option<ValueContainer<OrderId>>,
+    pub parents: Option<ValueContainer<Vec<OrderId>>>,
     pub committer: Option<ValueContainer<UserId>>,
     pub committed_at: Option<ValueContainer<DateTime<Utc>>>,
     pub committed_at_range: Option<ValueContainer<Range<DateTime<Utc>>>>,
@@ -86,6 +88,11 @@ impl Filter for OrderDiffFilter {
             b = b.with_filter(PARENT_COLUMN, v.value.0);
         }
 
+        if let Some(v) = self.parents {
+            let parents: Vec<Uuid> = v.value.into_iter().map(|parent| parent.0).collect();
+            b = b.with_filter::<Uuid, _>(PARENT_COLUMN, parents);
+        }
+
         if let Some(v) = self.committer {
             b = b.with_filter(COMMITTER_COLUMN, v.value.0);
         }
@@ -0,0 +1,283 @@
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::prelude::*;
+use failure::{self, Fallible};
+use serde_json::{self, Value};
+use tokio_postgres::rows::Row;
+
+use stq_api::orders::*;
+use stq_db::statement::*;
+use stq_types::*;
+
+use super::*;
+
+const ID_COLUMN: &str = "id";
+const ORDER_ID_COLUMN: &str = "order_id";
+const EVENT_TYPE_COLUMN: &str = "event_type";
+const PAYLOAD_COLUMN: &str = "payload";
+const CREATED_AT_COLUMN: &str = "created_at";
+const STATUS_COLUMN: &str = "status";
+const ATTEMPTS_COLUMN: &str = "attempts";
+const NEXT_ATTEMPT_AT_COLUMN: &str = "next_attempt_at";
+const LAST_ERROR_COLUMN: &str = "last_error";
+const DELIVERED_TO_COLUMN: &str = "delivered_to";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct OrderEventId(pub i64);
+
+impl fmt::Display for OrderEventId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderEventType {
+    Created,
+    StateChanged,
+    /// Lines of the order changed
+    Updated,
+    Deleted,
+    /// Carrier reported a delivery problem of the sent order
+    DeliveryIssue,
+    /// Sent order stays in sent state past the tracking window
+    Stale,
+}
+
+impl fmt::Display for OrderEventType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::OrderEventType::*;
+
+        write!(
+            f,
+            "{}",
+            match self {
+                Created => "created",
+                StateChanged => "state_changed",
+                Updated => "updated",
+                Deleted => "deleted",
+                DeliveryIssue => "delivery_issue",
+                Stale => "stale",
+            }
+        )
+    }
+}
+
+impl FromStr for OrderEventType {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use self::OrderEventType::*;
+
+        Ok(match s {
+            "created" => Created,
+            "state_changed" => StateChanged,
+            "updated" => Updated,
+            "deleted" => Deleted,
+            "delivery_issue" => DeliveryIssue,
+            "stale" => Stale,
+            other => bail!("Unknown order event type: {}", other),
+        })
+    }
+}
+
+/// Delivery status of the order event
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderEventStatus {
+    /// Waiting for (re)delivery
+    Pending,
+    /// Accepted by all webhooks
+    Delivered,
+    /// Dead letter: delivery attempts are exhausted
+    Failed,
+}
+
+impl fmt::Display for OrderEventStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::OrderEventStatus::*;
+
+        write!(
+            f,
+            "{}",
+            match self {
+                Pending => "pending",
+                Delivered => "delivered",
+                Failed => "failed",
+            }
+        )
+    }
+}
+
+impl FromStr for OrderEventStatus {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use self::OrderEventStatus::*;
+
+        Ok(match s {
+            "pending" => Pending,
+            "delivered" => Delivered,
+            "failed" => Failed,
+            other => bail!("Unknown order event status: {}", other),
+        })
+    }
+}
+
+/// Order change recorded in the outbox along with the change itself
+#[derive(Clone, Debug, PartialEq)]
+pub struct OrderEvent {
+    pub id: OrderEventId,
+    pub order_id: OrderId,
+    pub event_type: OrderEventType,
+    pub payload: Value,
+    pub created_at: DateTime<Utc>,
+    pub status: OrderEventStatus,
+    pub attempts: i32,
+    pub next_attempt_at: DateTime<Utc>,
+    pub last_error: Option<String>,
+    /// URLs of the webhooks that have already accepted the event
+    pub delivered_to: Vec<String>,
+}
+
+impl From<Row> for OrderEvent {
+    fn from(row: Row) -> Self {
+        Self {
+            id: OrderEventId(row.get(ID_COLUMN)),
+            order_id: OrderId(row.get(ORDER_ID_COLUMN)),
+            event_type: OrderEventType::from_str(row.get(EVENT_TYPE_COLUMN)).unwrap(),
+            payload: row.get(PAYLOAD_COLUMN),
+            created_at: row.get(CREATED_AT_COLUMN),
+            status: OrderEventStatus::from_str(row.get(STATUS_COLUMN)).unwrap(),
+            attempts: row.get(ATTEMPTS_COLUMN),
+            next_attempt_at: row.get(NEXT_ATTEMPT_AT_COLUMN),
+            last_error: row.get(LAST_ERROR_COLUMN),
+            delivered_to: serde_json::from_value(row.get::<Value, _>(DELIVERED_TO_COLUMN)).unwrap_or_default(),
+        }
+    }
+}
+
+/// Webhook request body
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct OrderEventMessage {
+    pub id: OrderEventId,
+    pub order_id: OrderId,
+    pub event_type: OrderEventType,
+    pub created_at: DateTime<Utc>,
+    pub order: Value,
+}
+
+impl From<OrderEvent> for OrderEventMessage {
+    fn from(v: OrderEvent) -> Self {
+        Self {
+            id: v.id,
+            order_id: v.order_id,
+            event_type: v.event_type,
+            created_at: v.created_at,
+            order: v.payload,
+        }
+    }
+}
+
+pub struct OrderEventInserter {
+    pub order_id: OrderId,
+    pub event_type: OrderEventType,
+    pub payload: Value,
+}
+
+impl OrderEventInserter {
+    /// Creates event carrying the snapshot of the order
+    pub fn from_order(event_type: OrderEventType, order: &Order) -> Fallible<Self> {
+        Ok(Self {
+            order_id: order.id,
+            event_type,
+            payload: serde_json::to_value(order)?,
+        })
+    }
+}
+
+impl Inserter for OrderEventInserter {
+    fn into_insert_builder(self, table: &'static str) -> InsertBuilder {
+        InsertBuilder::new(table)
+            .with_arg(ORDER_ID_COLUMN, self.order_id.0)
+            .with_arg(EVENT_TYPE_COLUMN, self.event_type.to_string())
+            .with_arg(PAYLOAD_COLUMN, self.payload)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct OrderEventFilter {
+    pub id: Option<ValueContainer<OrderEventId>>,
+    pub order_id: Option<ValueContainer<OrderId>>,
+    pub status: Option<ValueContainer<OrderEventStatus>>,
+}
+
+impl Filter for OrderEventFilter {
+    fn into_filtered_operation_builder(self, table: &'static str) -> FilteredOperationBuilder {
+        let mut b = FilteredOperationBuilder::new(table);
+
+        if let Some(v) = self.id {
+            b = b.with_filter(ID_COLUMN, v.value.0);
+        }
+
+        if let Some(v) = self.order_id {
+            b = b.with_filter(ORDER_ID_COLUMN, v.value.0);
+        }
+
+        if let Some(v) = self.status {
+            b = b.with_filter(STATUS_COLUMN, v.value.to_string());
+        }
+
+        b
+    }
+}
+
+/// Result of the delivery attempt
+#[derive(Clone, Debug, Default)]
+pub struct OrderEventUpdateData {
+    pub status: Option<OrderEventStatus>,
+    pub attempts: Option<i32>,
+    pub next_attempt_at: Option<DateTime<Utc>>,
+    pub last_error: Option<Option<String>>,
+    pub delivered_to: Option<Vec<String>>,
+}
+
+pub struct OrderEventUpdater {
+    pub mask: OrderEventFilter,
+    pub data: OrderEventUpdateData,
+}
+
+impl Updater for OrderEventUpdater {
+    fn into_update_builder(self, table: &'static str) -> UpdateBuilder {
+        let OrderEventUpdater { mask, data } = self;
+
+        let mut b = UpdateBuilder::from(mask.into_filtered_operation_builder(table));
+
+        if let Some(status) = data.status {
+            b = b.with_value(STATUS_COLUMN, status.to_string());
+        }
+
+        if let Some(attempts) = data.attempts {
+            b = b.with_value(ATTEMPTS_COLUMN, attempts);
+        }
+
+        if let Some(next_attempt_at) = data.next_attempt_at {
+            b = b.with_value(NEXT_ATTEMPT_AT_COLUMN, next_attempt_at);
+        }
+
+        if let Some(last_error) = data.last_error {
+            b = b.with_value(LAST_ERROR_COLUMN, last_error);
+        }
+
+        if let Some(delivered_to) = data.delivered_to {
+            b = b.with_value(
+                DELIVERED_TO_COLUMN,
+                serde_json::to_value(delivered_to).unwrap_or_else(|_| Value::Array(vec![])),
+            );
+        }
+
+        b
+    }
+}
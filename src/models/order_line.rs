@@ -0,0 +1,323 @@
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::prelude::*;
+use tokio_postgres::rows::Row;
+use uuid::Uuid;
+
+use stq_db::statement::*;
+use stq_types::*;
+
+use super::*;
+
+const ID_COLUMN: &str = "id";
+const ORDER_ID_COLUMN: &str = "order_id";
+const POSITION_COLUMN: &str = "position";
+const CREATED_FROM_COLUMN: &str = "created_from";
+const STORE_COLUMN: &str = "store";
+const CUSTOMER_COLUMN: &str = "customer";
+const PRODUCT_COLUMN: &str = "product";
+const QUANTITY_COLUMN: &str = "quantity";
+const PRICE_COLUMN: &str = "price";
+const PRE_ORDER_COLUMN: &str = "pre_order";
+const PRE_ORDER_DAYS_COLUMN: &str = "pre_order_days";
+const COUPON_ID_COLUMN: &str = "coupon_id";
+const COUPON_PERCENT_COLUMN: &str = "coupon_percent";
+const COUPON_DISCOUNT_COLUMN: &str = "coupon_discount";
+const PRODUCT_DISCOUNT_COLUMN: &str = "product_discount";
+const DELIVERY_COMPANY_COLUMN: &str = "delivery_company";
+const COMPANY_PACKAGE_ID_COLUMN: &str = "company_package_id";
+const SHIPPING_ID_COLUMN: &str = "shipping_id";
+const DELIVERY_PRICE_COLUMN: &str = "delivery_price";
+const DELIVERY_PRICING_COLUMN: &str = "delivery_pricing";
+const PRODUCT_CASHBACK_COLUMN: &str = "product_cashback";
+const TAX_AMOUNT_COLUMN: &str = "tax_amount";
+const TOTAL_AMOUNT_COLUMN: &str = "total_amount";
+const COMMENT_COLUMN: &str = "comment";
+const CANCELLED_AT_COLUMN: &str = "cancelled_at";
+const CREATED_AT_COLUMN: &str = "created_at";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct OrderLineId(pub Uuid);
+
+impl OrderLineId {
+    pub fn new() -> Self {
+        OrderLineId(Uuid::new_v4())
+    }
+}
+
+impl fmt::Display for OrderLineId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Product bought within the order.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct OrderLine {
+    pub id: OrderLineId,
+    pub order_id: OrderId,
+    /// Number of the line within the order starting from zero
+    pub position: i32,
+    /// Cart item the line was converted from, not set for "buy now" orders
+    pub created_from: Option<CartItemId>,
+    pub store: StoreId,
+    pub customer: UserId,
+    pub product: ProductId,
+    pub quantity: Quantity,
+    pub price: Money,
+    pub pre_order: bool,
+    pub pre_order_days: i32,
+    pub coupon_id: Option<CouponId>,
+    pub coupon_percent: Option<i32>,
+    pub coupon_discount: Option<Money>,
+    pub product_discount: Option<Money>,
+    pub delivery_company: Option<String>,
+    pub company_package_id: Option<CompanyPackageId>,
+    pub shipping_id: Option<ShippingId>,
+    pub delivery_price: Money,
+    pub delivery_pricing: DeliveryPricing,
+    pub product_cashback: Option<CashbackPercent>,
+    /// Taxes of the line, exclusive ones are included into `total_amount`
+    pub tax_amount: Money,
+    pub total_amount: Money,
+    pub comment: String,
+    pub cancelled_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<Row> for OrderLine {
+    fn from(row: Row) -> Self {
+        Self {
+            id: OrderLineId(row.get(ID_COLUMN)),
+            order_id: OrderId(row.get(ORDER_ID_COLUMN)),
+            position: row.get(POSITION_COLUMN),
+            created_from: row.get::<Option<Uuid>, _>(CREATED_FROM_COLUMN).map(CartItemId),
+            store: StoreId(row.get(STORE_COLUMN)),
+            customer: UserId(row.get(CUSTOMER_COLUMN)),
+            product: ProductId(row.get(PRODUCT_COLUMN)),
+            quantity: Quantity(row.get(QUANTITY_COLUMN)),
+            price: row.get(PRICE_COLUMN),
+            pre_order: row.get(PRE_ORDER_COLUMN),
+            pre_order_days: row.get(PRE_ORDER_DAYS_COLUMN),
+            coupon_id: row.get::<Option<i32>, _>(COUPON_ID_COLUMN).map(CouponId),
+            coupon_percent: row.get(COUPON_PERCENT_COLUMN),
+            coupon_discount: row.get(COUPON_DISCOUNT_COLUMN),
+            product_discount: row.get(PRODUCT_DISCOUNT_COLUMN),
+            delivery_company: row.get(DELIVERY_COMPANY_COLUMN),
+            company_package_id: row.get::<Option<i32>, _>(COMPANY_PACKAGE_ID_COLUMN).map(CompanyPackageId),
+            shipping_id: row.get::<Option<i32>, _>(SHIPPING_ID_COLUMN).map(ShippingId),
+            delivery_price: row.get(DELIVERY_PRICE_COLUMN),
+            delivery_pricing: DeliveryPricing::from_str(row.get(DELIVERY_PRICING_COLUMN)).unwrap(),
+            product_cashback: row.get::<Option<f64>, _>(PRODUCT_CASHBACK_COLUMN).map(CashbackPercent),
+            tax_amount: row.get(TAX_AMOUNT_COLUMN),
+            total_amount: row.get(TOTAL_AMOUNT_COLUMN),
+            comment: row.get(COMMENT_COLUMN),
+            cancelled_at: row.get(CANCELLED_AT_COLUMN),
+            created_at: row.get(CREATED_AT_COLUMN),
+        }
+    }
+}
+
+impl OrderLine {
+    /// Delivery included into `total_amount`, shared delivery is charged by the first active line of the package
+    pub fn delivery_charge(&self, active_lines: &[OrderLine]) -> Money {
+        let charged_before = active_lines.iter().any(|other| {
+            other.id != self.id
+                && other.position < self.position
+                && other.quantity.0 > 0
+                && share_delivery(
+                    self.delivery_pricing,
+                    self.company_package_id,
+                    other.delivery_pricing,
+                    other.company_package_id,
+                )
+        });
+
+        if charged_before {
+            Money::zero()
+        } else {
+            delivery_amount(self.quantity, self.delivery_price, self.delivery_pricing)
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct OrderLineInserter {
+    pub id: OrderLineId,
+    pub order_id: OrderId,
+    pub position: i32,
+    pub created_from: Option<CartItemId>,
+    pub store: StoreId,
+    pub customer: UserId,
+    pub product: ProductId,
+    pub quantity: Quantity,
+    pub price: Money,
+    pub pre_order: bool,
+    pub pre_order_days: i32,
+    pub coupon_id: Option<CouponId>,
+    pub coupon_percent: Option<i32>,
+    pub coupon_discount: Option<Money>,
+    pub product_discount: Option<Money>,
+    pub delivery_company: Option<String>,
+    pub company_package_id: Option<CompanyPackageId>,
+    pub shipping_id: Option<ShippingId>,
+    pub delivery_price: Money,
+    pub delivery_pricing: DeliveryPricing,
+    pub product_cashback: Option<CashbackPercent>,
+    pub tax_amount: Money,
+    pub total_amount: Money,
+    pub comment: String,
+}
+
+impl OrderLineInserter {
+    /// Line of the product described by the single product fields of the new order
+    pub fn from_order(order_id: OrderId, position: i32, order: &OrderInserter, comment: String) -> Self {
+        Self {
+            id: OrderLineId::new(),
+            order_id,
+            position,
+            created_from: order.created_from,
+            store: order.store,
+            customer: order.customer,
+            product: order.product,
+            quantity: order.quantity,
+            price: order.price,
+            pre_order: order.pre_order,
+            pre_order_days: order.pre_order_days,
+            coupon_id: order.coupon_id,
+            coupon_percent: order.coupon_percent,
+            coupon_discount: order.coupon_discount,
+            product_discount: order.product_discount,
+            delivery_company: order.delivery_company.clone(),
+            company_package_id: order.company_package_id,
+            shipping_id: order.shipping_id,
+            delivery_price: order.delivery_price,
+            delivery_pricing: order.delivery_pricing,
+            product_cashback: order.product_cashback,
+            tax_amount: order.tax_amount,
+            total_amount: order.total_amount,
+            comment,
+        }
+    }
+}
+
+impl Inserter for OrderLineInserter {
+    fn into_insert_builder(self, table: &'static str) -> InsertBuilder {
+        InsertBuilder::new(table)
+            .with_arg(ID_COLUMN, self.id.0)
+            .with_arg(ORDER_ID_COLUMN, self.order_id.0)
+            .with_arg(POSITION_COLUMN, self.position)
+            .with_arg(CREATED_FROM_COLUMN, self.created_from.map(|v| v.0))
+            .with_arg(STORE_COLUMN, self.store.0)
+            .with_arg(CUSTOMER_COLUMN, self.customer.0)
+            .with_arg(PRODUCT_COLUMN, self.product.0)
+            .with_arg(QUANTITY_COLUMN, self.quantity.0)
+            .with_arg(PRICE_COLUMN, self.price)
+            .with_arg(PRE_ORDER_COLUMN, self.pre_order)
+            .with_arg(PRE_ORDER_DAYS_COLUMN, self.pre_order_days)
+            .with_arg(COUPON_ID_COLUMN, self.coupon_id.map(|v| v.0))
+            .with_arg(COUPON_PERCENT_COLUMN, self.coupon_percent)
+            .with_arg(COUPON_DISCOUNT_COLUMN, self.coupon_discount)
+            .with_arg(PRODUCT_DISCOUNT_COLUMN, self.product_discount)
+            .with_arg(DELIVERY_COMPANY_COLUMN, self.delivery_company)
+            .with_arg(COMPANY_PACKAGE_ID_COLUMN, self.company_package_id.map(|v| v.0))
+            .with_arg(SHIPPING_ID_COLUMN, self.shipping_id.map(|v| v.0))
+            .with_arg(DELIVERY_PRICE_COLUMN, self.delivery_price)
+            .with_arg(DELIVERY_PRICING_COLUMN, self.delivery_pricing.to_string())
+            .with_arg(PRODUCT_CASHBACK_COLUMN, self.product_cashback.map(|v| v.0))
+            .with_arg(TAX_AMOUNT_COLUMN, self.tax_amount)
+            .with_arg(TOTAL_AMOUNT_COLUMN, self.total_amount)
+            .with_arg(COMMENT_COLUMN, self.comment)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct OrderLineFilter {
+    pub do_order: bool,
+    pub id: Option<ValueContainer<OrderLineId>>,
+    pub order_id: Option<ValueContainer<OrderId>>,
+    pub order_ids: Option<ValueContainer<Vec<OrderId>>>,
+    /// `Some(None)` selects active lines only
+    pub cancelled_at: Option<ValueContainer<Option<DateTime<Utc>>>>,
+}
+
+impl OrderLineFilter {
+    pub fn with_ordering(mut self, flag: bool) -> Self {
+        self.do_order = flag;
+        self
+    }
+
+    /// Lines of the order that are not cancelled
+    pub fn active(order_id: OrderId) -> Self {
+        Self {
+            order_id: Some(order_id.into()),
+            cancelled_at: Some(None.into()),
+            ..Default::default()
+        }
+    }
+}
+
+impl Filter for OrderLineFilter {
+    fn into_filtered_operation_builder(self, table: &'static str) -> FilteredOperationBuilder {
+        let mut b = FilteredOperationBuilder::new(table);
+
+        if let Some(v) = self.id {
+            b = b.with_filter(ID_COLUMN, v.value.0);
+        }
+
+        if let Some(v) = self.order_id {
+            b = b.with_filter(ORDER_ID_COLUMN, v.value.0);
+        }
+
+        if let Some(v) = self.order_ids {
+            let ids: Vec<Uuid> = v.value.into_iter().map(|id| id.0).collect();
+            b = b.with_filter::<Uuid, _>(ORDER_ID_COLUMN, ids);
+        }
+
+        if let Some(v) = self.cancelled_at {
+            b = b.with_filter(CANCELLED_AT_COLUMN, v.value);
+        }
+
+        if self.do_order {
+            b = b.with_extra("ORDER BY position, id");
+        }
+
+        b
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct OrderLineUpdateData {
+    pub cancelled_at: Option<DateTime<Utc>>,
+    pub total_amount: Option<Money>,
+}
+
+pub struct OrderLineUpdater {
+    pub mask: OrderLineFilter,
+    pub data: OrderLineUpdateData,
+}
+
+impl Updater for OrderLineUpdater {
+    fn into_update_builder(self, table: &'static str) -> UpdateBuilder {
+        let OrderLineUpdater { mask, data } = self;
+
+        let mut b = UpdateBuilder::from(mask.into_filtered_operation_builder(table));
+
+        if let Some(cancelled_at) = data.cancelled_at {
+            b = b.with_value(CANCELLED_AT_COLUMN, cancelled_at);
+        }
+
+        if let Some(total_amount) = data.total_amount {
+            b = b.with_value(TOTAL_AMOUNT_COLUMN, total_amount);
+        }
+
+        b
+    }
+}
+
+/// Body of the request to cancel one line of the order
+#[derive(Clone, Debug, Deserialize)]
+pub struct CancelOrderLinePayload {
+    pub comment: Option<String>,
+}
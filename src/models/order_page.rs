@@ -0,0 +1,309 @@
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::prelude::*;
+use failure::{self, Fail};
+use hex;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use uuid::Uuid;
+
+use stq_api::orders::*;
+use stq_types::*;
+
+use super::*;
+use errors::Error;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderSortField {
+    CreatedAt,
+    UpdatedAt,
+    TotalAmount,
+    Slug,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortDirection {
+    Asc,
+    Desc,
+}
+
+/// Order of the found orders, ties are broken by order id in the same direction
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OrderSort {
+    pub field: OrderSortField,
+    pub direction: SortDirection,
+}
+
+/// Newest orders first
+impl Default for OrderSort {
+    fn default() -> Self {
+        Self {
+            field: OrderSortField::CreatedAt,
+            direction: SortDirection::Desc,
+        }
+    }
+}
+
+/// Value of the sort field of the order
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OrderSortKey {
+    CreatedAt(DateTime<Utc>),
+    UpdatedAt(DateTime<Utc>),
+    TotalAmount(Money),
+    Slug(OrderSlug),
+    /// Rank of the order in the text search
+    Relevance(f32),
+}
+
+impl OrderSortKey {
+    pub fn of(field: OrderSortField, order: &Order) -> Self {
+        use self::OrderSortField::*;
+
+        match field {
+            CreatedAt => OrderSortKey::CreatedAt(order.created_at),
+            UpdatedAt => OrderSortKey::UpdatedAt(order.updated_at),
+            TotalAmount => OrderSortKey::TotalAmount(order.total_amount.into()),
+            Slug => OrderSortKey::Slug(order.slug),
+        }
+    }
+
+    /// Sort field the key comes from, `None` for the rank of the text search
+    pub fn field(&self) -> Option<OrderSortField> {
+        match self {
+            OrderSortKey::CreatedAt(_) => Some(OrderSortField::CreatedAt),
+            OrderSortKey::UpdatedAt(_) => Some(OrderSortField::UpdatedAt),
+            OrderSortKey::TotalAmount(_) => Some(OrderSortField::TotalAmount),
+            OrderSortKey::Slug(_) => Some(OrderSortField::Slug),
+            OrderSortKey::Relevance(_) => None,
+        }
+    }
+}
+
+fn format_timestamp(v: DateTime<Utc>) -> String {
+    format!("{}.{:06}", v.timestamp(), v.timestamp_subsec_micros())
+}
+
+fn parse_timestamp(s: &str) -> Result<DateTime<Utc>, failure::Error> {
+    let mut parts = s.splitn(2, '.');
+    let (secs, micros) = match (parts.next(), parts.next()) {
+        (Some(secs), Some(micros)) => (secs.parse::<i64>()?, micros.parse::<u32>()?),
+        _ => bail!("Invalid timestamp: {}", s),
+    };
+    match Utc.timestamp_opt(secs, micros * 1000).single() {
+        Some(v) => Ok(v),
+        None => bail!("Invalid timestamp: {}", s),
+    }
+}
+
+impl fmt::Display for OrderSortKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OrderSortKey::CreatedAt(v) => write!(f, "created_at:{}", format_timestamp(*v)),
+            OrderSortKey::UpdatedAt(v) => write!(f, "updated_at:{}", format_timestamp(*v)),
+            OrderSortKey::TotalAmount(v) => write!(f, "total_amount:{}", v),
+            OrderSortKey::Slug(v) => write!(f, "slug:{}", v.0),
+            OrderSortKey::Relevance(v) => write!(f, "relevance:{}", v),
+        }
+    }
+}
+
+impl FromStr for OrderSortKey {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, ':');
+        Ok(match (parts.next(), parts.next()) {
+            (Some("created_at"), Some(v)) => OrderSortKey::CreatedAt(parse_timestamp(v)?),
+            (Some("updated_at"), Some(v)) => OrderSortKey::UpdatedAt(parse_timestamp(v)?),
+            (Some("total_amount"), Some(v)) => OrderSortKey::TotalAmount(v.parse()?),
+            (Some("slug"), Some(v)) => OrderSortKey::Slug(OrderSlug(v.parse()?)),
+            (Some("relevance"), Some(v)) => OrderSortKey::Relevance(v.parse()?),
+            _ => bail!("Invalid sort key: {}", s),
+        })
+    }
+}
+
+/// Position of the order in the sorted list of orders
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OrderCursor {
+    pub key: OrderSortKey,
+    pub id: OrderId,
+}
+
+impl OrderCursor {
+    pub fn of(field: OrderSortField, order: &Order) -> Self {
+        Self {
+            key: OrderSortKey::of(field, order),
+            id: order.id,
+        }
+    }
+}
+
+/// Clients should treat the cursor as an opaque string
+impl fmt::Display for OrderCursor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", hex::encode(format!("{}|{}", self.key, self.id.0)))
+    }
+}
+
+impl FromStr for OrderCursor {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let raw = String::from_utf8(hex::decode(s)?)?;
+
+        let mut parts = raw.splitn(2, '|');
+        let (key, id) = match (parts.next(), parts.next()) {
+            (Some(key), Some(id)) => (key, id),
+            _ => bail!("Invalid order cursor: {}", s),
+        };
+
+        Ok(Self {
+            key: key.parse()?,
+            id: OrderId(Uuid::parse_str(id)?),
+        })
+    }
+}
+
+impl Serialize for OrderCursor {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for OrderCursor {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        OrderCursor::from_str(&s).map_err(de::Error::custom)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+pub struct OrderPageRequest {
+    /// Page size, `DEFAULT_LIMIT` if not set, at most `MAX_LIMIT`
+    pub limit: Option<i32>,
+    /// `next_cursor` of the previous page, the first page is returned if not set
+    pub after: Option<OrderCursor>,
+}
+
+impl OrderPageRequest {
+    pub const DEFAULT_LIMIT: i32 = 50;
+    pub const MAX_LIMIT: i32 = 500;
+
+    /// Page requested with `limit` and `after` parameters, `None` if neither is set.
+    /// A cursor that can not be parsed fails the request instead of restarting from the first page.
+    pub fn from_params(limit: Option<i32>, after: Option<String>) -> Result<Option<Self>, failure::Error> {
+        if limit.is_none() && after.is_none() {
+            return Ok(None);
+        }
+
+        let after = match after {
+            Some(after) => Some(after.parse::<OrderCursor>().map_err(|e| e.context(Error::InvalidCursor))?),
+            None => None,
+        };
+
+        Ok(Some(Self { limit, after }))
+    }
+
+    pub fn limit(&self) -> i32 {
+        self.limit.unwrap_or(Self::DEFAULT_LIMIT).max(1).min(Self::MAX_LIMIT)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct OrderPage {
+    pub orders: Vec<Order>,
+    /// Set if there may be more orders after this page
+    pub next_cursor: Option<OrderCursor>,
+}
+
+impl OrderPage {
+    /// Builds the page out of at most `limit + 1` orders, the extra one only tells that the next page exists
+    pub fn new(mut orders: Vec<Order>, limit: i32, sort_field: OrderSortField) -> Self {
+        let limit = limit as usize;
+        let next_cursor = if orders.len() > limit {
+            orders.truncate(limit);
+            orders.last().map(|order| OrderCursor::of(sort_field, order))
+        } else {
+            None
+        };
+
+        Self { orders, next_cursor }
+    }
+}
+
+/// Body of the order search request
+#[derive(Deserialize)]
+pub struct OrderSearchPayload {
+    #[serde(flatten)]
+    pub terms: OrderSearchTerms,
+    #[serde(flatten)]
+    pub filters: OrderSearchFilters,
+    /// Found orders are returned as a page only if `limit` or `after` is set, all of them otherwise
+    pub limit: Option<i32>,
+    pub after: Option<String>,
+    #[serde(default)]
+    pub sort: OrderSort,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursor_roundtrip() {
+        let id = OrderId(Uuid::new_v4());
+        let keys = vec![
+            OrderSortKey::CreatedAt(Utc.ymd(2019, 2, 25).and_hms_micro(10, 11, 12, 345678)),
+            OrderSortKey::UpdatedAt(Utc.ymd(1969, 12, 31).and_hms_micro(23, 59, 59, 5)),
+            OrderSortKey::TotalAmount(Money::from_f64(1234.5678)),
+            OrderSortKey::Slug(OrderSlug(42)),
+            OrderSortKey::Relevance(0.75),
+        ];
+
+        for key in keys {
+            let cursor = OrderCursor { key, id };
+            assert_eq!(cursor.to_string().parse::<OrderCursor>().unwrap(), cursor);
+        }
+        assert!("not a cursor".parse::<OrderCursor>().is_err());
+        assert!(hex::encode("created_at:1550000000.000000").parse::<OrderCursor>().is_err());
+        assert!(hex::encode(format!("price:1|{}", id.0)).parse::<OrderCursor>().is_err());
+    }
+
+    #[test]
+    fn limit_is_clamped() {
+        let page = |limit| OrderPageRequest { limit, after: None };
+
+        assert_eq!(page(None).limit(), OrderPageRequest::DEFAULT_LIMIT);
+        assert_eq!(page(Some(0)).limit(), 1);
+        assert_eq!(page(Some(10)).limit(), 10);
+        assert_eq!(page(Some(100_000)).limit(), OrderPageRequest::MAX_LIMIT);
+    }
+
+    #[test]
+    fn page_is_requested_by_params() {
+        let cursor = OrderCursor {
+            key: OrderSortKey::Slug(OrderSlug(42)),
+            id: OrderId(Uuid::new_v4()),
+        };
+
+        assert_eq!(OrderPageRequest::from_params(None, None).unwrap(), None);
+        assert_eq!(
+            OrderPageRequest::from_params(Some(10), None).unwrap(),
+            Some(OrderPageRequest {
+                limit: Some(10),
+                after: None
+            })
+        );
+        assert_eq!(
+            OrderPageRequest::from_params(None, Some(cursor.to_string())).unwrap(),
+            Some(OrderPageRequest {
+                limit: None,
+                after: Some(cursor)
+            })
+        );
+        assert!(OrderPageRequest::from_params(Some(10), Some("not a cursor".to_string())).is_err());
+    }
+}
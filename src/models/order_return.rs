@@ -0,0 +1,434 @@
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::prelude::*;
+use failure;
+use serde_json::{self, Value};
+use tokio_postgres::rows::Row;
+use uuid::Uuid;
+
+use stq_db::statement::*;
+use stq_static_resources::{CommitterRole, OrderState};
+use stq_types::*;
+
+use super::*;
+
+const ID_COLUMN: &str = "id";
+const ORDER_ID_COLUMN: &str = "order_id";
+const ORDER_LINE_ID_COLUMN: &str = "order_line_id";
+const CUSTOMER_COLUMN: &str = "customer";
+const STORE_COLUMN: &str = "store";
+const QUANTITY_COLUMN: &str = "quantity";
+const REASON_COLUMN: &str = "reason";
+const PHOTOS_COLUMN: &str = "photos";
+const STATE_COLUMN: &str = "state";
+const TRACK_ID_COLUMN: &str = "track_id";
+const CREATED_AT_COLUMN: &str = "created_at";
+const UPDATED_AT_COLUMN: &str = "updated_at";
+
+const PARENT_COLUMN: &str = "parent";
+const COMMITTER_COLUMN: &str = "committer";
+const COMMITTER_ROLE_COLUMN: &str = "committer_role";
+const COMMITTED_AT_COLUMN: &str = "committed_at";
+const COMMENT_COLUMN: &str = "comment";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct OrderReturnId(pub Uuid);
+
+impl OrderReturnId {
+    pub fn new() -> Self {
+        OrderReturnId(Uuid::new_v4())
+    }
+}
+
+impl fmt::Display for OrderReturnId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReturnState {
+    /// Opened by the customer, waiting for the seller
+    Requested,
+    /// Seller agreed to take the products back
+    Approved,
+    Rejected,
+    /// Products are sent back, the return track id is recorded
+    Shipped,
+    /// Seller confirmed receipt of the products, the refund is requested from Saga
+    Received,
+}
+
+impl fmt::Display for ReturnState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ReturnState::*;
+
+        write!(
+            f,
+            "{}",
+            match self {
+                Requested => "requested",
+                Approved => "approved",
+                Rejected => "rejected",
+                Shipped => "shipped",
+                Received => "received",
+            }
+        )
+    }
+}
+
+impl FromStr for ReturnState {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use self::ReturnState::*;
+
+        Ok(match s {
+            "requested" => Requested,
+            "approved" => Approved,
+            "rejected" => Rejected,
+            "shipped" => Shipped,
+            "received" => Received,
+            other => bail!("Unknown return state: {}", other),
+        })
+    }
+}
+
+/// Returns committer roles that are allowed to move a return from `from` to `to`.
+/// Empty slice means that the transition is not allowed at all.
+pub fn allowed_return_committers(from: ReturnState, to: ReturnState) -> &'static [CommitterRole] {
+    use self::ReturnState::*;
+    use stq_static_resources::CommitterRole::*;
+
+    match (from, to) {
+        (Requested, Approved) => &[Seller, System],
+        (Requested, Rejected) => &[Seller, System],
+
+        (Approved, Shipped) => &[Customer, Seller, System],
+        // Products handed over to the seller without a track id
+        (Approved, Received) => &[Seller, System],
+
+        // Return track id may be corrected
+        (Shipped, Shipped) => &[Customer, Seller, System],
+        (Shipped, Received) => &[Seller, System],
+
+        _ => &[],
+    }
+}
+
+/// Checks whether `committer_role` may move a return from `from` to `to`
+pub fn is_return_transition_allowed(from: ReturnState, to: ReturnState, committer_role: CommitterRole) -> bool {
+    allowed_return_committers(from, to).contains(&committer_role)
+}
+
+/// Products of orders in these states have reached the customer and may be returned
+pub fn is_returnable(state: OrderState) -> bool {
+    match state {
+        OrderState::Delivered | OrderState::Received | OrderState::Complete | OrderState::Dispute => true,
+        _ => false,
+    }
+}
+
+/// Request of the customer to return some units of the order
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct OrderReturn {
+    pub id: OrderReturnId,
+    pub order_id: OrderId,
+    /// Line the units are returned from, not set for returns of multi-line orders opened before returns were made per line
+    pub order_line_id: Option<OrderLineId>,
+    pub customer: UserId,
+    pub store: StoreId,
+    pub quantity: Quantity,
+    pub reason: String,
+    /// URLs of photos of the products
+    pub photos: Vec<String>,
+    pub state: ReturnState,
+    pub track_id: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl From<Row> for OrderReturn {
+    fn from(row: Row) -> Self {
+        Self {
+            id: OrderReturnId(row.get(ID_COLUMN)),
+            order_id: OrderId(row.get(ORDER_ID_COLUMN)),
+            order_line_id: row.get::<Option<Uuid>, _>(ORDER_LINE_ID_COLUMN).map(OrderLineId),
+            customer: UserId(row.get(CUSTOMER_COLUMN)),
+            store: StoreId(row.get(STORE_COLUMN)),
+            quantity: Quantity(row.get(QUANTITY_COLUMN)),
+            reason: row.get(REASON_COLUMN),
+            photos: serde_json::from_value(row.get::<Value, _>(PHOTOS_COLUMN)).unwrap_or_default(),
+            state: ReturnState::from_str(row.get(STATE_COLUMN)).unwrap(),
+            track_id: row.get(TRACK_ID_COLUMN),
+            created_at: row.get(CREATED_AT_COLUMN),
+            updated_at: row.get(UPDATED_AT_COLUMN),
+        }
+    }
+}
+
+/// Return opened by the customer
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct NewOrderReturnPayload {
+    pub order_line_id: OrderLineId,
+    pub quantity: Quantity,
+    pub reason: String,
+    #[serde(default)]
+    pub photos: Vec<String>,
+}
+
+impl NewOrderReturnPayload {
+    pub fn validate(&self) -> Result<(), failure::Error> {
+        if self.quantity.0 <= 0 {
+            bail!("Returned quantity has to be positive, got {}", self.quantity.0);
+        }
+
+        if self.reason.trim().is_empty() {
+            bail!("Return reason is required");
+        }
+
+        if let Some(photo) = self
+            .photos
+            .iter()
+            .find(|photo| !(photo.starts_with("https://") || photo.starts_with("http://")))
+        {
+            bail!("Photo has to be an HTTP URL, got {}", photo);
+        }
+
+        Ok(())
+    }
+}
+
+pub struct OrderReturnInserter {
+    pub id: OrderReturnId,
+    pub order_id: OrderId,
+    pub order_line_id: OrderLineId,
+    pub customer: UserId,
+    pub store: StoreId,
+    pub quantity: Quantity,
+    pub reason: String,
+    pub photos: Vec<String>,
+}
+
+impl Inserter for OrderReturnInserter {
+    fn into_insert_builder(self, table: &'static str) -> InsertBuilder {
+        InsertBuilder::new(table)
+            .with_arg(ID_COLUMN, self.id.0)
+            .with_arg(ORDER_ID_COLUMN, self.order_id.0)
+            .with_arg(ORDER_LINE_ID_COLUMN, self.order_line_id.0)
+            .with_arg(CUSTOMER_COLUMN, self.customer.0)
+            .with_arg(STORE_COLUMN, self.store.0)
+            .with_arg(QUANTITY_COLUMN, self.quantity.0)
+            .with_arg(REASON_COLUMN, self.reason)
+            .with_arg(
+                PHOTOS_COLUMN,
+                serde_json::to_value(self.photos).unwrap_or_else(|_| Value::Array(vec![])),
+            )
+            .with_arg(STATE_COLUMN, ReturnState::Requested.to_string())
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct OrderReturnFilter {
+    pub do_order: bool,
+    pub id: Option<ValueContainer<OrderReturnId>>,
+    pub order_id: Option<ValueContainer<OrderId>>,
+    pub state: Option<ValueContainer<ReturnState>>,
+}
+
+impl OrderReturnFilter {
+    pub fn with_ordering(mut self, flag: bool) -> Self {
+        self.do_order = flag;
+        self
+    }
+}
+
+impl Filter for OrderReturnFilter {
+    fn into_filtered_operation_builder(self, table: &'static str) -> FilteredOperationBuilder {
+        let mut b = FilteredOperationBuilder::new(table);
+
+        if let Some(v) = self.id {
+            b = b.with_filter(ID_COLUMN, v.value.0);
+        }
+
+        if let Some(v) = self.order_id {
+            b = b.with_filter(ORDER_ID_COLUMN, v.value.0);
+        }
+
+        if let Some(v) = self.state {
+            b = b.with_filter(STATE_COLUMN, v.value.to_string());
+        }
+
+        if self.do_order {
+            b = b.with_extra("ORDER BY created_at");
+        }
+
+        b
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct OrderReturnUpdateData {
+    pub state: Option<ReturnState>,
+    pub track_id: Option<Option<String>>,
+}
+
+pub struct OrderReturnUpdater {
+    pub mask: OrderReturnFilter,
+    pub data: OrderReturnUpdateData,
+}
+
+impl Updater for OrderReturnUpdater {
+    fn into_update_builder(self, table: &'static str) -> UpdateBuilder {
+        let OrderReturnUpdater { mask, data } = self;
+
+        let mut b = UpdateBuilder::from(mask.into_filtered_operation_builder(table)).with_value(UPDATED_AT_COLUMN, Utc::now());
+
+        if let Some(state) = data.state {
+            b = b.with_value(STATE_COLUMN, state.to_string());
+        }
+
+        if let Some(track_id) = data.track_id {
+            b = b.with_value(TRACK_ID_COLUMN, track_id);
+        }
+
+        b
+    }
+}
+
+/// Moves the return to the next state, the committer role is derived from the caller
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct SetReturnStatePayload {
+    pub state: ReturnState,
+    /// Required when the products are shipped
+    pub track_id: Option<String>,
+    pub comment: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct OrderReturnDiffId(pub Uuid);
+
+/// State change of the return
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct OrderReturnDiff {
+    pub id: OrderReturnDiffId,
+    pub parent: OrderReturnId,
+    pub committer: UserId,
+    pub committer_role: CommitterRole,
+    pub committed_at: DateTime<Utc>,
+    pub state: ReturnState,
+    pub comment: Option<String>,
+}
+
+impl From<Row> for OrderReturnDiff {
+    fn from(row: Row) -> Self {
+        Self {
+            id: OrderReturnDiffId(row.get(ID_COLUMN)),
+            parent: OrderReturnId(row.get(PARENT_COLUMN)),
+            committer: UserId(row.get(COMMITTER_COLUMN)),
+            committer_role: row.get(COMMITTER_ROLE_COLUMN),
+            committed_at: row.get(COMMITTED_AT_COLUMN),
+            state: ReturnState::from_str(row.get(STATE_COLUMN)).unwrap(),
+            comment: row.get(COMMENT_COLUMN),
+        }
+    }
+}
+
+pub struct OrderReturnDiffInserter {
+    pub parent: OrderReturnId,
+    pub committer: UserId,
+    pub committer_role: CommitterRole,
+    pub state: ReturnState,
+    pub comment: Option<String>,
+}
+
+impl Inserter for OrderReturnDiffInserter {
+    fn into_insert_builder(self, table: &'static str) -> InsertBuilder {
+        InsertBuilder::new(table)
+            .with_arg(PARENT_COLUMN, self.parent.0)
+            .with_arg(COMMITTER_COLUMN, self.committer.0)
+            .with_arg(COMMITTER_ROLE_COLUMN, self.committer_role)
+            .with_arg(STATE_COLUMN, self.state.to_string())
+            .with_arg(COMMENT_COLUMN, self.comment)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct OrderReturnDiffFilter {
+    pub parent: Option<ValueContainer<OrderReturnId>>,
+}
+
+impl Filter for OrderReturnDiffFilter {
+    fn into_filtered_operation_builder(self, table: &'static str) -> FilteredOperationBuilder {
+        let mut b = FilteredOperationBuilder::new(table);
+
+        if let Some(v) = self.parent {
+            b = b.with_filter(PARENT_COLUMN, v.value.0);
+        }
+
+        b.with_extra("ORDER BY committed_at")
+    }
+}
+
+pub struct DummyOrderReturnDiffUpdater {}
+impl Updater for DummyOrderReturnDiffUpdater {
+    fn into_update_builder(self, _table: &'static str) -> UpdateBuilder {
+        unreachable!()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_regular_return_flow() {
+        let flow = [
+            (ReturnState::Requested, ReturnState::Approved, CommitterRole::Seller),
+            (ReturnState::Approved, ReturnState::Shipped, CommitterRole::Customer),
+            (ReturnState::Shipped, ReturnState::Received, CommitterRole::Seller),
+        ];
+
+        for (from, to, committer_role) in flow.iter().cloned() {
+            assert!(is_return_transition_allowed(from, to, committer_role));
+        }
+
+        assert!(!is_return_transition_allowed(
+            ReturnState::Requested,
+            ReturnState::Approved,
+            CommitterRole::Customer
+        ));
+        assert!(!is_return_transition_allowed(
+            ReturnState::Shipped,
+            ReturnState::Received,
+            CommitterRole::Customer
+        ));
+        assert!(!is_return_transition_allowed(
+            ReturnState::Rejected,
+            ReturnState::Approved,
+            CommitterRole::Seller
+        ));
+        assert!(!is_return_transition_allowed(
+            ReturnState::Received,
+            ReturnState::Received,
+            CommitterRole::System
+        ));
+    }
+
+    #[test]
+    fn validates_new_return() {
+        let payload = |quantity, reason: &str, photo: &str| NewOrderReturnPayload {
+            order_line_id: OrderLineId::new(),
+            quantity: Quantity(quantity),
+            reason: reason.to_string(),
+            photos: vec![photo.to_string()],
+        };
+
+        assert!(payload(1, "Broken", "https://s3.amazonaws.com/photo.png").validate().is_ok());
+        assert!(payload(0, "Broken", "https://s3.amazonaws.com/photo.png").validate().is_err());
+        assert!(payload(1, " ", "https://s3.amazonaws.com/photo.png").validate().is_err());
+        assert!(payload(1, "Broken", "file:///etc/passwd").validate().is_err());
+    }
+}
@@ -0,0 +1,113 @@
+use stq_static_resources::{CommitterRole, OrderState};
+
+/// Returns committer roles that are allowed to move an order from `from` to `to`.
+/// Empty slice means that the transition is not allowed at all.
+pub fn allowed_committers(from: OrderState, to: OrderState) -> &'static [CommitterRole] {
+    use stq_static_resources::CommitterRole::*;
+    use stq_static_resources::OrderState::*;
+
+    match (from, to) {
+        (New, PaymentAwaited) => &[Customer, System],
+        (New, Cancelled) => &[Customer, Seller, System],
+
+        (PaymentAwaited, TransactionPending) => &[System],
+        (PaymentAwaited, Paid) => &[System],
+        (PaymentAwaited, AmountExpired) => &[System],
+        (PaymentAwaited, Cancelled) => &[Customer, System],
+
+        (TransactionPending, Paid) => &[System],
+        (TransactionPending, AmountExpired) => &[System],
+        (TransactionPending, Cancelled) => &[System],
+
+        (AmountExpired, Cancelled) => &[System],
+
+        (Paid, InProcessing) => &[Seller, System],
+        (Paid, Cancelled) => &[Seller, System],
+
+        (InProcessing, Sent) => &[Seller, System],
+        (InProcessing, Cancelled) => &[Seller, System],
+
+        // Seller may correct the track id of the sent order
+        (Sent, Sent) => &[Seller, System],
+        (Sent, Delivered) => &[Seller, System],
+        (Sent, Dispute) => &[Customer, System],
+
+        (Delivered, Received) => &[Customer, System],
+        (Delivered, Complete) => &[System],
+        (Delivered, Dispute) => &[Customer, System],
+
+        (Received, Complete) => &[Customer, System],
+        (Received, Dispute) => &[Customer, System],
+
+        (Dispute, Refunded) => &[Seller, System],
+        (Dispute, Complete) => &[System],
+
+        (Cancelled, Refunded) => &[System],
+
+        _ => &[],
+    }
+}
+
+/// Checks whether `committer_role` may move an order from `from` to `to`
+pub fn is_transition_allowed(from: OrderState, to: OrderState, committer_role: CommitterRole) -> bool {
+    allowed_committers(from, to).contains(&committer_role)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_regular_order_flow() {
+        let flow = [
+            (OrderState::New, OrderState::PaymentAwaited, CommitterRole::Customer),
+            (OrderState::PaymentAwaited, OrderState::Paid, CommitterRole::System),
+            (OrderState::Paid, OrderState::InProcessing, CommitterRole::Seller),
+            (OrderState::InProcessing, OrderState::Sent, CommitterRole::Seller),
+            (OrderState::Sent, OrderState::Delivered, CommitterRole::System),
+            (OrderState::Delivered, OrderState::Complete, CommitterRole::System),
+        ];
+
+        for (from, to, committer_role) in flow.iter().cloned() {
+            assert!(
+                is_transition_allowed(from, to, committer_role),
+                "{} -> {} must be allowed for {:?}",
+                from,
+                to,
+                committer_role
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_skipping_states() {
+        let transitions = [
+            (OrderState::New, OrderState::Complete),
+            (OrderState::Refunded, OrderState::Paid),
+            (OrderState::Complete, OrderState::Sent),
+        ];
+
+        for (from, to) in transitions.iter().cloned() {
+            for committer_role in [CommitterRole::Customer, CommitterRole::Seller, CommitterRole::System]
+                .iter()
+                .cloned()
+            {
+                assert!(!is_transition_allowed(from, to, committer_role));
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_wrong_committer() {
+        let transitions = [
+            (OrderState::PaymentAwaited, OrderState::Paid, CommitterRole::Customer),
+            (OrderState::PaymentAwaited, OrderState::Paid, CommitterRole::Seller),
+            (OrderState::InProcessing, OrderState::Sent, CommitterRole::Customer),
+            (OrderState::Delivered, OrderState::Complete, CommitterRole::Seller),
+        ];
+
+        for (from, to, committer_role) in transitions.iter().cloned() {
+            assert!(!is_transition_allowed(from, to, committer_role));
+        }
+    }
+}
@@ -0,0 +1,69 @@
+use stq_api::orders::{BuyNow, ConvertCartPayload};
+use stq_static_resources::Currency;
+use stq_types::*;
+
+use super::*;
+
+/// Checkout to quote, the body is the same as the one of the checkout itself
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum QuotePayload {
+    Cart(ConvertCartPayload),
+    BuyNow(BuyNow),
+}
+
+/// Amounts of one product as it would be ordered
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct QuoteItem {
+    /// Not set for buy now
+    pub cart_item_id: Option<CartItemId>,
+    pub product: ProductId,
+    pub quantity: Quantity,
+    /// Price of one unit
+    pub price: Money,
+    /// Discount of one unit
+    pub product_discount: Option<Money>,
+    /// Coupon discount allocated to the product, see `CouponScope`
+    pub coupon_discount: Option<Money>,
+    pub delivery_price: Money,
+    pub delivery_pricing: DeliveryPricing,
+    /// Delivery charged for the product, zero if it is charged with another product of the same package
+    pub delivery_amount: Money,
+    pub cashback: Option<Money>,
+    /// Exclusive taxes are included into `total_amount`
+    pub tax_amount: Money,
+    pub taxes: Vec<CalculatedTax>,
+    pub total_amount: Money,
+}
+
+/// Amounts of the order the products of one store in one currency would make
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct QuoteOrder {
+    pub store: StoreId,
+    pub currency: Currency,
+    pub items: Vec<QuoteItem>,
+    /// Price of all units before discounts
+    pub subtotal: Money,
+    pub product_discount: Money,
+    pub coupon_discount: Money,
+    pub delivery_amount: Money,
+    pub cashback: Money,
+    pub tax_amount: Money,
+    pub total_amount: Money,
+}
+
+/// Cart item the checkout would fail on
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct QuoteFailure {
+    pub cart_item_id: CartItemId,
+    pub store: StoreId,
+    pub product: ProductId,
+    pub reason: String,
+}
+
+/// Orders the checkout would create, nothing is stored
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Quote {
+    pub orders: Vec<QuoteOrder>,
+    pub failures: Vec<QuoteFailure>,
+}
@@ -0,0 +1,307 @@
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::prelude::*;
+use failure;
+use tokio_postgres::rows::Row;
+use uuid::Uuid;
+
+use stq_db::statement::*;
+use stq_static_resources::{CommitterRole, Currency};
+use stq_types::*;
+
+use super::*;
+
+const ID_COLUMN: &str = "id";
+const ORDER_ID_COLUMN: &str = "order_id";
+const CUSTOMER_COLUMN: &str = "customer";
+const STORE_COLUMN: &str = "store";
+const RETURN_ID_COLUMN: &str = "return_id";
+const AMOUNT_COLUMN: &str = "amount";
+const CURRENCY_COLUMN: &str = "currency";
+const REASON_COLUMN: &str = "reason";
+const COMMITTER_COLUMN: &str = "committer";
+const COMMITTER_ROLE_COLUMN: &str = "committer_role";
+const SAGA_STATUS_COLUMN: &str = "saga_status";
+const CREATED_AT_COLUMN: &str = "created_at";
+const UPDATED_AT_COLUMN: &str = "updated_at";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct RefundId(pub Uuid);
+
+impl RefundId {
+    pub fn new() -> Self {
+        RefundId(Uuid::new_v4())
+    }
+}
+
+impl fmt::Display for RefundId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Money given back to the customer of the order
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Refund {
+    pub id: RefundId,
+    pub order_id: OrderId,
+    pub customer: UserId,
+    pub store: StoreId,
+    /// Set when the refund is made for the received return
+    pub return_id: Option<OrderReturnId>,
+    pub amount: Money,
+    pub currency: Currency,
+    pub reason: String,
+    pub committer: UserId,
+    pub committer_role: CommitterRole,
+    /// Whether Saga has acknowledged the refund request
+    pub saga_status: SagaNotificationStatus,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl From<Row> for Refund {
+    fn from(row: Row) -> Self {
+        Self {
+            id: RefundId(row.get(ID_COLUMN)),
+            order_id: OrderId(row.get(ORDER_ID_COLUMN)),
+            customer: UserId(row.get(CUSTOMER_COLUMN)),
+            store: StoreId(row.get(STORE_COLUMN)),
+            return_id: row.get::<Option<Uuid>, _>(RETURN_ID_COLUMN).map(OrderReturnId),
+            amount: row.get(AMOUNT_COLUMN),
+            currency: Currency::from_str(row.get(CURRENCY_COLUMN)).unwrap(),
+            reason: row.get(REASON_COLUMN),
+            committer: UserId(row.get(COMMITTER_COLUMN)),
+            committer_role: row.get(COMMITTER_ROLE_COLUMN),
+            saga_status: SagaNotificationStatus::from_str(row.get(SAGA_STATUS_COLUMN)).unwrap(),
+            created_at: row.get(CREATED_AT_COLUMN),
+            updated_at: row.get(UPDATED_AT_COLUMN),
+        }
+    }
+}
+
+/// Refund made by the seller or the support, in the currency of the order
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct NewRefundPayload {
+    pub amount: Money,
+    pub reason: String,
+}
+
+impl NewRefundPayload {
+    /// Validates the payload against the currency of the refunded order
+    pub fn validate(&self, currency: Currency) -> Result<(), failure::Error> {
+        if self.amount <= Money::zero() {
+            bail!("Refund amount has to be positive, got {}", self.amount);
+        }
+
+        if self.amount != self.amount.round_to(currency) {
+            bail!("Refund amount {} is not exact in minor units of {}", self.amount, currency);
+        }
+
+        if self.reason.trim().is_empty() {
+            bail!("Refund reason is required");
+        }
+
+        Ok(())
+    }
+}
+
+/// Part of the paid amount of the order that is not refunded yet, zero for unpaid orders
+pub fn refundable_amount(order: &DbOrder) -> Money {
+    let DbOrder(ref order, ref meta) = *order;
+    let paid_amount = meta.paid_amount.unwrap_or_default().round_to(order.currency);
+    if meta.refunded_amount >= paid_amount {
+        Money::zero()
+    } else {
+        paid_amount - meta.refunded_amount
+    }
+}
+
+/// Amount paid for `quantity` units of the returned lines, delivery is not refunded.
+/// Returned lines have to be among `active_lines` of the order, the ones charging shared delivery are found there.
+pub fn returned_units_amount(returned_lines: &[&OrderLine], active_lines: &[OrderLine], quantity: Quantity, currency: Currency) -> Money {
+    let ordered = returned_lines.iter().fold(0, |sum, line| sum + line.quantity.0);
+    if ordered <= 0 {
+        return Money::zero();
+    }
+
+    let products_amount = returned_lines.iter().fold(Money::zero(), |sum, line| {
+        sum + line.total_amount - line.delivery_charge(active_lines)
+    });
+    // Returned and kept units weigh the same
+    let weights = [
+        Money::from_units(i128::from(quantity.0)),
+        Money::from_units(i128::from(ordered - quantity.0)),
+    ];
+    products_amount.allocate(&weights, minor_units(currency))[0]
+}
+
+pub struct RefundInserter {
+    pub id: RefundId,
+    pub order_id: OrderId,
+    pub customer: UserId,
+    pub store: StoreId,
+    pub return_id: Option<OrderReturnId>,
+    pub amount: Money,
+    pub currency: Currency,
+    pub reason: String,
+    pub committer: UserId,
+    pub committer_role: CommitterRole,
+}
+
+impl Inserter for RefundInserter {
+    fn into_insert_builder(self, table: &'static str) -> InsertBuilder {
+        InsertBuilder::new(table)
+            .with_arg(ID_COLUMN, self.id.0)
+            .with_arg(ORDER_ID_COLUMN, self.order_id.0)
+            .with_arg(CUSTOMER_COLUMN, self.customer.0)
+            .with_arg(STORE_COLUMN, self.store.0)
+            .with_arg(RETURN_ID_COLUMN, self.return_id.map(|v| v.0))
+            .with_arg(AMOUNT_COLUMN, self.amount)
+            .with_arg(CURRENCY_COLUMN, self.currency.to_string())
+            .with_arg(REASON_COLUMN, self.reason)
+            .with_arg(COMMITTER_COLUMN, self.committer.0)
+            .with_arg(COMMITTER_ROLE_COLUMN, self.committer_role)
+            .with_arg(SAGA_STATUS_COLUMN, SagaNotificationStatus::Pending.to_string())
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct RefundFilter {
+    pub do_order: bool,
+    pub id: Option<ValueContainer<RefundId>>,
+    pub order_id: Option<ValueContainer<OrderId>>,
+    pub created_at: Option<ValueContainer<Range<DateTime<Utc>>>>,
+}
+
+impl RefundFilter {
+    pub fn with_ordering(mut self, flag: bool) -> Self {
+        self.do_order = flag;
+        self
+    }
+}
+
+impl Filter for RefundFilter {
+    fn into_filtered_operation_builder(self, table: &'static str) -> FilteredOperationBuilder {
+        let mut b = FilteredOperationBuilder::new(table);
+
+        if let Some(v) = self.id {
+            b = b.with_filter(ID_COLUMN, v.value.0);
+        }
+
+        if let Some(v) = self.order_id {
+            b = b.with_filter(ORDER_ID_COLUMN, v.value.0);
+        }
+
+        if let Some(v) = self.created_at {
+            b = b.with_filter::<DateTime<Utc>, _>(CREATED_AT_COLUMN, v.value);
+        }
+
+        if self.do_order {
+            b = b.with_extra("ORDER BY created_at");
+        }
+
+        b
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct RefundUpdateData {
+    pub saga_status: Option<SagaNotificationStatus>,
+}
+
+pub struct RefundUpdater {
+    pub mask: RefundFilter,
+    pub data: RefundUpdateData,
+}
+
+impl Updater for RefundUpdater {
+    fn into_update_builder(self, table: &'static str) -> UpdateBuilder {
+        let RefundUpdater { mask, data } = self;
+
+        let mut b = UpdateBuilder::from(mask.into_filtered_operation_builder(table)).with_value(UPDATED_AT_COLUMN, Utc::now());
+
+        if let Some(saga_status) = data.saga_status {
+            b = b.with_value(SAGA_STATUS_COLUMN, saga_status.to_string());
+        }
+
+        b
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_new_refund() {
+        let payload = |amount: &str, reason: &str| NewRefundPayload {
+            amount: amount.parse().unwrap(),
+            reason: reason.to_string(),
+        };
+
+        assert!(payload("10.5", "Damaged on delivery").validate(Currency::RUB).is_ok());
+        assert!(payload("0", "Damaged on delivery").validate(Currency::RUB).is_err());
+        assert!(payload("-1", "Damaged on delivery").validate(Currency::RUB).is_err());
+        assert!(payload("10.5", "").validate(Currency::RUB).is_err());
+        assert!(payload("10.005", "Damaged on delivery").validate(Currency::RUB).is_err());
+    }
+
+    fn line(position: i32, quantity: i32, delivery_pricing: DeliveryPricing, total_amount: &str) -> OrderLine {
+        OrderLine {
+            id: OrderLineId::new(),
+            order_id: OrderId(Uuid::nil()),
+            position,
+            created_from: None,
+            store: StoreId(1),
+            customer: UserId(1),
+            product: ProductId(position),
+            quantity: Quantity(quantity),
+            price: "10".parse().unwrap(),
+            pre_order: false,
+            pre_order_days: 0,
+            coupon_id: None,
+            coupon_percent: None,
+            coupon_discount: None,
+            product_discount: None,
+            delivery_company: None,
+            company_package_id: Some(CompanyPackageId(1)),
+            shipping_id: None,
+            delivery_price: "5".parse().unwrap(),
+            delivery_pricing,
+            product_cashback: None,
+            tax_amount: Money::zero(),
+            total_amount: total_amount.parse().unwrap(),
+            comment: String::new(),
+            cancelled_at: None,
+            created_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn refunds_returned_units_without_their_delivery() {
+        let amount = |s: &str| s.parse::<Money>().unwrap();
+
+        // Delivery is charged for every unit
+        let lines = vec![line(0, 3, DeliveryPricing::PerUnit, "45")];
+        assert_eq!(
+            returned_units_amount(&[&lines[0]], &lines, Quantity(1), Currency::RUB),
+            amount("10")
+        );
+
+        // Shared delivery is charged by the first line of the package only
+        let lines = vec![
+            line(0, 2, DeliveryPricing::PerPackage, "25"),
+            line(1, 3, DeliveryPricing::PerPackage, "30"),
+        ];
+        assert_eq!(
+            returned_units_amount(&[&lines[0]], &lines, Quantity(1), Currency::RUB),
+            amount("10")
+        );
+        assert_eq!(
+            returned_units_amount(&[&lines[1]], &lines, Quantity(3), Currency::RUB),
+            amount("30")
+        );
+    }
+}
@@ -0,0 +1,96 @@
+use chrono::prelude::*;
+use tokio_postgres::rows::Row;
+use uuid::Uuid;
+
+use stq_db::statement::*;
+use stq_static_resources::{CommitterRole, OrderState};
+use stq_types::*;
+
+use super::*;
+
+const ID_COLUMN: &str = "id";
+const PARENT_COLUMN: &str = "parent";
+const COMMITTER_COLUMN: &str = "committer";
+const COMMITTER_ROLE_COLUMN: &str = "committer_role";
+const FROM_STATE_COLUMN: &str = "from_state";
+const TO_STATE_COLUMN: &str = "to_state";
+const ATTEMPTED_AT_COLUMN: &str = "attempted_at";
+const COMMENT_COLUMN: &str = "comment";
+
+/// Order state change that was rejected by the transition table
+#[derive(Clone, Debug, PartialEq)]
+pub struct RejectedOrderTransition {
+    pub id: Uuid,
+    pub parent: OrderId,
+    pub committer: UserId,
+    pub committer_role: CommitterRole,
+    pub from_state: OrderState,
+    pub to_state: OrderState,
+    pub attempted_at: DateTime<Utc>,
+    pub comment: Option<String>,
+}
+
+impl From<Row> for RejectedOrderTransition {
+    fn from(row: Row) -> Self {
+        Self {
+            id: row.get(ID_COLUMN),
+            parent: OrderId(row.get(PARENT_COLUMN)),
+            committer: UserId(row.get(COMMITTER_COLUMN)),
+            committer_role: row.get(COMMITTER_ROLE_COLUMN),
+            from_state: row.get(FROM_STATE_COLUMN),
+            to_state: row.get(TO_STATE_COLUMN),
+            attempted_at: row.get(ATTEMPTED_AT_COLUMN),
+            comment: row.get(COMMENT_COLUMN),
+        }
+    }
+}
+
+pub struct RejectedOrderTransitionInserter {
+    pub parent: OrderId,
+    pub committer: UserId,
+    pub committer_role: CommitterRole,
+    pub from_state: OrderState,
+    pub to_state: OrderState,
+    pub attempted_at: DateTime<Utc>,
+    pub comment: Option<String>,
+}
+
+impl Inserter for RejectedOrderTransitionInserter {
+    fn into_insert_builder(self, table: &'static str) -> InsertBuilder {
+        InsertBuilder::new(table)
+            .with_arg(PARENT_COLUMN, self.parent.0)
+            .with_arg(COMMITTER_COLUMN, self.committer.0)
+            .with_arg(COMMITTER_ROLE_COLUMN, self.committer_role)
+            .with_arg(FROM_STATE_COLUMN, self.from_state)
+            .with_arg(TO_STATE_COLUMN, self.to_state)
+            .with_arg(ATTEMPTED_AT_COLUMN, self.attempted_at)
+            .with_arg(COMMENT_COLUMN, self.comment)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct RejectedOrderTransitionFilter {
+    pub parent: Option<ValueContainer<OrderId>>,
+    pub committer: Option<ValueContainer<UserId>>,
+    pub attempted_at_range: Option<ValueContainer<Range<DateTime<Utc>>>>,
+}
+
+impl Filter for RejectedOrderTransitionFilter {
+    fn into_filtered_operation_builder(self, table: &'static str) -> FilteredOperationBuilder {
+        let mut b = FilteredOperationBuilder::new(table);
+
+        if let Some(v) = self.parent {
+            b = b.with_filter(PARENT_COLUMN, v.value.0);
+        }
+
+        if let Some(v) = self.committer {
+            b = b.with_filter(COMMITTER_COLUMN, v.value.0);
+        }
+
+        if let Some(v) = self.attempted_at_range {
+            b = b.with_filter::<DateTime<Utc>, _>(ATTEMPTED_AT_COLUMN, v.value);
+        }
+
+        b
+    }
+}
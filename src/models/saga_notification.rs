@@ -0,0 +1,242 @@
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::prelude::*;
+use failure;
+use tokio_postgres::rows::Row;
+use uuid::Uuid;
+
+use stq_db::statement::*;
+use stq_types::*;
+
+use super::*;
+
+const ID_COLUMN: &str = "id";
+const ORDER_ID_COLUMN: &str = "order_id";
+const KIND_COLUMN: &str = "kind";
+const REFUND_ID_COLUMN: &str = "refund_id";
+const STATUS_COLUMN: &str = "status";
+const ATTEMPTS_COLUMN: &str = "attempts";
+const LAST_ERROR_COLUMN: &str = "last_error";
+const CREATED_AT_COLUMN: &str = "created_at";
+const ACKNOWLEDGED_AT_COLUMN: &str = "acknowledged_at";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct SagaNotificationId(pub i64);
+
+impl fmt::Display for SagaNotificationId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SagaNotificationStatus {
+    /// Not acknowledged by Saga yet
+    Pending,
+    Acknowledged,
+}
+
+impl fmt::Display for SagaNotificationStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::SagaNotificationStatus::*;
+
+        write!(
+            f,
+            "{}",
+            match self {
+                Pending => "pending",
+                Acknowledged => "acknowledged",
+            }
+        )
+    }
+}
+
+impl FromStr for SagaNotificationStatus {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use self::SagaNotificationStatus::*;
+
+        Ok(match s {
+            "pending" => Pending,
+            "acknowledged" => Acknowledged,
+            other => bail!("Unknown saga notification status: {}", other),
+        })
+    }
+}
+
+/// Payment Saga has to make for the order
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SagaNotificationKind {
+    /// Seller must be paid for the completed order
+    SellerPayment,
+    /// Customer must be refunded, fully or partially
+    Refund,
+}
+
+impl fmt::Display for SagaNotificationKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::SagaNotificationKind::*;
+
+        write!(
+            f,
+            "{}",
+            match self {
+                SellerPayment => "seller_payment",
+                Refund => "refund",
+            }
+        )
+    }
+}
+
+impl FromStr for SagaNotificationKind {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use self::SagaNotificationKind::*;
+
+        Ok(match s {
+            "seller_payment" => SellerPayment,
+            "refund" => Refund,
+            other => bail!("Unknown saga notification kind: {}", other),
+        })
+    }
+}
+
+/// Saga has to be told about the payment it must make for the order
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SagaNotification {
+    pub id: SagaNotificationId,
+    pub order_id: OrderId,
+    pub kind: SagaNotificationKind,
+    /// Set for refunds
+    pub refund_id: Option<RefundId>,
+    pub status: SagaNotificationStatus,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub acknowledged_at: Option<DateTime<Utc>>,
+}
+
+impl From<Row> for SagaNotification {
+    fn from(row: Row) -> Self {
+        Self {
+            id: SagaNotificationId(row.get(ID_COLUMN)),
+            order_id: OrderId(row.get(ORDER_ID_COLUMN)),
+            kind: SagaNotificationKind::from_str(row.get(KIND_COLUMN)).unwrap(),
+            refund_id: row.get::<Option<Uuid>, _>(REFUND_ID_COLUMN).map(RefundId),
+            status: SagaNotificationStatus::from_str(row.get(STATUS_COLUMN)).unwrap(),
+            attempts: row.get(ATTEMPTS_COLUMN),
+            last_error: row.get(LAST_ERROR_COLUMN),
+            created_at: row.get(CREATED_AT_COLUMN),
+            acknowledged_at: row.get(ACKNOWLEDGED_AT_COLUMN),
+        }
+    }
+}
+
+pub struct SagaNotificationInserter {
+    pub order_id: OrderId,
+    pub kind: SagaNotificationKind,
+    pub refund_id: Option<RefundId>,
+}
+
+impl SagaNotificationInserter {
+    pub fn seller_payment(order_id: OrderId) -> Self {
+        Self {
+            order_id,
+            kind: SagaNotificationKind::SellerPayment,
+            refund_id: None,
+        }
+    }
+
+    pub fn refund(order_id: OrderId, refund_id: RefundId) -> Self {
+        Self {
+            order_id,
+            kind: SagaNotificationKind::Refund,
+            refund_id: Some(refund_id),
+        }
+    }
+}
+
+impl Inserter for SagaNotificationInserter {
+    fn into_insert_builder(self, table: &'static str) -> InsertBuilder {
+        InsertBuilder::new(table)
+            .with_arg(ORDER_ID_COLUMN, self.order_id.0)
+            .with_arg(KIND_COLUMN, self.kind.to_string())
+            .with_arg(REFUND_ID_COLUMN, self.refund_id.map(|v| v.0))
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SagaNotificationFilter {
+    pub do_order: bool,
+    pub id: Option<ValueContainer<SagaNotificationId>>,
+    pub order_id: Option<ValueContainer<OrderId>>,
+    pub status: Option<ValueContainer<SagaNotificationStatus>>,
+}
+
+impl Filter for SagaNotificationFilter {
+    fn into_filtered_operation_builder(self, table: &'static str) -> FilteredOperationBuilder {
+        let mut b = FilteredOperationBuilder::new(table);
+
+        if let Some(v) = self.id {
+            b = b.with_filter(ID_COLUMN, v.value.0);
+        }
+
+        if let Some(v) = self.order_id {
+            b = b.with_filter(ORDER_ID_COLUMN, v.value.0);
+        }
+
+        if let Some(v) = self.status {
+            b = b.with_filter(STATUS_COLUMN, v.value.to_string());
+        }
+
+        if self.do_order {
+            b = b.with_extra("ORDER BY id");
+        }
+
+        b
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SagaNotificationUpdateData {
+    pub status: Option<SagaNotificationStatus>,
+    pub attempts: Option<i32>,
+    pub last_error: Option<Option<String>>,
+    pub acknowledged_at: Option<DateTime<Utc>>,
+}
+
+pub struct SagaNotificationUpdater {
+    pub mask: SagaNotificationFilter,
+    pub data: SagaNotificationUpdateData,
+}
+
+impl Updater for SagaNotificationUpdater {
+    fn into_update_builder(self, table: &'static str) -> UpdateBuilder {
+        let SagaNotificationUpdater { mask, data } = self;
+
+        let mut b = UpdateBuilder::from(mask.into_filtered_operation_builder(table));
+
+        if let Some(status) = data.status {
+            b = b.with_value(STATUS_COLUMN, status.to_string());
+        }
+
+        if let Some(attempts) = data.attempts {
+            b = b.with_value(ATTEMPTS_COLUMN, attempts);
+        }
+
+        if let Some(last_error) = data.last_error {
+            b = b.with_value(LAST_ERROR_COLUMN, last_error);
+        }
+
+        if let Some(acknowledged_at) = data.acknowledged_at {
+            b = b.with_value(ACKNOWLEDGED_AT_COLUMN, acknowledged_at);
+        }
+
+        b
+    }
+}
@@ -0,0 +1,484 @@
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::prelude::*;
+use failure;
+use serde_json::{self, Value};
+use tokio_postgres::rows::Row;
+use uuid::Uuid;
+
+use stq_db::statement::*;
+use stq_static_resources::OrderState;
+use stq_types::*;
+
+use super::{OrderLine, OrderLineId};
+
+const ID_COLUMN: &str = "id";
+const ORDER_ID_COLUMN: &str = "order_id";
+const CUSTOMER_COLUMN: &str = "customer";
+const STORE_COLUMN: &str = "store";
+const CARRIER_COLUMN: &str = "carrier";
+const TRACKING_NUMBER_COLUMN: &str = "tracking_number";
+const QUANTITY_COLUMN: &str = "quantity";
+const LINES_COLUMN: &str = "lines";
+const STATUS_COLUMN: &str = "status";
+const CREATED_AT_COLUMN: &str = "created_at";
+const UPDATED_AT_COLUMN: &str = "updated_at";
+const DELIVERED_AT_COLUMN: &str = "delivered_at";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ShipmentId(pub Uuid);
+
+impl ShipmentId {
+    pub fn new() -> Self {
+        ShipmentId(Uuid::new_v4())
+    }
+}
+
+impl fmt::Display for ShipmentId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShipmentStatus {
+    /// Handed over to the carrier
+    InTransit,
+    Delivered,
+}
+
+impl fmt::Display for ShipmentStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ShipmentStatus::*;
+
+        write!(
+            f,
+            "{}",
+            match self {
+                InTransit => "in_transit",
+                Delivered => "delivered",
+            }
+        )
+    }
+}
+
+impl FromStr for ShipmentStatus {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use self::ShipmentStatus::*;
+
+        Ok(match s {
+            "in_transit" => InTransit,
+            "delivered" => Delivered,
+            other => bail!("Unknown shipment status: {}", other),
+        })
+    }
+}
+
+/// Shipments may be added to orders in these states
+pub fn is_shippable(state: OrderState) -> bool {
+    match state {
+        OrderState::Paid | OrderState::InProcessing | OrderState::Sent => true,
+        _ => false,
+    }
+}
+
+/// Order with shipments is delivered once every one of them is delivered and they hold every unit of its active lines.
+/// Shipments added before they held units per line are compared against the units of the whole order.
+pub fn all_units_delivered(lines: &[OrderLine], shipments: &[Shipment]) -> bool {
+    if !shipments.iter().all(|shipment| shipment.status == ShipmentStatus::Delivered) {
+        return false;
+    }
+
+    if shipments.iter().any(|shipment| shipment.lines.is_empty()) {
+        let ordered = lines.iter().fold(0, |sum, line| sum + line.quantity.0);
+        let shipped = shipments.iter().fold(0, |sum, shipment| sum + shipment.quantity.0);
+        return shipped >= ordered;
+    }
+
+    lines.iter().all(|line| {
+        let shipped = shipments
+            .iter()
+            .flat_map(|shipment| shipment.lines.iter())
+            .filter(|shipment_line| shipment_line.order_line_id == line.id)
+            .fold(0, |sum, shipment_line| sum + shipment_line.quantity.0);
+        shipped >= line.quantity.0
+    })
+}
+
+/// Units of the order line in the box
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShipmentLine {
+    pub order_line_id: OrderLineId,
+    pub quantity: Quantity,
+}
+
+/// Units of all lines in the box
+pub fn shipped_quantity(lines: &[ShipmentLine]) -> Quantity {
+    Quantity(lines.iter().fold(0, |sum, line| sum + line.quantity.0))
+}
+
+/// Box of the order sent with its own tracking number, possibly by its own carrier
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Shipment {
+    pub id: ShipmentId,
+    pub order_id: OrderId,
+    pub customer: UserId,
+    pub store: StoreId,
+    pub carrier: String,
+    pub tracking_number: String,
+    /// Units of the order in the box
+    pub quantity: Quantity,
+    /// Units of every line in the box, empty for shipments added before they held units per line
+    pub lines: Vec<ShipmentLine>,
+    pub status: ShipmentStatus,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub delivered_at: Option<DateTime<Utc>>,
+}
+
+impl From<Row> for Shipment {
+    fn from(row: Row) -> Self {
+        Self {
+            id: ShipmentId(row.get(ID_COLUMN)),
+            order_id: OrderId(row.get(ORDER_ID_COLUMN)),
+            customer: UserId(row.get(CUSTOMER_COLUMN)),
+            store: StoreId(row.get(STORE_COLUMN)),
+            carrier: row.get(CARRIER_COLUMN),
+            tracking_number: row.get(TRACKING_NUMBER_COLUMN),
+            quantity: Quantity(row.get(QUANTITY_COLUMN)),
+            lines: serde_json::from_value(row.get::<Value, _>(LINES_COLUMN)).unwrap_or_default(),
+            status: ShipmentStatus::from_str(row.get(STATUS_COLUMN)).unwrap(),
+            created_at: row.get(CREATED_AT_COLUMN),
+            updated_at: row.get(UPDATED_AT_COLUMN),
+            delivered_at: row.get(DELIVERED_AT_COLUMN),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct NewShipmentPayload {
+    pub carrier: String,
+    pub tracking_number: String,
+    pub lines: Vec<ShipmentLine>,
+}
+
+impl NewShipmentPayload {
+    pub fn validate(&self) -> Result<(), failure::Error> {
+        validate_shipment(Some(&self.carrier), Some(&self.tracking_number), Some(&self.lines))
+    }
+}
+
+/// Corrects the shipment or changes its status, fields that are not set are kept
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+pub struct ShipmentUpdatePayload {
+    pub carrier: Option<String>,
+    pub tracking_number: Option<String>,
+    pub lines: Option<Vec<ShipmentLine>>,
+    pub status: Option<ShipmentStatus>,
+}
+
+impl ShipmentUpdatePayload {
+    pub fn validate(&self) -> Result<(), failure::Error> {
+        validate_shipment(
+            self.carrier.as_ref(),
+            self.tracking_number.as_ref(),
+            self.lines.as_ref().map(|lines| lines.as_slice()),
+        )
+    }
+}
+
+fn validate_shipment(
+    carrier: Option<&String>,
+    tracking_number: Option<&String>,
+    lines: Option<&[ShipmentLine]>,
+) -> Result<(), failure::Error> {
+    if carrier.map(|carrier| carrier.trim().is_empty()).unwrap_or(false) {
+        bail!("Shipment carrier is required");
+    }
+
+    if tracking_number
+        .map(|tracking_number| tracking_number.trim().is_empty())
+        .unwrap_or(false)
+    {
+        bail!("Shipment tracking number is required");
+    }
+
+    if let Some(lines) = lines {
+        if lines.is_empty() {
+            bail!("Shipment has to hold units of at least one line");
+        }
+
+        for (i, line) in lines.iter().enumerate() {
+            if line.quantity.0 <= 0 {
+                bail!(
+                    "Shipped quantity of line {} has to be positive, got {}",
+                    line.order_line_id,
+                    line.quantity.0
+                );
+            }
+
+            if lines[..i].iter().any(|other| other.order_line_id == line.order_line_id) {
+                bail!("Line {} is listed in the shipment more than once", line.order_line_id);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub struct ShipmentInserter {
+    pub id: ShipmentId,
+    pub order_id: OrderId,
+    pub customer: UserId,
+    pub store: StoreId,
+    pub carrier: String,
+    pub tracking_number: String,
+    pub lines: Vec<ShipmentLine>,
+}
+
+impl Inserter for ShipmentInserter {
+    fn into_insert_builder(self, table: &'static str) -> InsertBuilder {
+        InsertBuilder::new(table)
+            .with_arg(ID_COLUMN, self.id.0)
+            .with_arg(ORDER_ID_COLUMN, self.order_id.0)
+            .with_arg(CUSTOMER_COLUMN, self.customer.0)
+            .with_arg(STORE_COLUMN, self.store.0)
+            .with_arg(CARRIER_COLUMN, self.carrier)
+            .with_arg(TRACKING_NUMBER_COLUMN, self.tracking_number)
+            .with_arg(QUANTITY_COLUMN, shipped_quantity(&self.lines).0)
+            .with_arg(
+                LINES_COLUMN,
+                serde_json::to_value(self.lines).unwrap_or_else(|_| Value::Array(vec![])),
+            )
+            .with_arg(STATUS_COLUMN, ShipmentStatus::InTransit.to_string())
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ShipmentFilter {
+    pub do_order: bool,
+    pub id: Option<ValueContainer<ShipmentId>>,
+    pub order_id: Option<ValueContainer<OrderId>>,
+    pub status: Option<ValueContainer<ShipmentStatus>>,
+    pub tracking_number: Option<ValueContainer<String>>,
+}
+
+impl ShipmentFilter {
+    pub fn with_ordering(mut self, flag: bool) -> Self {
+        self.do_order = flag;
+        self
+    }
+}
+
+impl Filter for ShipmentFilter {
+    fn into_filtered_operation_builder(self, table: &'static str) -> FilteredOperationBuilder {
+        let mut b = FilteredOperationBuilder::new(table);
+
+        if let Some(v) = self.id {
+            b = b.with_filter(ID_COLUMN, v.value.0);
+        }
+
+        if let Some(v) = self.order_id {
+            b = b.with_filter(ORDER_ID_COLUMN, v.value.0);
+        }
+
+        if let Some(v) = self.status {
+            b = b.with_filter(STATUS_COLUMN, v.value.to_string());
+        }
+
+        if let Some(v) = self.tracking_number {
+            b = b.with_filter(TRACKING_NUMBER_COLUMN, v.value);
+        }
+
+        if self.do_order {
+            b = b.with_extra("ORDER BY created_at");
+        }
+
+        b
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ShipmentUpdateData {
+    pub carrier: Option<String>,
+    pub tracking_number: Option<String>,
+    pub lines: Option<Vec<ShipmentLine>>,
+    pub status: Option<ShipmentStatus>,
+    pub delivered_at: Option<Option<DateTime<Utc>>>,
+}
+
+impl From<ShipmentUpdatePayload> for ShipmentUpdateData {
+    fn from(payload: ShipmentUpdatePayload) -> Self {
+        let ShipmentUpdatePayload {
+            carrier,
+            tracking_number,
+            lines,
+            status,
+        } = payload;
+
+        Self {
+            carrier,
+            tracking_number,
+            lines,
+            status,
+            delivered_at: status.map(|status| match status {
+                ShipmentStatus::Delivered => Some(Utc::now()),
+                ShipmentStatus::InTransit => None,
+            }),
+        }
+    }
+}
+
+pub struct ShipmentUpdater {
+    pub mask: ShipmentFilter,
+    pub data: ShipmentUpdateData,
+}
+
+impl Updater for ShipmentUpdater {
+    fn into_update_builder(self, table: &'static str) -> UpdateBuilder {
+        let ShipmentUpdater { mask, data } = self;
+
+        let mut b = UpdateBuilder::from(mask.into_filtered_operation_builder(table)).with_value(UPDATED_AT_COLUMN, Utc::now());
+
+        if let Some(carrier) = data.carrier {
+            b = b.with_value(CARRIER_COLUMN, carrier);
+        }
+
+        if let Some(tracking_number) = data.tracking_number {
+            b = b.with_value(TRACKING_NUMBER_COLUMN, tracking_number);
+        }
+
+        if let Some(lines) = data.lines {
+            b = b
+                .with_value(QUANTITY_COLUMN, shipped_quantity(&lines).0)
+                .with_value(LINES_COLUMN, serde_json::to_value(lines).unwrap_or_else(|_| Value::Array(vec![])));
+        }
+
+        if let Some(status) = data.status {
+            b = b.with_value(STATUS_COLUMN, status.to_string());
+        }
+
+        if let Some(delivered_at) = data.delivered_at {
+            b = b.with_value(DELIVERED_AT_COLUMN, delivered_at);
+        }
+
+        b
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn order_line(quantity: i32) -> OrderLine {
+        OrderLine {
+            id: OrderLineId::new(),
+            order_id: OrderId(Uuid::nil()),
+            position: 0,
+            created_from: None,
+            store: StoreId(1),
+            customer: UserId(1),
+            product: ProductId(1),
+            quantity: Quantity(quantity),
+            price: "10".parse().unwrap(),
+            pre_order: false,
+            pre_order_days: 0,
+            coupon_id: None,
+            coupon_percent: None,
+            coupon_discount: None,
+            product_discount: None,
+            delivery_company: None,
+            company_package_id: None,
+            shipping_id: None,
+            delivery_price: "0".parse().unwrap(),
+            delivery_pricing: Default::default(),
+            product_cashback: None,
+            tax_amount: "0".parse().unwrap(),
+            total_amount: "10".parse().unwrap(),
+            comment: String::new(),
+            cancelled_at: None,
+            created_at: Utc::now(),
+        }
+    }
+
+    fn shipment(status: ShipmentStatus, lines: Vec<ShipmentLine>) -> Shipment {
+        Shipment {
+            id: ShipmentId::new(),
+            order_id: OrderId(Uuid::nil()),
+            customer: UserId(1),
+            store: StoreId(1),
+            carrier: "UPS".to_string(),
+            tracking_number: "1Z12345E0205271688".to_string(),
+            quantity: shipped_quantity(&lines),
+            lines,
+            status,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+            delivered_at: None,
+        }
+    }
+
+    fn shipment_line(line: &OrderLine, quantity: i32) -> ShipmentLine {
+        ShipmentLine {
+            order_line_id: line.id,
+            quantity: Quantity(quantity),
+        }
+    }
+
+    #[test]
+    fn order_is_delivered_with_all_units_of_every_line() {
+        let lines = vec![order_line(2), order_line(1)];
+
+        assert!(all_units_delivered(
+            &lines,
+            &[
+                shipment(ShipmentStatus::Delivered, vec![shipment_line(&lines[0], 1)]),
+                shipment(
+                    ShipmentStatus::Delivered,
+                    vec![shipment_line(&lines[0], 1), shipment_line(&lines[1], 1)]
+                ),
+            ]
+        ));
+        assert!(!all_units_delivered(
+            &lines,
+            &[
+                shipment(ShipmentStatus::Delivered, vec![shipment_line(&lines[0], 2)]),
+                shipment(ShipmentStatus::InTransit, vec![shipment_line(&lines[1], 1)]),
+            ]
+        ));
+        // Three units are shipped, but one of them is missing from the second line
+        assert!(!all_units_delivered(
+            &lines,
+            &[shipment(ShipmentStatus::Delivered, vec![shipment_line(&lines[0], 3)])]
+        ));
+    }
+
+    #[test]
+    fn validates_shipment_lines() {
+        let line = order_line(2);
+        let payload = |carrier: &str, tracking_number: &str, lines| NewShipmentPayload {
+            carrier: carrier.to_string(),
+            tracking_number: tracking_number.to_string(),
+            lines,
+        };
+
+        assert!(payload("UPS", "1Z12345E0205271688", vec![shipment_line(&line, 2)])
+            .validate()
+            .is_ok());
+        assert!(payload("", "1Z12345E0205271688", vec![shipment_line(&line, 2)]).validate().is_err());
+        assert!(payload("UPS", " ", vec![shipment_line(&line, 2)]).validate().is_err());
+        assert!(payload("UPS", "1Z12345E0205271688", vec![]).validate().is_err());
+        assert!(payload("UPS", "1Z12345E0205271688", vec![shipment_line(&line, 0)])
+            .validate()
+            .is_err());
+        assert!(
+            payload("UPS", "1Z12345E0205271688", vec![shipment_line(&line, 1), shipment_line(&line, 1)])
+                .validate()
+                .is_err()
+        );
+    }
+}
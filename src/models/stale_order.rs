@@ -0,0 +1,80 @@
+use chrono::prelude::*;
+
+use stq_api::orders::*;
+use stq_static_resources::OrderState;
+
+use super::*;
+
+/// Order staying in its state longer than expected, as listed to superadmins
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct StaleOrder {
+    #[serde(flatten)]
+    pub order: Order,
+    /// When the order entered its current state
+    pub state_since: DateTime<Utc>,
+    /// Full days the order is in its current state
+    pub age_days: i64,
+    pub escalated_at: Option<DateTime<Utc>>,
+}
+
+impl StaleOrder {
+    pub fn new(order: DbOrder, state_since: DateTime<Utc>, now: DateTime<Utc>) -> Self {
+        let DbOrder(order, meta) = order;
+
+        Self {
+            order,
+            state_since,
+            age_days: (now - state_since).num_days(),
+            escalated_at: meta.escalated_at,
+        }
+    }
+}
+
+/// When the order entered the state, given its history newest first.
+/// Diffs keeping the state, e.g. comments of the system, do not reset it.
+pub fn state_entered_at(state: OrderState, diffs: &[OrderDiff]) -> Option<DateTime<Utc>> {
+    diffs
+        .iter()
+        .take_while(|diff| diff.state == state)
+        .last()
+        .map(|diff| diff.committed_at)
+}
+
+#[cfg(test)]
+mod tests {
+    use stq_static_resources::CommitterRole;
+    use stq_types::*;
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn diff(state: OrderState, day: u32) -> OrderDiff {
+        OrderDiff {
+            id: OrderDiffId(Uuid::new_v4()),
+            parent: OrderId(Uuid::nil()),
+            committer: UserId(1),
+            committed_at: Utc.ymd(2019, 3, day).and_hms(12, 0, 0),
+            state,
+            comment: None,
+            committer_role: Some(CommitterRole::System),
+        }
+    }
+
+    #[test]
+    fn finds_when_order_entered_state() {
+        let diffs = vec![
+            diff(OrderState::Sent, 20),
+            diff(OrderState::Sent, 10),
+            diff(OrderState::InProcessing, 5),
+            diff(OrderState::Sent, 3),
+            diff(OrderState::New, 1),
+        ];
+
+        assert_eq!(
+            state_entered_at(OrderState::Sent, &diffs),
+            Some(Utc.ymd(2019, 3, 10).and_hms(12, 0, 0))
+        );
+        assert_eq!(state_entered_at(OrderState::InProcessing, &diffs), None);
+        assert_eq!(state_entered_at(OrderState::Sent, &[]), None);
+    }
+}
@@ -0,0 +1,423 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::iter;
+
+use chrono::prelude::*;
+use tokio_postgres::rows::Row;
+use uuid::Uuid;
+
+use stq_db::statement::*;
+use stq_static_resources::Currency;
+use stq_types::*;
+
+use super::*;
+
+const ID_COLUMN: &str = "id";
+const COUNTRY_COLUMN: &str = "country";
+const REGION_COLUMN: &str = "region";
+const PRODUCT_COLUMN: &str = "product";
+const ORDER_ID_COLUMN: &str = "order_id";
+const ORDER_LINE_ID_COLUMN: &str = "order_line_id";
+const STORE_COLUMN: &str = "store";
+const CUSTOMER_COLUMN: &str = "customer";
+const NAME_COLUMN: &str = "name";
+const TAX_CATEGORY_COLUMN: &str = "tax_category";
+const RATE_COLUMN: &str = "rate";
+const INCLUSIVE_COLUMN: &str = "inclusive";
+const TAXABLE_AMOUNT_COLUMN: &str = "taxable_amount";
+const TAX_AMOUNT_COLUMN: &str = "tax_amount";
+const CREATED_AT_COLUMN: &str = "created_at";
+
+/// Category of products taxed the same way
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct TaxCategory(pub String);
+
+impl Default for TaxCategory {
+    fn default() -> Self {
+        TaxCategory("standard".to_string())
+    }
+}
+
+impl fmt::Display for TaxCategory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct TaxRuleId(pub i32);
+
+impl fmt::Display for TaxRuleId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Tax of the country, or of its region when the region is set, for products of the category
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TaxRule {
+    pub id: TaxRuleId,
+    /// Matched against `AddressFull::country` ignoring case
+    pub country: String,
+    /// Matched against `AddressFull::administrative_area_level_1` ignoring case
+    pub region: Option<String>,
+    pub tax_category: TaxCategory,
+    pub name: String,
+    /// Share of 1, like the product discount
+    pub rate: f64,
+    /// Whether the price already includes the tax
+    pub inclusive: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<Row> for TaxRule {
+    fn from(row: Row) -> Self {
+        Self {
+            id: TaxRuleId(row.get(ID_COLUMN)),
+            country: row.get(COUNTRY_COLUMN),
+            region: row.get(REGION_COLUMN),
+            tax_category: TaxCategory(row.get(TAX_CATEGORY_COLUMN)),
+            name: row.get(NAME_COLUMN),
+            rate: row.get(RATE_COLUMN),
+            inclusive: row.get(INCLUSIVE_COLUMN),
+            created_at: row.get(CREATED_AT_COLUMN),
+        }
+    }
+}
+
+impl TaxRule {
+    fn applies_to(&self, address: &AddressFull, tax_category: &TaxCategory) -> bool {
+        let same = |rule_value: &str, value: &Option<String>| {
+            value
+                .as_ref()
+                .map(|value| value.trim().to_lowercase() == rule_value.trim().to_lowercase())
+                .unwrap_or(false)
+        };
+
+        self.tax_category == *tax_category
+            && same(&self.country, &address.country)
+            && self
+                .region
+                .as_ref()
+                .map(|region| same(region, &address.administrative_area_level_1))
+                .unwrap_or(true)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct TaxRuleInserter {
+    pub country: String,
+    pub region: Option<String>,
+    #[serde(default)]
+    pub tax_category: TaxCategory,
+    pub name: String,
+    pub rate: f64,
+    pub inclusive: bool,
+}
+
+impl Inserter for TaxRuleInserter {
+    fn into_insert_builder(self, table: &'static str) -> InsertBuilder {
+        InsertBuilder::new(table)
+            .with_arg(COUNTRY_COLUMN, self.country)
+            .with_arg(REGION_COLUMN, self.region)
+            .with_arg(TAX_CATEGORY_COLUMN, self.tax_category.0)
+            .with_arg(NAME_COLUMN, self.name)
+            .with_arg(RATE_COLUMN, self.rate)
+            .with_arg(INCLUSIVE_COLUMN, self.inclusive)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct TaxRuleFilter {
+    pub id: Option<ValueContainer<TaxRuleId>>,
+}
+
+impl Filter for TaxRuleFilter {
+    fn into_filtered_operation_builder(self, table: &'static str) -> FilteredOperationBuilder {
+        let mut b = FilteredOperationBuilder::new(table);
+
+        if let Some(v) = self.id {
+            b = b.with_filter(ID_COLUMN, v.value.0);
+        }
+
+        b
+    }
+}
+
+pub struct DummyTaxRuleUpdater {}
+impl Updater for DummyTaxRuleUpdater {
+    fn into_update_builder(self, _table: &'static str) -> UpdateBuilder {
+        unreachable!()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ProductTaxCategory {
+    pub product: ProductId,
+    pub tax_category: TaxCategory,
+}
+
+impl From<Row> for ProductTaxCategory {
+    fn from(row: Row) -> Self {
+        Self {
+            product: ProductId(row.get(PRODUCT_COLUMN)),
+            tax_category: TaxCategory(row.get(TAX_CATEGORY_COLUMN)),
+        }
+    }
+}
+
+impl Inserter for ProductTaxCategory {
+    fn into_insert_builder(self, table: &'static str) -> InsertBuilder {
+        InsertBuilder::new(table)
+            .with_arg(PRODUCT_COLUMN, self.product.0)
+            .with_arg(TAX_CATEGORY_COLUMN, self.tax_category.0)
+    }
+}
+
+/// Body of the request to set the tax category of the product
+#[derive(Clone, Debug, Deserialize)]
+pub struct SetProductTaxCategoryPayload {
+    pub tax_category: TaxCategory,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ProductTaxCategoryFilter {
+    pub product: Option<ValueContainer<ProductId>>,
+}
+
+impl Filter for ProductTaxCategoryFilter {
+    fn into_filtered_operation_builder(self, table: &'static str) -> FilteredOperationBuilder {
+        let mut b = FilteredOperationBuilder::new(table);
+
+        if let Some(v) = self.product {
+            b = b.with_filter(PRODUCT_COLUMN, v.value.0);
+        }
+
+        b
+    }
+}
+
+pub struct DummyProductTaxCategoryUpdater {}
+impl Updater for DummyProductTaxCategoryUpdater {
+    fn into_update_builder(self, _table: &'static str) -> UpdateBuilder {
+        unreachable!()
+    }
+}
+
+/// Tax rules of the delivery address along with categories of the products being ordered
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TaxSettings {
+    pub rules: Vec<TaxRule>,
+    pub product_categories: HashMap<ProductId, TaxCategory>,
+}
+
+impl TaxSettings {
+    /// Taxes of the product delivered to the address, every matching rule makes its own tax.
+    /// Inclusive taxes are extracted from `taxable_amount`, exclusive ones are charged on top of its net part.
+    pub fn calculate(&self, address: &AddressFull, product: ProductId, taxable_amount: Money, currency: Currency) -> Vec<CalculatedTax> {
+        let tax_category = self.product_categories.get(&product).cloned().unwrap_or_default();
+        let rules = self
+            .rules
+            .iter()
+            .filter(|rule| rule.applies_to(address, &tax_category))
+            .collect::<Vec<_>>();
+
+        // The taxable amount is split between its net part and inclusive taxes in proportion to 1 and their rates
+        let weights = iter::once(Money::from_f64(1.0))
+            .chain(rules.iter().filter(|rule| rule.inclusive).map(|rule| Money::from_f64(rule.rate)))
+            .collect::<Vec<_>>();
+        let mut parts = taxable_amount.allocate(&weights, minor_units(currency)).into_iter();
+        let net_amount = parts.next().unwrap_or_default();
+
+        rules
+            .into_iter()
+            .map(|rule| CalculatedTax {
+                name: rule.name.clone(),
+                tax_category: rule.tax_category.clone(),
+                rate: rule.rate,
+                inclusive: rule.inclusive,
+                taxable_amount,
+                tax_amount: if rule.inclusive {
+                    parts.next().unwrap_or_default()
+                } else {
+                    net_amount.share(rule.rate).round_to(currency)
+                },
+            })
+            .collect()
+    }
+}
+
+/// Tax charged on the product by one rule
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CalculatedTax {
+    pub name: String,
+    pub tax_category: TaxCategory,
+    pub rate: f64,
+    pub inclusive: bool,
+    /// Amount of the product the tax is calculated from, delivery is not taxed
+    pub taxable_amount: Money,
+    pub tax_amount: Money,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct OrderTaxLineId(pub Uuid);
+
+impl OrderTaxLineId {
+    pub fn new() -> Self {
+        OrderTaxLineId(Uuid::new_v4())
+    }
+}
+
+/// Tax of the order line.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct OrderTaxLine {
+    pub id: OrderTaxLineId,
+    pub order_id: OrderId,
+    pub order_line_id: OrderLineId,
+    pub store: StoreId,
+    pub customer: UserId,
+    #[serde(flatten)]
+    pub tax: CalculatedTax,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<Row> for OrderTaxLine {
+    fn from(row: Row) -> Self {
+        Self {
+            id: OrderTaxLineId(row.get(ID_COLUMN)),
+            order_id: OrderId(row.get(ORDER_ID_COLUMN)),
+            order_line_id: OrderLineId(row.get(ORDER_LINE_ID_COLUMN)),
+            store: StoreId(row.get(STORE_COLUMN)),
+            customer: UserId(row.get(CUSTOMER_COLUMN)),
+            tax: CalculatedTax {
+                name: row.get(NAME_COLUMN),
+                tax_category: TaxCategory(row.get(TAX_CATEGORY_COLUMN)),
+                rate: row.get(RATE_COLUMN),
+                inclusive: row.get(INCLUSIVE_COLUMN),
+                taxable_amount: row.get(TAXABLE_AMOUNT_COLUMN),
+                tax_amount: row.get(TAX_AMOUNT_COLUMN),
+            },
+            created_at: row.get(CREATED_AT_COLUMN),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct OrderTaxLineInserter {
+    pub id: OrderTaxLineId,
+    pub order_id: OrderId,
+    pub order_line_id: OrderLineId,
+    pub store: StoreId,
+    pub customer: UserId,
+    pub tax: CalculatedTax,
+}
+
+impl Inserter for OrderTaxLineInserter {
+    fn into_insert_builder(self, table: &'static str) -> InsertBuilder {
+        InsertBuilder::new(table)
+            .with_arg(ID_COLUMN, self.id.0)
+            .with_arg(ORDER_ID_COLUMN, self.order_id.0)
+            .with_arg(ORDER_LINE_ID_COLUMN, self.order_line_id.0)
+            .with_arg(STORE_COLUMN, self.store.0)
+            .with_arg(CUSTOMER_COLUMN, self.customer.0)
+            .with_arg(NAME_COLUMN, self.tax.name)
+            .with_arg(TAX_CATEGORY_COLUMN, self.tax.tax_category.0)
+            .with_arg(RATE_COLUMN, self.tax.rate)
+            .with_arg(INCLUSIVE_COLUMN, self.tax.inclusive)
+            .with_arg(TAXABLE_AMOUNT_COLUMN, self.tax.taxable_amount)
+            .with_arg(TAX_AMOUNT_COLUMN, self.tax.tax_amount)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct OrderTaxLineFilter {
+    pub do_order: bool,
+    pub order_id: Option<ValueContainer<OrderId>>,
+}
+
+impl OrderTaxLineFilter {
+    pub fn with_ordering(mut self, flag: bool) -> Self {
+        self.do_order = flag;
+        self
+    }
+}
+
+impl Filter for OrderTaxLineFilter {
+    fn into_filtered_operation_builder(self, table: &'static str) -> FilteredOperationBuilder {
+        let mut b = FilteredOperationBuilder::new(table);
+
+        if let Some(v) = self.order_id {
+            b = b.with_filter(ORDER_ID_COLUMN, v.value.0);
+        }
+
+        if self.do_order {
+            b = b.with_extra("ORDER BY created_at, id");
+        }
+
+        b
+    }
+}
+
+pub struct DummyOrderTaxLineUpdater {}
+impl Updater for DummyOrderTaxLineUpdater {
+    fn into_update_builder(self, _table: &'static str) -> UpdateBuilder {
+        unreachable!()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(region: Option<&str>, tax_category: &str, rate: f64, inclusive: bool) -> TaxRule {
+        TaxRule {
+            id: TaxRuleId(1),
+            country: "Canada".to_string(),
+            region: region.map(|v| v.to_string()),
+            tax_category: TaxCategory(tax_category.to_string()),
+            name: format!("{:?} {}", region, rate),
+            rate,
+            inclusive,
+            created_at: Utc::now(),
+        }
+    }
+
+    fn address(country: &str, region: &str) -> AddressFull {
+        AddressFull {
+            country: Some(country.to_string()),
+            administrative_area_level_1: Some(region.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn calculates_matching_taxes() {
+        let money = |v: &str| v.parse::<Money>().unwrap();
+        let mut product_categories = HashMap::new();
+        product_categories.insert(ProductId(2), TaxCategory("books".to_string()));
+        let settings = TaxSettings {
+            rules: vec![
+                rule(None, "standard", 0.05, false),
+                rule(Some("Quebec"), "standard", 0.1, false),
+                rule(None, "books", 0.2, true),
+            ],
+            product_categories,
+        };
+
+        let quebec = settings.calculate(&address("canada", "QUEBEC"), ProductId(1), money("100"), Currency::RUB);
+        let quebec = quebec.iter().map(|tax| tax.tax_amount).collect::<Vec<_>>();
+        assert_eq!(quebec, vec![money("5"), money("10")]);
+
+        let ontario = settings.calculate(&address("Canada", "Ontario"), ProductId(1), money("100"), Currency::RUB);
+        let ontario = ontario.iter().map(|tax| tax.tax_amount).collect::<Vec<_>>();
+        assert_eq!(ontario, vec![money("5")]);
+
+        let books = settings.calculate(&address("Canada", "Ontario"), ProductId(2), money("120"), Currency::RUB);
+        let books = books.iter().map(|tax| tax.tax_amount).collect::<Vec<_>>();
+        assert_eq!(books, vec![money("20")]);
+
+        assert!(settings
+            .calculate(&address("France", "Ontario"), ProductId(1), money("100"), Currency::RUB)
+            .is_empty());
+    }
+}
@@ -0,0 +1,318 @@
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::prelude::*;
+use failure;
+use tokio_postgres::rows::Row;
+use uuid::Uuid;
+
+use stq_db::statement::*;
+use stq_static_resources::OrderState;
+use stq_types::*;
+
+use super::*;
+
+const ID_COLUMN: &str = "id";
+const ORDER_ID_COLUMN: &str = "order_id";
+const SHIPMENT_ID_COLUMN: &str = "shipment_id";
+const CUSTOMER_COLUMN: &str = "customer";
+const STORE_COLUMN: &str = "store";
+const CARRIER_COLUMN: &str = "carrier";
+const TRACKING_NUMBER_COLUMN: &str = "tracking_number";
+const STATUS_CODE_COLUMN: &str = "status_code";
+const DESCRIPTION_COLUMN: &str = "description";
+const LOCATION_COLUMN: &str = "location";
+const OCCURRED_AT_COLUMN: &str = "occurred_at";
+const CREATED_AT_COLUMN: &str = "created_at";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct TrackingEventId(pub Uuid);
+
+impl TrackingEventId {
+    pub fn new() -> Self {
+        TrackingEventId(Uuid::new_v4())
+    }
+}
+
+impl fmt::Display for TrackingEventId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// State of the parcel as reported by its carrier
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryState {
+    NotDelivered,
+    Delivered,
+    /// Carrier can not deliver the parcel as planned, e.g. the address is wrong or the parcel is damaged
+    Exception,
+    /// Parcel is on its way back to the seller
+    ReturnedToSender,
+    /// Receiver was not found, the carrier will try again or hold the parcel
+    DeliveryAttempted,
+}
+
+impl DeliveryState {
+    /// States configured delivery rules are applied to
+    pub fn is_problem(self) -> bool {
+        match self {
+            DeliveryState::Exception | DeliveryState::ReturnedToSender | DeliveryState::DeliveryAttempted => true,
+            DeliveryState::NotDelivered | DeliveryState::Delivered => false,
+        }
+    }
+}
+
+impl fmt::Display for DeliveryState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::DeliveryState::*;
+
+        write!(
+            f,
+            "{}",
+            match self {
+                NotDelivered => "not_delivered",
+                Delivered => "delivered",
+                Exception => "exception",
+                ReturnedToSender => "returned_to_sender",
+                DeliveryAttempted => "delivery_attempted",
+            }
+        )
+    }
+}
+
+impl FromStr for DeliveryState {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use self::DeliveryState::*;
+
+        Ok(match s {
+            "not_delivered" => NotDelivered,
+            "delivered" => Delivered,
+            "exception" => Exception,
+            "returned_to_sender" => ReturnedToSender,
+            "delivery_attempted" => DeliveryAttempted,
+            other => bail!("Unknown delivery state: {}", other),
+        })
+    }
+}
+
+/// What is done with the sent order once its carrier reports a delivery problem
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryIssueAction {
+    /// Sets `attention_reason` of the order for its seller
+    Flag,
+    /// Moves the order to `order_state` of the rule
+    SetState,
+    /// Sends the `delivery_issue` order event
+    Notify,
+}
+
+/// Rule applied to sent orders once their carrier reports the delivery state, configured in `[[sent_orders.delivery_rules]]`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DeliveryRule {
+    pub delivery_state: DeliveryState,
+    pub action: DeliveryIssueAction,
+    /// Target state of the `set_state` action
+    pub order_state: Option<OrderState>,
+}
+
+impl DeliveryRule {
+    pub fn validate(&self) -> Result<(), failure::Error> {
+        if !self.delivery_state.is_problem() {
+            bail!("Delivery rules apply to delivery problems only, got {}", self.delivery_state);
+        }
+
+        if self.action == DeliveryIssueAction::SetState && self.order_state.is_none() {
+            bail!("Order state is required by the set_state action");
+        }
+
+        Ok(())
+    }
+}
+
+/// Comment of the order diff made by the delivery rule
+pub fn delivery_issue_comment(state: DeliveryState) -> String {
+    format!(
+        "Carrier reported a delivery problem: {}",
+        match state {
+            DeliveryState::Exception => "delivery exception",
+            DeliveryState::ReturnedToSender => "package returned to sender",
+            DeliveryState::DeliveryAttempted => "delivery attempted, receiver not found",
+            DeliveryState::NotDelivered => "package in transit",
+            DeliveryState::Delivered => "package delivered",
+        }
+    )
+}
+
+/// Activity of the parcel as reported by its carrier
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TrackingActivity {
+    #[serde(default)]
+    pub status_code: String,
+    #[serde(default)]
+    pub description: String,
+    pub location: Option<String>,
+    pub occurred_at: Option<DateTime<Utc>>,
+}
+
+/// Tracking update pushed by the carrier, carriers with other formats are translated to it before the push
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct CarrierPush {
+    pub carrier: String,
+    pub tracking_number: String,
+    pub state: DeliveryState,
+    #[serde(default)]
+    pub activities: Vec<TrackingActivity>,
+}
+
+/// Parcel of the order the activities are reported for, either the order itself or one of its shipments
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrackedParcel {
+    pub order_id: OrderId,
+    pub shipment_id: Option<ShipmentId>,
+    pub customer: UserId,
+    pub store: StoreId,
+    pub carrier: String,
+    pub tracking_number: String,
+}
+
+/// Recorded activity of the order parcel
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TrackingEvent {
+    pub id: TrackingEventId,
+    pub order_id: OrderId,
+    pub shipment_id: Option<ShipmentId>,
+    pub customer: UserId,
+    pub store: StoreId,
+    pub carrier: String,
+    pub tracking_number: String,
+    pub status_code: String,
+    pub description: String,
+    pub location: Option<String>,
+    /// Not every carrier reports when the activity happened
+    pub occurred_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<Row> for TrackingEvent {
+    fn from(row: Row) -> Self {
+        Self {
+            id: TrackingEventId(row.get(ID_COLUMN)),
+            order_id: OrderId(row.get(ORDER_ID_COLUMN)),
+            shipment_id: row.get::<Option<Uuid>, _>(SHIPMENT_ID_COLUMN).map(ShipmentId),
+            customer: UserId(row.get(CUSTOMER_COLUMN)),
+            store: StoreId(row.get(STORE_COLUMN)),
+            carrier: row.get(CARRIER_COLUMN),
+            tracking_number: row.get(TRACKING_NUMBER_COLUMN),
+            status_code: row.get(STATUS_CODE_COLUMN),
+            description: row.get(DESCRIPTION_COLUMN),
+            location: row.get(LOCATION_COLUMN),
+            occurred_at: row.get(OCCURRED_AT_COLUMN),
+            created_at: row.get(CREATED_AT_COLUMN),
+        }
+    }
+}
+
+/// Activities recorded before are skipped, so inserting returns only the new ones
+pub struct TrackingEventInserter {
+    pub id: TrackingEventId,
+    pub parcel: TrackedParcel,
+    pub activity: TrackingActivity,
+}
+
+impl Inserter for TrackingEventInserter {
+    fn into_insert_builder(self, table: &'static str) -> InsertBuilder {
+        let TrackingEventInserter { id, parcel, activity } = self;
+
+        InsertBuilder::new(table)
+            .with_arg(ID_COLUMN, id.0)
+            .with_arg(ORDER_ID_COLUMN, parcel.order_id.0)
+            .with_arg(SHIPMENT_ID_COLUMN, parcel.shipment_id.map(|v| v.0))
+            .with_arg(CUSTOMER_COLUMN, parcel.customer.0)
+            .with_arg(STORE_COLUMN, parcel.store.0)
+            .with_arg(CARRIER_COLUMN, parcel.carrier)
+            .with_arg(TRACKING_NUMBER_COLUMN, parcel.tracking_number)
+            .with_arg(STATUS_CODE_COLUMN, activity.status_code)
+            .with_arg(DESCRIPTION_COLUMN, activity.description)
+            .with_arg(LOCATION_COLUMN, activity.location)
+            .with_arg(OCCURRED_AT_COLUMN, activity.occurred_at)
+            .with_extra("ON CONFLICT DO NOTHING")
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct TrackingEventFilter {
+    pub do_order: bool,
+    pub id: Option<ValueContainer<TrackingEventId>>,
+    pub order_id: Option<ValueContainer<OrderId>>,
+}
+
+impl TrackingEventFilter {
+    pub fn with_ordering(mut self, flag: bool) -> Self {
+        self.do_order = flag;
+        self
+    }
+}
+
+impl Filter for TrackingEventFilter {
+    fn into_filtered_operation_builder(self, table: &'static str) -> FilteredOperationBuilder {
+        let mut b = FilteredOperationBuilder::new(table);
+
+        if let Some(v) = self.id {
+            b = b.with_filter(ID_COLUMN, v.value.0);
+        }
+
+        if let Some(v) = self.order_id {
+            b = b.with_filter(ORDER_ID_COLUMN, v.value.0);
+        }
+
+        if self.do_order {
+            b = b.with_extra("ORDER BY occurred_at NULLS LAST, created_at");
+        }
+
+        b
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_delivery_rules() {
+        let rule = |delivery_state, action, order_state| DeliveryRule {
+            delivery_state,
+            action,
+            order_state,
+        };
+
+        assert!(rule(DeliveryState::Exception, DeliveryIssueAction::Flag, None).validate().is_ok());
+        assert!(rule(
+            DeliveryState::ReturnedToSender,
+            DeliveryIssueAction::SetState,
+            Some(OrderState::Dispute)
+        )
+        .validate()
+        .is_ok());
+        assert!(rule(DeliveryState::ReturnedToSender, DeliveryIssueAction::SetState, None)
+            .validate()
+            .is_err());
+        assert!(rule(DeliveryState::Delivered, DeliveryIssueAction::Notify, None)
+            .validate()
+            .is_err());
+
+        for state in &[
+            DeliveryState::NotDelivered,
+            DeliveryState::Delivered,
+            DeliveryState::Exception,
+            DeliveryState::ReturnedToSender,
+            DeliveryState::DeliveryAttempted,
+        ] {
+            assert_eq!(DeliveryState::from_str(&state.to_string()).unwrap(), *state);
+        }
+    }
+}
@@ -0,0 +1,39 @@
+use futures::future;
+use futures::prelude::*;
+
+use stq_db::repo::*;
+use stq_types::CouponId;
+
+use super::raw;
+use models::*;
+
+const TABLE: &str = "coupon_rules";
+
+pub trait CouponRuleRepo: DbRepo<CouponRule, CouponRuleInserter, CouponRuleFilter, DummyCouponRuleUpdater, RepoError> {}
+
+pub type CouponRuleRepoImpl = DbRepoImpl<CouponRule, CouponRuleInserter, CouponRuleFilter, DummyCouponRuleUpdater>;
+impl CouponRuleRepo for CouponRuleRepoImpl {}
+
+type Repo = CouponRuleRepoImpl;
+
+/// Rules are managed by superadmins only, which is checked by the service
+pub fn make_su_repo() -> Repo {
+    Repo::new(TABLE)
+}
+
+/// Selects rules of the coupons
+pub fn select_applicable(conn: RepoConnection, coupons: Vec<CouponId>) -> RepoConnectionFuture<CouponRules> {
+    if coupons.is_empty() {
+        return Box::new(future::ok((CouponRules::default(), conn)));
+    }
+    let coupons = coupons.into_iter().map(|v| v.0).collect::<Vec<i32>>();
+
+    Box::new(
+        raw::query(
+            conn,
+            "SELECT * FROM coupon_rules WHERE coupon_id = ANY($1)",
+            vec![Box::new(coupons)],
+        )
+        .map(|(rows, conn)| (CouponRules(rows.into_iter().map(CouponRule::from).collect()), conn)),
+    )
+}
@@ -0,0 +1,48 @@
+use futures::prelude::*;
+
+use stq_db::repo::*;
+use stq_types::{CompanyPackageId, StoreId};
+
+use super::raw;
+use models::*;
+
+const TABLE: &str = "delivery_pricing_rules";
+
+pub trait DeliveryPricingRuleRepo:
+    DbRepo<DeliveryPricingRule, DeliveryPricingRuleInserter, DeliveryPricingRuleFilter, DummyDeliveryPricingRuleUpdater, RepoError>
+{
+}
+
+pub type DeliveryPricingRuleRepoImpl =
+    DbRepoImpl<DeliveryPricingRule, DeliveryPricingRuleInserter, DeliveryPricingRuleFilter, DummyDeliveryPricingRuleUpdater>;
+impl DeliveryPricingRuleRepo for DeliveryPricingRuleRepoImpl {}
+
+type Repo = DeliveryPricingRuleRepoImpl;
+
+/// Rules are managed by superadmins only, which is checked by the service
+pub fn make_su_repo() -> Repo {
+    Repo::new(TABLE)
+}
+
+/// Selects rules of the stores and of the packages
+pub fn select_applicable(
+    conn: RepoConnection,
+    stores: Vec<StoreId>,
+    company_package_ids: Vec<CompanyPackageId>,
+) -> RepoConnectionFuture<DeliveryPricingRules> {
+    let stores = stores.into_iter().map(|v| v.0).collect::<Vec<i32>>();
+    let company_package_ids = company_package_ids.into_iter().map(|v| v.0).collect::<Vec<i32>>();
+    Box::new(
+        raw::query(
+            conn,
+            "SELECT * FROM delivery_pricing_rules WHERE store = ANY($1) OR company_package_id = ANY($2)",
+            vec![Box::new(stores), Box::new(company_package_ids)],
+        )
+        .map(|(rows, conn)| {
+            (
+                DeliveryPricingRules(rows.into_iter().map(DeliveryPricingRule::from).collect()),
+                conn,
+            )
+        }),
+    )
+}
@@ -0,0 +1,44 @@
+use futures::prelude::*;
+
+use stq_db::repo::*;
+
+use super::raw;
+use models::*;
+
+const TABLE: &str = "job_runs";
+
+pub trait JobRunRepo: DbRepo<JobRun, JobRunInserter, JobRunFilter, JobRunUpdater, RepoError> {}
+
+pub type JobRunRepoImpl = DbRepoImpl<JobRun, JobRunInserter, JobRunFilter, JobRunUpdater>;
+impl JobRunRepo for JobRunRepoImpl {}
+
+type Repo = JobRunRepoImpl;
+
+pub fn make_su_repo() -> Repo {
+    Repo::new(TABLE)
+}
+
+/// Selects the latest run of every job, only among runs with `status` if it is set
+pub fn select_last_runs(conn: RepoConnection, status: Option<JobRunStatus>) -> RepoConnectionFuture<Vec<JobRun>> {
+    let status = status.map(|status| status.to_string());
+    Box::new(
+        raw::query(
+            conn,
+            "SELECT DISTINCT ON (job_name) * FROM job_runs WHERE $1::VARCHAR IS NULL OR status = $1 ORDER BY job_name, id DESC",
+            vec![Box::new(status)],
+        )
+        .map(|(rows, conn)| (rows.into_iter().map(JobRun::from).collect(), conn)),
+    )
+}
+
+/// Selects the latest runs of the job, newest first
+pub fn select_runs(conn: RepoConnection, job_name: String, limit: i64) -> RepoConnectionFuture<Vec<JobRun>> {
+    Box::new(
+        raw::query(
+            conn,
+            "SELECT * FROM job_runs WHERE job_name = $1 ORDER BY id DESC LIMIT $2",
+            vec![Box::new(job_name), Box::new(limit)],
+        )
+        .map(|(rows, conn)| (rows.into_iter().map(JobRun::from).collect(), conn)),
+    )
+}
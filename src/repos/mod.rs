@@ -1,8 +1,58 @@
 pub mod cart_item;
 pub use self::cart_item::*;
 
+pub mod coupon_rule;
+pub use self::coupon_rule::*;
+
+pub mod delivery_pricing_rule;
+pub use self::delivery_pricing_rule::*;
+
 pub mod order;
 pub use self::order::*;
 
 pub mod order_diff;
 pub use self::order_diff::*;
+
+pub mod order_line;
+pub use self::order_line::*;
+
+pub mod order_return;
+pub use self::order_return::*;
+
+pub mod order_return_diff;
+pub use self::order_return_diff::*;
+
+pub mod order_tax_line;
+pub use self::order_tax_line::*;
+
+pub mod product_tax_category;
+pub use self::product_tax_category::*;
+
+pub mod refund;
+pub use self::refund::*;
+
+pub mod rejected_order_transition;
+pub use self::rejected_order_transition::*;
+
+pub mod shipment;
+pub use self::shipment::*;
+
+pub mod tax_rule;
+pub use self::tax_rule::*;
+
+pub mod tracking_event;
+pub use self::tracking_event::*;
+
+pub mod raw;
+
+pub mod transaction;
+pub use self::transaction::*;
+
+pub mod order_event;
+pub use self::order_event::*;
+
+pub mod saga_notification;
+pub use self::saga_notification::*;
+
+pub mod job_run;
+pub use self::job_run::*;
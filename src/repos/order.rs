@@ -1,7 +1,14 @@
+use futures::prelude::*;
+
 use models::*;
 
-use acl::OrdersAcl;
+use tokio_postgres::types::ToSql;
+
+use super::raw;
+use acl::{check_order_acl, OrdersAcl};
+use stq_api::orders::OrderSearchTerms;
 use stq_db::repo::*;
+use stq_types::{OrderId, ProductId};
 
 const TABLE: &str = "orders";
 
@@ -18,32 +25,239 @@ pub fn make_su_repo() -> Repo {
 
 type AclContext = (DbOrder, Action);
 
-fn check_acl(login: UserLogin, (entry, action): &mut AclContext) -> bool {
-    use self::RepoLogin::*;
+pub fn make_repo(login: UserLogin) -> Repo {
+    make_su_repo().with_afterop_acl_engine(OrdersAcl(move |(entry, action): &mut AclContext| {
+        check_order_acl(&login, entry, *action != Action::Delete)
+    }))
+}
+
+/// Text query of the order search along with the other search terms and filters
+pub struct OrderTextSearch {
+    pub q: String,
+    pub terms: OrderSearchTerms,
+    pub filters: OrderSearchFilters,
+    /// Rank and id of the last order of the previous page
+    pub after: Option<(f32, OrderId)>,
+    pub limit: Option<i64>,
+}
+
+/// Selects orders visible to the caller that match the text query and the filters, most relevant first,
+/// along with their rank. Words of the query match as prefixes of the words of receiver name, email and phone,
+/// slug, track id and address, misspelled or partial words match by trigram similarity.
+///
+/// The statement is raw, so the caller's scope is applied here the same way the repo ACL applies it to reads.
+pub fn select_by_text(conn: RepoConnection, login: &UserLogin, search: OrderTextSearch) -> RepoConnectionFuture<Vec<(DbOrder, f32)>> {
+    let OrderTextSearch {
+        q,
+        terms,
+        filters,
+        after,
+        limit,
+    } = search;
+
+    let mut conditions = Conditions::default();
+    let tsquery = conditions.param(prefix_tsquery(&q));
+    let words = conditions.param(q.to_lowercase());
+    let rank = format!(
+        "ts_rank(to_tsvector('simple', search_text), to_tsquery('simple', {})) + word_similarity({}, search_text)",
+        tsquery, words
+    );
+    conditions.push(format!(
+        "(to_tsvector('simple', search_text) @@ to_tsquery('simple', {}) OR {} <% search_text)",
+        tsquery, words
+    ));
+
+    push_scope(&mut conditions, login);
+    push_search_terms(&mut conditions, terms);
+    push_search_filters(&mut conditions, filters);
+
+    let mut statement = format!(
+        "SELECT * FROM (SELECT *, {} AS rank FROM orders WHERE {}) AS found",
+        rank,
+        conditions.sql.join(" AND ")
+    );
+    if let Some((after_rank, after_id)) = after {
+        let after_rank = conditions.param(after_rank);
+        let after_id = conditions.param(after_id.0);
+        statement.push_str(&format!(
+            " WHERE rank < {rank} OR (rank = {rank} AND id > {id})",
+            rank = after_rank,
+            id = after_id
+        ));
+    }
+    statement.push_str(" ORDER BY rank DESC, id");
+    if let Some(limit) = limit {
+        let limit = conditions.param(limit);
+        statement.push_str(&format!(" LIMIT {}", limit));
+    }
+
+    Box::new(raw::query(conn, &statement, conditions.params).map(|(rows, conn)| {
+        let orders = rows
+            .into_iter()
+            .map(|row| {
+                let rank: f32 = row.get("rank");
+                (DbOrder::from(row), rank)
+            })
+            .collect();
+        (orders, conn)
+    }))
+}
+
+/// `WHERE` conditions of a raw statement along with their parameters
+#[derive(Default)]
+struct Conditions {
+    sql: Vec<String>,
+    params: Vec<Box<ToSql + Send>>,
+}
+
+impl Conditions {
+    /// Adds the parameter, returns its placeholder
+    fn param<V: ToSql + Send + 'static>(&mut self, value: V) -> String {
+        self.params.push(Box::new(value));
+        format!("${}", self.params.len())
+    }
+
+    fn push(&mut self, condition: String) {
+        self.sql.push(condition);
+    }
+
+    /// Adds `column op value` condition
+    fn push_cmp<V: ToSql + Send + 'static>(&mut self, column: &str, op: &str, value: V) {
+        let placeholder = self.param(value);
+        self.push(format!("{} {} {}", column, op, placeholder));
+    }
+}
+
+/// Superadmins see all orders, store managers orders of their stores, customers their own orders
+fn push_scope(conditions: &mut Conditions, login: &UserLogin) {
     use models::UserRole::*;
 
-    if let User { caller_roles, caller_id } = login {
-        for role_entry in caller_roles {
-            match role_entry.role {
-                Superadmin => {
-                    return true;
-                }
-                StoreManager(managed_store) => {
-                    if managed_store == entry.0.store {
-                        return *action != Action::Delete;
-                    }
-                }
-            }
+    let (caller_id, caller_roles) = match *login {
+        RepoLogin::User {
+            caller_id,
+            ref caller_roles,
+        } => (caller_id, caller_roles),
+        _ => {
+            conditions.push("FALSE".to_string());
+            return;
         }
+    };
 
-        if caller_id == entry.0.customer {
-            return *action != Action::Delete;
+    if caller_roles.iter().any(|role_entry| role_entry.role == Superadmin) {
+        return;
+    }
+
+    let mut visible = vec![format!("customer = {}", conditions.param(caller_id.0))];
+    for role_entry in caller_roles {
+        if let StoreManager(managed_store) = role_entry.role {
+            visible.push(format!("store = {}", conditions.param(managed_store.0)));
         }
     }
+    conditions.push(format!("({})", visible.join(" OR ")));
+}
 
-    false
+/// Same terms as `OrderFilter::from_search_terms` applies
+fn push_search_terms(conditions: &mut Conditions, terms: OrderSearchTerms) {
+    if let Some(v) = terms.slug {
+        conditions.push_cmp("slug", "=", v.0);
+    }
+    if let Some(v) = terms.created_from {
+        conditions.push_cmp("created_at", ">=", v);
+    }
+    if let Some(v) = terms.created_to {
+        conditions.push_cmp("created_at", "<=", v);
+    }
+    if let Some(v) = terms.updated_from {
+        conditions.push_cmp("updated_at", ">=", v);
+    }
+    if let Some(v) = terms.updated_to {
+        conditions.push_cmp("updated_at", "<=", v);
+    }
+    if let Some(v) = terms.payment_status {
+        conditions.push_cmp("payment_status", "=", v);
+    }
+    if let Some(v) = terms.customer {
+        conditions.push_cmp("customer", "=", v.0);
+    }
+    if let Some(v) = terms.store {
+        conditions.push_cmp("store", "=", v.0);
+    }
+    if let Some(v) = terms.state {
+        conditions.push_cmp("state", "=", v);
+    }
 }
 
-pub fn make_repo(login: UserLogin) -> Repo {
-    make_su_repo().with_afterop_acl_engine(OrdersAcl(move |ctx: &mut AclContext| check_acl(login.clone(), ctx)))
+/// Same filters as `OrderFilter::with_search_filters` applies
+fn push_search_filters(conditions: &mut Conditions, filters: OrderSearchFilters) {
+    if let Some(states) = filters.states {
+        let placeholders = states.into_iter().map(|state| conditions.param(state)).collect::<Vec<_>>();
+        if placeholders.is_empty() {
+            conditions.push("FALSE".to_string());
+        } else {
+            conditions.push(format!("state IN ({})", placeholders.join(", ")));
+        }
+    }
+    if let Some(v) = filters.product {
+        let product = conditions.param(v.0);
+        conditions.push(has_product_line(&product));
+    }
+    if let Some(v) = filters.delivery_company {
+        conditions.push_cmp("delivery_company", "=", v);
+    }
+    if let Some(v) = filters.track_id {
+        conditions.push_cmp("track_id", "=", v);
+    }
+    if let Some(v) = filters.pre_order {
+        conditions.push_cmp("pre_order", "=", v);
+    }
+    if let Some(v) = filters.currency_type {
+        conditions.push_cmp("currency_type", "=", v);
+    }
+    if let Some(v) = filters.total_amount_from {
+        conditions.push_cmp("total_amount", ">=", v);
+    }
+    if let Some(v) = filters.total_amount_to {
+        conditions.push_cmp("total_amount", "<=", v);
+    }
+    if let Some(v) = filters.receiver_email {
+        conditions.push_cmp("receiver_email", "=", v);
+    }
+    if let Some(v) = filters.receiver_phone {
+        conditions.push_cmp("receiver_phone", "=", v);
+    }
+}
+
+/// Selects ids of the orders having a line of the product, cancelled or not
+pub fn select_ids_with_product(conn: RepoConnection, product: ProductId) -> RepoConnectionFuture<Vec<OrderId>> {
+    let mut conditions = Conditions::default();
+    let product = conditions.param(product.0);
+    let statement = format!("SELECT id FROM orders WHERE {}", has_product_line(&product));
+
+    Box::new(
+        raw::query(conn, &statement, conditions.params)
+            .map(|(rows, conn)| (rows.into_iter().map(|row| OrderId(row.get("id"))).collect(), conn)),
+    )
+}
+
+/// Condition on `orders` matching the orders with a line of the product given by the placeholder
+fn has_product_line(product: &str) -> String {
+    format!(
+        "EXISTS (SELECT 1 FROM order_lines WHERE order_lines.order_id = orders.id AND order_lines.product = {})",
+        product
+    )
+}
+
+/// Turns free text into `tsquery` requiring every word as a prefix, dropping `tsquery` operators
+pub fn prefix_tsquery(q: &str) -> String {
+    q.split_whitespace()
+        .map(|word| {
+            word.chars()
+                .filter(|c| c.is_alphanumeric() || "@.-_".contains(*c))
+                .flat_map(char::to_lowercase)
+                .collect::<String>()
+        })
+        .filter(|word| !word.is_empty())
+        .map(|word| format!("{}:*", word))
+        .collect::<Vec<_>>()
+        .join(" & ")
 }
@@ -0,0 +1,43 @@
+use futures::prelude::*;
+
+use stq_db::repo::*;
+
+use super::raw;
+use models::*;
+
+const TABLE: &str = "order_events";
+
+pub trait OrderEventRepo: DbRepo<OrderEvent, OrderEventInserter, OrderEventFilter, OrderEventUpdater, RepoError> {}
+
+pub type OrderEventRepoImpl = DbRepoImpl<OrderEvent, OrderEventInserter, OrderEventFilter, OrderEventUpdater>;
+impl OrderEventRepo for OrderEventRepoImpl {}
+
+type Repo = OrderEventRepoImpl;
+
+pub fn make_su_repo() -> Repo {
+    Repo::new(TABLE)
+}
+
+#[cfg_attr(feature = "cargo-clippy", allow(clippy::needless_pass_by_value))]
+pub fn make_repo(_login_data: UserLogin) -> Repo {
+    make_su_repo()
+}
+
+/// Selects oldest pending events that are due for delivery.
+/// Events of the order wait until its older pending events are delivered, so webhooks get them in order.
+pub fn select_due_events(conn: RepoConnection, limit: i64) -> RepoConnectionFuture<Vec<OrderEvent>> {
+    Box::new(
+        raw::query(
+            conn,
+            "SELECT * FROM order_events AS e \
+             WHERE e.status = 'pending' AND e.next_attempt_at <= now() \
+             AND NOT EXISTS ( \
+                 SELECT 1 FROM order_events AS older \
+                 WHERE older.order_id = e.order_id AND older.status = 'pending' AND older.id < e.id \
+             ) \
+             ORDER BY e.id LIMIT $1",
+            vec![Box::new(limit)],
+        )
+        .map(|(rows, conn)| (rows.into_iter().map(OrderEvent::from).collect(), conn)),
+    )
+}
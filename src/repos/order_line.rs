@@ -0,0 +1,26 @@
+use models::*;
+
+use acl::{check_order_acl, OrdersAcl};
+use stq_db::repo::*;
+
+const TABLE: &str = "order_lines";
+
+pub trait OrderLineRepo: DbRepo<OrderLine, OrderLineInserter, OrderLineFilter, OrderLineUpdater, RepoError> {}
+
+pub type OrderLineRepoImpl = DbRepoImpl<OrderLine, OrderLineInserter, OrderLineFilter, OrderLineUpdater>;
+impl OrderLineRepo for OrderLineRepoImpl {}
+
+type Repo = OrderLineRepoImpl;
+
+pub fn make_su_repo() -> Repo {
+    Repo::new(TABLE)
+}
+
+type AclContext = (OrderLine, Action);
+
+/// Lines are visible to whoever can see the order itself
+pub fn make_repo(login: UserLogin) -> Repo {
+    make_su_repo().with_afterop_acl_engine(OrdersAcl(move |(entry, action): &mut AclContext| {
+        check_order_acl(&login, entry, *action != Action::Delete)
+    }))
+}
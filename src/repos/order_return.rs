@@ -0,0 +1,26 @@
+use models::*;
+
+use acl::{check_order_acl, OrdersAcl};
+use stq_db::repo::*;
+
+const TABLE: &str = "order_returns";
+
+pub trait OrderReturnRepo: DbRepo<OrderReturn, OrderReturnInserter, OrderReturnFilter, OrderReturnUpdater, RepoError> {}
+
+pub type OrderReturnRepoImpl = DbRepoImpl<OrderReturn, OrderReturnInserter, OrderReturnFilter, OrderReturnUpdater>;
+impl OrderReturnRepo for OrderReturnRepoImpl {}
+
+type Repo = OrderReturnRepoImpl;
+
+pub fn make_su_repo() -> Repo {
+    Repo::new(TABLE)
+}
+
+type AclContext = (OrderReturn, Action);
+
+/// Returns are visible to whoever can see the order itself, the service checks who may open them
+pub fn make_repo(login: UserLogin) -> Repo {
+    make_su_repo().with_afterop_acl_engine(OrdersAcl(move |(entry, action): &mut AclContext| {
+        check_order_acl(&login, entry, *action != Action::Delete)
+    }))
+}
@@ -0,0 +1,20 @@
+use stq_db::repo::*;
+
+use models::*;
+
+const TABLE: &str = "order_return_diffs";
+
+pub trait OrderReturnDiffRepo:
+    DbRepo<OrderReturnDiff, OrderReturnDiffInserter, OrderReturnDiffFilter, DummyOrderReturnDiffUpdater, RepoError>
+{
+}
+
+pub type OrderReturnDiffRepoImpl = DbRepoImpl<OrderReturnDiff, OrderReturnDiffInserter, OrderReturnDiffFilter, DummyOrderReturnDiffUpdater>;
+impl OrderReturnDiffRepo for OrderReturnDiffRepoImpl {}
+
+type Repo = OrderReturnDiffRepoImpl;
+
+/// History is written along with the changes of returns, which are checked by their own ACL
+pub fn make_su_repo() -> Repo {
+    Repo::new(TABLE)
+}
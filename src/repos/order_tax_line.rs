@@ -0,0 +1,26 @@
+use models::*;
+
+use acl::{check_order_acl, OrdersAcl};
+use stq_db::repo::*;
+
+const TABLE: &str = "order_tax_lines";
+
+pub trait OrderTaxLineRepo: DbRepo<OrderTaxLine, OrderTaxLineInserter, OrderTaxLineFilter, DummyOrderTaxLineUpdater, RepoError> {}
+
+pub type OrderTaxLineRepoImpl = DbRepoImpl<OrderTaxLine, OrderTaxLineInserter, OrderTaxLineFilter, DummyOrderTaxLineUpdater>;
+impl OrderTaxLineRepo for OrderTaxLineRepoImpl {}
+
+type Repo = OrderTaxLineRepoImpl;
+
+pub fn make_su_repo() -> Repo {
+    Repo::new(TABLE)
+}
+
+type AclContext = (OrderTaxLine, Action);
+
+/// Taxes are visible to whoever can see the order itself
+pub fn make_repo(login: UserLogin) -> Repo {
+    make_su_repo().with_afterop_acl_engine(OrdersAcl(move |(entry, action): &mut AclContext| {
+        check_order_acl(&login, entry, *action != Action::Delete)
+    }))
+}
@@ -0,0 +1,21 @@
+use stq_db::repo::*;
+
+use models::*;
+
+const TABLE: &str = "product_tax_categories";
+
+pub trait ProductTaxCategoryRepo:
+    DbRepo<ProductTaxCategory, ProductTaxCategory, ProductTaxCategoryFilter, DummyProductTaxCategoryUpdater, RepoError>
+{
+}
+
+pub type ProductTaxCategoryRepoImpl =
+    DbRepoImpl<ProductTaxCategory, ProductTaxCategory, ProductTaxCategoryFilter, DummyProductTaxCategoryUpdater>;
+impl ProductTaxCategoryRepo for ProductTaxCategoryRepoImpl {}
+
+type Repo = ProductTaxCategoryRepoImpl;
+
+/// Categories are managed by superadmins only, which is checked by the service
+pub fn make_su_repo() -> Repo {
+    Repo::new(TABLE)
+}
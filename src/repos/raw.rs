@@ -0,0 +1,19 @@
+use futures::prelude::*;
+use futures_state_stream::StateStream;
+use tokio_postgres::rows::Row;
+use tokio_postgres::types::ToSql;
+
+use stq_db::repo::*;
+
+/// Runs raw SQL statement that can not be expressed with statement builders and collects resulting rows
+pub fn query(conn: RepoConnection, statement: &str, params: Vec<Box<ToSql + Send>>) -> RepoConnectionFuture<Vec<Row>> {
+    Box::new(
+        conn.prepare2(statement)
+            .and_then(move |(statement, conn)| conn.query2(&statement, params).collect()),
+    )
+}
+
+/// Runs raw SQL statement without parameters, discarding resulting rows
+pub fn execute(conn: RepoConnection, statement: &str) -> impl Future<Item = RepoConnection, Error = (RepoError, RepoConnection)> {
+    query(conn, statement, vec![]).map(|(_, conn)| conn)
+}
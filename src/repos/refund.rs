@@ -0,0 +1,26 @@
+use models::*;
+
+use acl::{check_order_acl, OrdersAcl};
+use stq_db::repo::*;
+
+const TABLE: &str = "refunds";
+
+pub trait RefundRepo: DbRepo<Refund, RefundInserter, RefundFilter, RefundUpdater, RepoError> {}
+
+pub type RefundRepoImpl = DbRepoImpl<Refund, RefundInserter, RefundFilter, RefundUpdater>;
+impl RefundRepo for RefundRepoImpl {}
+
+type Repo = RefundRepoImpl;
+
+pub fn make_su_repo() -> Repo {
+    Repo::new(TABLE)
+}
+
+type AclContext = (Refund, Action);
+
+/// Refunds are visible to whoever can see the order itself, the service checks who may make them
+pub fn make_repo(login: UserLogin) -> Repo {
+    make_su_repo().with_afterop_acl_engine(OrdersAcl(move |(entry, action): &mut AclContext| {
+        check_order_acl(&login, entry, *action != Action::Delete)
+    }))
+}
@@ -0,0 +1,43 @@
+use stq_db::repo::*;
+use stq_db::statement::*;
+
+use models::*;
+
+const TABLE: &str = "rejected_order_transitions";
+
+pub struct DummyRejectedOrderTransitionUpdater {}
+impl Updater for DummyRejectedOrderTransitionUpdater {
+    fn into_update_builder(self, _table: &'static str) -> UpdateBuilder {
+        unreachable!()
+    }
+}
+
+pub trait RejectedOrderTransitionRepo:
+    DbRepo<
+    RejectedOrderTransition,
+    RejectedOrderTransitionInserter,
+    RejectedOrderTransitionFilter,
+    DummyRejectedOrderTransitionUpdater,
+    RepoError,
+>
+{
+}
+
+pub type RejectedOrderTransitionRepoImpl = DbRepoImpl<
+    RejectedOrderTransition,
+    RejectedOrderTransitionInserter,
+    RejectedOrderTransitionFilter,
+    DummyRejectedOrderTransitionUpdater,
+>;
+impl RejectedOrderTransitionRepo for RejectedOrderTransitionRepoImpl {}
+
+type Repo = RejectedOrderTransitionRepoImpl;
+
+pub fn make_su_repo() -> Repo {
+    Repo::new(TABLE)
+}
+
+#[cfg_attr(feature = "cargo-clippy", allow(clippy::needless_pass_by_value))]
+pub fn make_repo(_login_data: UserLogin) -> Repo {
+    make_su_repo()
+}
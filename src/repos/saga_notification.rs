@@ -0,0 +1,41 @@
+use stq_db::repo::*;
+
+use acl::OrdersAcl;
+use models::*;
+
+const TABLE: &str = "saga_notifications";
+
+pub trait SagaNotificationRepo:
+    DbRepo<SagaNotification, SagaNotificationInserter, SagaNotificationFilter, SagaNotificationUpdater, RepoError>
+{
+}
+
+pub type SagaNotificationRepoImpl = DbRepoImpl<SagaNotification, SagaNotificationInserter, SagaNotificationFilter, SagaNotificationUpdater>;
+impl SagaNotificationRepo for SagaNotificationRepoImpl {}
+
+type Repo = SagaNotificationRepoImpl;
+
+pub fn make_su_repo() -> Repo {
+    Repo::new(TABLE)
+}
+
+type AclContext = (SagaNotification, Action);
+
+fn check_acl(login: UserLogin, (_entry, _action): &mut AclContext) -> bool {
+    use self::RepoLogin::*;
+    use models::UserRole::*;
+
+    if let User { caller_roles, .. } = login {
+        for role_entry in caller_roles {
+            if let Superadmin = role_entry.role {
+                return true;
+            }
+        }
+    }
+
+    false
+}
+
+pub fn make_repo(login: UserLogin) -> Repo {
+    make_su_repo().with_afterop_acl_engine(OrdersAcl(move |ctx: &mut AclContext| check_acl(login.clone(), ctx)))
+}
@@ -0,0 +1,26 @@
+use models::*;
+
+use acl::{check_order_acl, OrdersAcl};
+use stq_db::repo::*;
+
+const TABLE: &str = "shipments";
+
+pub trait ShipmentRepo: DbRepo<Shipment, ShipmentInserter, ShipmentFilter, ShipmentUpdater, RepoError> {}
+
+pub type ShipmentRepoImpl = DbRepoImpl<Shipment, ShipmentInserter, ShipmentFilter, ShipmentUpdater>;
+impl ShipmentRepo for ShipmentRepoImpl {}
+
+type Repo = ShipmentRepoImpl;
+
+pub fn make_su_repo() -> Repo {
+    Repo::new(TABLE)
+}
+
+type AclContext = (Shipment, Action);
+
+/// Shipments are visible to whoever can see the order itself, the service checks who may send them
+pub fn make_repo(login: UserLogin) -> Repo {
+    make_su_repo().with_afterop_acl_engine(OrdersAcl(move |(entry, action): &mut AclContext| {
+        check_order_acl(&login, entry, *action != Action::Delete)
+    }))
+}
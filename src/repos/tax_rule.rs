@@ -0,0 +1,55 @@
+use futures::future;
+use futures::prelude::*;
+
+use stq_db::repo::*;
+use stq_types::ProductId;
+
+use super::raw;
+use models::*;
+
+const TABLE: &str = "tax_rules";
+
+pub trait TaxRuleRepo: DbRepo<TaxRule, TaxRuleInserter, TaxRuleFilter, DummyTaxRuleUpdater, RepoError> {}
+
+pub type TaxRuleRepoImpl = DbRepoImpl<TaxRule, TaxRuleInserter, TaxRuleFilter, DummyTaxRuleUpdater>;
+impl TaxRuleRepo for TaxRuleRepoImpl {}
+
+type Repo = TaxRuleRepoImpl;
+
+/// Rules are managed by superadmins only, which is checked by the service
+pub fn make_su_repo() -> Repo {
+    Repo::new(TABLE)
+}
+
+/// Selects rules of the country along with tax categories of the products, nothing is taxed without the country
+pub fn select_tax_settings(conn: RepoConnection, country: Option<String>, products: Vec<ProductId>) -> RepoConnectionFuture<TaxSettings> {
+    let country = match country {
+        Some(country) => country,
+        None => return Box::new(future::ok((TaxSettings::default(), conn))),
+    };
+    let products = products.into_iter().map(|v| v.0).collect::<Vec<i32>>();
+
+    Box::new(
+        raw::query(
+            conn,
+            "SELECT * FROM tax_rules WHERE lower(country) = lower(trim($1)) ORDER BY id",
+            vec![Box::new(country)],
+        )
+        .and_then(move |(rows, conn)| {
+            let rules = rows.into_iter().map(TaxRule::from).collect::<Vec<_>>();
+            raw::query(
+                conn,
+                "SELECT * FROM product_tax_categories WHERE product = ANY($1)",
+                vec![Box::new(products)],
+            )
+            .map(move |(rows, conn)| {
+                let product_categories = rows
+                    .into_iter()
+                    .map(ProductTaxCategory::from)
+                    .map(|v| (v.product, v.tax_category))
+                    .collect();
+                (TaxSettings { rules, product_categories }, conn)
+            })
+        }),
+    )
+}
@@ -0,0 +1,38 @@
+use stq_db::repo::*;
+use stq_db::statement::*;
+
+use models::*;
+
+use acl::{check_order_acl, OrdersAcl};
+
+const TABLE: &str = "tracking_events";
+
+pub struct DummyTrackingEventUpdater {}
+impl Updater for DummyTrackingEventUpdater {
+    fn into_update_builder(self, _table: &'static str) -> UpdateBuilder {
+        unreachable!()
+    }
+}
+
+pub trait TrackingEventRepo:
+    DbRepo<TrackingEvent, TrackingEventInserter, TrackingEventFilter, DummyTrackingEventUpdater, RepoError>
+{
+}
+
+pub type TrackingEventRepoImpl = DbRepoImpl<TrackingEvent, TrackingEventInserter, TrackingEventFilter, DummyTrackingEventUpdater>;
+impl TrackingEventRepo for TrackingEventRepoImpl {}
+
+type Repo = TrackingEventRepoImpl;
+
+pub fn make_su_repo() -> Repo {
+    Repo::new(TABLE)
+}
+
+type AclContext = (TrackingEvent, Action);
+
+/// Tracking events are visible to whoever can see the order itself, they are recorded by the system only
+pub fn make_repo(login: UserLogin) -> Repo {
+    make_su_repo().with_afterop_acl_engine(OrdersAcl(move |(entry, action): &mut AclContext| {
+        check_order_acl(&login, entry, *action == Action::Select)
+    }))
+}
@@ -0,0 +1,29 @@
+use futures::prelude::*;
+
+use stq_db::repo::*;
+
+use super::raw;
+
+/// Runs `f` inside of a single database transaction.
+/// All changes are committed if `f` succeeds and rolled back otherwise.
+pub fn run_in_transaction<T, F, U>(conn: RepoConnection, f: F) -> RepoConnectionFuture<T>
+where
+    T: 'static,
+    F: FnOnce(RepoConnection) -> U + 'static,
+    U: IntoFuture<Item = (T, RepoConnection), Error = (RepoError, RepoConnection)> + 'static,
+{
+    Box::new(raw::execute(conn, "BEGIN").and_then(move |conn| {
+        f(conn).into_future().then(|res| -> RepoConnectionFuture<T> {
+            match res {
+                Ok((out, conn)) => Box::new(raw::execute(conn, "COMMIT").map(move |conn| (out, conn))),
+                Err((e, conn)) => Box::new(raw::execute(conn, "ROLLBACK").then(move |res| match res {
+                    Ok(conn) => Err((e, conn)),
+                    Err((rollback_error, conn)) => {
+                        error!("Failed to rollback transaction: {:?}", rollback_error);
+                        Err((e, conn))
+                    }
+                })),
+            }
+        })
+    }))
+}
@@ -34,6 +34,39 @@ table! {
     }
 }
 
+table! {
+    coupon_rules (coupon_id) {
+        coupon_id -> Int4,
+        scope -> Varchar,
+        fixed_amount -> Nullable<Numeric>,
+        currency -> Nullable<Varchar>,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    delivery_pricing_rules (id) {
+        id -> Int4,
+        store -> Nullable<Int4>,
+        company_package_id -> Nullable<Int4>,
+        pricing -> Varchar,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    job_runs (id) {
+        id -> Int8,
+        job_name -> Varchar,
+        started_at -> Timestamptz,
+        finished_at -> Nullable<Timestamptz>,
+        status -> Varchar,
+        items_processed -> Int4,
+        items_changed -> Int4,
+        error -> Nullable<Varchar>,
+    }
+}
+
 table! {
     order_diffs (id) {
         id -> Uuid,
@@ -46,6 +79,98 @@ table! {
     }
 }
 
+table! {
+    order_events (id) {
+        id -> Int8,
+        order_id -> Uuid,
+        event_type -> Varchar,
+        payload -> Jsonb,
+        created_at -> Timestamptz,
+        status -> Varchar,
+        attempts -> Int4,
+        next_attempt_at -> Timestamptz,
+        last_error -> Nullable<Varchar>,
+        delivered_to -> Jsonb,
+    }
+}
+
+table! {
+    order_lines (id) {
+        id -> Uuid,
+        order_id -> Uuid,
+        position -> Int4,
+        created_from -> Nullable<Uuid>,
+        store -> Int4,
+        customer -> Int4,
+        product -> Int4,
+        quantity -> Int4,
+        price -> Numeric,
+        pre_order -> Bool,
+        pre_order_days -> Int4,
+        coupon_id -> Nullable<Int4>,
+        coupon_percent -> Nullable<Int4>,
+        coupon_discount -> Nullable<Numeric>,
+        product_discount -> Nullable<Numeric>,
+        delivery_company -> Nullable<Varchar>,
+        company_package_id -> Nullable<Int4>,
+        shipping_id -> Nullable<Int4>,
+        delivery_price -> Numeric,
+        product_cashback -> Nullable<Float8>,
+        total_amount -> Numeric,
+        comment -> Varchar,
+        cancelled_at -> Nullable<Timestamptz>,
+        created_at -> Timestamptz,
+        delivery_pricing -> Varchar,
+        tax_amount -> Numeric,
+    }
+}
+
+table! {
+    order_return_diffs (id) {
+        id -> Uuid,
+        parent -> Uuid,
+        committer -> Int4,
+        committer_role -> Varchar,
+        committed_at -> Timestamptz,
+        state -> Varchar,
+        comment -> Nullable<Varchar>,
+    }
+}
+
+table! {
+    order_returns (id) {
+        id -> Uuid,
+        order_id -> Uuid,
+        order_line_id -> Nullable<Uuid>,
+        customer -> Int4,
+        store -> Int4,
+        quantity -> Int4,
+        reason -> Varchar,
+        photos -> Jsonb,
+        state -> Varchar,
+        track_id -> Nullable<Varchar>,
+        created_at -> Timestamptz,
+        updated_at -> Timestamptz,
+    }
+}
+
+table! {
+    order_tax_lines (id) {
+        id -> Uuid,
+        order_id -> Uuid,
+        order_line_id -> Uuid,
+        store -> Int4,
+        customer -> Int4,
+        name -> Varchar,
+        tax_category -> Varchar,
+        rate -> Float8,
+        inclusive -> Bool,
+        taxable_amount -> Numeric,
+        tax_amount -> Numeric,
+        created_at -> Timestamptz,
+    }
+}
+
 table! {
     orders (id) {
         id -> Uuid,
@@ -53,7 +178,7 @@ table! {
         store -> Int4,
         customer -> Int4,
         product -> Int4,
-        price -> Float8,
+        price -> Numeric,
         quantity -> Int4,
         receiver_name -> Varchar,
         administrative_area_level_1 -> Nullable<Varchar>,
@@ -79,17 +204,65 @@ table! {
         pre_order -> Bool,
         pre_order_days -> Int4,
         coupon_id -> Nullable<Int4>,
-        product_discount -> Nullable<Float8>,
+        product_discount -> Nullable<Numeric>,
         coupon_percent -> Nullable<Int4>,
-        coupon_discount -> Nullable<Float8>,
-        total_amount -> Float8,
+        coupon_discount -> Nullable<Numeric>,
+        total_amount -> Numeric,
         receiver_email -> Nullable<Varchar>,
         company_package_id -> Nullable<Int4>,
-        delivery_price -> Float8,
+        delivery_price -> Numeric,
         shipping_id -> Nullable<Int4>,
         uuid -> Uuid,
         product_cashback -> Nullable<Float8>,
         currency_type -> Varchar,
+        version -> Int4,
+        search_text -> Text,
+        delivery_pricing -> Varchar,
+        tax_amount -> Numeric,
+        refunded_amount -> Numeric,
+        delivery_state -> Nullable<Varchar>,
+        attention_reason -> Nullable<Varchar>,
+        escalated_at -> Nullable<Timestamptz>,
+        paid_amount -> Nullable<Numeric>,
+    }
+}
+
+table! {
+    product_tax_categories (product) {
+        product -> Int4,
+        tax_category -> Varchar,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    rejected_order_transitions (id) {
+        id -> Uuid,
+        parent -> Uuid,
+        committer -> Int4,
+        committer_role -> Varchar,
+        from_state -> Varchar,
+        to_state -> Varchar,
+        attempted_at -> Timestamptz,
+        comment -> Nullable<Varchar>,
+    }
+}
+
+table! {
+    refunds (id) {
+        id -> Uuid,
+        order_id -> Uuid,
+        customer -> Int4,
+        store -> Int4,
+        return_id -> Nullable<Uuid>,
+        amount -> Numeric,
+        currency -> Varchar,
+        reason -> Varchar,
+        committer -> Int4,
+        committer_role -> Varchar,
+        saga_status -> Varchar,
+        created_at -> Timestamptz,
+        updated_at -> Timestamptz,
     }
 }
 
@@ -102,12 +275,102 @@ table! {
     }
 }
 
+table! {
+    saga_notifications (id) {
+        id -> Int8,
+        order_id -> Uuid,
+        status -> Varchar,
+        attempts -> Int4,
+        last_error -> Nullable<Varchar>,
+        created_at -> Timestamptz,
+        acknowledged_at -> Nullable<Timestamptz>,
+        kind -> Varchar,
+        refund_id -> Nullable<Uuid>,
+    }
+}
+
+table! {
+    shipments (id) {
+        id -> Uuid,
+        order_id -> Uuid,
+        customer -> Int4,
+        store -> Int4,
+        carrier -> Varchar,
+        tracking_number -> Varchar,
+        quantity -> Int4,
+        lines -> Jsonb,
+        status -> Varchar,
+        created_at -> Timestamptz,
+        updated_at -> Timestamptz,
+        delivered_at -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    tax_rules (id) {
+        id -> Int4,
+        country -> Varchar,
+        region -> Nullable<Varchar>,
+        tax_category -> Varchar,
+        name -> Varchar,
+        rate -> Float8,
+        inclusive -> Bool,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    tracking_events (id) {
+        id -> Uuid,
+        order_id -> Uuid,
+        shipment_id -> Nullable<Uuid>,
+        customer -> Int4,
+        store -> Int4,
+        carrier -> Varchar,
+        tracking_number -> Varchar,
+        status_code -> Varchar,
+        description -> Varchar,
+        location -> Nullable<Varchar>,
+        occurred_at -> Nullable<Timestamptz>,
+        created_at -> Timestamptz,
+    }
+}
+
 joinable!(order_diffs -> orders (parent));
+joinable!(order_lines -> orders (order_id));
+joinable!(order_return_diffs -> order_returns (parent));
+joinable!(order_returns -> order_lines (order_line_id));
+joinable!(order_returns -> orders (order_id));
+joinable!(order_tax_lines -> order_lines (order_line_id));
+joinable!(order_tax_lines -> orders (order_id));
+joinable!(refunds -> order_returns (return_id));
+joinable!(refunds -> orders (order_id));
+joinable!(rejected_order_transitions -> orders (parent));
+joinable!(saga_notifications -> refunds (refund_id));
+joinable!(shipments -> orders (order_id));
+joinable!(saga_notifications -> orders (order_id));
+joinable!(tracking_events -> orders (order_id));
+joinable!(tracking_events -> shipments (shipment_id));
 
 allow_tables_to_appear_in_same_query!(
     cart_items_session,
     cart_items_user,
+    coupon_rules,
+    delivery_pricing_rules,
+    job_runs,
     order_diffs,
+    order_events,
+    order_lines,
+    order_return_diffs,
+    order_returns,
+    order_tax_lines,
     orders,
+    product_tax_categories,
+    refunds,
+    rejected_order_transitions,
     roles,
+    saga_notifications,
+    shipments,
+    tax_rules,
+    tracking_events,
 );
@@ -0,0 +1,89 @@
+use futures::future;
+use futures::prelude::*;
+
+use super::ensure_superadmin;
+use super::types::ServiceFuture;
+use errors::*;
+use models::*;
+use repos;
+use repos::*;
+use types::*;
+
+use stq_db::repo::*;
+use stq_types::CouponId;
+
+const SUPERADMINS_ONLY: &str = "Coupon rules are managed by superadmins only";
+
+/// Service that manages scopes and fixed amounts of coupons
+pub trait CouponRuleService {
+    /// Returns all coupon rules
+    fn get_rules(&self) -> ServiceFuture<Vec<CouponRule>>;
+    /// Sets the rule of the coupon, replacing its previous rule
+    fn set_rule(&self, rule: CouponRuleInserter) -> ServiceFuture<CouponRule>;
+    /// Deletes the rule, the coupon gives its percent discount on one unit of the product afterwards
+    fn delete_rule(&self, coupon_id: CouponId) -> ServiceFuture<Option<CouponRule>>;
+}
+
+pub struct CouponRuleServiceImpl {
+    db_pool: DbPool,
+    login_data: UserLogin,
+}
+
+impl CouponRuleServiceImpl {
+    pub fn new(db_pool: DbPool, login_data: UserLogin) -> Self {
+        Self { db_pool, login_data }
+    }
+}
+
+impl CouponRuleService for CouponRuleServiceImpl {
+    fn get_rules(&self) -> ServiceFuture<Vec<CouponRule>> {
+        if let Err(e) = ensure_superadmin(&self.login_data, SUPERADMINS_ONLY) {
+            return Box::new(future::err(e));
+        }
+
+        Box::new(
+            self.db_pool
+                .run(|conn| repos::coupon_rule::make_su_repo().select(conn, Default::default())),
+        )
+    }
+
+    fn set_rule(&self, rule: CouponRuleInserter) -> ServiceFuture<CouponRule> {
+        if let Err(e) = ensure_superadmin(&self.login_data, SUPERADMINS_ONLY) {
+            return Box::new(future::err(e));
+        }
+
+        if let Err(e) = rule.validate() {
+            return Box::new(future::err(e.context(Error::ParseError).into()));
+        }
+
+        Box::new(self.db_pool.run(move |conn| {
+            run_in_transaction(conn, move |conn| {
+                repos::coupon_rule::make_su_repo()
+                    .delete(
+                        conn,
+                        CouponRuleFilter {
+                            coupon_id: Some(rule.coupon_id.into()),
+                        },
+                    )
+                    .and_then(move |(_, conn)| repos::coupon_rule::make_su_repo().insert_exactly_one(conn, rule))
+            })
+        }))
+    }
+
+    fn delete_rule(&self, coupon_id: CouponId) -> ServiceFuture<Option<CouponRule>> {
+        if let Err(e) = ensure_superadmin(&self.login_data, SUPERADMINS_ONLY) {
+            return Box::new(future::err(e));
+        }
+
+        Box::new(self.db_pool.run(move |conn| {
+            repos::coupon_rule::make_su_repo()
+                .delete(
+                    conn,
+                    CouponRuleFilter {
+                        coupon_id: Some(coupon_id.into()),
+                    },
+                )
+                .map(|(mut rules, conn)| (rules.pop(), conn))
+        }))
+    }
+}
@@ -0,0 +1,85 @@
+use futures::future;
+use futures::prelude::*;
+
+use super::ensure_superadmin;
+use super::types::ServiceFuture;
+use errors::*;
+use models::*;
+use repos;
+use repos::*;
+use types::*;
+
+use stq_db::repo::*;
+
+const SUPERADMINS_ONLY: &str = "Delivery pricing is managed by superadmins only";
+
+/// Service that manages how delivery is charged for products of stores and packages
+pub trait DeliveryPricingService {
+    /// Returns all delivery pricing rules
+    fn get_rules(&self) -> ServiceFuture<Vec<DeliveryPricingRule>>;
+    /// Sets delivery pricing of the store or the package, replacing its previous rule
+    fn set_rule(&self, rule: DeliveryPricingRuleInserter) -> ServiceFuture<DeliveryPricingRule>;
+    /// Deletes the rule, delivery of orders created afterwards is charged per unit unless another rule applies
+    fn delete_rule(&self, rule_id: DeliveryPricingRuleId) -> ServiceFuture<Option<DeliveryPricingRule>>;
+}
+
+pub struct DeliveryPricingServiceImpl {
+    db_pool: DbPool,
+    login_data: UserLogin,
+}
+
+impl DeliveryPricingServiceImpl {
+    pub fn new(db_pool: DbPool, login_data: UserLogin) -> Self {
+        Self { db_pool, login_data }
+    }
+}
+
+impl DeliveryPricingService for DeliveryPricingServiceImpl {
+    fn get_rules(&self) -> ServiceFuture<Vec<DeliveryPricingRule>> {
+        if let Err(e) = ensure_superadmin(&self.login_data, SUPERADMINS_ONLY) {
+            return Box::new(future::err(e));
+        }
+
+        Box::new(
+            self.db_pool
+                .run(|conn| repos::delivery_pricing_rule::make_su_repo().select(conn, Default::default())),
+        )
+    }
+
+    fn set_rule(&self, rule: DeliveryPricingRuleInserter) -> ServiceFuture<DeliveryPricingRule> {
+        if let Err(e) = ensure_superadmin(&self.login_data, SUPERADMINS_ONLY) {
+            return Box::new(future::err(e));
+        }
+
+        let replaced_rule_filter = match rule.replaced_rule_filter() {
+            Ok(filter) => filter,
+            Err(e) => return Box::new(future::err(e.context(Error::ParseError).into())),
+        };
+
+        Box::new(self.db_pool.run(move |conn| {
+            run_in_transaction(conn, move |conn| {
+                repos::delivery_pricing_rule::make_su_repo()
+                    .delete(conn, replaced_rule_filter)
+                    .and_then(move |(_, conn)| repos::delivery_pricing_rule::make_su_repo().insert_exactly_one(conn, rule))
+            })
+        }))
+    }
+
+    fn delete_rule(&self, rule_id: DeliveryPricingRuleId) -> ServiceFuture<Option<DeliveryPricingRule>> {
+        if let Err(e) = ensure_superadmin(&self.login_data, SUPERADMINS_ONLY) {
+            return Box::new(future::err(e));
+        }
+
+        Box::new(self.db_pool.run(move |conn| {
+            repos::delivery_pricing_rule::make_su_repo()
+                .delete(
+                    conn,
+                    DeliveryPricingRuleFilter {
+                        id: Some(rule_id.into()),
+                        ..Default::default()
+                    },
+                )
+                .map(|(mut rules, conn)| (rules.pop(), conn))
+        }))
+    }
+}
@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+
+use futures::future;
+use futures::prelude::*;
+
+use super::ensure_superadmin;
+use super::types::ServiceFuture;
+use errors::*;
+use models::*;
+use repos;
+use types::*;
+
+const SUPERADMINS_ONLY: &str = "Job history is available to superadmins only";
+
+/// Service that provides history of background jobs
+pub trait JobService {
+    /// Returns every job that has run at least once with its latest run and latest successful run
+    fn get_jobs(&self) -> ServiceFuture<Vec<Job>>;
+    /// Returns latest runs of the job, newest first
+    fn get_job_runs(&self, job_name: String, limit: i64) -> ServiceFuture<Vec<JobRun>>;
+}
+
+pub struct JobServiceImpl {
+    db_pool: DbPool,
+    login_data: UserLogin,
+}
+
+impl JobServiceImpl {
+    pub fn new(db_pool: DbPool, login_data: UserLogin) -> Self {
+        Self { db_pool, login_data }
+    }
+}
+
+impl JobService for JobServiceImpl {
+    fn get_jobs(&self) -> ServiceFuture<Vec<Job>> {
+        if let Err(e) = ensure_superadmin(&self.login_data, SUPERADMINS_ONLY) {
+            return Box::new(future::err(e));
+        }
+
+        Box::new(
+            self.db_pool
+                .run(|conn| {
+                    repos::job_run::select_last_runs(conn, None).and_then(|(last_runs, conn)| {
+                        repos::job_run::select_last_runs(conn, Some(JobRunStatus::Succeeded))
+                            .map(move |(last_successes, conn)| ((last_runs, last_successes), conn))
+                    })
+                })
+                .map(|(last_runs, last_successes)| {
+                    let mut last_successes = last_successes
+                        .into_iter()
+                        .map(|run| (run.job_name.clone(), run))
+                        .collect::<HashMap<_, _>>();
+
+                    last_runs
+                        .into_iter()
+                        .map(|run| Job {
+                            name: run.job_name.clone(),
+                            last_success: last_successes.remove(&run.job_name),
+                            last_run: run,
+                        })
+                        .collect()
+                }),
+        )
+    }
+
+    fn get_job_runs(&self, job_name: String, limit: i64) -> ServiceFuture<Vec<JobRun>> {
+        if let Err(e) = ensure_superadmin(&self.login_data, SUPERADMINS_ONLY) {
+            return Box::new(future::err(e));
+        }
+
+        Box::new(self.db_pool.run(move |conn| repos::job_run::select_runs(conn, job_name, limit)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio_core::reactor::Core;
+
+    use stq_db::repo::*;
+    use stq_types::UserId;
+
+    use test_utils::*;
+
+    #[test]
+    fn jobs_show_last_run_and_last_success() {
+        let mut core = Core::new().unwrap();
+        let db_pool = create_db_pool(&mut core);
+        let job_name = "test_job_history".to_string();
+
+        let insert_run = |core: &mut Core, status: JobRunStatus| {
+            let job_name = job_name.clone();
+            core.run(db_pool.run(move |conn| {
+                repos::job_run::make_su_repo()
+                    .insert_exactly_one(
+                        conn,
+                        JobRunInserter {
+                            job_name: job_name.clone(),
+                        },
+                    )
+                    .and_then(move |(run, conn)| {
+                        repos::job_run::make_su_repo().update(
+                            conn,
+                            JobRunUpdater {
+                                mask: JobRunFilter {
+                                    id: Some(run.id.into()),
+                                    ..Default::default()
+                                },
+                                data: JobRunUpdateData {
+                                    status: Some(status),
+                                    ..Default::default()
+                                },
+                            },
+                        )
+                    })
+            }))
+            .unwrap()
+            .pop()
+            .unwrap()
+        };
+        let succeeded = insert_run(&mut core, JobRunStatus::Succeeded);
+        let failed = insert_run(&mut core, JobRunStatus::Failed);
+
+        let service = JobServiceImpl::new(db_pool.clone(), super_user());
+        let job = core
+            .run(service.get_jobs())
+            .unwrap()
+            .into_iter()
+            .find(|job| job.name == job_name)
+            .unwrap();
+        assert_eq!(job.last_run.id, failed.id);
+        assert_eq!(job.last_success.map(|run| run.id), Some(succeeded.id));
+
+        let runs = core.run(service.get_job_runs(job_name.clone(), 1)).unwrap();
+        assert_eq!(runs.into_iter().map(|run| run.id).collect::<Vec<_>>(), vec![failed.id]);
+
+        let user = RepoLogin::User {
+            caller_id: UserId(2),
+            caller_roles: vec![],
+        };
+        assert!(core.run(JobServiceImpl::new(db_pool.clone(), user).get_jobs()).is_err());
+
+        core.run(db_pool.run(move |conn| {
+            repos::job_run::make_su_repo().delete(
+                conn,
+                JobRunFilter {
+                    job_name: Some(job_name.into()),
+                    ..Default::default()
+                },
+            )
+        }))
+        .unwrap();
+    }
+}
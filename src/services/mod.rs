@@ -6,3 +6,65 @@ pub use self::cart::*;
 
 pub mod order;
 pub use self::order::*;
+
+pub mod order_return;
+pub use self::order_return::*;
+
+pub mod refund;
+pub use self::refund::*;
+
+pub mod shipment;
+pub use self::shipment::*;
+
+pub mod tracking;
+pub use self::tracking::*;
+
+pub mod job;
+pub use self::job::*;
+
+pub mod coupon;
+pub use self::coupon::*;
+
+pub mod delivery_pricing;
+pub use self::delivery_pricing::*;
+
+pub mod tax;
+pub use self::tax::*;
+
+use failure::Error as FailureError;
+
+use errors::Error;
+use models::{RepoLogin, UserLogin, UserRole};
+use stq_roles::models::RoleEntry;
+use stq_types::{RoleEntryId, UserId};
+
+/// Fails with `Forbidden` and the message unless the caller is a superadmin
+pub fn ensure_superadmin(login_data: &UserLogin, message: &'static str) -> Result<(), FailureError> {
+    if let RepoLogin::User { ref caller_roles, .. } = *login_data {
+        if caller_roles.iter().any(|role_entry| role_entry.role == UserRole::Superadmin) {
+            return Ok(());
+        }
+    }
+
+    Err(format_err!("{}", message).context(Error::Forbidden).into())
+}
+
+/// Id of the caller to record as the committer, `-1` for callers that are not users
+pub fn calling_user(login_data: &UserLogin) -> UserId {
+    match *login_data {
+        RepoLogin::User { caller_id, .. } => caller_id,
+        _ => UserId(-1),
+    }
+}
+
+/// Login of the service itself, used by loaders and carrier pushes that act on behalf of no user
+pub fn system_login() -> UserLogin {
+    RepoLogin::User {
+        caller_id: UserId(1),
+        caller_roles: vec![RoleEntry {
+            id: RoleEntryId::new(),
+            user_id: UserId(1),
+            role: UserRole::Superadmin,
+        }],
+    }
+}
@@ -1,11 +1,16 @@
 use std::collections::{HashMap, HashSet};
 use std::rc::Rc;
+use std::slice;
 
 use chrono::prelude::*;
 use chrono::Duration as ChronoDuration;
+use failure::Error as FailureError;
 use futures::future;
 use futures::prelude::*;
 
+use super::calling_user;
+use super::ensure_superadmin;
+use super::shipment::ensure_shipments_delivered;
 use super::types::ServiceFuture;
 use errors::*;
 use models::*;
@@ -14,11 +19,10 @@ use repos::*;
 use types::*;
 
 use stq_api::orders::*;
-use stq_db::{connection::BoxedConnection, repo::*};
-use stq_static_resources::{CommitterRole, OrderState};
+use stq_db::{connection::BoxedConnection, repo::*, statement::Range};
+use stq_static_resources::{CommitterRole, Currency, OrderState};
 use stq_types::*;
-
-pub const ZERO_DISCOUNT: f64 = 0.0001;
+use uuid::Uuid;
 
 #[derive(Clone, Debug)]
 pub enum RoleRemoveFilter {
@@ -29,13 +33,30 @@ pub enum RoleRemoveFilter {
 pub trait OrderService {
     fn convert_cart(&self, payload: ConvertCartPayload) -> ServiceFuture<Vec<Order>>;
     fn create_buy_now(&self, payload: BuyNow, conversion_id: Option<ConversionId>) -> ServiceFuture<Vec<Order>>;
+    /// Calculates the orders the checkout would create without changing the cart or creating orders
+    fn quote(&self, payload: QuotePayload) -> ServiceFuture<Quote>;
     fn delete_order_and_revert_cart_conversion(&self, convertation_id: ConversionId) -> ServiceFuture<()>;
-    fn get_order(&self, id: OrderIdentifier) -> ServiceFuture<Option<Order>>;
+    fn get_order(&self, id: OrderIdentifier) -> ServiceFuture<Option<OrderWithMeta>>;
     fn get_order_diff(&self, id: OrderIdentifier) -> ServiceFuture<Vec<OrderDiff>>;
-    fn get_orders_for_user(&self, user_id: UserId) -> ServiceFuture<Vec<Order>>;
-    fn get_orders_for_store(&self, store_id: StoreId) -> ServiceFuture<Vec<Order>>;
+    /// Returns all lines of the order including cancelled ones, oldest first
+    fn get_order_lines(&self, order_id: OrderId) -> ServiceFuture<Vec<OrderLine>>;
+    /// Returns taxes of all lines of the order including cancelled ones
+    fn get_order_taxes(&self, order_id: OrderId) -> ServiceFuture<Vec<OrderTaxLine>>;
+    /// Cancels one line of the order and recalculates the order total, the last active line can not be cancelled.
+    /// The line may be cancelled by whoever may cancel the whole order.
+    fn cancel_order_line(&self, order_id: OrderId, line_id: OrderLineId, comment: Option<String>) -> ServiceFuture<Option<Order>>;
+    /// Returns all user's orders, newest first
+    fn get_all_orders_for_user(&self, user_id: UserId) -> ServiceFuture<Vec<Order>>;
+    /// Returns one page of user's orders, newest first
+    fn get_orders_for_user(&self, user_id: UserId, page: OrderPageRequest) -> ServiceFuture<OrderPage>;
+    /// Returns one page of store's orders, newest first
+    fn get_orders_for_store(&self, store_id: StoreId, page: OrderPageRequest) -> ServiceFuture<OrderPage>;
+    /// Returns orders that entered the state since `from`
     fn get_orders_with_state(&self, state: OrderState, from: DateTime<Utc>) -> ServiceFuture<Vec<Order>>;
-    fn search_by_diffs(&self, diff_filter: OrderDiffFilter) -> ServiceFuture<Vec<Order>>;
+    /// Returns orders staying in the state for at least `min_age`, the oldest first. Available to superadmins only.
+    fn get_stale_orders(&self, state: OrderState, min_age: ChronoDuration) -> ServiceFuture<Vec<StaleOrder>>;
+    /// Returns orders having diffs that match the filter along with their active lines
+    fn search_by_diffs(&self, diff_filter: OrderDiffFilter) -> ServiceFuture<Vec<(OrderWithMeta, Vec<OrderLine>)>>;
     fn delete_order(&self, id: OrderIdentifier) -> ServiceFuture<()>;
     fn set_order_state(
         &self,
@@ -44,10 +65,33 @@ pub trait OrderService {
         comment: Option<String>,
         track_id: Option<String>,
         committer_role: CommitterRole,
+        precondition: OrderUpdatePrecondition,
     ) -> ServiceFuture<Option<Order>>;
+    /// Moves the order on behalf of the caller, the committer role is derived from the caller's roles and the order
+    fn set_order_state_by_caller(
+        &self,
+        order_id: OrderIdentifier,
+        state: OrderState,
+        comment: Option<String>,
+        track_id: Option<String>,
+        precondition: OrderUpdatePrecondition,
+    ) -> ServiceFuture<Option<OrderWithMeta>>;
     /// Search using the terms provided.
     fn search(&self, terms: OrderSearchTerms) -> ServiceFuture<Vec<Order>>;
+    /// Search using the terms and filters provided, returns one page of found orders in the requested order.
+    /// If the text query is set, returns the most relevant orders instead, ignoring sort.
+    fn search_page(
+        &self,
+        terms: OrderSearchTerms,
+        filters: OrderSearchFilters,
+        sort: OrderSort,
+        page: OrderPageRequest,
+    ) -> ServiceFuture<OrderPage>;
+    /// Same as `search_page`, but returns all found orders
+    fn search_all(&self, terms: OrderSearchTerms, filters: OrderSearchFilters, sort: OrderSort) -> ServiceFuture<Vec<Order>>;
     fn track_delivered_orders(&self, max_delivered_state_duration: ChronoDuration) -> ServiceFuture<Vec<Order>>;
+    /// Returns notifications of Saga about payments for orders, oldest first
+    fn get_saga_notifications(&self, status: Option<SagaNotificationStatus>) -> ServiceFuture<Vec<SagaNotification>>;
 }
 
 pub struct OrderServiceImpl {
@@ -55,7 +99,12 @@ pub struct OrderServiceImpl {
     pub login_data: UserLogin,
     pub cart_repo_factory: Rc<Fn() -> Box<CartItemRepo>>,
     pub order_repo_factory: Rc<Fn() -> Box<OrderRepo>>,
+    pub order_line_repo_factory: Rc<Fn() -> Box<OrderLineRepo>>,
+    pub order_tax_line_repo_factory: Rc<Fn() -> Box<OrderTaxLineRepo>>,
     pub order_diff_repo_factory: Rc<Fn() -> Box<OrderDiffRepo>>,
+    pub rejected_order_transition_repo_factory: Rc<Fn() -> Box<RejectedOrderTransitionRepo>>,
+    pub order_event_repo_factory: Rc<Fn() -> Box<OrderEventRepo>>,
+    pub saga_notification_repo_factory: Rc<Fn() -> Box<SagaNotificationRepo>>,
 }
 
 impl OrderServiceImpl {
@@ -73,351 +122,509 @@ impl OrderServiceImpl {
                 let login_data = login_data.clone();
                 move || Box::new(repos::order::make_repo(login_data.clone()))
             }),
+            order_line_repo_factory: Rc::new({
+                let login_data = login_data.clone();
+                move || Box::new(repos::order_line::make_repo(login_data.clone()))
+            }),
+            order_tax_line_repo_factory: Rc::new({
+                let login_data = login_data.clone();
+                move || Box::new(repos::order_tax_line::make_repo(login_data.clone()))
+            }),
+            rejected_order_transition_repo_factory: Rc::new({
+                let login_data = login_data.clone();
+                move || Box::new(repos::rejected_order_transition::make_repo(login_data.clone()))
+            }),
+            order_event_repo_factory: Rc::new({
+                let login_data = login_data.clone();
+                move || Box::new(repos::order_event::make_repo(login_data.clone()))
+            }),
+            saga_notification_repo_factory: Rc::new({
+                let login_data = login_data.clone();
+                move || Box::new(repos::saga_notification::make_repo(login_data.clone()))
+            }),
             db_pool,
             login_data,
         }
     }
+
+    /// Moves the order inside of the caller's transaction, so the order changes along with the caller's changes.
+    /// Rejected transitions are not recorded, they fail with `Error::ForbiddenStateTransition` and roll the transaction back.
+    pub fn set_order_state_in_transaction(
+        &self,
+        conn: RepoConnection,
+        order_id: OrderId,
+        state: OrderState,
+        comment: Option<String>,
+        committer_role: CommitterRole,
+        precondition: OrderUpdatePrecondition,
+    ) -> RepoConnectionFuture<Option<Order>> {
+        let cart_repo_factory = self.cart_repo_factory.clone();
+        let order_repo_factory = self.order_repo_factory.clone();
+        let order_line_repo_factory = self.order_line_repo_factory.clone();
+        let order_diff_repo_factory = self.order_diff_repo_factory.clone();
+        let order_event_repo_factory = self.order_event_repo_factory.clone();
+        let calling_user = calling_user(&self.login_data);
+
+        Box::new(
+            (self.order_repo_factory)()
+                .select(conn, OrderFilter::from(OrderIdentifier::Id(order_id)))
+                .map(|(mut orders, conn)| (orders.pop(), conn))
+                .and_then(move |(current_order, conn)| -> RepoConnectionFuture<Option<DbOrder>> {
+                    let current_order = match current_order {
+                        Some(current_order) => current_order,
+                        None => return Box::new(future::ok((None, conn))),
+                    };
+
+                    if let Err(e) = ensure_precondition(&current_order, &precondition) {
+                        return Box::new(future::err((e, conn)));
+                    }
+
+                    if !is_transition_allowed(current_order.0.state, state, committer_role) {
+                        return Box::new(future::err((
+                            format_err!(
+                                "Order {} can not be moved from {} to {} by {:?}",
+                                order_id,
+                                current_order.0.state,
+                                state,
+                                committer_role
+                            )
+                            .context(Error::ForbiddenStateTransition)
+                            .into(),
+                            conn,
+                        )));
+                    }
+
+                    write_order_state(
+                        conn,
+                        current_order,
+                        state,
+                        comment,
+                        None,
+                        cart_repo_factory,
+                        order_repo_factory,
+                        order_line_repo_factory,
+                        order_diff_repo_factory,
+                        order_event_repo_factory,
+                        calling_user,
+                        committer_role,
+                    )
+                })
+                .map(|(order, conn)| (order.map(|order| order.0), conn)),
+        )
+    }
 }
 
 impl OrderService for OrderServiceImpl {
     fn convert_cart(&self, payload: ConvertCartPayload) -> ServiceFuture<Vec<Order>> {
-        use self::RepoLogin::*;
-        let ConvertCartPayload {
-            conversion_id,
-            user_id,
-            seller_prices,
-            address,
-            receiver_name,
-            receiver_phone,
-            receiver_email,
-            coupons,
-            delivery_info,
-            product_info,
-            uuid,
-            currency_type,
-        } = payload;
-
         let order_repo_factory = self.order_repo_factory.clone();
+        let order_line_repo_factory = self.order_line_repo_factory.clone();
         let order_diffs_repo_factory = self.order_diff_repo_factory.clone();
         let cart_repo_factory = self.cart_repo_factory.clone();
-        let calling_user = match self.login_data.clone() {
-            User { caller_id, .. } => caller_id,
-            _ => UserId(-1),
-        };
-        let mut transaction_id = TransactionId::new(uuid);
+        let order_event_repo_factory = self.order_event_repo_factory.clone();
+        let calling_user = calling_user(&self.login_data);
+        let order_tax_line_repo_factory = self.order_tax_line_repo_factory.clone();
+        let company_package_ids = payload.delivery_info.values().map(|info| info.company_package_id.clone()).collect();
+        let country = payload.address.country.clone();
 
         Box::new(self.db_pool.run(move |conn| {
-            (cart_repo_factory)()
-                .delete(
-                    conn,
-                    CartItemFilter {
-                        customer: Some(user_id.into()),
-                        meta_filter: CartItemMetaFilter {
-                            selected: Some(true),
-                            currency_type,
-                            ..Default::default()
-                        },
-                    },
-                )
-                // Create orders from cart items
-                .and_then(move |(cart, conn)| {
-                    let mut order_items = Vec::new();
-                    for cart_item in cart {
-                        if let Some(seller_price) = seller_prices.get(&cart_item.product_id).cloned() {
-                            transaction_id = transaction_id.next();
-                            let ProductSellerPrice { price, currency, discount } = seller_price;
-                            let (company_package_id, shipping_id, delivery_name, delivery_price) =
-                                match delivery_info.get(&cart_item.product_id).cloned() {
-                                    None => (None, None, None, 0.0),
-                                    Some(delivery_info) => (
-                                        Some(delivery_info.company_package_id.clone()),
-                                        Some(delivery_info.shipping_id.clone()),
-                                        Some(delivery_info.name.clone()),
-                                        delivery_info.price,
-                                    ),
-                                };
-
-                            let coupon_percent = cart_item
-                                .coupon_id
-                                .and_then(|coupon_id| coupons.get(&coupon_id))
-                                .map(|coupon| coupon.percent);
-
-                            let product_cashback = product_info.get(&cart_item.product_id).and_then(|product| product.cashback);
-
-                            let TotalAmount {
-                                total_amount,
-                                coupon_discount,
-                                product_discount,
-                            } = calculate_total_amount(cart_item.quantity, price, discount, coupon_percent, delivery_price);
-                            order_items.push((
-                                OrderInserter {
-                                    id: None,
-                                    created_from: Some(cart_item.id),
-                                    conversion_id,
-                                    customer: user_id,
-                                    store: cart_item.store_id,
-                                    product: cart_item.product_id,
-                                    quantity: cart_item.quantity,
-                                    price,
-                                    currency,
-                                    address: address.clone(),
-                                    receiver_name: receiver_name.clone(),
-                                    receiver_phone: receiver_phone.clone(),
-                                    receiver_email: receiver_email.clone(),
-                                    state: OrderState::New,
-                                    delivery_company: delivery_name,
-                                    track_id: None,
-                                    pre_order: cart_item.pre_order,
-                                    pre_order_days: cart_item.pre_order_days,
-                                    coupon_id: cart_item.coupon_id,
-                                    coupon_percent,
-                                    coupon_discount,
-                                    product_discount,
-                                    total_amount,
-                                    company_package_id,
-                                    delivery_price,
-                                    shipping_id,
-                                    uuid: transaction_id.clone().into(),
-                                    product_cashback,
-                                    currency_type: cart_item.currency_type,
-                                },
-                                cart_item.comment,
-                            ))
-                        } else {
-                            return Err((
-                                format_err!("Missing price information for product {}", cart_item.product_id)
-                                    .context(Error::MissingPrice)
-                                    .into(),
-                                conn,
-                            ));
+            run_in_transaction(conn, move |conn| {
+                let filter = selected_cart_items_filter(&payload);
+                (cart_repo_factory)()
+                    .delete(conn, filter)
+                    .and_then(move |(cart, conn)| {
+                        select_cart_checkout_settings(conn, &cart, company_package_ids, country)
+                            .map(move |(settings, conn)| ((cart, settings), conn))
+                    })
+                    // Group cart items into orders, one order per store
+                    .and_then(move |((cart, settings), conn)| {
+                        let (new_orders, failures) = plan_cart_orders(&payload, cart, &settings);
+                        match failures.into_iter().next() {
+                            Some(failure) => Err((format_err!("{}", failure.reason).context(Error::MissingPrice).into(), conn)),
+                            None => Ok((new_orders, conn)),
                         }
-                    }
-                    Ok((order_items, conn))
-                })
-                // Insert new orders into database
-                .and_then({
-                    move |(new_orders, conn)| {
+                    })
+                    // Insert new orders into database
+                    .and_then(move |(new_orders, conn)| {
                         let mut out: RepoConnectionFuture<Vec<Order>>;
                         out = Box::new(future::ok((Default::default(), conn)));
 
-                        for (new_order, comment) in new_orders {
+                        for new_order in new_orders {
                             out = Box::new(out.and_then({
-                                let comment = comment.clone();
                                 let order_repo_factory = order_repo_factory.clone();
+                                let order_line_repo_factory = order_line_repo_factory.clone();
+                                let order_tax_line_repo_factory = order_tax_line_repo_factory.clone();
                                 let order_diffs_repo_factory = order_diffs_repo_factory.clone();
+                                let order_event_repo_factory = order_event_repo_factory.clone();
                                 move |(mut out_data, conn)| {
-                                    // Insert new order along with the record in history
-                                    (order_repo_factory)()
-                                        .insert_exactly_one(conn, new_order)
-                                        .and_then(move |(inserted_order, conn)| {
-                                            (order_diffs_repo_factory)()
-                                                .insert_exactly_one(
-                                                    conn,
-                                                    OrderDiffInserter {
-                                                        parent: inserted_order.0.id,
-                                                        committer: calling_user,
-                                                        committed_at: Utc::now(),
-                                                        state: OrderState::New,
-                                                        comment: Some(comment),
-                                                        committer_role: CommitterRole::Customer,
-                                                    },
-                                                )
-                                                .map(|(_, conn)| (inserted_order, conn))
-                                        })
-                                        .map({
-                                            move |(order, conn): (DbOrder, RepoConnection)| {
-                                                out_data.push(order.0);
-                                                (out_data, conn)
-                                            }
-                                        })
+                                    insert_new_order(
+                                        conn,
+                                        order_repo_factory,
+                                        order_line_repo_factory,
+                                        order_tax_line_repo_factory,
+                                        order_diffs_repo_factory,
+                                        order_event_repo_factory,
+                                        calling_user,
+                                        new_order,
+                                    )
+                                    .map(move |(order, conn)| {
+                                        out_data.push(order);
+                                        (out_data, conn)
+                                    })
                                 }
                             }));
                         }
 
                         out
-                    }
-                })
+                    })
+            })
         }))
     }
 
     fn delete_order_and_revert_cart_conversion(&self, conversion_id: ConversionId) -> ServiceFuture<()> {
         let order_repo_factory = self.order_repo_factory.clone();
+        let order_line_repo_factory = self.order_line_repo_factory.clone();
         let order_diff_repo_factory = self.order_diff_repo_factory.clone();
         let cart_repo_factory = self.cart_repo_factory.clone();
+        let order_event_repo_factory = self.order_event_repo_factory.clone();
         Box::new(self.db_pool.run(move |conn| {
-            (order_repo_factory)()
-                .select(
-                    conn,
-                    OrderFilter {
-                        conversion_id: Some(conversion_id.into()),
-                        ..Default::default()
-                    },
-                )
-                .and_then({
-                    let order_diff_repo_factory = order_diff_repo_factory.clone();
-                    move |(orders, conn)| {
-                        let mut out = Box::new(future::ok((Default::default(), conn))) as Box<Future<Item = _, Error = _>>;
-
-                        for order in orders {
-                            out = Box::new(out.and_then({
-                                let order_diff_repo_factory = order_diff_repo_factory.clone();
-                                move |(mut orders_with_diffs, conn): (Vec<(DbOrder, Vec<DbOrderDiff>)>, _)| {
-                                    (order_diff_repo_factory)()
-                                        .delete(
-                                            conn,
-                                            OrderDiffFilter {
-                                                parent: Some(order.0.id.into()),
-                                                ..Default::default()
-                                            },
-                                        )
-                                        .map(move |(order_diffs, conn)| {
-                                            orders_with_diffs.push((order, order_diffs));
-                                            (orders_with_diffs, conn)
-                                        })
-                                }
-                            }));
+            run_in_transaction(conn, move |conn| {
+                (order_repo_factory)()
+                    .select(
+                        conn,
+                        OrderFilter {
+                            conversion_id: Some(conversion_id.into()),
+                            ..Default::default()
+                        },
+                    )
+                    // Lines are deleted along with their orders, so they are selected beforehand
+                    .and_then(move |(orders, conn)| select_orders_with_lines(conn, order_line_repo_factory, orders))
+                    .and_then({
+                        let order_diff_repo_factory = order_diff_repo_factory.clone();
+                        move |(orders_with_lines, conn)| {
+                            let mut out: RepoConnectionFuture<()> = Box::new(future::ok(((), conn)));
+
+                            for (order, _) in &orders_with_lines {
+                                out = Box::new(out.and_then({
+                                    let order_diff_repo_factory = order_diff_repo_factory.clone();
+                                    let order_id = order.id;
+                                    move |((), conn)| {
+                                        (order_diff_repo_factory)()
+                                            .delete(
+                                                conn,
+                                                OrderDiffFilter {
+                                                    parent: Some(order_id.into()),
+                                                    ..Default::default()
+                                                },
+                                            )
+                                            .map(|(_, conn)| ((), conn))
+                                    }
+                                }));
+                            }
+
+                            out.map(move |((), conn)| (orders_with_lines, conn))
                         }
-
-                        out
-                    }
-                })
-                .and_then({
-                    let order_repo_factory = order_repo_factory.clone();
-                    move |(orders_with_diffs, conn)| {
-                        (order_repo_factory)()
-                            .delete(
-                                conn,
-                                OrderFilter {
-                                    conversion_id: Some(conversion_id.into()),
-                                    ..Default::default()
-                                },
-                            )
-                            .map(move |(_, conn)| (orders_with_diffs, conn))
-                    }
-                })
-                .and_then(move |(orders_with_diffs, conn)| {
-                    merge_cart_from_orders(conn, cart_repo_factory, orders_with_diffs).map(|conn| ((), conn))
-                })
+                    })
+                    .and_then({
+                        let order_repo_factory = order_repo_factory.clone();
+                        move |(orders_with_lines, conn)| {
+                            (order_repo_factory)()
+                                .delete(
+                                    conn,
+                                    OrderFilter {
+                                        conversion_id: Some(conversion_id.into()),
+                                        ..Default::default()
+                                    },
+                                )
+                                .map(move |(_, conn)| (orders_with_lines, conn))
+                        }
+                    })
+                    .and_then(move |(orders_with_lines, conn)| {
+                        let deleted_orders = orders_with_lines.iter().map(|(order, _)| order.clone()).collect();
+                        write_order_events(conn, order_event_repo_factory, OrderEventType::Deleted, deleted_orders)
+                            .map(move |((), conn)| (orders_with_lines, conn))
+                    })
+                    .and_then(move |(orders_with_lines, conn)| {
+                        merge_cart_from_orders(conn, cart_repo_factory, orders_with_lines).map(|conn| ((), conn))
+                    })
+            })
         }))
     }
 
     fn create_buy_now(&self, payload: BuyNow, conversion_id: Option<ConversionId>) -> ServiceFuture<Vec<Order>> {
-        use self::RepoLogin::*;
-
         let order_repo_factory = self.order_repo_factory.clone();
+        let order_line_repo_factory = self.order_line_repo_factory.clone();
         let order_diffs_repo_factory = self.order_diff_repo_factory.clone();
-        let calling_user = match self.login_data.clone() {
-            User { caller_id, .. } => caller_id,
-            _ => UserId(-1),
-        };
+        let order_event_repo_factory = self.order_event_repo_factory.clone();
+        let calling_user = calling_user(&self.login_data);
+
+        let order_tax_line_repo_factory = self.order_tax_line_repo_factory.clone();
 
         Box::new(self.db_pool.run(move |conn| {
-            let coupon_percent = payload.coupon.as_ref().map(|c| c.percent);
-            let coupon_id = payload.coupon.as_ref().map(|c| c.id);
-            let (company_package_id, shipping_id, delivery_name, delivery_price) = match payload.delivery_info.clone() {
-                None => (None, None, None, 0.0),
-                Some(delivery_info) => (
-                    Some(delivery_info.company_package_id.clone()),
-                    Some(delivery_info.shipping_id.clone()),
-                    Some(delivery_info.name.clone()),
-                    delivery_info.price,
-                ),
-            };
+            run_in_transaction(conn, move |conn| {
+                select_buy_now_checkout_settings(conn, &payload).and_then(move |(settings, conn)| {
+                    insert_new_order(
+                        conn,
+                        order_repo_factory,
+                        order_line_repo_factory,
+                        order_tax_line_repo_factory,
+                        order_diffs_repo_factory,
+                        order_event_repo_factory,
+                        calling_user,
+                        plan_buy_now_order(payload, conversion_id, &settings),
+                    )
+                    .map(|(order, conn)| (vec![order], conn))
+                })
+            })
+        }))
+    }
 
-            let TotalAmount {
-                total_amount,
-                coupon_discount,
-                product_discount,
-            } = calculate_total_amount(
-                payload.quantity,
-                payload.price.price,
-                payload.price.discount,
-                coupon_percent,
-                delivery_price,
-            );
+    fn quote(&self, payload: QuotePayload) -> ServiceFuture<Quote> {
+        let cart_repo_factory = self.cart_repo_factory.clone();
 
-            let order_item = (
-                OrderInserter {
-                    id: None,
-                    created_from: None,
-                    conversion_id,
-                    customer: payload.customer_id,
-                    store: payload.store_id,
-                    product: payload.product_id,
-                    quantity: payload.quantity,
-                    price: payload.price.price,
-                    currency: payload.price.currency,
-                    address: payload.address,
-                    receiver_name: payload.receiver_name,
-                    receiver_phone: payload.receiver_phone,
-                    receiver_email: payload.receiver_email,
-                    state: OrderState::New,
-                    delivery_company: delivery_name,
-                    track_id: None,
-                    pre_order: payload.pre_order,
-                    pre_order_days: payload.pre_order_days,
-                    coupon_id,
-                    coupon_percent,
-                    coupon_discount,
-                    product_discount,
-                    total_amount,
-                    company_package_id,
-                    delivery_price,
-                    shipping_id,
-                    uuid: payload.uuid,
-                    product_cashback: payload.product_info.cashback,
-                    currency_type: payload.price.currency.currency_type(),
-                },
-                "Buy now".to_string(),
-            );
+        Box::new(self.db_pool.run(move |conn| -> RepoConnectionFuture<Quote> {
+            match payload {
+                QuotePayload::Cart(payload) => {
+                    let company_package_ids = payload.delivery_info.values().map(|info| info.company_package_id.clone()).collect();
+                    let country = payload.address.country.clone();
+                    let filter = selected_cart_items_filter(&payload);
+                    Box::new(
+                        (cart_repo_factory)()
+                            .select(conn, filter)
+                            .and_then(move |(cart, conn)| {
+                                select_cart_checkout_settings(conn, &cart, company_package_ids, country)
+                                    .map(move |(settings, conn)| ((cart, settings), conn))
+                            })
+                            .map(move |((cart, settings), conn)| {
+                                let (new_orders, failures) = plan_cart_orders(&payload, cart, &settings);
+                                let quote = Quote {
+                                    orders: new_orders.iter().map(NewOrder::quote).collect(),
+                                    failures,
+                                };
+                                (quote, conn)
+                            }),
+                    )
+                }
+                QuotePayload::BuyNow(payload) => Box::new(select_buy_now_checkout_settings(conn, &payload).map(move |(settings, conn)| {
+                    let new_order = plan_buy_now_order(payload, None, &settings);
+                    let quote = Quote {
+                        orders: vec![new_order.quote()],
+                        failures: vec![],
+                    };
+                    (quote, conn)
+                })),
+            }
+        }))
+    }
 
-            let order_items = vec![order_item];
-            let mut out: RepoConnectionFuture<Vec<Order>>;
-            out = Box::new(future::ok((Default::default(), conn)));
+    fn get_order_lines(&self, order_id: OrderId) -> ServiceFuture<Vec<OrderLine>> {
+        let order_line_repo_factory = self.order_line_repo_factory.clone();
+        Box::new(self.db_pool.run(move |conn| {
+            (order_line_repo_factory)().select(
+                conn,
+                OrderLineFilter {
+                    order_id: Some(order_id.into()),
+                    ..Default::default()
+                }
+                .with_ordering(true),
+            )
+        }))
+    }
 
-            for (new_order, comment) in order_items {
-                out = Box::new(out.and_then({
-                    let comment = comment.clone();
-                    let order_repo_factory = order_repo_factory.clone();
-                    let order_diffs_repo_factory = order_diffs_repo_factory.clone();
-                    move |(mut out_data, conn)| {
-                        // Insert new order along with the record in history
-                        (order_repo_factory)()
-                            .insert_exactly_one(conn, new_order)
-                            .and_then(move |(inserted_order, conn)| {
-                                let order_diff = OrderDiffInserter {
-                                    parent: inserted_order.0.id,
-                                    committer: calling_user,
-                                    committed_at: Utc::now(),
-                                    state: OrderState::New,
-                                    comment: Some(comment),
-                                    committer_role: CommitterRole::Customer,
-                                };
+    fn get_order_taxes(&self, order_id: OrderId) -> ServiceFuture<Vec<OrderTaxLine>> {
+        let order_tax_line_repo_factory = self.order_tax_line_repo_factory.clone();
+        Box::new(self.db_pool.run(move |conn| {
+            (order_tax_line_repo_factory)().select(
+                conn,
+                OrderTaxLineFilter {
+                    order_id: Some(order_id.into()),
+                    ..Default::default()
+                }
+                .with_ordering(true),
+            )
+        }))
+    }
 
-                                (order_diffs_repo_factory)()
-                                    .insert_exactly_one(conn, order_diff)
-                                    .map(|(_, conn)| (inserted_order, conn))
-                            })
-                            .map({
-                                move |(order, conn): (DbOrder, RepoConnection)| {
-                                    out_data.push(order.0);
-                                    (out_data, conn)
-                                }
-                            })
-                    }
-                }));
-            }
+    fn cancel_order_line(&self, order_id: OrderId, line_id: OrderLineId, comment: Option<String>) -> ServiceFuture<Option<Order>> {
+        let login_data = self.login_data.clone();
+
+        let order_repo_factory = self.order_repo_factory.clone();
+        let order_line_repo_factory = self.order_line_repo_factory.clone();
+        let order_diff_repo_factory = self.order_diff_repo_factory.clone();
+        let order_event_repo_factory = self.order_event_repo_factory.clone();
+        let calling_user = calling_user(&self.login_data);
+
+        Box::new(self.db_pool.run(move |conn| {
+            run_in_transaction(conn, move |conn| {
+                (order_repo_factory)()
+                    .select(
+                        conn,
+                        OrderFilter {
+                            id: Some(order_id.into()),
+                            ..Default::default()
+                        },
+                    )
+                    .map(|(mut orders, conn)| (orders.pop(), conn))
+                    .and_then(move |(current_order, conn)| -> RepoConnectionFuture<Option<Order>> {
+                        let current_order = match current_order {
+                            Some(current_order) => current_order,
+                            None => return Box::new(future::ok((None, conn))),
+                        };
+                        let current_state = current_order.0.state;
+                        let current_version = current_order.1.version;
+                        let committer_role = match committer_role_of(&login_data, &current_order.0) {
+                            Ok(committer_role) => committer_role,
+                            Err(e) => return Box::new(future::err((e, conn))),
+                        };
+
+                        // Line is a part of the order, so it may be cancelled whenever the whole order may be
+                        if !is_transition_allowed(current_state, OrderState::Cancelled, committer_role) {
+                            return Box::new(future::err((
+                                format_err!(
+                                    "Lines of order {} in state {} can not be cancelled by {:?}",
+                                    order_id,
+                                    current_state,
+                                    committer_role
+                                )
+                                .context(Error::ForbiddenStateTransition)
+                                .into(),
+                                conn,
+                            )));
+                        }
 
-            out
+                        Box::new(
+                            (order_line_repo_factory)()
+                                .select(conn, OrderLineFilter::active(order_id).with_ordering(true))
+                                .and_then(move |(lines, conn)| -> RepoConnectionFuture<Option<Order>> {
+                                    let (cancelled_lines, mut remaining_lines): (Vec<_>, Vec<_>) =
+                                        lines.into_iter().partition(|line| line.id == line_id);
+                                    let cancelled_line = match cancelled_lines.into_iter().next() {
+                                        Some(line) => line,
+                                        None => return Box::new(future::ok((None, conn))),
+                                    };
+                                    let delivery_successor = take_over_delivery(&cancelled_line, &mut remaining_lines);
+                                    let primary_line = match remaining_lines.first() {
+                                        Some(line) => line.clone(),
+                                        None => {
+                                            return Box::new(future::err((
+                                                format_err!(
+                                                    "Line {} is the last one of order {}, the order has to be cancelled instead",
+                                                    line_id,
+                                                    order_id
+                                                )
+                                                .context(Error::ForbiddenStateTransition)
+                                                .into(),
+                                                conn,
+                                            )));
+                                        }
+                                    };
+                                    let total_amount = remaining_lines.iter().fold(Money::zero(), |sum, line| sum + line.total_amount);
+                                    let tax_amount = remaining_lines.iter().fold(Money::zero(), |sum, line| sum + line.tax_amount);
+
+                                    Box::new(
+                                        (order_line_repo_factory)()
+                                            .update(
+                                                conn,
+                                                OrderLineUpdater {
+                                                    mask: OrderLineFilter {
+                                                        id: Some(line_id.into()),
+                                                        ..Default::default()
+                                                    },
+                                                    data: OrderLineUpdateData {
+                                                        cancelled_at: Some(Utc::now()),
+                                                        ..Default::default()
+                                                    },
+                                                },
+                                            )
+                                            .and_then({
+                                                let order_line_repo_factory = order_line_repo_factory.clone();
+                                                move |(_, conn)| -> RepoConnectionFuture<()> {
+                                                    match delivery_successor {
+                                                        Some(line) => Box::new(
+                                                            (order_line_repo_factory)()
+                                                                .update(
+                                                                    conn,
+                                                                    OrderLineUpdater {
+                                                                        mask: OrderLineFilter {
+                                                                            id: Some(line.id.into()),
+                                                                            ..Default::default()
+                                                                        },
+                                                                        data: OrderLineUpdateData {
+                                                                            total_amount: Some(line.total_amount),
+                                                                            ..Default::default()
+                                                                        },
+                                                                    },
+                                                                )
+                                                                .map(|(_, conn)| ((), conn)),
+                                                        ),
+                                                        None => Box::new(future::ok(((), conn))),
+                                                    }
+                                                }
+                                            })
+                                            // The order keeps describing its first active line
+                                            .and_then(move |((), conn)| {
+                                                (order_repo_factory)().update(
+                                                    conn,
+                                                    OrderUpdater {
+                                                        mask: OrderFilter {
+                                                            id: Some(order_id.into()),
+                                                            version: Some(current_version.into()),
+                                                            ..Default::default()
+                                                        },
+                                                        data: OrderUpdateData {
+                                                            primary_line: Some(primary_line),
+                                                            total_amount: Some(total_amount),
+                                                            tax_amount: Some(tax_amount),
+                                                            ..Default::default()
+                                                        },
+                                                    },
+                                                )
+                                            })
+                                            .and_then(move |(mut updated_orders, conn)| match updated_orders.pop() {
+                                                Some(order) => Ok((order, conn)),
+                                                None => Err((
+                                                    format_err!(
+                                                        "Order {} was modified concurrently, version {} is stale",
+                                                        order_id,
+                                                        current_version
+                                                    )
+                                                    .context(Error::OrderConflict)
+                                                    .into(),
+                                                    conn,
+                                                )),
+                                            })
+                                            .and_then(move |(order, conn): (DbOrder, RepoConnection)| {
+                                                (order_diff_repo_factory)()
+                                                    .insert_exactly_one(
+                                                        conn,
+                                                        OrderDiffInserter {
+                                                            parent: order_id,
+                                                            committer: calling_user,
+                                                            committed_at: Utc::now(),
+                                                            state: order.0.state,
+                                                            comment: Some(comment.unwrap_or_else(|| format!("Line {} cancelled", line_id))),
+                                                            committer_role,
+                                                        },
+                                                    )
+                                                    .map(|(_, conn)| (order, conn))
+                                            })
+                                            .and_then(move |(order, conn)| {
+                                                write_order_event(conn, order_event_repo_factory, OrderEventType::Updated, &order.0)
+                                                    .map(move |((), conn)| (Some(order.0), conn))
+                                            }),
+                                    )
+                                }),
+                        )
+                    })
+            })
         }))
     }
 
-    fn get_order(&self, order_id: OrderIdentifier) -> ServiceFuture<Option<Order>> {
+    fn get_order(&self, order_id: OrderIdentifier) -> ServiceFuture<Option<OrderWithMeta>> {
         let order_repo_factory = self.order_repo_factory.clone();
         Box::new(
             self.db_pool
                 .run(move |conn| (order_repo_factory)().select(conn, OrderFilter::from(order_id)))
-                .map(|mut orders| orders.pop().map(|v| v.0)),
+                .map(|mut orders| orders.pop().map(OrderWithMeta::from)),
         )
     }
 
@@ -445,24 +652,22 @@ impl OrderService for OrderServiceImpl {
         )
     }
 
-    fn get_orders_for_store(&self, store_id: StoreId) -> ServiceFuture<Vec<Order>> {
+    fn get_orders_for_store(&self, store_id: StoreId, page: OrderPageRequest) -> ServiceFuture<OrderPage> {
         let order_repo_factory = self.order_repo_factory.clone();
-        Box::new(
-            self.db_pool
-                .run(move |conn| {
-                    (order_repo_factory)().select(
-                        conn,
-                        OrderFilter {
-                            store: Some(store_id.into()),
-                            ..Default::default()
-                        },
-                    )
-                })
-                .map(|v| v.into_iter().map(|v| v.0).collect()),
-        )
+        Box::new(self.db_pool.run(move |conn| {
+            select_order_page(
+                conn,
+                order_repo_factory,
+                OrderFilter {
+                    store: Some(store_id.into()),
+                    ..Default::default()
+                },
+                page,
+            )
+        }))
     }
 
-    fn get_orders_for_user(&self, customer: UserId) -> ServiceFuture<Vec<Order>> {
+    fn get_all_orders_for_user(&self, customer: UserId) -> ServiceFuture<Vec<Order>> {
         let order_repo_factory = self.order_repo_factory.clone();
         Box::new(
             self.db_pool
@@ -472,20 +677,42 @@ impl OrderService for OrderServiceImpl {
                         OrderFilter {
                             customer: Some(customer.into()),
                             ..Default::default()
-                        },
+                        }
+                        .with_ordering(true),
                     )
                 })
                 .map(|v| v.into_iter().map(|v| v.0).collect()),
         )
     }
 
+    fn get_orders_for_user(&self, customer: UserId, page: OrderPageRequest) -> ServiceFuture<OrderPage> {
+        let order_repo_factory = self.order_repo_factory.clone();
+        Box::new(self.db_pool.run(move |conn| {
+            select_order_page(
+                conn,
+                order_repo_factory,
+                OrderFilter {
+                    customer: Some(customer.into()),
+                    ..Default::default()
+                },
+                page,
+            )
+        }))
+    }
+
     fn delete_order(&self, order_id: OrderIdentifier) -> ServiceFuture<()> {
         let order_repo_factory = self.order_repo_factory.clone();
-        Box::new(
-            self.db_pool
-                .run(move |conn| (order_repo_factory)().delete(conn, OrderFilter::from(order_id)))
-                .map(|_| ()),
-        )
+        let order_event_repo_factory = self.order_event_repo_factory.clone();
+        Box::new(self.db_pool.run(move |conn| {
+            run_in_transaction(conn, move |conn| {
+                (order_repo_factory)()
+                    .delete(conn, OrderFilter::from(order_id))
+                    .and_then(move |(deleted_orders, conn)| {
+                        let deleted_orders = deleted_orders.into_iter().map(|order| order.0).collect();
+                        write_order_events(conn, order_event_repo_factory, OrderEventType::Deleted, deleted_orders)
+                    })
+            })
+        }))
     }
 
     fn search(&self, terms: OrderSearchTerms) -> ServiceFuture<Vec<Order>> {
@@ -499,6 +726,67 @@ impl OrderService for OrderServiceImpl {
         )
     }
 
+    fn search_page(
+        &self,
+        terms: OrderSearchTerms,
+        filters: OrderSearchFilters,
+        sort: OrderSort,
+        page: OrderPageRequest,
+    ) -> ServiceFuture<OrderPage> {
+        let db_pool = self.db_pool.clone();
+        let order_repo_factory = self.order_repo_factory.clone();
+        let login_data = self.login_data.clone();
+
+        if let Some(q) = filters.q.clone() {
+            return Box::new(db_pool.run(move |conn| select_relevant_orders(conn, &login_data, q, terms, filters, page)));
+        }
+
+        let product = filters.product;
+        Box::new(
+            future::result(OrderFilter::from_search_terms(terms))
+                .map(move |filter| filter.with_search_filters(filters).with_sort(sort))
+                .and_then(move |filter| {
+                    db_pool.run(move |conn| {
+                        restrict_to_product(conn, filter, product)
+                            .and_then(move |(filter, conn)| select_order_page(conn, order_repo_factory, filter, page))
+                    })
+                }),
+        )
+    }
+
+    fn search_all(&self, terms: OrderSearchTerms, filters: OrderSearchFilters, sort: OrderSort) -> ServiceFuture<Vec<Order>> {
+        let db_pool = self.db_pool.clone();
+        let order_repo_factory = self.order_repo_factory.clone();
+        let login_data = self.login_data.clone();
+
+        if let Some(q) = filters.q.clone() {
+            return Box::new(db_pool.run(move |conn| {
+                let search = repos::order::OrderTextSearch {
+                    q,
+                    terms,
+                    filters,
+                    after: None,
+                    limit: None,
+                };
+                repos::order::select_by_text(conn, &login_data, search)
+                    .map(|(found, conn)| (found.into_iter().map(|(order, _)| order.0).collect(), conn))
+            }));
+        }
+
+        let product = filters.product;
+        Box::new(
+            future::result(OrderFilter::from_search_terms(terms))
+                .map(move |filter| filter.with_search_filters(filters).with_sort(sort).with_ordering(true))
+                .and_then(move |filter| {
+                    db_pool.run(move |conn| {
+                        restrict_to_product(conn, filter, product)
+                            .and_then(move |(filter, conn)| (order_repo_factory)().select(conn, filter))
+                    })
+                })
+                .map(|orders| orders.into_iter().map(|v| v.0).collect()),
+        )
+    }
+
     fn set_order_state(
         &self,
         order_id: OrderIdentifier,
@@ -506,34 +794,97 @@ impl OrderService for OrderServiceImpl {
         comment: Option<String>,
         track_id: Option<String>,
         committer_role: CommitterRole,
+        precondition: OrderUpdatePrecondition,
     ) -> ServiceFuture<Option<Order>> {
-        use self::RepoLogin::*;
+        let cart_repo_factory = self.cart_repo_factory.clone();
+        let order_repo_factory = self.order_repo_factory.clone();
+        let order_line_repo_factory = self.order_line_repo_factory.clone();
+        let order_diff_repo_factory = self.order_diff_repo_factory.clone();
+        let rejected_order_transition_repo_factory = self.rejected_order_transition_repo_factory.clone();
+        let order_event_repo_factory = self.order_event_repo_factory.clone();
+        let db_pool = self.db_pool.clone();
+        let calling_user = calling_user(&self.login_data);
+
+        Box::new(
+            set_order_state(
+                order_id,
+                state,
+                comment,
+                track_id,
+                cart_repo_factory,
+                order_repo_factory,
+                order_line_repo_factory,
+                order_diff_repo_factory,
+                rejected_order_transition_repo_factory,
+                order_event_repo_factory,
+                db_pool,
+                calling_user,
+                committer_role,
+                precondition,
+            )
+            .map(|order| order.map(|order| order.0)),
+        )
+    }
 
+    fn set_order_state_by_caller(
+        &self,
+        order_id: OrderIdentifier,
+        state: OrderState,
+        comment: Option<String>,
+        track_id: Option<String>,
+        precondition: OrderUpdatePrecondition,
+    ) -> ServiceFuture<Option<OrderWithMeta>> {
+        let login_data = self.login_data.clone();
         let cart_repo_factory = self.cart_repo_factory.clone();
         let order_repo_factory = self.order_repo_factory.clone();
+        let order_line_repo_factory = self.order_line_repo_factory.clone();
         let order_diff_repo_factory = self.order_diff_repo_factory.clone();
+        let rejected_order_transition_repo_factory = self.rejected_order_transition_repo_factory.clone();
+        let order_event_repo_factory = self.order_event_repo_factory.clone();
         let db_pool = self.db_pool.clone();
-        let calling_user = match self.login_data.clone() {
-            User { caller_id, .. } => caller_id,
-            _ => UserId(-1),
-        };
+        let calling_user = calling_user(&self.login_data);
 
-        set_order_state(
-            order_id,
-            state,
-            comment,
-            track_id,
-            cart_repo_factory,
-            order_repo_factory,
-            order_diff_repo_factory,
-            db_pool,
-            calling_user,
-            committer_role,
+        Box::new(
+            self.db_pool
+                .run({
+                    let order_repo_factory = order_repo_factory.clone();
+                    move |conn| (order_repo_factory)().select(conn, OrderFilter::from(order_id))
+                })
+                .map(|mut orders| orders.pop())
+                .and_then(move |order| -> ServiceFuture<Option<OrderWithMeta>> {
+                    let order = match order {
+                        Some(order) => order,
+                        None => return Box::new(future::ok(None)),
+                    };
+                    let committer_role = match committer_role_of(&login_data, &order.0) {
+                        Ok(committer_role) => committer_role,
+                        Err(e) => return Box::new(future::err(e)),
+                    };
+
+                    Box::new(
+                        set_order_state(
+                            OrderIdentifier::Id(order.0.id),
+                            state,
+                            comment,
+                            track_id,
+                            cart_repo_factory,
+                            order_repo_factory,
+                            order_line_repo_factory,
+                            order_diff_repo_factory,
+                            rejected_order_transition_repo_factory,
+                            order_event_repo_factory,
+                            db_pool,
+                            calling_user,
+                            committer_role,
+                            precondition,
+                        )
+                        .map(|order| order.map(OrderWithMeta::from)),
+                    )
+                }),
         )
     }
 
     fn track_delivered_orders(&self, max_delivered_state_duration: ChronoDuration) -> ServiceFuture<Vec<Order>> {
-        use self::RepoLogin::User;
         let now = ::chrono::offset::Utc::now();
         let old_order_date = now - max_delivered_state_duration;
         let search_old_delivered_diffs = move |order_id: OrderId| -> OrderDiffFilter {
@@ -552,14 +903,14 @@ impl OrderService for OrderServiceImpl {
             ..OrderSearchTerms::default()
         };
 
-        let calling_user = match self.login_data.clone() {
-            User { caller_id, .. } => caller_id,
-            _ => UserId(-1),
-        };
+        let calling_user = calling_user(&self.login_data);
 
         let cart_repo_factory = self.cart_repo_factory.clone();
         let order_repo_factory = self.order_repo_factory.clone();
+        let order_line_repo_factory = self.order_line_repo_factory.clone();
         let order_diff_repo_factory2 = self.order_diff_repo_factory.clone();
+        let rejected_order_transition_repo_factory = self.rejected_order_transition_repo_factory.clone();
+        let order_event_repo_factory = self.order_event_repo_factory.clone();
         let db_pool2 = self.db_pool.clone();
 
         let result = self
@@ -586,59 +937,101 @@ impl OrderService for OrderServiceImpl {
                         None,
                         cart_repo_factory.clone(),
                         order_repo_factory.clone(),
+                        order_line_repo_factory.clone(),
                         order_diff_repo_factory2.clone(),
+                        rejected_order_transition_repo_factory.clone(),
+                        order_event_repo_factory.clone(),
                         db_pool2.clone(),
                         calling_user,
                         CommitterRole::System,
+                        OrderUpdatePrecondition::with_expected_state(OrderState::Delivered),
                     )
                 })
             })
             .and_then(::futures::future::join_all)
-            .map(|orders| orders.into_iter().filter_map(|order| order).collect());
+            .map(|orders| orders.into_iter().filter_map(|order| order.map(|order| order.0)).collect());
 
         Box::new(result)
     }
 
+    fn get_saga_notifications(&self, status: Option<SagaNotificationStatus>) -> ServiceFuture<Vec<SagaNotification>> {
+        let saga_notification_repo_factory = self.saga_notification_repo_factory.clone();
+        Box::new(self.db_pool.run(move |conn| {
+            (saga_notification_repo_factory)().select(
+                conn,
+                SagaNotificationFilter {
+                    do_order: true,
+                    status: status.map(From::from),
+                    ..Default::default()
+                },
+            )
+        }))
+    }
+
     fn get_orders_with_state(&self, state: OrderState, from: DateTime<Utc>) -> ServiceFuture<Vec<Order>> {
-        let search_orders_in_state = OrderSearchTerms {
-            state: Some(state),
-            ..OrderSearchTerms::default()
-        };
-        let orders_in_state = self.search(search_orders_in_state);
+        let order_repo_factory = self.order_repo_factory.clone();
+        let order_diff_repo_factory = self.order_diff_repo_factory.clone();
 
-        let search_diffs = {
-            OrderDiffFilter {
-                state: Some(state).map(From::from),
-                committed_at_range: ::models::common::into_range(Some(from), None),
-                ..OrderDiffFilter::default()
-            }
-        };
+        Box::new(
+            self.db_pool
+                .run(move |conn| {
+                    select_orders_in_state(
+                        conn,
+                        order_repo_factory,
+                        order_diff_repo_factory,
+                        state,
+                        into_range(Some(from), None),
+                    )
+                })
+                .map(move |orders| {
+                    orders
+                        .into_iter()
+                        .filter(|(_, state_since)| *state_since >= from)
+                        .map(|(order, _)| order.0)
+                        .collect()
+                }),
+        )
+    }
 
+    fn get_stale_orders(&self, state: OrderState, min_age: ChronoDuration) -> ServiceFuture<Vec<StaleOrder>> {
+        if let Err(e) = ensure_superadmin(&self.login_data, "Stale orders are available to superadmins only") {
+            return Box::new(future::err(e));
+        }
+
+        let order_repo_factory = self.order_repo_factory.clone();
         let order_diff_repo_factory = self.order_diff_repo_factory.clone();
-        let db_pool = self.db_pool.clone();
+        let now = Utc::now();
 
-        let diffs_in_max_state_duration = db_pool.run(move |conn| (order_diff_repo_factory)().select(conn, search_diffs));
-
-        let result = orders_in_state
-            .join(diffs_in_max_state_duration)
-            .map(|(orders_in_state, recent_diffs)| {
-                let orders_in_state_ids: HashSet<OrderId> = orders_in_state.iter().map(|order| order.id).collect();
-                let recent_diffs_ids: HashSet<OrderId> = recent_diffs.iter().map(|diff| diff.0.parent).collect();
-                let mut by_id: HashMap<OrderId, Order> = orders_in_state.into_iter().map(|order| (order.id, order)).collect();
-                orders_in_state_ids
-                    .intersection(&recent_diffs_ids)
-                    .filter_map(|order_id| by_id.remove(&order_id))
-                    .collect()
-            });
-        Box::new(result)
+        Box::new(
+            self.db_pool
+                .run(move |conn| {
+                    select_orders_in_state(
+                        conn,
+                        order_repo_factory,
+                        order_diff_repo_factory,
+                        state,
+                        into_range(None, Some(now - min_age)),
+                    )
+                })
+                .map(move |orders| {
+                    let mut stale_orders = orders
+                        .into_iter()
+                        .filter(|(_, state_since)| now - *state_since >= min_age)
+                        .map(|(order, state_since)| StaleOrder::new(order, state_since, now))
+                        .collect::<Vec<_>>();
+                    stale_orders.sort_by_key(|stale_order| stale_order.state_since);
+                    stale_orders
+                }),
+        )
     }
 
-    fn search_by_diffs(&self, diff_filter: OrderDiffFilter) -> ServiceFuture<Vec<Order>> {
+    fn search_by_diffs(&self, diff_filter: OrderDiffFilter) -> ServiceFuture<Vec<(OrderWithMeta, Vec<OrderLine>)>> {
         let order_diff_repo_factory = self.order_diff_repo_factory.clone();
         let db_pool_diff = self.db_pool.clone();
 
         let db_pool_order = self.db_pool.clone();
         let order_repo_factory = self.order_repo_factory.clone();
+        let order_line_repo_factory = self.order_line_repo_factory.clone();
 
         let result = db_pool_diff
             .run(move |conn| (order_diff_repo_factory)().select(conn, diff_filter))
@@ -647,71 +1040,906 @@ impl OrderService for OrderServiceImpl {
                 ids: Some(oder_ids.into()),
                 ..Default::default()
             })
-            .and_then(move |filter| db_pool_order.run(move |conn| (order_repo_factory)().select(conn, filter)))
-            .map(|db_orders| db_orders.into_iter().map(|db_order| db_order.0).collect());
+            .and_then(move |filter| {
+                db_pool_order.run(move |conn| {
+                    (order_repo_factory)().select(conn, filter).and_then(move |(db_orders, conn)| {
+                        let line_filter = OrderLineFilter {
+                            order_ids: Some(db_orders.iter().map(|order| order.0.id).collect::<Vec<_>>().into()),
+                            cancelled_at: Some(None.into()),
+                            ..Default::default()
+                        };
+                        (order_line_repo_factory)()
+                            .select(conn, line_filter.with_ordering(true))
+                            .map(move |(lines, conn)| {
+                                let orders_with_lines = db_orders
+                                    .into_iter()
+                                    .map(|order| {
+                                        let order_lines = lines.iter().filter(|line| line.order_id == order.0.id).cloned().collect();
+                                        (OrderWithMeta::from(order), order_lines)
+                                    })
+                                    .collect();
+                                (orders_with_lines, conn)
+                            })
+                    })
+                })
+            });
 
         Box::new(result)
     }
 }
 
 struct TotalAmount {
-    coupon_discount: Option<ProductPrice>,
-    product_discount: Option<ProductPrice>,
-    total_amount: ProductPrice,
+    product_discount: Option<Money>,
+    total_amount: Money,
 }
 
+/// Calculates the product discount and total amount of the order in minor units of its currency.
+/// Prices and discounts of one unit are rounded half away from zero before they are summed up,
+/// so the total is exact and always equals the sum of the amounts shown to the customer.
+/// Delivery price is charged for every unit or once, depending on `delivery_pricing`.
+/// Coupons are applied afterwards by `apply_coupons`, as their discounts may spread over several products.
 fn calculate_total_amount(
     quantity: Quantity,
-    product_price: ProductPrice,
-    product_discount_percent: Option<f64>,
-    coupon_discount_percent: Option<i32>,
-    delivery_price_per_product: f64,
+    currency: Currency,
+    product_price: Money,
+    product_discount_rate: Option<f64>,
+    delivery_price: Money,
+    delivery_pricing: DeliveryPricing,
 ) -> TotalAmount {
-    let product_discount_percent = product_discount_percent.filter(|p| *p > ZERO_DISCOUNT);
-    let TotalAmount {
-        coupon_discount,
+    let product_price = product_price.round_to(currency);
+    let delivery_price = delivery_price.round_to(currency);
+    // Discount less than half of the minor unit is no discount at all
+    let product_discount = product_discount_rate
+        .map(|rate| product_price.share(rate).round_to(currency))
+        .filter(|discount| *discount > Money::zero());
+    let total_amount = if quantity.0 > 0 {
+        (product_price - product_discount.unwrap_or_default()) * quantity.0
+    } else {
+        Money::zero()
+    };
+    TotalAmount {
         product_discount,
-        total_amount,
-    } = match (product_discount_percent, coupon_discount_percent) {
-        (Some(product_discount_percent), _) => {
-            let product_discount = product_discount_percent * product_price.0;
-            let total_amount = if quantity.0 > 0 {
-                (product_price.0 - product_discount) * quantity.0 as f64
-            } else {
-                0.0
-            };
-            TotalAmount {
-                coupon_discount: None,
-                product_discount: Some(ProductPrice(product_discount)),
-                total_amount: ProductPrice(total_amount),
+        total_amount: total_amount + delivery_amount(quantity, delivery_price, delivery_pricing),
+    }
+}
+
+/// Amount of the line the coupon of the scope is given on, products sold at a discount get no coupon discount
+fn coupon_base_amount(line: &OrderLineInserter, scope: CouponScope) -> Money {
+    if line.product_discount.is_some() || line.quantity.0 <= 0 {
+        return Money::zero();
+    }
+    match scope {
+        CouponScope::Unit => line.price,
+        CouponScope::Product | CouponScope::Store | CouponScope::Cart => line.price * line.quantity.0,
+    }
+}
+
+/// Lines the coupon attached to the line at `position` applies to.
+/// A line keeps the coupon of its own, coupons of wider scopes spread over the lines without one.
+fn coupon_lines(new_orders: &[NewOrder], position: (usize, usize), coupon_id: CouponId, scope: CouponScope) -> Vec<(usize, usize)> {
+    if scope == CouponScope::Unit || scope == CouponScope::Product {
+        return vec![position];
+    }
+
+    let (order_index, _) = position;
+    let currency = new_orders[order_index].order.currency;
+    let mut lines = Vec::new();
+
+    for (index, new_order) in new_orders.iter().enumerate() {
+        let in_scope = if scope == CouponScope::Store {
+            index == order_index
+        } else {
+            new_order.order.currency == currency
+        };
+        if !in_scope {
+            continue;
+        }
+        for (line_index, line) in new_order.lines.iter().enumerate() {
+            if line.coupon_id.map_or(true, |id| id == coupon_id) {
+                lines.push((index, line_index));
             }
         }
-        (None, Some(coupon_discount_percent)) => {
-            let coupon_discount = coupon_discount_percent as f64 / 100.0 * product_price.0;
-            let total_amount = if quantity.0 > 0 {
-                product_price.0 - coupon_discount + product_price.0 * (quantity.0 - 1) as f64
-            } else {
-                0.0
+    }
+
+    lines
+}
+
+/// Applies coupons attached to the lines, each coupon once per its scope.
+/// Discount of the coupon is allocated to the lines it applies to in proportion to their amounts.
+/// Has to be called once all lines are grouped into orders and before taxes are applied.
+fn apply_coupons(new_orders: &mut [NewOrder], coupon_rules: &CouponRules) {
+    let mut applied: Vec<(CouponId, Vec<(usize, usize)>)> = Vec::new();
+
+    for order_index in 0..new_orders.len() {
+        for line_index in 0..new_orders[order_index].lines.len() {
+            let position = (order_index, line_index);
+            let (coupon_id, percent) = {
+                let line = &new_orders[order_index].lines[line_index];
+                match (line.coupon_id, line.coupon_percent) {
+                    (Some(coupon_id), Some(percent)) => (coupon_id, percent),
+                    _ => continue,
+                }
             };
-            TotalAmount {
-                coupon_discount: Some(ProductPrice(coupon_discount)),
-                product_discount: None,
-                total_amount: ProductPrice(total_amount),
+            if applied.iter().any(|(id, lines)| *id == coupon_id && lines.contains(&position)) {
+                continue;
+            }
+
+            let scope = coupon_rules.scope_of(coupon_id);
+            let currency = new_orders[order_index].order.currency;
+            let lines = coupon_lines(new_orders, position, coupon_id, scope);
+            let amounts = lines
+                .iter()
+                .map(|&(o, l)| coupon_base_amount(&new_orders[o].lines[l], scope))
+                .collect::<Vec<_>>();
+            let total = amounts.iter().fold(Money::zero(), |acc, amount| acc + *amount);
+            let discount = coupon_rules.discount(coupon_id, percent, total, currency);
+
+            for (&(o, l), (amount, line_discount)) in lines
+                .iter()
+                .zip(amounts.iter().zip(allocate_discount(discount, &amounts, currency)))
+            {
+                // Products sold at a discount and the ones not ordered get no coupon discount
+                if !amount.is_zero() {
+                    new_orders[o].apply_line_coupon(l, coupon_id, percent, line_discount);
+                }
             }
+            applied.push((coupon_id, lines));
         }
-        (None, None) => TotalAmount {
-            coupon_discount: None,
-            product_discount: None,
-            total_amount: ProductPrice(quantity.0 as f64 * product_price.0),
+    }
+}
+
+/// Whether the delivery of the line is already charged by one of the earlier lines
+fn delivery_charged_before(earlier_lines: &[OrderLineInserter], line: &OrderLineInserter) -> bool {
+    earlier_lines.iter().any(|other| {
+        share_delivery(
+            line.delivery_pricing,
+            line.company_package_id,
+            other.delivery_pricing,
+            other.company_package_id,
+        )
+    })
+}
+
+/// Moves the delivery charge of the cancelled line to the next active line shipped in the same package,
+/// returns the line whose total has changed
+fn take_over_delivery(cancelled_line: &OrderLine, remaining_lines: &mut [OrderLine]) -> Option<OrderLine> {
+    let shares_delivery = |line: &OrderLine| {
+        share_delivery(
+            cancelled_line.delivery_pricing,
+            cancelled_line.company_package_id,
+            line.delivery_pricing,
+            line.company_package_id,
+        )
+    };
+
+    // Shared delivery is charged by the first line of the package only
+    if remaining_lines
+        .iter()
+        .any(|line| shares_delivery(line) && line.position < cancelled_line.position)
+    {
+        return None;
+    }
+
+    let successor = remaining_lines
+        .iter_mut()
+        .find(|line| shares_delivery(line) && line.quantity.0 > 0)?;
+    successor.total_amount = successor.total_amount + successor.delivery_price;
+    Some(successor.clone())
+}
+
+/// Order being created along with its lines and their taxes
+struct NewOrder {
+    order: OrderInserter,
+    lines: Vec<OrderLineInserter>,
+    taxes: Vec<OrderTaxLineInserter>,
+}
+
+impl NewOrder {
+    /// Order of one line, the id of the order is generated beforehand for the line to refer to it
+    fn new(order: OrderInserter, comment: String) -> Self {
+        let id = order.id.unwrap_or_else(|| OrderId(Uuid::new_v4()));
+        let line = OrderLineInserter::from_order(id, 0, &order, comment);
+        Self {
+            order: OrderInserter { id: Some(id), ..order },
+            lines: vec![line],
+            taxes: vec![],
+        }
+    }
+
+    /// Adds the product described by `line_order` as the next line, the order keeps describing its first line.
+    /// Delivery shared with the previous lines is charged by the first of them only.
+    fn add_line(&mut self, line_order: &OrderInserter, comment: String) {
+        let id = self.order.id.expect("New order always has an id");
+        let mut line = OrderLineInserter::from_order(id, self.lines.len() as i32, line_order, comment);
+        if delivery_charged_before(&self.lines, &line) && line.quantity.0 > 0 {
+            line.total_amount = line.total_amount - line.delivery_price;
+        }
+        self.order.total_amount = self.order.total_amount + line.total_amount;
+        self.lines.push(line);
+    }
+
+    /// Gives the coupon discount allocated to the line, the order keeps describing its first line
+    fn apply_line_coupon(&mut self, index: usize, coupon_id: CouponId, coupon_percent: i32, coupon_discount: Money) {
+        {
+            let line = &mut self.lines[index];
+            line.coupon_id = Some(coupon_id);
+            line.coupon_percent = Some(coupon_percent);
+            line.coupon_discount = Some(coupon_discount);
+            line.total_amount = line.total_amount - coupon_discount;
+        }
+        if index == 0 {
+            self.order.coupon_id = Some(coupon_id);
+            self.order.coupon_percent = Some(coupon_percent);
+            self.order.coupon_discount = Some(coupon_discount);
+        }
+        self.order.total_amount = self.order.total_amount - coupon_discount;
+    }
+
+    /// Delivery charged by the line, zero if one of the earlier lines charges it
+    fn line_delivery_amount(&self, index: usize) -> Money {
+        let line = &self.lines[index];
+        if delivery_charged_before(&self.lines[..index], line) {
+            Money::zero()
+        } else {
+            delivery_amount(line.quantity, line.delivery_price, line.delivery_pricing)
+        }
+    }
+
+    /// Calculates taxes of the lines, exclusive taxes are added to the totals of the lines and of the order.
+    /// Has to be called once all lines are added.
+    fn apply_taxes(&mut self, tax_settings: &TaxSettings) {
+        let order_id = self.order.id.expect("New order always has an id");
+        let currency = self.order.currency;
+
+        for index in 0..self.lines.len() {
+            let taxable_amount = self.lines[index].total_amount - self.line_delivery_amount(index);
+            let taxes = tax_settings.calculate(&self.order.address, self.lines[index].product, taxable_amount, currency);
+            let tax_amount = taxes.iter().fold(Money::zero(), |acc, tax| acc + tax.tax_amount);
+            let exclusive_tax_amount = taxes
+                .iter()
+                .filter(|tax| !tax.inclusive)
+                .fold(Money::zero(), |acc, tax| acc + tax.tax_amount);
+
+            let (line_id, store, customer) = {
+                let line = &mut self.lines[index];
+                line.tax_amount = tax_amount;
+                line.total_amount = line.total_amount + exclusive_tax_amount;
+                (line.id, line.store, line.customer)
+            };
+            self.order.tax_amount = self.order.tax_amount + tax_amount;
+            self.order.total_amount = self.order.total_amount + exclusive_tax_amount;
+            self.taxes.extend(taxes.into_iter().map(|tax| OrderTaxLineInserter {
+                id: OrderTaxLineId::new(),
+                order_id,
+                order_line_id: line_id,
+                store,
+                customer,
+                tax,
+            }));
+        }
+    }
+
+    /// Comments of the lines left by the customer
+    fn comment(&self) -> String {
+        self.lines
+            .iter()
+            .map(|line| line.comment.as_str())
+            .filter(|comment| !comment.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Amounts of the order and of its lines
+    fn quote(&self) -> QuoteOrder {
+        let currency = self.order.currency;
+        let mut quote = QuoteOrder {
+            store: self.order.store,
+            currency,
+            items: vec![],
+            subtotal: Money::zero(),
+            product_discount: Money::zero(),
+            coupon_discount: Money::zero(),
+            delivery_amount: Money::zero(),
+            cashback: Money::zero(),
+            tax_amount: self.order.tax_amount,
+            total_amount: self.order.total_amount,
+        };
+
+        for (index, line) in self.lines.iter().enumerate() {
+            let delivery_amount = self.line_delivery_amount(index);
+            let taxes = self
+                .taxes
+                .iter()
+                .filter(|tax| tax.order_line_id == line.id)
+                .map(|tax| tax.tax.clone())
+                .collect::<Vec<_>>();
+            let exclusive_tax_amount = taxes
+                .iter()
+                .filter(|tax| !tax.inclusive)
+                .fold(Money::zero(), |acc, tax| acc + tax.tax_amount);
+            // Cashback is paid for the products, not for their delivery or taxes on top of them
+            let cashback = line.product_cashback.map(|cashback| {
+                (line.total_amount - delivery_amount - exclusive_tax_amount)
+                    .share(cashback.0)
+                    .round_to(currency)
+            });
+
+            quote.subtotal = quote.subtotal + line.price * line.quantity.0;
+            quote.product_discount = quote.product_discount + line.product_discount.unwrap_or_default() * line.quantity.0;
+            quote.coupon_discount = quote.coupon_discount + line.coupon_discount.unwrap_or_default();
+            quote.delivery_amount = quote.delivery_amount + delivery_amount;
+            quote.cashback = quote.cashback + cashback.unwrap_or_default();
+            quote.items.push(QuoteItem {
+                cart_item_id: line.created_from,
+                product: line.product,
+                quantity: line.quantity,
+                price: line.price,
+                product_discount: line.product_discount,
+                coupon_discount: line.coupon_discount,
+                delivery_price: line.delivery_price,
+                delivery_pricing: line.delivery_pricing,
+                delivery_amount,
+                cashback,
+                tax_amount: line.tax_amount,
+                taxes,
+                total_amount: line.total_amount,
+            });
+        }
+
+        quote
+    }
+}
+
+/// Settings the amounts of new orders depend on
+struct CheckoutSettings {
+    delivery_pricing_rules: DeliveryPricingRules,
+    tax_settings: TaxSettings,
+    coupon_rules: CouponRules,
+}
+
+/// Selects settings applicable to the products of the stores delivered to the country and to the coupons used
+fn select_checkout_settings(
+    conn: RepoConnection,
+    stores: Vec<StoreId>,
+    products: Vec<ProductId>,
+    company_package_ids: Vec<CompanyPackageId>,
+    coupons: Vec<CouponId>,
+    country: Option<String>,
+) -> RepoConnectionFuture<CheckoutSettings> {
+    Box::new(
+        repos::delivery_pricing_rule::select_applicable(conn, stores, company_package_ids)
+            .and_then(move |(delivery_pricing_rules, conn)| {
+                repos::tax_rule::select_tax_settings(conn, country, products)
+                    .map(move |(tax_settings, conn)| (delivery_pricing_rules, tax_settings, conn))
+            })
+            .and_then(move |(delivery_pricing_rules, tax_settings, conn)| {
+                repos::coupon_rule::select_applicable(conn, coupons).map(move |(coupon_rules, conn)| {
+                    let settings = CheckoutSettings {
+                        delivery_pricing_rules,
+                        tax_settings,
+                        coupon_rules,
+                    };
+                    (settings, conn)
+                })
+            }),
+    )
+}
+
+fn select_cart_checkout_settings(
+    conn: RepoConnection,
+    cart: &[CartItem],
+    company_package_ids: Vec<CompanyPackageId>,
+    country: Option<String>,
+) -> RepoConnectionFuture<CheckoutSettings> {
+    let stores = cart.iter().map(|cart_item| cart_item.store_id).collect();
+    let products = cart.iter().map(|cart_item| cart_item.product_id).collect();
+    let coupons = cart.iter().filter_map(|cart_item| cart_item.coupon_id).collect();
+    select_checkout_settings(conn, stores, products, company_package_ids, coupons, country)
+}
+
+fn select_buy_now_checkout_settings(conn: RepoConnection, payload: &BuyNow) -> RepoConnectionFuture<CheckoutSettings> {
+    let company_package_ids = payload.delivery_info.iter().map(|info| info.company_package_id.clone()).collect();
+    select_checkout_settings(
+        conn,
+        vec![payload.store_id],
+        vec![payload.product_id],
+        company_package_ids,
+        payload.coupon.iter().map(|coupon| coupon.id).collect(),
+        payload.address.country.clone(),
+    )
+}
+
+/// Cart items selected for the checkout
+fn selected_cart_items_filter(payload: &ConvertCartPayload) -> CartItemFilter {
+    CartItemFilter {
+        customer: Some(payload.user_id.into()),
+        meta_filter: CartItemMetaFilter {
+            selected: Some(true),
+            currency_type: payload.currency_type,
+            ..Default::default()
         },
+    }
+}
+
+/// Groups the cart items into new orders, one order per store and currency.
+/// Items that can not be ordered are left out and returned with the reason.
+fn plan_cart_orders(payload: &ConvertCartPayload, cart: Vec<CartItem>, settings: &CheckoutSettings) -> (Vec<NewOrder>, Vec<QuoteFailure>) {
+    let mut new_orders: Vec<NewOrder> = Vec::new();
+    let mut failures = Vec::new();
+    let mut transaction_id = TransactionId::new(payload.uuid);
+
+    for cart_item in cart {
+        let ProductSellerPrice { price, currency, discount } = match payload.seller_prices.get(&cart_item.product_id).cloned() {
+            Some(seller_price) => seller_price,
+            None => {
+                failures.push(QuoteFailure {
+                    cart_item_id: cart_item.id,
+                    store: cart_item.store_id,
+                    product: cart_item.product_id,
+                    reason: format!("Missing price information for product {}", cart_item.product_id),
+                });
+                continue;
+            }
+        };
+        let price = Money::from(price).round_to(currency);
+        let (company_package_id, shipping_id, delivery_name, delivery_price) =
+            match payload.delivery_info.get(&cart_item.product_id).cloned() {
+                None => (None, None, None, Money::zero()),
+                Some(delivery_info) => (
+                    Some(delivery_info.company_package_id.clone()),
+                    Some(delivery_info.shipping_id.clone()),
+                    Some(delivery_info.name.clone()),
+                    Money::from_f64(delivery_info.price).round_to(currency),
+                ),
+            };
+
+        let coupon_percent = cart_item
+            .coupon_id
+            .and_then(|coupon_id| payload.coupons.get(&coupon_id))
+            .map(|coupon| coupon.percent);
+
+        let product_cashback = payload.product_info.get(&cart_item.product_id).and_then(|product| product.cashback);
+        let delivery_pricing = settings.delivery_pricing_rules.pricing_for(cart_item.store_id, company_package_id);
+
+        let TotalAmount {
+            total_amount,
+            product_discount,
+        } = calculate_total_amount(cart_item.quantity, currency, price, discount, delivery_price, delivery_pricing);
+        let order = OrderInserter {
+            id: None,
+            created_from: Some(cart_item.id),
+            conversion_id: payload.conversion_id,
+            customer: payload.user_id,
+            store: cart_item.store_id,
+            product: cart_item.product_id,
+            quantity: cart_item.quantity,
+            price,
+            currency,
+            address: payload.address.clone(),
+            receiver_name: payload.receiver_name.clone(),
+            receiver_phone: payload.receiver_phone.clone(),
+            receiver_email: payload.receiver_email.clone(),
+            state: OrderState::New,
+            delivery_company: delivery_name,
+            track_id: None,
+            pre_order: cart_item.pre_order,
+            pre_order_days: cart_item.pre_order_days,
+            coupon_id: cart_item.coupon_id,
+            coupon_percent,
+            coupon_discount: None,
+            product_discount,
+            total_amount,
+            company_package_id,
+            delivery_price,
+            delivery_pricing,
+            tax_amount: Money::zero(),
+            shipping_id,
+            uuid: transaction_id.clone().into(),
+            product_cashback,
+            currency_type: cart_item.currency_type,
+        };
+
+        // Amounts in different currencies can not be summed up, so such items make separate orders
+        let same_order = new_orders
+            .iter()
+            .position(|new_order| new_order.order.store == order.store && new_order.order.currency == order.currency);
+        match same_order {
+            Some(index) => new_orders[index].add_line(&order, cart_item.comment),
+            None => {
+                transaction_id = transaction_id.next();
+                let order = OrderInserter {
+                    uuid: transaction_id.clone().into(),
+                    ..order
+                };
+                new_orders.push(NewOrder::new(order, cart_item.comment));
+            }
+        }
+    }
+
+    apply_coupons(&mut new_orders, &settings.coupon_rules);
+    for new_order in &mut new_orders {
+        new_order.apply_taxes(&settings.tax_settings);
+    }
+
+    (new_orders, failures)
+}
+
+/// Order of the single product bought right away
+fn plan_buy_now_order(payload: BuyNow, conversion_id: Option<ConversionId>, settings: &CheckoutSettings) -> NewOrder {
+    let store = payload.store_id;
+    let coupon_percent = payload.coupon.as_ref().map(|c| c.percent);
+    let coupon_id = payload.coupon.as_ref().map(|c| c.id);
+    let currency = payload.price.currency;
+    let price = Money::from(payload.price.price).round_to(currency);
+    let (company_package_id, shipping_id, delivery_name, delivery_price) = match payload.delivery_info.clone() {
+        None => (None, None, None, Money::zero()),
+        Some(delivery_info) => (
+            Some(delivery_info.company_package_id.clone()),
+            Some(delivery_info.shipping_id.clone()),
+            Some(delivery_info.name.clone()),
+            Money::from_f64(delivery_info.price).round_to(currency),
+        ),
     };
-    TotalAmount {
-        coupon_discount,
+    let delivery_pricing = settings.delivery_pricing_rules.pricing_for(store, company_package_id);
+
+    let TotalAmount {
+        total_amount,
         product_discount,
-        total_amount: ProductPrice(total_amount.0 + quantity.0 as f64 * delivery_price_per_product),
+    } = calculate_total_amount(
+        payload.quantity,
+        currency,
+        price,
+        payload.price.discount,
+        delivery_price,
+        delivery_pricing,
+    );
+
+    let order = OrderInserter {
+        id: None,
+        created_from: None,
+        conversion_id,
+        customer: payload.customer_id,
+        store,
+        product: payload.product_id,
+        quantity: payload.quantity,
+        price,
+        currency,
+        address: payload.address,
+        receiver_name: payload.receiver_name,
+        receiver_phone: payload.receiver_phone,
+        receiver_email: payload.receiver_email,
+        state: OrderState::New,
+        delivery_company: delivery_name,
+        track_id: None,
+        pre_order: payload.pre_order,
+        pre_order_days: payload.pre_order_days,
+        coupon_id,
+        coupon_percent,
+        coupon_discount: None,
+        product_discount,
+        total_amount,
+        company_package_id,
+        delivery_price,
+        delivery_pricing,
+        tax_amount: Money::zero(),
+        shipping_id,
+        uuid: payload.uuid,
+        product_cashback: payload.product_info.cashback,
+        currency_type: currency.currency_type(),
+    };
+
+    let mut new_order = NewOrder::new(order, "Buy now".to_string());
+    apply_coupons(slice::from_mut(&mut new_order), &settings.coupon_rules);
+    new_order.apply_taxes(&settings.tax_settings);
+    new_order
+}
+
+/// Inserts the order with its lines, the record in history and the outbox event
+fn insert_new_order(
+    conn: RepoConnection,
+    order_repo_factory: Rc<Fn() -> Box<OrderRepo>>,
+    order_line_repo_factory: Rc<Fn() -> Box<OrderLineRepo>>,
+    order_tax_line_repo_factory: Rc<Fn() -> Box<OrderTaxLineRepo>>,
+    order_diff_repo_factory: Rc<Fn() -> Box<OrderDiffRepo>>,
+    order_event_repo_factory: Rc<Fn() -> Box<OrderEventRepo>>,
+    calling_user: UserId,
+    new_order: NewOrder,
+) -> RepoConnectionFuture<Order> {
+    let comment = new_order.comment();
+    let NewOrder { order, lines, taxes } = new_order;
+
+    Box::new(
+        (order_repo_factory)()
+            .insert_exactly_one(conn, order)
+            .and_then(move |(order, conn)| {
+                let mut out: RepoConnectionFuture<()> = Box::new(future::ok(((), conn)));
+
+                for line in lines {
+                    out = Box::new(out.and_then({
+                        let order_line_repo_factory = order_line_repo_factory.clone();
+                        move |((), conn)| {
+                            (order_line_repo_factory)()
+                                .insert_exactly_one(conn, line)
+                                .map(|(_, conn)| ((), conn))
+                        }
+                    }));
+                }
+
+                for tax in taxes {
+                    out = Box::new(out.and_then({
+                        let order_tax_line_repo_factory = order_tax_line_repo_factory.clone();
+                        move |((), conn)| {
+                            (order_tax_line_repo_factory)()
+                                .insert_exactly_one(conn, tax)
+                                .map(|(_, conn)| ((), conn))
+                        }
+                    }));
+                }
+
+                out.map(move |((), conn)| (order, conn))
+            })
+            .and_then(move |(order, conn): (DbOrder, RepoConnection)| {
+                (order_diff_repo_factory)()
+                    .insert_exactly_one(
+                        conn,
+                        OrderDiffInserter {
+                            parent: order.0.id,
+                            committer: calling_user,
+                            committed_at: Utc::now(),
+                            state: OrderState::New,
+                            comment: Some(comment),
+                            committer_role: CommitterRole::Customer,
+                        },
+                    )
+                    .map(|(_, conn)| (order, conn))
+            })
+            .and_then(move |(order, conn)| {
+                write_order_event(conn, order_event_repo_factory, OrderEventType::Created, &order.0).map(move |((), conn)| (order.0, conn))
+            }),
+    )
+}
+
+/// Role the caller acts in on the order: superadmins act on behalf of the system,
+/// managers of its store as its seller and its buyer as the customer
+pub fn committer_role_of(login_data: &UserLogin, order: &Order) -> Result<CommitterRole, FailureError> {
+    if let RepoLogin::User {
+        caller_id,
+        ref caller_roles,
+    } = *login_data
+    {
+        if caller_roles.iter().any(|role_entry| role_entry.role == UserRole::Superadmin) {
+            return Ok(CommitterRole::System);
+        }
+
+        if caller_roles
+            .iter()
+            .any(|role_entry| role_entry.role == UserRole::StoreManager(order.store))
+        {
+            return Ok(CommitterRole::Seller);
+        }
+
+        if caller_id == order.customer {
+            return Ok(CommitterRole::Customer);
+        }
+    }
+
+    Err(
+        format_err!("Order {} may be changed by its customer, its seller or superadmins only", order.id)
+            .context(Error::Forbidden)
+            .into(),
+    )
+}
+
+/// Orders in the state that were moved into it within `entered_at`, along with the time they entered the state.
+/// The range is matched against `order_diffs.committed_at` so that only the candidates are loaded,
+/// an order which re-entered the state later is still returned with its latest entry time.
+fn select_orders_in_state(
+    conn: RepoConnection,
+    order_repo_factory: Rc<Fn() -> Box<OrderRepo>>,
+    order_diff_repo_factory: Rc<Fn() -> Box<OrderDiffRepo>>,
+    state: OrderState,
+    entered_at: Option<ValueContainer<Range<DateTime<Utc>>>>,
+) -> RepoConnectionFuture<Vec<(DbOrder, DateTime<Utc>)>> {
+    let order_diff_repo = (order_diff_repo_factory)();
+    Box::new(
+        order_diff_repo
+            .select(
+                conn,
+                OrderDiffFilter {
+                    state: Some(state.into()),
+                    committed_at_range: entered_at,
+                    ..Default::default()
+                },
+            )
+            .and_then(move |(entries, conn)| -> RepoConnectionFuture<Vec<DbOrder>> {
+                let candidates = entries
+                    .into_iter()
+                    .map(|DbOrderDiff(diff)| diff.parent)
+                    .collect::<HashSet<OrderId>>()
+                    .into_iter()
+                    .collect::<Vec<_>>();
+                if candidates.is_empty() {
+                    return Box::new(future::ok((vec![], conn)));
+                }
+
+                (order_repo_factory)().select(
+                    conn,
+                    OrderFilter {
+                        ids: Some(candidates.into()),
+                        state: Some(state.into()),
+                        ..Default::default()
+                    },
+                )
+            })
+            .and_then(move |(orders, conn)| -> RepoConnectionFuture<Vec<(DbOrder, DateTime<Utc>)>> {
+                if orders.is_empty() {
+                    return Box::new(future::ok((vec![], conn)));
+                }
+
+                let parents = orders.iter().map(|order| order.0.id).collect::<Vec<_>>();
+                Box::new(
+                    (order_diff_repo_factory)()
+                        .select(
+                            conn,
+                            OrderDiffFilter {
+                                parents: Some(parents.into()),
+                                ..Default::default()
+                            }
+                            .with_ordering(true),
+                        )
+                        .map(move |(diffs, conn)| {
+                            let mut diffs_by_order: HashMap<OrderId, Vec<OrderDiff>> = HashMap::new();
+                            for DbOrderDiff(diff) in diffs {
+                                diffs_by_order.entry(diff.parent).or_insert_with(Vec::new).push(diff);
+                            }
+
+                            let orders = orders
+                                .into_iter()
+                                .map(|order| {
+                                    let state_since = diffs_by_order
+                                        .get(&order.0.id)
+                                        .and_then(|diffs| state_entered_at(state, diffs))
+                                        .unwrap_or(order.0.created_at);
+                                    (order, state_since)
+                                })
+                                .collect();
+
+                            (orders, conn)
+                        }),
+                )
+            }),
+    )
+}
+
+/// Selects one page of orders matching the filter in the order of its sort.
+/// Orders are compared by `(sort key, id)`, the cursor has to come from a page of the same sort field.
+fn select_order_page(
+    conn: RepoConnection,
+    order_repo_factory: Rc<Fn() -> Box<OrderRepo>>,
+    filter: OrderFilter,
+    page: OrderPageRequest,
+) -> RepoConnectionFuture<OrderPage> {
+    let limit = page.limit();
+    let filter = filter.with_ordering(true);
+    let sort_field = filter.sort.field;
+
+    match page.after {
+        None => Box::new(
+            select_orders(conn, order_repo_factory, filter, limit + 1)
+                .map(move |(orders, conn)| (OrderPage::new(orders, limit, sort_field), conn)),
+        ),
+        Some(after) => {
+            if after.key.field() != Some(sort_field) {
+                return Box::new(future::err((
+                    format_err!("Cursor of {:?} sort can not be used with {:?} sort", after.key.field(), sort_field)
+                        .context(Error::InvalidCursor)
+                        .into(),
+                    conn,
+                )));
+            }
+
+            // Orders with the same sort key as the cursor order come first, then the following ones
+            let same_key_filter = filter.clone().at_cursor(after);
+            let following_filter = filter.beyond_cursor(after);
+
+            Box::new(
+                select_orders(conn, order_repo_factory.clone(), same_key_filter, limit + 1).and_then(move |(mut orders, conn)| {
+                    let remaining = limit + 1 - orders.len() as i32;
+                    if remaining <= 0 {
+                        return future::Either::A(future::ok((OrderPage::new(orders, limit, sort_field), conn)));
+                    }
+
+                    future::Either::B(select_orders(conn, order_repo_factory, following_filter, remaining).map(
+                        move |(following_orders, conn)| {
+                            orders.extend(following_orders);
+                            (OrderPage::new(orders, limit, sort_field), conn)
+                        },
+                    ))
+                }),
+            )
+        }
     }
 }
 
+/// Restricts the filter to the orders having a line of the product, if it is set
+fn restrict_to_product(conn: RepoConnection, filter: OrderFilter, product: Option<ProductId>) -> RepoConnectionFuture<OrderFilter> {
+    match product {
+        None => Box::new(future::ok((filter, conn))),
+        Some(product) => Box::new(repos::order::select_ids_with_product(conn, product).map(move |(ids, conn)| {
+            let filter = OrderFilter {
+                ids: Some(ids.into()),
+                ..filter
+            };
+            (filter, conn)
+        })),
+    }
+}
+
+/// Selects one page of orders matching the text query and the other search terms, most relevant first.
+/// The cursor has to come from a page of the same text search.
+fn select_relevant_orders(
+    conn: RepoConnection,
+    login_data: &UserLogin,
+    q: String,
+    terms: OrderSearchTerms,
+    filters: OrderSearchFilters,
+    page: OrderPageRequest,
+) -> RepoConnectionFuture<OrderPage> {
+    let after = match page.after {
+        None => None,
+        Some(OrderCursor {
+            key: OrderSortKey::Relevance(rank),
+            id,
+        }) => Some((rank, id)),
+        Some(after) => {
+            return Box::new(future::err((
+                format_err!("Cursor of {:?} sort can not be used with the text search", after.key.field())
+                    .context(Error::InvalidCursor)
+                    .into(),
+                conn,
+            )));
+        }
+    };
+    let limit = page.limit();
+
+    let search = repos::order::OrderTextSearch {
+        q,
+        terms,
+        filters,
+        after,
+        limit: Some(i64::from(limit) + 1),
+    };
+    Box::new(
+        repos::order::select_by_text(conn, login_data, search).map(move |(mut found, conn)| {
+            let next_cursor = if found.len() > limit as usize {
+                found.truncate(limit as usize);
+                found.last().map(|(order, rank)| OrderCursor {
+                    key: OrderSortKey::Relevance(*rank),
+                    id: order.0.id,
+                })
+            } else {
+                None
+            };
+            let orders = found.into_iter().map(|(order, _)| order.0).collect();
+
+            (OrderPage { orders, next_cursor }, conn)
+        }),
+    )
+}
+
+fn select_orders(
+    conn: RepoConnection,
+    order_repo_factory: Rc<Fn() -> Box<OrderRepo>>,
+    filter: OrderFilter,
+    limit: i32,
+) -> RepoConnectionFuture<Vec<Order>> {
+    Box::new(
+        (order_repo_factory)()
+            .select_full(conn, filter, Some(limit), None)
+            .map(|(orders, conn)| (orders.into_iter().map(|v| v.0).collect(), conn)),
+    )
+}
+
 fn set_order_state(
     order_id: OrderIdentifier,
     state: OrderState,
@@ -719,105 +1947,312 @@ fn set_order_state(
     track_id: Option<String>,
     cart_repo_factory: Rc<Fn() -> Box<CartItemRepo>>,
     order_repo_factory: Rc<Fn() -> Box<OrderRepo>>,
+    order_line_repo_factory: Rc<Fn() -> Box<OrderLineRepo>>,
     order_diff_repo_factory: Rc<Fn() -> Box<OrderDiffRepo>>,
+    rejected_order_transition_repo_factory: Rc<Fn() -> Box<RejectedOrderTransitionRepo>>,
+    order_event_repo_factory: Rc<Fn() -> Box<OrderEventRepo>>,
     db_pool: DbPool,
     calling_user: UserId,
     committer_role: CommitterRole,
-) -> ServiceFuture<Option<Order>> {
+    precondition: OrderUpdatePrecondition,
+) -> ServiceFuture<Option<DbOrder>> {
     let result = db_pool
-        .run(move |conn| {
+        .run({
+            let order_repo_factory = order_repo_factory.clone();
+            move |conn| (order_repo_factory)().select(conn, OrderFilter::from(order_id))
+        })
+        .map(|mut orders| orders.pop())
+        // Check the preconditions and the transition against the state table, then update the order
+        .and_then(move |current_order| -> ServiceFuture<Option<DbOrder>> {
+            let current_order = match current_order {
+                Some(current_order) => current_order,
+                None => return Box::new(future::ok(None)),
+            };
+            let current_state = current_order.0.state;
+            let id = current_order.0.id;
+
+            if let Err(e) = ensure_precondition(&current_order, &precondition) {
+                return Box::new(future::err(e));
+            }
+
+            if !is_transition_allowed(current_state, state, committer_role) {
+                return reject_order_transition(
+                    db_pool,
+                    rejected_order_transition_repo_factory,
+                    RejectedOrderTransitionInserter {
+                        parent: id,
+                        committer: calling_user,
+                        committer_role,
+                        from_state: current_state,
+                        to_state: state,
+                        attempted_at: Utc::now(),
+                        comment,
+                    },
+                );
+            }
+
+            // Update the order, write its history and revert the cart atomically
+            Box::new(db_pool.run(move |conn| {
+                run_in_transaction(conn, move |conn| {
+                    write_order_state(
+                        conn,
+                        current_order,
+                        state,
+                        comment,
+                        track_id,
+                        cart_repo_factory,
+                        order_repo_factory,
+                        order_line_repo_factory,
+                        order_diff_repo_factory,
+                        order_event_repo_factory,
+                        calling_user,
+                        committer_role,
+                    )
+                })
+            }))
+        });
+
+    Box::new(result)
+}
+
+/// Fails with `Error::OrderConflict` unless the order is in the expected state and version
+fn ensure_precondition(current_order: &DbOrder, precondition: &OrderUpdatePrecondition) -> Result<(), FailureError> {
+    if precondition.holds_for(current_order) {
+        return Ok(());
+    }
+
+    Err(format_err!(
+        "Order {} is in state {} with version {}, expected {:?}",
+        current_order.0.id,
+        current_order.0.state,
+        current_order.1.version,
+        precondition
+    )
+    .context(Error::OrderConflict)
+    .into())
+}
+
+/// Moves the order to the state, writes its history and reverts the cart if the payment expired.
+/// Must be called inside of the transaction, the transition has to be checked by the caller.
+fn write_order_state(
+    conn: RepoConnection,
+    current_order: DbOrder,
+    state: OrderState,
+    comment: Option<String>,
+    track_id: Option<String>,
+    cart_repo_factory: Rc<Fn() -> Box<CartItemRepo>>,
+    order_repo_factory: Rc<Fn() -> Box<OrderRepo>>,
+    order_line_repo_factory: Rc<Fn() -> Box<OrderLineRepo>>,
+    order_diff_repo_factory: Rc<Fn() -> Box<OrderDiffRepo>>,
+    order_event_repo_factory: Rc<Fn() -> Box<OrderEventRepo>>,
+    calling_user: UserId,
+    committer_role: CommitterRole,
+) -> RepoConnectionFuture<Option<DbOrder>> {
+    let id = current_order.0.id;
+    let current_version = current_order.1.version;
+
+    // Order sent in several shipments is delivered along with the last one of them
+    let shipments_checked: RepoConnectionFuture<()> = if state == OrderState::Delivered {
+        ensure_shipments_delivered(conn, id)
+    } else {
+        Box::new(future::ok(((), conn)))
+    };
+
+    shipments_checked
+        .and_then(move |((), conn)| {
             (order_repo_factory)().update(
                 conn,
                 OrderUpdater {
-                    mask: order_id.into(),
+                    mask: OrderFilter {
+                        id: Some(id.into()),
+                        version: Some(current_version.into()),
+                        ..Default::default()
+                    },
                     data: OrderUpdateData {
                         state: Some(state),
                         track_id,
+                        ..Default::default()
                     },
                 },
             )
         })
-        .map(|mut out_data| out_data.pop())
+        .map(|(mut out_data, conn)| (out_data.pop(), conn))
         // Insert new order diff into database
-        .and_then(move |updated_order| {
-            db_pool.run(move |conn| {
-                if let Some(order) = updated_order {
-                    let order_clone = order.clone();
-                    Box::new(
-                        (order_diff_repo_factory)()
-                            .insert_exactly_one(
-                                conn,
-                                OrderDiffInserter {
-                                    parent: order.0.id,
-                                    committer: calling_user,
-                                    committed_at: Utc::now(),
-                                    state: order.0.state,
-                                    comment,
-                                    committer_role,
-                                },
-                            )
-                            // Revert cart from the order if the payment expired
-                            .and_then(move |(_, conn)| match order.0.state {
-                                OrderState::AmountExpired => Box::new(
-                                    (order_diff_repo_factory)()
-                                        .select(
-                                            conn,
-                                            OrderDiffFilter {
-                                                parent: Some(order.0.id.into()),
-                                                ..Default::default()
-                                            },
-                                        )
-                                        .and_then(|(order_diffs, conn)| {
-                                            let order_with_diffs = vec![(order_clone, order_diffs)];
-                                            merge_cart_from_orders(conn, cart_repo_factory, order_with_diffs)
-                                        })
-                                        .map(|conn| (Some(order.0), conn)),
-                                ),
-                                _ => Box::new(future::ok((Some(order.0), conn))) as Box<Future<Item = _, Error = _>>,
-                            }),
-                    )
-                } else {
-                    Box::new(future::ok((None, conn))) as RepoConnectionFuture<Option<Order>>
+        .and_then(move |(updated_order, conn)| -> RepoConnectionFuture<Option<DbOrder>> {
+            if let Some(order) = updated_order {
+                let order_clone = order.clone();
+                Box::new(
+                    (order_diff_repo_factory)()
+                        .insert_exactly_one(
+                            conn,
+                            OrderDiffInserter {
+                                parent: order.0.id,
+                                committer: calling_user,
+                                committed_at: Utc::now(),
+                                state: order.0.state,
+                                comment,
+                                committer_role,
+                            },
+                        )
+                        .and_then({
+                            let order = order.clone();
+                            move |(_, conn)| write_order_event(conn, order_event_repo_factory, OrderEventType::StateChanged, &order.0)
+                        })
+                        // Seller has to be paid for the completed order, Saga is notified about it later.
+                        // The notification is a consequence of the allowed transition, so caller's ACL is not applied
+                        .and_then(move |((), conn)| -> RepoConnectionFuture<()> {
+                            if state == OrderState::Complete {
+                                Box::new(
+                                    repos::saga_notification::make_su_repo()
+                                        .insert_exactly_one(conn, SagaNotificationInserter::seller_payment(id))
+                                        .map(|(_, conn)| ((), conn)),
+                                )
+                            } else {
+                                Box::new(future::ok(((), conn)))
+                            }
+                        })
+                        // Revert cart from the order if the payment expired
+                        .and_then(move |((), conn)| match order.0.state {
+                            OrderState::AmountExpired => Box::new(
+                                select_orders_with_lines(conn, order_line_repo_factory, vec![order_clone])
+                                    .and_then(|(orders_with_lines, conn)| {
+                                        merge_cart_from_orders(conn, cart_repo_factory, orders_with_lines)
+                                    })
+                                    .map(|conn| (Some(order), conn)),
+                            ),
+                            _ => Box::new(future::ok((Some(order), conn))) as Box<Future<Item = _, Error = _>>,
+                        }),
+                )
+            } else {
+                // The order was modified after it had been selected
+                Box::new(future::err((
+                    format_err!("Order {} was modified concurrently, version {} is stale", id, current_version)
+                        .context(Error::OrderConflict)
+                        .into(),
+                    conn,
+                )))
+            }
+        })
+}
+
+/// Writes the order change into the outbox, must be called inside of the transaction making the change
+pub(crate) fn write_order_event(
+    conn: RepoConnection,
+    order_event_repo_factory: Rc<Fn() -> Box<OrderEventRepo>>,
+    event_type: OrderEventType,
+    order: &Order,
+) -> RepoConnectionFuture<()> {
+    match OrderEventInserter::from_order(event_type, order) {
+        Ok(inserter) => Box::new(
+            (order_event_repo_factory)()
+                .insert_exactly_one(conn, inserter)
+                .map(|(_, conn)| ((), conn)),
+        ),
+        Err(e) => Box::new(future::err((e, conn))),
+    }
+}
+
+fn write_order_events(
+    conn: RepoConnection,
+    order_event_repo_factory: Rc<Fn() -> Box<OrderEventRepo>>,
+    event_type: OrderEventType,
+    orders: Vec<Order>,
+) -> RepoConnectionFuture<()> {
+    let mut out: RepoConnectionFuture<()> = Box::new(future::ok(((), conn)));
+
+    for order in orders {
+        out = Box::new(out.and_then({
+            let order_event_repo_factory = order_event_repo_factory.clone();
+            move |((), conn)| write_order_event(conn, order_event_repo_factory, event_type, &order)
+        }));
+    }
+
+    out
+}
+
+/// Records rejected state change for auditing and fails with `Error::ForbiddenStateTransition`
+fn reject_order_transition(
+    db_pool: DbPool,
+    rejected_order_transition_repo_factory: Rc<Fn() -> Box<RejectedOrderTransitionRepo>>,
+    rejection: RejectedOrderTransitionInserter,
+) -> ServiceFuture<Option<DbOrder>> {
+    let error_message = format!(
+        "Order {} can not be moved from {} to {} by {:?}",
+        rejection.parent, rejection.from_state, rejection.to_state, rejection.committer_role
+    );
+    warn!("{}", error_message);
+
+    Box::new(
+        db_pool
+            .run(move |conn| (rejected_order_transition_repo_factory)().insert_exactly_one(conn, rejection))
+            .then(move |res| {
+                if let Err(e) = res {
+                    error!("Failed to record rejected order transition: {:?}", e);
                 }
-            })
-        });
 
-    Box::new(result)
+                Err(format_err!("{}", error_message).context(Error::ForbiddenStateTransition).into())
+            }),
+    )
 }
 
+/// Pairs the orders with their active lines
+fn select_orders_with_lines(
+    conn: RepoConnection,
+    order_line_repo_factory: Rc<Fn() -> Box<OrderLineRepo>>,
+    orders: Vec<DbOrder>,
+) -> RepoConnectionFuture<Vec<(Order, Vec<OrderLine>)>> {
+    if orders.is_empty() {
+        return Box::new(future::ok((vec![], conn)));
+    }
+
+    let filter = OrderLineFilter {
+        order_ids: Some(orders.iter().map(|order| order.0.id).collect::<Vec<_>>().into()),
+        cancelled_at: Some(None.into()),
+        ..Default::default()
+    };
+
+    Box::new(
+        (order_line_repo_factory)()
+            .select(conn, filter.with_ordering(true))
+            .map(move |(lines, conn)| {
+                let orders_with_lines = orders
+                    .into_iter()
+                    .map(|order| {
+                        let order_lines = lines.iter().filter(|line| line.order_id == order.0.id).cloned().collect();
+                        (order.0, order_lines)
+                    })
+                    .collect();
+                (orders_with_lines, conn)
+            }),
+    )
+}
+
+/// Puts active lines of the orders back into the customer's cart
 fn merge_cart_from_orders(
     conn: BoxedConnection<RepoError>,
     cart_repo_factory: Rc<Fn() -> Box<CartItemRepo>>,
-    orders_with_diffs: Vec<(DbOrder, Vec<DbOrderDiff>)>,
+    orders_with_lines: Vec<(Order, Vec<OrderLine>)>,
 ) -> impl Future<Item = BoxedConnection<RepoError>, Error = (RepoError, BoxedConnection<RepoError>)> {
-    let new_cart_items = orders_with_diffs.into_iter().map(|(order, diffs)| {
-        let mut cart_item = CartItem {
-            id: order.0.created_from,
-            customer: CartCustomer::User(order.0.customer),
-            product_id: order.0.product,
-            quantity: order.0.quantity,
-            selected: true,
-            comment: "".into(),
-            store_id: order.0.store,
-            pre_order: order.0.pre_order,
-            pre_order_days: order.0.pre_order_days,
-            coupon_id: order.0.coupon_id,
-            delivery_method_id: order.0.shipping_id.map(|id| DeliveryMethodId::ShippingPackage { id }),
-            currency_type: order.0.currency_type,
-            user_country_code: None,
-        };
-        for diff in diffs {
-            if diff.0.state == OrderState::New {
-                if let Some(comment) = diff.0.comment {
-                    cart_item.comment = comment;
-                    break;
-                }
-            }
-        }
-        CartItemInserter {
+    let new_cart_items = orders_with_lines.into_iter().flat_map(|(order, lines)| {
+        lines.into_iter().map(move |line| CartItemInserter {
             strategy: CartItemMergeStrategy::Replacer,
-            data: cart_item,
-        }
+            data: CartItem {
+                // Lines of "buy now" orders were never in the cart
+                id: line.created_from.unwrap_or_else(CartItemId::new),
+                customer: CartCustomer::User(line.customer),
+                product_id: line.product,
+                quantity: line.quantity,
+                selected: true,
+                comment: line.comment,
+                store_id: line.store,
+                pre_order: line.pre_order,
+                pre_order_days: line.pre_order_days,
+                coupon_id: line.coupon_id,
+                delivery_method_id: line.shipping_id.map(|id| DeliveryMethodId::ShippingPackage { id }),
+                currency_type: order.currency_type,
+                user_country_code: None,
+            },
+        })
     });
 
     let mut out = Box::new(future::ok(conn)) as Box<Future<Item = _, Error = _>>;
@@ -836,37 +2271,801 @@ fn merge_cart_from_orders(
 mod tests {
     use super::*;
 
+    use serde_json;
+    use tokio_core::reactor::Core;
+
+    use stq_db::statement::*;
+    use test_utils::*;
+
+    /// Order diff repo that fails on every insert, used to break the state change right after the order update
+    struct FailingOrderDiffRepo;
+
+    impl DbRepo<DbOrderDiff, OrderDiffInserter, OrderDiffFilter, DummyOrderDiffUpdater, RepoError> for FailingOrderDiffRepo {}
+
+    impl DbRepoInsert<DbOrderDiff, OrderDiffInserter, RepoError> for FailingOrderDiffRepo {
+        fn insert(&self, conn: RepoConnection, _inserter: OrderDiffInserter) -> RepoConnectionFuture<Vec<DbOrderDiff>> {
+            Box::new(future::err((format_err!("Injected order diff insert failure"), conn)))
+        }
+    }
+
+    impl DbRepoSelect<DbOrderDiff, OrderDiffFilter, RepoError> for FailingOrderDiffRepo {
+        fn select_full(
+            &self,
+            conn: RepoConnection,
+            filter: OrderDiffFilter,
+            limit: Option<i32>,
+            op: Option<SelectOperation>,
+        ) -> RepoConnectionFuture<Vec<DbOrderDiff>> {
+            repos::order_diff::make_su_repo().select_full(conn, filter, limit, op)
+        }
+    }
+
+    impl DbRepoUpdate<DbOrderDiff, DummyOrderDiffUpdater, RepoError> for FailingOrderDiffRepo {
+        fn update(&self, conn: RepoConnection, updater: DummyOrderDiffUpdater) -> RepoConnectionFuture<Vec<DbOrderDiff>> {
+            repos::order_diff::make_su_repo().update(conn, updater)
+        }
+    }
+
+    impl DbRepoDelete<DbOrderDiff, OrderDiffFilter, RepoError> for FailingOrderDiffRepo {
+        fn delete(&self, conn: RepoConnection, filter: OrderDiffFilter) -> RepoConnectionFuture<Vec<DbOrderDiff>> {
+            repos::order_diff::make_su_repo().delete(conn, filter)
+        }
+    }
+
+    impl OrderDiffRepo for FailingOrderDiffRepo {}
+
+    #[test]
+    fn set_order_state_rolls_back_update_if_diff_insert_fails() {
+        let mut core = Core::new().expect("Unexpected error creating event loop core");
+        let db_pool = create_db_pool(&mut core);
+
+        let order = core
+            .run(db_pool.run(|conn| repos::order::make_su_repo().insert_exactly_one(conn, order_fixture())))
+            .unwrap()
+            .0;
+
+        let mut service = OrderServiceImpl::new(db_pool.clone(), super_user());
+        service.order_diff_repo_factory = Rc::new(|| Box::new(FailingOrderDiffRepo) as Box<OrderDiffRepo>);
+
+        let res = core.run(service.set_order_state(
+            OrderIdentifier::Id(order.id),
+            OrderState::PaymentAwaited,
+            None,
+            None,
+            CommitterRole::Customer,
+            OrderUpdatePrecondition::default(),
+        ));
+        assert!(res.is_err());
+
+        let stored_order = core.run(service.get_order(OrderIdentifier::Id(order.id))).unwrap().unwrap();
+        assert_eq!(stored_order.order.state, OrderState::New);
+
+        core.run(service.delete_order(OrderIdentifier::Id(order.id))).unwrap();
+    }
+
+    #[test]
+    fn set_order_state_checks_expected_version() {
+        let mut core = Core::new().expect("Unexpected error creating event loop core");
+        let db_pool = create_db_pool(&mut core);
+
+        let order = core
+            .run(db_pool.run(|conn| repos::order::make_su_repo().insert_exactly_one(conn, order_fixture())))
+            .unwrap();
+        let order_id = OrderIdentifier::Id(order.0.id);
+        let initial_version = order.1.version;
+
+        let service = OrderServiceImpl::new(db_pool.clone(), super_user());
+
+        core.run(service.set_order_state(
+            order_id,
+            OrderState::PaymentAwaited,
+            None,
+            None,
+            CommitterRole::Customer,
+            OrderUpdatePrecondition {
+                expected_state: Some(OrderState::New),
+                expected_version: Some(initial_version),
+            },
+        ))
+        .unwrap();
+
+        let stored_order = core.run(service.get_order(order_id)).unwrap().unwrap();
+        assert_eq!(stored_order.meta.version, initial_version.next());
+
+        // The first update has already consumed the initial version
+        let res = core.run(service.set_order_state(
+            order_id,
+            OrderState::Cancelled,
+            None,
+            None,
+            CommitterRole::Customer,
+            OrderUpdatePrecondition {
+                expected_state: None,
+                expected_version: Some(initial_version),
+            },
+        ));
+        assert!(res.is_err());
+
+        let stored_order = core.run(service.get_order(order_id)).unwrap().unwrap();
+        assert_eq!(stored_order.order.state, OrderState::PaymentAwaited);
+
+        core.run(service.delete_order(order_id)).unwrap();
+    }
+
+    #[test]
+    fn any_order_update_bumps_version() {
+        let mut core = Core::new().expect("Unexpected error creating event loop core");
+        let db_pool = create_db_pool(&mut core);
+
+        let order = core
+            .run(db_pool.run(|conn| repos::order::make_su_repo().insert_exactly_one(conn, order_fixture())))
+            .unwrap();
+        let order_id = order.0.id;
+        let initial_version = order.1.version;
+
+        let updated_order = core
+            .run(db_pool.run(move |conn| {
+                repos::order::make_su_repo().update(
+                    conn,
+                    OrderUpdater {
+                        mask: OrderFilter {
+                            id: Some(order_id.into()),
+                            ..Default::default()
+                        },
+                        data: OrderUpdateData {
+                            track_id: Some("TRACK-1".to_string()),
+                            ..Default::default()
+                        },
+                    },
+                )
+            }))
+            .unwrap()
+            .pop()
+            .unwrap();
+        assert_eq!(updated_order.1.version, initial_version.next());
+
+        let service = OrderServiceImpl::new(db_pool.clone(), super_user());
+        core.run(service.delete_order(OrderIdentifier::Id(order_id))).unwrap();
+    }
+
+    #[test]
+    fn order_changes_are_written_into_outbox() {
+        let mut core = Core::new().expect("Unexpected error creating event loop core");
+        let db_pool = create_db_pool(&mut core);
+
+        let order = core
+            .run(db_pool.run(|conn| repos::order::make_su_repo().insert_exactly_one(conn, order_fixture())))
+            .unwrap()
+            .0;
+        let order_id = OrderIdentifier::Id(order.id);
+
+        let service = OrderServiceImpl::new(db_pool.clone(), super_user());
+        core.run(service.set_order_state(
+            order_id,
+            OrderState::PaymentAwaited,
+            None,
+            None,
+            CommitterRole::Customer,
+            OrderUpdatePrecondition::default(),
+        ))
+        .unwrap();
+        core.run(service.delete_order(order_id)).unwrap();
+
+        let events_filter = OrderEventFilter {
+            order_id: Some(order.id.into()),
+            ..Default::default()
+        };
+        let mut events = core
+            .run(db_pool.run(move |conn| repos::order_event::make_su_repo().delete(conn, events_filter)))
+            .unwrap();
+        events.sort_by_key(|event| event.id);
+
+        let event_types = events.iter().map(|event| event.event_type).collect::<Vec<_>>();
+        assert_eq!(event_types, vec![OrderEventType::StateChanged, OrderEventType::Deleted]);
+        assert_eq!(events[0].status, OrderEventStatus::Pending);
+        assert_eq!(
+            events[0].payload["state"],
+            serde_json::to_value(OrderState::PaymentAwaited).unwrap()
+        );
+    }
+
+    #[test]
+    fn orders_for_user_are_paginated_newest_first() {
+        let mut core = Core::new().expect("Unexpected error creating event loop core");
+        let db_pool = create_db_pool(&mut core);
+        let customer = UserId(7234214);
+
+        let mut order_ids = vec![];
+        for _ in 0..3 {
+            let order = core
+                .run(db_pool.run(move |conn| {
+                    repos::order::make_su_repo().insert_exactly_one(
+                        conn,
+                        OrderInserter {
+                            customer,
+                            ..order_fixture()
+                        },
+                    )
+                }))
+                .unwrap();
+            order_ids.push(order.0.id);
+        }
+        order_ids.reverse();
+
+        let service = OrderServiceImpl::new(db_pool.clone(), super_user());
+        let first_page = core
+            .run(service.get_orders_for_user(
+                customer,
+                OrderPageRequest {
+                    limit: Some(2),
+                    after: None,
+                },
+            ))
+            .unwrap();
+        assert_eq!(
+            first_page.orders.iter().map(|order| order.id).collect::<Vec<_>>(),
+            order_ids[..2].to_vec()
+        );
+        assert!(first_page.next_cursor.is_some());
+
+        let second_page = core
+            .run(service.get_orders_for_user(
+                customer,
+                OrderPageRequest {
+                    limit: Some(2),
+                    after: first_page.next_cursor,
+                },
+            ))
+            .unwrap();
+        assert_eq!(
+            second_page.orders.iter().map(|order| order.id).collect::<Vec<_>>(),
+            order_ids[2..].to_vec()
+        );
+        assert_eq!(second_page.next_cursor, None);
+
+        for order_id in order_ids {
+            core.run(service.delete_order(OrderIdentifier::Id(order_id))).unwrap();
+        }
+    }
+
+    #[test]
+    fn search_filters_and_sorts_orders() {
+        let mut core = Core::new().expect("Unexpected error creating event loop core");
+        let db_pool = create_db_pool(&mut core);
+        let store = StoreId(7234215);
+
+        let mut order_ids = vec![];
+        for (total_amount, state) in vec![
+            (300.0, OrderState::Sent),
+            (100.0, OrderState::Paid),
+            (200.0, OrderState::Sent),
+            (50.0, OrderState::Cancelled),
+        ] {
+            let order = core
+                .run(db_pool.run(move |conn| {
+                    repos::order::make_su_repo().insert_exactly_one(
+                        conn,
+                        OrderInserter {
+                            store,
+                            state,
+                            total_amount: Money::from_f64(total_amount),
+                            ..order_fixture()
+                        },
+                    )
+                }))
+                .unwrap();
+            order_ids.push(order.0.id);
+        }
+
+        let service = OrderServiceImpl::new(db_pool.clone(), super_user());
+        let terms = || OrderSearchTerms {
+            store: Some(store),
+            ..Default::default()
+        };
+        let filters = OrderSearchFilters {
+            states: Some(vec![OrderState::Paid, OrderState::Sent]),
+            total_amount_to: Some(Money::from_f64(250.0)),
+            receiver_email: Some("arch@itect.com".to_string()),
+            ..Default::default()
+        };
+        let sort = OrderSort {
+            field: OrderSortField::TotalAmount,
+            direction: SortDirection::Asc,
+        };
+
+        let first_page = core
+            .run(service.search_page(
+                terms(),
+                filters.clone(),
+                sort,
+                OrderPageRequest {
+                    limit: Some(1),
+                    after: None,
+                },
+            ))
+            .unwrap();
+        assert_eq!(
+            first_page.orders.iter().map(|order| order.id).collect::<Vec<_>>(),
+            vec![order_ids[1]]
+        );
+
+        let second_page = core
+            .run(service.search_page(
+                terms(),
+                filters.clone(),
+                sort,
+                OrderPageRequest {
+                    limit: Some(1),
+                    after: first_page.next_cursor,
+                },
+            ))
+            .unwrap();
+        assert_eq!(
+            second_page.orders.iter().map(|order| order.id).collect::<Vec<_>>(),
+            vec![order_ids[2]]
+        );
+        assert_eq!(second_page.next_cursor, None);
+
+        let mismatched_sort = core.run(service.search_page(
+            terms(),
+            filters,
+            OrderSort::default(),
+            OrderPageRequest {
+                limit: Some(1),
+                after: first_page.next_cursor,
+            },
+        ));
+        assert!(mismatched_sort.is_err());
+
+        for order_id in order_ids {
+            core.run(service.delete_order(OrderIdentifier::Id(order_id))).unwrap();
+        }
+    }
+
+    #[test]
+    fn search_finds_orders_by_text() {
+        let mut core = Core::new().expect("Unexpected error creating event loop core");
+        let db_pool = create_db_pool(&mut core);
+        let store = StoreId(7234216);
+
+        let mut order_ids = vec![];
+        for (receiver_name, track_id) in vec![("John Smith", "1ZA8F3920396014562"), ("Jane Doe", "1ZB7E2810285903451")] {
+            let order = core
+                .run(db_pool.run(move |conn| {
+                    repos::order::make_su_repo().insert_exactly_one(
+                        conn,
+                        OrderInserter {
+                            store,
+                            receiver_name: receiver_name.to_string(),
+                            track_id: Some(track_id.to_string()),
+                            ..order_fixture()
+                        },
+                    )
+                }))
+                .unwrap();
+            order_ids.push(order.0.id);
+        }
+
+        let service = OrderServiceImpl::new(db_pool.clone(), super_user());
+        let search = |q: &str| {
+            let page = service.search_page(
+                OrderSearchTerms {
+                    store: Some(store),
+                    ..Default::default()
+                },
+                OrderSearchFilters {
+                    q: Some(q.to_string()),
+                    ..Default::default()
+                },
+                OrderSort::default(),
+                OrderPageRequest::default(),
+            );
+            page.map(|page| page.orders.into_iter().map(|order| order.id).collect::<Vec<_>>())
+        };
+
+        assert_eq!(core.run(search("John Smith 1za8")).unwrap(), vec![order_ids[0]]);
+        assert_eq!(core.run(search("1ZB7E2810285903451")).unwrap(), vec![order_ids[1]]);
+        assert_eq!(core.run(search("nobody")).unwrap(), vec![]);
+
+        // Found orders are paginated by the database
+        let search_page = |after| {
+            service.search_page(
+                OrderSearchTerms {
+                    store: Some(store),
+                    ..Default::default()
+                },
+                OrderSearchFilters {
+                    q: Some("1z".to_string()),
+                    ..Default::default()
+                },
+                OrderSort::default(),
+                OrderPageRequest { limit: Some(1), after },
+            )
+        };
+        let first_page = core.run(search_page(None)).unwrap();
+        assert_eq!(first_page.orders.len(), 1);
+        assert!(first_page.next_cursor.is_some());
+        let second_page = core.run(search_page(first_page.next_cursor)).unwrap();
+        assert_eq!(second_page.orders.len(), 1);
+        assert_ne!(second_page.orders[0].id, first_page.orders[0].id);
+
+        // Orders out of the caller's scope are not found
+        let stranger = OrderServiceImpl::new(
+            db_pool.clone(),
+            RepoLogin::User {
+                caller_id: UserId(7234217),
+                caller_roles: vec![],
+            },
+        );
+        let found = core
+            .run(stranger.search_page(
+                OrderSearchTerms::default(),
+                OrderSearchFilters {
+                    q: Some("John Smith".to_string()),
+                    ..Default::default()
+                },
+                OrderSort::default(),
+                OrderPageRequest::default(),
+            ))
+            .unwrap();
+        assert!(found.orders.iter().all(|order| !order_ids.contains(&order.id)));
+
+        for order_id in order_ids {
+            core.run(service.delete_order(OrderIdentifier::Id(order_id))).unwrap();
+        }
+    }
+
+    #[test]
+    fn search_filters_orders_by_product_of_any_line() {
+        let mut core = Core::new().expect("Unexpected error creating event loop core");
+        let db_pool = create_db_pool(&mut core);
+        let store = StoreId(7234217);
+
+        let mut new_order = NewOrder::new(
+            OrderInserter {
+                store,
+                product: ProductId(634827),
+                ..order_fixture()
+            },
+            "".to_string(),
+        );
+        new_order.add_line(
+            &OrderInserter {
+                store,
+                product: ProductId(634828),
+                ..order_fixture()
+            },
+            "".to_string(),
+        );
+        let order_id = new_order.order.id.unwrap();
+
+        let service = OrderServiceImpl::new(db_pool.clone(), super_user());
+        core.run(db_pool.run({
+            let order_repo_factory = service.order_repo_factory.clone();
+            let order_line_repo_factory = service.order_line_repo_factory.clone();
+            let order_tax_line_repo_factory = service.order_tax_line_repo_factory.clone();
+            let order_diff_repo_factory = service.order_diff_repo_factory.clone();
+            let order_event_repo_factory = service.order_event_repo_factory.clone();
+            move |conn| {
+                insert_new_order(
+                    conn,
+                    order_repo_factory,
+                    order_line_repo_factory,
+                    order_tax_line_repo_factory,
+                    order_diff_repo_factory,
+                    order_event_repo_factory,
+                    UserId(1),
+                    new_order,
+                )
+            }
+        }))
+        .unwrap();
+
+        let terms = || OrderSearchTerms {
+            store: Some(store),
+            ..Default::default()
+        };
+        let filters = |product| OrderSearchFilters {
+            product: Some(ProductId(product)),
+            ..Default::default()
+        };
+        for product in vec![634827, 634828] {
+            let found = core
+                .run(service.search_all(terms(), filters(product), OrderSort::default()))
+                .unwrap();
+            assert_eq!(found.iter().map(|order| order.id).collect::<Vec<_>>(), vec![order_id]);
+
+            let page = core
+                .run(service.search_page(terms(), filters(product), OrderSort::default(), OrderPageRequest::default()))
+                .unwrap();
+            assert_eq!(page.orders.iter().map(|order| order.id).collect::<Vec<_>>(), vec![order_id]);
+        }
+        let found = core
+            .run(service.search_all(terms(), filters(634829), OrderSort::default()))
+            .unwrap();
+        assert!(found.is_empty());
+
+        core.run(service.delete_order(OrderIdentifier::Id(order_id))).unwrap();
+    }
+
+    #[test]
+    fn cancelling_order_line_recalculates_order() {
+        let mut core = Core::new().expect("Unexpected error creating event loop core");
+        let db_pool = create_db_pool(&mut core);
+
+        let mut new_order = NewOrder::new(order_fixture(), "Gift wrap".to_string());
+        new_order.add_line(
+            &OrderInserter {
+                product: ProductId(634826),
+                price: Money::from_f64(50.0),
+                total_amount: Money::from_f64(50.0),
+                ..order_fixture()
+            },
+            "".to_string(),
+        );
+        let order_id = new_order.order.id.unwrap();
+
+        let service = OrderServiceImpl::new(db_pool.clone(), super_user());
+        let order = core
+            .run(db_pool.run({
+                let order_repo_factory = service.order_repo_factory.clone();
+                let order_line_repo_factory = service.order_line_repo_factory.clone();
+                let order_tax_line_repo_factory = service.order_tax_line_repo_factory.clone();
+                let order_diff_repo_factory = service.order_diff_repo_factory.clone();
+                let order_event_repo_factory = service.order_event_repo_factory.clone();
+                move |conn| {
+                    insert_new_order(
+                        conn,
+                        order_repo_factory,
+                        order_line_repo_factory,
+                        order_tax_line_repo_factory,
+                        order_diff_repo_factory,
+                        order_event_repo_factory,
+                        UserId(1),
+                        new_order,
+                    )
+                }
+            }))
+            .unwrap();
+        assert_eq!(Money::from(order.total_amount), Money::from_f64(150.0));
+        assert_eq!(order.product, ProductId(634825));
+
+        let lines = core.run(service.get_order_lines(order_id)).unwrap();
+        assert_eq!(
+            lines.iter().map(|line| line.product).collect::<Vec<_>>(),
+            vec![ProductId(634825), ProductId(634826)]
+        );
+
+        let order = core.run(service.cancel_order_line(order_id, lines[0].id, None)).unwrap().unwrap();
+        assert_eq!(Money::from(order.total_amount), Money::from_f64(50.0));
+        assert_eq!(order.product, ProductId(634826));
+
+        // The last line can only be cancelled along with the order
+        let res = core.run(service.cancel_order_line(order_id, lines[1].id, None));
+        assert!(res.is_err());
+
+        let lines = core.run(service.get_order_lines(order_id)).unwrap();
+        assert!(lines[0].cancelled_at.is_some());
+        assert_eq!(lines[1].cancelled_at, None);
+
+        core.run(service.delete_order(OrderIdentifier::Id(order_id))).unwrap();
+    }
+
     #[test]
     fn correctly_calculates_total_amount() {
-        let no_discount = calculate_total_amount(Quantity(2), ProductPrice(100.0), None, None, 0.0);
-        assert_eq!(no_discount.total_amount, ProductPrice(200.0));
+        let money = |v: &str| v.parse::<Money>().unwrap();
+        let calculate = |price: &str, discount, delivery: &str| {
+            calculate_total_amount(
+                Quantity(2),
+                Currency::RUB,
+                money(price),
+                discount,
+                money(delivery),
+                DeliveryPricing::PerUnit,
+            )
+        };
+
+        let no_discount = calculate("100", None, "0");
+        assert_eq!(no_discount.total_amount, money("200"));
         assert_eq!(no_discount.product_discount, None);
-        assert_eq!(no_discount.coupon_discount, None);
 
-        let no_discount_with_delivery = calculate_total_amount(Quantity(2), ProductPrice(100.0), None, None, 100.0);
-        assert_eq!(no_discount_with_delivery.total_amount, ProductPrice(400.0));
+        let no_discount_with_delivery = calculate("100", None, "100");
+        assert_eq!(no_discount_with_delivery.total_amount, money("400"));
         assert_eq!(no_discount_with_delivery.product_discount, None);
-        assert_eq!(no_discount_with_delivery.coupon_discount, None);
-
-        let product_discount = calculate_total_amount(Quantity(2), ProductPrice(100.0), Some(0.2), None, 0.0);
-        assert_eq!(product_discount.total_amount, ProductPrice(160.0));
-        assert_eq!(product_discount.product_discount, Some(ProductPrice(20.0)));
-        assert_eq!(product_discount.coupon_discount, None);
-
-        let coupon_discount = calculate_total_amount(Quantity(2), ProductPrice(100.0), None, Some(30), 0.0);
-        assert_eq!(coupon_discount.total_amount, ProductPrice(170.0));
-        assert_eq!(coupon_discount.product_discount, None);
-        assert_eq!(coupon_discount.coupon_discount, Some(ProductPrice(30.0)));
-
-        let product_and_coupon_discount = calculate_total_amount(Quantity(2), ProductPrice(100.0), Some(0.2), Some(25), 0.0);
-        assert_eq!(product_and_coupon_discount.total_amount, ProductPrice(160.0));
-        assert_eq!(product_and_coupon_discount.product_discount, Some(ProductPrice(20.0)));
-        assert_eq!(product_and_coupon_discount.coupon_discount, None);
-
-        let product_and_coupon_discount_with_delivery =
-            calculate_total_amount(Quantity(2), ProductPrice(100.0), Some(0.2), Some(25), 100.0);
-        assert_eq!(product_and_coupon_discount_with_delivery.total_amount, ProductPrice(360.0));
-        assert_eq!(product_and_coupon_discount_with_delivery.product_discount, Some(ProductPrice(20.0)));
-        assert_eq!(product_and_coupon_discount_with_delivery.coupon_discount, None);
+
+        let product_discount = calculate("100", Some(0.2), "0");
+        assert_eq!(product_discount.total_amount, money("160"));
+        assert_eq!(product_discount.product_discount, Some(money("20")));
+
+        let product_discount_with_delivery = calculate("100", Some(0.2), "100");
+        assert_eq!(product_discount_with_delivery.total_amount, money("360"));
+        assert_eq!(product_discount_with_delivery.product_discount, Some(money("20")));
+
+        let rounded_discount = calculate("0.1", Some(0.15), "0.105");
+        assert_eq!(rounded_discount.product_discount, Some(money("0.02")));
+        assert_eq!(rounded_discount.total_amount, money("0.38"));
+
+        let negligible_discount = calculate("0.1", Some(0.00001), "0");
+        assert_eq!(negligible_discount.product_discount, None);
+        assert_eq!(negligible_discount.total_amount, money("0.2"));
+    }
+
+    #[test]
+    fn allocates_coupon_discounts_by_scope() {
+        let money = |v: &str| v.parse::<Money>().unwrap();
+        let rule = |coupon_id, scope, fixed_amount: Option<&str>| CouponRule {
+            coupon_id: CouponId(coupon_id),
+            scope,
+            fixed_amount: fixed_amount.map(money),
+            currency: fixed_amount.map(|_| Currency::RUB),
+            created_at: Utc::now(),
+        };
+        let rules = CouponRules(vec![
+            rule(2, CouponScope::Product, None),
+            rule(3, CouponScope::Store, Some("30")),
+            rule(4, CouponScope::Cart, None),
+        ]);
+        let line = |store, price: &str, product_discount: Option<&str>, coupon: Option<(i32, i32)>| {
+            let total_amount = (money(price) - product_discount.map(money).unwrap_or_default()) * 2;
+            OrderInserter {
+                store: StoreId(store),
+                quantity: Quantity(2),
+                price: money(price),
+                product_discount: product_discount.map(money),
+                coupon_id: coupon.map(|(id, _)| CouponId(id)),
+                coupon_percent: coupon.map(|(_, percent)| percent),
+                total_amount,
+                ..order_fixture()
+            }
+        };
+        let totals = |new_orders: &[NewOrder]| {
+            new_orders
+                .iter()
+                .map(|new_order| new_order.lines.iter().map(|line| line.total_amount).collect::<Vec<_>>())
+                .collect::<Vec<_>>()
+        };
+
+        // Coupons without rules discount one unit, products sold at a discount get no coupon discount
+        let mut new_orders = vec![NewOrder::new(line(1, "100", None, Some((1, 30))), String::new())];
+        new_orders[0].add_line(&line(1, "100", Some("20"), Some((1, 25))), String::new());
+        new_orders[0].add_line(&line(1, "0.1", None, Some((1, 10))), String::new());
+        apply_coupons(&mut new_orders, &rules);
+        assert_eq!(totals(&new_orders), vec![vec![money("170"), money("160"), money("0.19")]]);
+        assert_eq!(new_orders[0].lines[0].coupon_discount, Some(money("30")));
+        assert_eq!(new_orders[0].lines[1].coupon_discount, None);
+        assert_eq!(new_orders[0].order.coupon_discount, Some(money("30")));
+        assert_eq!(new_orders[0].order.total_amount, money("330.19"));
+
+        // Product coupons discount all units
+        let mut new_orders = vec![NewOrder::new(line(1, "100", None, Some((2, 30))), String::new())];
+        apply_coupons(&mut new_orders, &rules);
+        assert_eq!(totals(&new_orders), vec![vec![money("140")]]);
+
+        // Store coupons spread over the products of the store without coupons of their own
+        let mut new_orders = vec![NewOrder::new(line(1, "50", None, None), String::new())];
+        new_orders[0].add_line(&line(1, "100", None, Some((3, 0))), String::new());
+        new_orders[0].add_line(&line(1, "100", None, Some((1, 10))), String::new());
+        new_orders.push(NewOrder::new(line(2, "100", None, None), String::new()));
+        apply_coupons(&mut new_orders, &rules);
+        assert_eq!(
+            totals(&new_orders),
+            vec![vec![money("90"), money("180"), money("190")], vec![money("200")]]
+        );
+        assert_eq!(new_orders[0].lines[0].coupon_id, Some(CouponId(3)));
+        assert_eq!(new_orders[0].order.total_amount, money("460"));
+
+        // Cart coupons spread over all stores
+        let mut new_orders = vec![NewOrder::new(line(1, "100", None, Some((4, 10))), String::new())];
+        new_orders.push(NewOrder::new(line(2, "50", None, None), String::new()));
+        apply_coupons(&mut new_orders, &rules);
+        assert_eq!(totals(&new_orders), vec![vec![money("180")], vec![money("90")]]);
+        assert_eq!(new_orders[1].lines[0].coupon_discount, Some(money("10")));
+    }
+
+    #[test]
+    fn charges_delivery_once_per_package() {
+        let money = |v: &str| v.parse::<Money>().unwrap();
+
+        let per_package = calculate_total_amount(
+            Quantity(2),
+            Currency::RUB,
+            money("100"),
+            Some(0.2),
+            money("100"),
+            DeliveryPricing::PerPackage,
+        );
+        assert_eq!(per_package.total_amount, money("260"));
+
+        let line = |product, company_package_id| OrderInserter {
+            product: ProductId(product),
+            quantity: Quantity(2),
+            total_amount: money("300"),
+            company_package_id,
+            delivery_price: money("100"),
+            delivery_pricing: DeliveryPricing::PerPackage,
+            ..order_fixture()
+        };
+        let mut new_order = NewOrder::new(line(1, Some(CompanyPackageId(1))), String::new());
+        new_order.add_line(&line(2, Some(CompanyPackageId(1))), String::new());
+        new_order.add_line(&line(3, Some(CompanyPackageId(2))), String::new());
+
+        let totals = new_order.lines.iter().map(|line| line.total_amount).collect::<Vec<_>>();
+        assert_eq!(totals, vec![money("300"), money("200"), money("300")]);
+        assert_eq!(new_order.order.total_amount, money("800"));
+    }
+
+    #[test]
+    fn quote_breaks_down_order_amounts() {
+        let money = |v: &str| v.parse::<Money>().unwrap();
+
+        let line = |product, product_discount: Option<&str>, total_amount: &str| OrderInserter {
+            product: ProductId(product),
+            quantity: Quantity(2),
+            price: money("100"),
+            product_discount: product_discount.map(money),
+            total_amount: money(total_amount),
+            company_package_id: Some(CompanyPackageId(1)),
+            delivery_price: money("10"),
+            delivery_pricing: DeliveryPricing::PerPackage,
+            product_cashback: Some(CashbackPercent(0.1)),
+            ..order_fixture()
+        };
+        let mut new_order = NewOrder::new(line(1, None, "210"), String::new());
+        new_order.add_line(&line(2, Some("20"), "170"), String::new());
+
+        let quote = new_order.quote();
+        assert_eq!(quote.subtotal, money("400"));
+        assert_eq!(quote.product_discount, money("40"));
+        assert_eq!(quote.coupon_discount, Money::zero());
+        assert_eq!(quote.delivery_amount, money("10"));
+        assert_eq!(quote.cashback, money("36"));
+        assert_eq!(quote.total_amount, money("370"));
+
+        let delivery = quote.items.iter().map(|item| item.delivery_amount).collect::<Vec<_>>();
+        assert_eq!(delivery, vec![money("10"), Money::zero()]);
+        let totals = quote.items.iter().map(|item| item.total_amount).collect::<Vec<_>>();
+        assert_eq!(totals, vec![money("210"), money("160")]);
+    }
+
+    #[test]
+    fn exclusive_taxes_are_added_to_totals() {
+        let money = |v: &str| v.parse::<Money>().unwrap();
+        let rule = |name: &str, rate, inclusive| TaxRule {
+            id: TaxRuleId(1),
+            country: "Canada".to_string(),
+            region: None,
+            tax_category: TaxCategory::default(),
+            name: name.to_string(),
+            rate,
+            inclusive,
+            created_at: Utc::now(),
+        };
+        let settings = TaxSettings {
+            rules: vec![rule("GST", 0.05, false), rule("VAT", 0.2, true)],
+            ..Default::default()
+        };
+
+        let mut new_order = NewOrder::new(
+            OrderInserter {
+                address: AddressFull {
+                    country: Some("Canada".to_string()),
+                    ..Default::default()
+                },
+                quantity: Quantity(1),
+                total_amount: money("130"),
+                delivery_price: money("10"),
+                ..order_fixture()
+            },
+            String::new(),
+        );
+        new_order.apply_taxes(&settings);
+
+        // Taxes are calculated from 120 without delivery, the net price is 100
+        let taxes = new_order.taxes.iter().map(|tax| tax.tax.tax_amount).collect::<Vec<_>>();
+        assert_eq!(taxes, vec![money("5"), money("20")]);
+        assert_eq!(new_order.lines[0].tax_amount, money("25"));
+        assert_eq!(new_order.lines[0].total_amount, money("135"));
+        assert_eq!(new_order.order.tax_amount, money("25"));
+        assert_eq!(new_order.order.total_amount, money("135"));
     }
 }
@@ -0,0 +1,523 @@
+use std::cmp;
+
+use failure::Error as FailureError;
+use futures::future;
+use futures::prelude::*;
+
+use super::calling_user;
+use super::order::committer_role_of;
+use super::refund::make_refund;
+use super::types::ServiceFuture;
+use errors::*;
+use models::*;
+use repos;
+use repos::*;
+use types::*;
+
+use stq_db::repo::*;
+use stq_static_resources::CommitterRole;
+use stq_types::{OrderId, Quantity, UserId};
+
+/// Service that handles returns of delivered products
+pub trait OrderReturnService {
+    /// Opens the return of some units of the order on behalf of its customer, `None` if the order is not found
+    fn create_return(&self, order_id: OrderId, payload: NewOrderReturnPayload) -> ServiceFuture<Option<OrderReturn>>;
+    /// Returns all returns of the order, oldest first
+    fn get_returns(&self, order_id: OrderId) -> ServiceFuture<Vec<OrderReturn>>;
+    fn get_return(&self, return_id: OrderReturnId) -> ServiceFuture<Option<OrderReturn>>;
+    /// Moves the return to the next state, the returned units are refunded once the products are received
+    fn set_return_state(&self, return_id: OrderReturnId, payload: SetReturnStatePayload) -> ServiceFuture<Option<OrderReturn>>;
+    /// Returns state changes of the return, oldest first
+    fn get_return_diffs(&self, return_id: OrderReturnId) -> ServiceFuture<Vec<OrderReturnDiff>>;
+}
+
+pub struct OrderReturnServiceImpl {
+    db_pool: DbPool,
+    login_data: UserLogin,
+}
+
+impl OrderReturnServiceImpl {
+    pub fn new(db_pool: DbPool, login_data: UserLogin) -> Self {
+        Self { db_pool, login_data }
+    }
+}
+
+impl OrderReturnService for OrderReturnServiceImpl {
+    fn create_return(&self, order_id: OrderId, payload: NewOrderReturnPayload) -> ServiceFuture<Option<OrderReturn>> {
+        if let Err(e) = payload.validate() {
+            return Box::new(future::err(e.context(Error::ParseError).into()));
+        }
+
+        let login_data = self.login_data.clone();
+        let calling_user = calling_user(&self.login_data);
+
+        Box::new(self.db_pool.run(move |conn| {
+            run_in_transaction(conn, move |conn| {
+                repos::order::make_repo(login_data.clone())
+                    .select(
+                        conn,
+                        OrderFilter {
+                            id: Some(order_id.into()),
+                            ..Default::default()
+                        },
+                    )
+                    .map(|(mut orders, conn)| (orders.pop(), conn))
+                    .and_then(move |(order, conn)| -> RepoConnectionFuture<Option<OrderReturn>> {
+                        let order = match order {
+                            Some(order) => order,
+                            None => return Box::new(future::ok((None, conn))),
+                        };
+                        if let Err(e) = ensure_may_open(&login_data, &order) {
+                            return Box::new(future::err((e, conn)));
+                        }
+                        if !is_returnable(order.0.state) {
+                            return Box::new(future::err((
+                                format_err!("Order {} in state {} can not be returned", order_id, order.0.state)
+                                    .context(Error::ForbiddenStateTransition)
+                                    .into(),
+                                conn,
+                            )));
+                        }
+
+                        Box::new(
+                            select_returnable_quantity(conn, login_data.clone(), order_id, payload.order_line_id)
+                                .and_then(move |(returnable_quantity, conn)| -> RepoConnectionFuture<OrderReturn> {
+                                    if payload.quantity.0 > returnable_quantity.0 {
+                                        return Box::new(future::err((
+                                            format_err!(
+                                                "Only {} units of line {} of order {} may be returned, got {}",
+                                                returnable_quantity.0,
+                                                payload.order_line_id,
+                                                order_id,
+                                                payload.quantity.0
+                                            )
+                                            .context(Error::ParseError)
+                                            .into(),
+                                            conn,
+                                        )));
+                                    }
+
+                                    let inserter = OrderReturnInserter {
+                                        id: OrderReturnId::new(),
+                                        order_id,
+                                        order_line_id: payload.order_line_id,
+                                        customer: order.0.customer,
+                                        store: order.0.store,
+                                        quantity: payload.quantity,
+                                        reason: payload.reason,
+                                        photos: payload.photos,
+                                    };
+                                    repos::order_return::make_repo(login_data).insert_exactly_one(conn, inserter)
+                                })
+                                .and_then(move |(order_return, conn)| {
+                                    repos::order_return_diff::make_su_repo()
+                                        .insert_exactly_one(
+                                            conn,
+                                            OrderReturnDiffInserter {
+                                                parent: order_return.id,
+                                                committer: calling_user,
+                                                committer_role: CommitterRole::Customer,
+                                                state: order_return.state,
+                                                comment: Some(order_return.reason.clone()),
+                                            },
+                                        )
+                                        .map(move |(_, conn)| (Some(order_return), conn))
+                                }),
+                        )
+                    })
+            })
+        }))
+    }
+
+    fn get_returns(&self, order_id: OrderId) -> ServiceFuture<Vec<OrderReturn>> {
+        let login_data = self.login_data.clone();
+
+        Box::new(self.db_pool.run(move |conn| {
+            repos::order_return::make_repo(login_data).select(
+                conn,
+                OrderReturnFilter {
+                    order_id: Some(order_id.into()),
+                    ..Default::default()
+                }
+                .with_ordering(true),
+            )
+        }))
+    }
+
+    fn get_return(&self, return_id: OrderReturnId) -> ServiceFuture<Option<OrderReturn>> {
+        let login_data = self.login_data.clone();
+
+        Box::new(self.db_pool.run(move |conn| {
+            repos::order_return::make_repo(login_data)
+                .select(
+                    conn,
+                    OrderReturnFilter {
+                        id: Some(return_id.into()),
+                        ..Default::default()
+                    },
+                )
+                .map(|(mut returns, conn)| (returns.pop(), conn))
+        }))
+    }
+
+    fn set_return_state(&self, return_id: OrderReturnId, payload: SetReturnStatePayload) -> ServiceFuture<Option<OrderReturn>> {
+        let login_data = self.login_data.clone();
+        let calling_user = calling_user(&self.login_data);
+
+        Box::new(self.db_pool.run(move |conn| {
+            run_in_transaction(conn, move |conn| {
+                repos::order_return::make_repo(login_data.clone())
+                    .select(
+                        conn,
+                        OrderReturnFilter {
+                            id: Some(return_id.into()),
+                            ..Default::default()
+                        },
+                    )
+                    .map(|(mut returns, conn)| (returns.pop(), conn))
+                    .and_then(
+                        move |(current_return, conn)| -> RepoConnectionFuture<Option<(OrderReturn, DbOrder)>> {
+                            let current_return = match current_return {
+                                Some(current_return) => current_return,
+                                None => return Box::new(future::ok((None, conn))),
+                            };
+                            // The caller may see the return, so the order is selected to derive the caller's role only
+                            Box::new(
+                                repos::order::make_su_repo()
+                                    .select(
+                                        conn,
+                                        OrderFilter {
+                                            id: Some(current_return.order_id.into()),
+                                            ..Default::default()
+                                        },
+                                    )
+                                    .map(move |(mut orders, conn)| (orders.pop().map(|order| (current_return, order)), conn)),
+                            )
+                        },
+                    )
+                    .and_then(move |(current, conn)| -> RepoConnectionFuture<Option<OrderReturn>> {
+                        let (current_return, order) = match current {
+                            Some(current) => current,
+                            None => return Box::new(future::ok((None, conn))),
+                        };
+                        let SetReturnStatePayload { state, track_id, comment } = payload;
+
+                        let committer_role = match committer_role_of(&login_data, &order.0) {
+                            Ok(committer_role) => committer_role,
+                            Err(e) => return Box::new(future::err((e, conn))),
+                        };
+                        if !is_return_transition_allowed(current_return.state, state, committer_role) {
+                            // Transitions made by others are forbidden to the caller, the rest are not allowed at all
+                            let error = if allowed_return_committers(current_return.state, state).is_empty() {
+                                Error::ForbiddenStateTransition
+                            } else {
+                                Error::Forbidden
+                            };
+                            return Box::new(future::err((
+                                format_err!(
+                                    "Return {} can not be moved from {} to {} by {:?}",
+                                    return_id,
+                                    current_return.state,
+                                    state,
+                                    committer_role
+                                )
+                                .context(error)
+                                .into(),
+                                conn,
+                            )));
+                        }
+                        if state == ReturnState::Shipped && track_id.is_none() {
+                            return Box::new(future::err((
+                                format_err!("Track id is required to ship return {}", return_id)
+                                    .context(Error::ParseError)
+                                    .into(),
+                                conn,
+                            )));
+                        }
+
+                        Box::new(
+                            repos::order_return::make_repo(login_data)
+                                .update(
+                                    conn,
+                                    OrderReturnUpdater {
+                                        // The return must not change since it has been selected
+                                        mask: OrderReturnFilter {
+                                            id: Some(return_id.into()),
+                                            state: Some(current_return.state.into()),
+                                            ..Default::default()
+                                        },
+                                        data: OrderReturnUpdateData {
+                                            state: Some(state),
+                                            track_id: track_id.map(Some),
+                                        },
+                                    },
+                                )
+                                .and_then(move |(mut updated_returns, conn)| match updated_returns.pop() {
+                                    Some(order_return) => Ok((order_return, conn)),
+                                    None => Err((
+                                        format_err!("Return {} was modified concurrently", return_id)
+                                            .context(Error::OrderConflict)
+                                            .into(),
+                                        conn,
+                                    )),
+                                })
+                                .and_then(move |(order_return, conn)| {
+                                    repos::order_return_diff::make_su_repo()
+                                        .insert_exactly_one(
+                                            conn,
+                                            OrderReturnDiffInserter {
+                                                parent: return_id,
+                                                committer: calling_user,
+                                                committer_role,
+                                                state,
+                                                comment,
+                                            },
+                                        )
+                                        .map(move |(_, conn)| (order_return, conn))
+                                })
+                                // Customer has to be refunded for the received products.
+                                // The refund is a consequence of the allowed transition, so caller's ACL is not applied
+                                .and_then(move |(order_return, conn)| -> RepoConnectionFuture<Option<OrderReturn>> {
+                                    if order_return.state == ReturnState::Received {
+                                        Box::new(
+                                            refund_return(conn, order_return.clone(), calling_user, committer_role)
+                                                .map(move |(_, conn)| (Some(order_return), conn)),
+                                        )
+                                    } else {
+                                        Box::new(future::ok((Some(order_return), conn)))
+                                    }
+                                }),
+                        )
+                    })
+            })
+        }))
+    }
+
+    fn get_return_diffs(&self, return_id: OrderReturnId) -> ServiceFuture<Vec<OrderReturnDiff>> {
+        let login_data = self.login_data.clone();
+
+        Box::new(self.db_pool.run(move |conn| {
+            // History is visible to whoever can see the return itself
+            repos::order_return::make_repo(login_data)
+                .select(
+                    conn,
+                    OrderReturnFilter {
+                        id: Some(return_id.into()),
+                        ..Default::default()
+                    },
+                )
+                .and_then(move |(returns, conn)| -> RepoConnectionFuture<Vec<OrderReturnDiff>> {
+                    if returns.is_empty() {
+                        return Box::new(future::ok((vec![], conn)));
+                    }
+
+                    repos::order_return_diff::make_su_repo().select(
+                        conn,
+                        OrderReturnDiffFilter {
+                            parent: Some(return_id.into()),
+                        },
+                    )
+                })
+        }))
+    }
+}
+
+/// Returns are opened by customers of the orders, superadmins may open them on their behalf
+fn ensure_may_open(login_data: &UserLogin, order: &DbOrder) -> Result<(), FailureError> {
+    if let RepoLogin::User {
+        caller_id,
+        ref caller_roles,
+    } = *login_data
+    {
+        if caller_id == order.0.customer || caller_roles.iter().any(|role_entry| role_entry.role == UserRole::Superadmin) {
+            return Ok(());
+        }
+    }
+
+    Err(format_err!("Returns of order {} are opened by its customer only", order.0.id)
+        .context(Error::Forbidden)
+        .into())
+}
+
+/// Units of the active line that are not returned yet, rejected returns do not count.
+/// Returns opened before returns were made per line count against every line of the order.
+fn select_returnable_quantity(
+    conn: RepoConnection,
+    login_data: UserLogin,
+    order_id: OrderId,
+    order_line_id: OrderLineId,
+) -> RepoConnectionFuture<Quantity> {
+    Box::new(
+        repos::order_line::make_repo(login_data.clone())
+            .select(conn, OrderLineFilter::active(order_id))
+            .and_then(move |(lines, conn)| -> RepoConnectionFuture<Quantity> {
+                let ordered = match lines.iter().find(|line| line.id == order_line_id) {
+                    Some(line) => line.quantity.0,
+                    None => {
+                        return Box::new(future::err((
+                            format_err!("Line {} is not an active line of order {}", order_line_id, order_id)
+                                .context(Error::ParseError)
+                                .into(),
+                            conn,
+                        )))
+                    }
+                };
+
+                Box::new(
+                    repos::order_return::make_repo(login_data)
+                        .select(
+                            conn,
+                            OrderReturnFilter {
+                                order_id: Some(order_id.into()),
+                                ..Default::default()
+                            },
+                        )
+                        .map(move |(returns, conn)| {
+                            let returned = returns
+                                .iter()
+                                .filter(|order_return| order_return.state != ReturnState::Rejected)
+                                .filter(|order_return| order_return.order_line_id.map_or(true, |line_id| line_id == order_line_id))
+                                .fold(0, |sum, order_return| sum + order_return.quantity.0);
+                            (Quantity(ordered - returned), conn)
+                        }),
+                )
+            }),
+    )
+}
+
+/// Refunds the amount paid for the returned units, as much of it as is not refunded yet
+fn refund_return(
+    conn: RepoConnection,
+    order_return: OrderReturn,
+    committer: UserId,
+    committer_role: CommitterRole,
+) -> RepoConnectionFuture<Option<Refund>> {
+    let order_id = order_return.order_id;
+    let return_id = order_return.id;
+
+    Box::new(
+        repos::order::make_su_repo()
+            .select(
+                conn,
+                OrderFilter {
+                    id: Some(order_id.into()),
+                    ..Default::default()
+                },
+            )
+            .and_then(move |(mut orders, conn)| match orders.pop() {
+                Some(order) => Ok((order, conn)),
+                None => Err((format_err!("Order {} of return {} not found", order_id, return_id), conn)),
+            })
+            .and_then(move |(order, conn)| {
+                repos::order_line::make_su_repo()
+                    .select(conn, OrderLineFilter::active(order_id))
+                    .and_then(move |(lines, conn)| -> RepoConnectionFuture<Option<Refund>> {
+                        // Returns opened before returns were made per line are refunded from all active lines
+                        let returned_lines = lines
+                            .iter()
+                            .filter(|line| order_return.order_line_id.map_or(true, |line_id| line.id == line_id))
+                            .collect::<Vec<_>>();
+                        let amount = cmp::min(
+                            returned_units_amount(&returned_lines, &lines, order_return.quantity, order.0.currency),
+                            refundable_amount(&order),
+                        );
+                        if amount.is_zero() {
+                            return Box::new(future::ok((None, conn)));
+                        }
+
+                        let reason = format!("Return {}: {}", order_return.id, order_return.reason);
+                        Box::new(
+                            make_refund(conn, order, Some(order_return.id), amount, reason, committer, committer_role)
+                                .map(|(refund, conn)| (Some(refund), conn)),
+                        )
+                    })
+            }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use failure::Context;
+    use tokio_core::reactor::Core;
+
+    use stq_static_resources::OrderState;
+    use stq_types::OrderIdentifier;
+
+    use super::*;
+    use services::{OrderService, OrderServiceImpl};
+    use test_utils::*;
+
+    fn is_forbidden(e: &FailureError) -> bool {
+        e.causes().any(
+            |cause| match cause.downcast_ref::<Context<Error>>().map(|context| context.get_context()) {
+                Some(&Error::Forbidden) => true,
+                _ => false,
+            },
+        )
+    }
+
+    #[test]
+    fn customer_does_not_approve_own_return() {
+        let mut core = Core::new().expect("Unexpected error creating event loop core");
+        let db_pool = create_db_pool(&mut core);
+
+        let fixture = OrderInserter {
+            state: OrderState::Delivered,
+            ..order_fixture()
+        };
+        let customer = fixture.customer;
+        let order = core
+            .run(db_pool.run({
+                let fixture = fixture.clone();
+                move |conn| repos::order::make_su_repo().insert_exactly_one(conn, fixture)
+            }))
+            .unwrap()
+            .0;
+        let order_id = order.id;
+        let line = core
+            .run(db_pool.run(move |conn| {
+                repos::order_line::make_su_repo()
+                    .insert_exactly_one(conn, OrderLineInserter::from_order(order_id, 0, &fixture, String::new()))
+            }))
+            .unwrap()
+            .0;
+
+        let customer_login = RepoLogin::User {
+            caller_id: customer,
+            caller_roles: vec![],
+        };
+        let customer_service = OrderReturnServiceImpl::new(db_pool.clone(), customer_login);
+        let order_return = core
+            .run(customer_service.create_return(
+                order_id,
+                NewOrderReturnPayload {
+                    order_line_id: line.id,
+                    quantity: Quantity(1),
+                    reason: "Broken".to_string(),
+                    photos: vec![],
+                },
+            ))
+            .unwrap()
+            .unwrap();
+
+        let approve = SetReturnStatePayload {
+            state: ReturnState::Approved,
+            track_id: None,
+            comment: None,
+        };
+        let e = core
+            .run(customer_service.set_return_state(order_return.id, approve.clone()))
+            .unwrap_err();
+        assert!(is_forbidden(&e));
+
+        // Support approves it on behalf of the seller
+        let approved = core
+            .run(OrderReturnServiceImpl::new(db_pool.clone(), super_user()).set_return_state(order_return.id, approve))
+            .unwrap()
+            .unwrap();
+        assert_eq!(approved.state, ReturnState::Approved);
+
+        core.run(OrderServiceImpl::new(db_pool, super_user()).delete_order(OrderIdentifier::Id(order_id)))
+            .unwrap();
+    }
+}
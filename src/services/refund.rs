@@ -0,0 +1,289 @@
+use chrono::prelude::*;
+use failure::Error as FailureError;
+use futures::future;
+use futures::prelude::*;
+
+use super::calling_user;
+use super::order::committer_role_of;
+use super::types::ServiceFuture;
+use errors::*;
+use models::*;
+use repos;
+use repos::*;
+use types::*;
+
+use stq_db::repo::*;
+use stq_static_resources::{CommitterRole, OrderState};
+use stq_types::{OrderId, UserId};
+
+/// Service that handles refunds of orders
+pub trait RefundService {
+    /// Refunds a part of the order to its customer, `None` if the order is not found
+    fn create_refund(&self, order_id: OrderId, payload: NewRefundPayload) -> ServiceFuture<Option<Refund>>;
+    /// Returns all refunds of the order, oldest first
+    fn get_refunds(&self, order_id: OrderId) -> ServiceFuture<Vec<Refund>>;
+    /// Returns refunds made since the moment, oldest first
+    fn get_refunds_since(&self, since: DateTime<Utc>) -> ServiceFuture<Vec<Refund>>;
+}
+
+pub struct RefundServiceImpl {
+    db_pool: DbPool,
+    login_data: UserLogin,
+}
+
+impl RefundServiceImpl {
+    pub fn new(db_pool: DbPool, login_data: UserLogin) -> Self {
+        Self { db_pool, login_data }
+    }
+}
+
+impl RefundService for RefundServiceImpl {
+    fn create_refund(&self, order_id: OrderId, payload: NewRefundPayload) -> ServiceFuture<Option<Refund>> {
+        let login_data = self.login_data.clone();
+        let calling_user = calling_user(&self.login_data);
+
+        Box::new(self.db_pool.run(move |conn| {
+            run_in_transaction(conn, move |conn| {
+                repos::order::make_repo(login_data.clone())
+                    .select(
+                        conn,
+                        OrderFilter {
+                            id: Some(order_id.into()),
+                            ..Default::default()
+                        },
+                    )
+                    .map(|(mut orders, conn)| (orders.pop(), conn))
+                    .and_then(move |(order, conn)| -> RepoConnectionFuture<Option<Refund>> {
+                        let order = match order {
+                            Some(order) => order,
+                            None => return Box::new(future::ok((None, conn))),
+                        };
+                        if let Err(e) = payload.validate(order.0.currency) {
+                            return Box::new(future::err((e.context(Error::ParseError).into(), conn)));
+                        }
+                        let committer_role = match refund_committer_role_of(&login_data, &order) {
+                            Ok(committer_role) => committer_role,
+                            Err(e) => return Box::new(future::err((e, conn))),
+                        };
+
+                        Box::new(
+                            make_refund(conn, order, None, payload.amount, payload.reason, calling_user, committer_role)
+                                .map(|(refund, conn)| (Some(refund), conn)),
+                        )
+                    })
+            })
+        }))
+    }
+
+    fn get_refunds(&self, order_id: OrderId) -> ServiceFuture<Vec<Refund>> {
+        let login_data = self.login_data.clone();
+
+        Box::new(self.db_pool.run(move |conn| {
+            // Refunds are visible to whoever can see the order, even if nothing is refunded yet
+            repos::order::make_repo(login_data.clone())
+                .select(
+                    conn,
+                    OrderFilter {
+                        id: Some(order_id.into()),
+                        ..Default::default()
+                    },
+                )
+                .and_then(move |(_, conn)| {
+                    repos::refund::make_repo(login_data).select(
+                        conn,
+                        RefundFilter {
+                            order_id: Some(order_id.into()),
+                            ..Default::default()
+                        }
+                        .with_ordering(true),
+                    )
+                })
+        }))
+    }
+
+    fn get_refunds_since(&self, since: DateTime<Utc>) -> ServiceFuture<Vec<Refund>> {
+        let login_data = self.login_data.clone();
+
+        Box::new(self.db_pool.run(move |conn| {
+            repos::refund::make_repo(login_data).select(
+                conn,
+                RefundFilter {
+                    created_at: into_range(Some(since), None),
+                    ..Default::default()
+                }
+                .with_ordering(true),
+            )
+        }))
+    }
+}
+
+/// Refunds are made by the seller of the order or by the support on behalf of the system
+fn refund_committer_role_of(login_data: &UserLogin, order: &DbOrder) -> Result<CommitterRole, FailureError> {
+    match committer_role_of(login_data, &order.0)? {
+        CommitterRole::Customer => Err(format_err!("Refunds of order {} are made by its seller only", order.0.id)
+            .context(Error::Forbidden)
+            .into()),
+        committer_role => Ok(committer_role),
+    }
+}
+
+/// Records the refund, adds it to the refunded amount of the order and asks Saga to pay it back.
+/// Only paid orders that are not refunded as a whole may be refunded, refunds never exceed the paid amount in sum.
+/// Bypasses ACL, callers have to check that the committer may refund the order.
+pub fn make_refund(
+    conn: RepoConnection,
+    order: DbOrder,
+    return_id: Option<OrderReturnId>,
+    amount: Money,
+    reason: String,
+    committer: UserId,
+    committer_role: CommitterRole,
+) -> RepoConnectionFuture<Refund> {
+    let refundable = refundable_amount(&order);
+    let DbOrder(order, meta) = order;
+    let order_id = order.id;
+    let current_version = meta.version;
+
+    if meta.paid_amount.is_none() || order.state == OrderState::Refunded {
+        return Box::new(future::err((
+            format_err!("Order {} in state {} can not be refunded", order_id, order.state)
+                .context(Error::ForbiddenStateTransition)
+                .into(),
+            conn,
+        )));
+    }
+
+    if amount != amount.round_to(order.currency) {
+        return Box::new(future::err((
+            format_err!("Refund amount {} is not exact in minor units of {}", amount, order.currency)
+                .context(Error::ParseError)
+                .into(),
+            conn,
+        )));
+    }
+
+    if amount > refundable {
+        return Box::new(future::err((
+            format_err!(
+                "Only {} {} of order {} may be refunded, got {}",
+                refundable,
+                order.currency,
+                order_id,
+                amount
+            )
+            .context(Error::ParseError)
+            .into(),
+            conn,
+        )));
+    }
+
+    let inserter = RefundInserter {
+        id: RefundId::new(),
+        order_id,
+        customer: order.customer,
+        store: order.store,
+        return_id,
+        amount,
+        currency: order.currency,
+        reason,
+        committer,
+        committer_role,
+    };
+
+    Box::new(
+        repos::order::make_su_repo()
+            .update(
+                conn,
+                OrderUpdater {
+                    mask: OrderFilter {
+                        id: Some(order_id.into()),
+                        version: Some(current_version.into()),
+                        ..Default::default()
+                    },
+                    data: OrderUpdateData {
+                        refunded_amount: Some(meta.refunded_amount + amount),
+                        ..Default::default()
+                    },
+                },
+            )
+            .and_then(move |(updated_orders, conn)| {
+                if updated_orders.is_empty() {
+                    Err((
+                        format_err!("Order {} was modified concurrently, version {} is stale", order_id, current_version)
+                            .context(Error::OrderConflict)
+                            .into(),
+                        conn,
+                    ))
+                } else {
+                    Ok(conn)
+                }
+            })
+            .and_then(move |conn| repos::refund::make_su_repo().insert_exactly_one(conn, inserter))
+            .and_then(move |(refund, conn)| {
+                repos::saga_notification::make_su_repo()
+                    .insert_exactly_one(conn, SagaNotificationInserter::refund(order_id, refund.id))
+                    .map(move |(_, conn)| (refund, conn))
+            }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_core::reactor::Core;
+
+    use stq_types::OrderIdentifier;
+
+    use super::*;
+    use services::{OrderService, OrderServiceImpl};
+    use test_utils::*;
+
+    #[test]
+    fn refunds_paid_orders_up_to_paid_amount() {
+        let mut core = Core::new().expect("Unexpected error creating event loop core");
+        let db_pool = create_db_pool(&mut core);
+
+        let order = core
+            .run(db_pool.run(|conn| repos::order::make_su_repo().insert_exactly_one(conn, order_fixture())))
+            .unwrap()
+            .0;
+        let order_id = order.id;
+
+        let service = RefundServiceImpl::new(db_pool.clone(), super_user());
+        let refund = |amount: f64| NewRefundPayload {
+            amount: Money::from_f64(amount),
+            reason: "Broken".to_string(),
+        };
+
+        // Order is not paid yet
+        assert!(core.run(service.create_refund(order_id, refund(10.0))).is_err());
+
+        core.run(db_pool.run(move |conn| {
+            repos::order::make_su_repo().update(
+                conn,
+                OrderUpdater {
+                    mask: OrderFilter {
+                        id: Some(order_id.into()),
+                        ..Default::default()
+                    },
+                    data: OrderUpdateData {
+                        state: Some(OrderState::Paid),
+                        ..Default::default()
+                    },
+                },
+            )
+        }))
+        .unwrap();
+
+        assert!(core.run(service.create_refund(order_id, refund(60.0))).unwrap().is_some());
+        assert!(core.run(service.create_refund(order_id, refund(60.0))).is_err());
+        assert!(core.run(service.create_refund(order_id, refund(40.0))).unwrap().is_some());
+        let refunds = core.run(service.get_refunds(order_id)).unwrap();
+        assert_eq!(
+            refunds.iter().fold(Money::zero(), |sum, refund| sum + refund.amount),
+            Money::from_f64(100.0)
+        );
+
+        core.run(OrderServiceImpl::new(db_pool, super_user()).delete_order(OrderIdentifier::Id(order_id)))
+            .unwrap();
+    }
+}
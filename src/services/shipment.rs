@@ -0,0 +1,312 @@
+use failure::Error as FailureError;
+use futures::future;
+use futures::prelude::*;
+
+use super::order::OrderServiceImpl;
+use super::types::ServiceFuture;
+use errors::*;
+use models::*;
+use repos;
+use repos::*;
+use types::*;
+
+use stq_db::repo::*;
+use stq_static_resources::{CommitterRole, OrderState};
+use stq_types::{OrderId, StoreId};
+
+/// Service that handles shipments of orders sent in several boxes
+pub trait ShipmentService {
+    /// Adds the shipment to the order on behalf of its seller, `None` if the order is not found
+    fn create_shipment(&self, order_id: OrderId, payload: NewShipmentPayload) -> ServiceFuture<Option<Shipment>>;
+    /// Returns all shipments of the order, oldest first
+    fn get_shipments(&self, order_id: OrderId) -> ServiceFuture<Vec<Shipment>>;
+    /// Updates the shipment, the sent order is delivered once its last shipment is delivered
+    fn update_shipment(&self, shipment_id: ShipmentId, payload: ShipmentUpdatePayload) -> ServiceFuture<Option<Shipment>>;
+}
+
+pub struct ShipmentServiceImpl {
+    db_pool: DbPool,
+    login_data: UserLogin,
+}
+
+impl ShipmentServiceImpl {
+    pub fn new(db_pool: DbPool, login_data: UserLogin) -> Self {
+        Self { db_pool, login_data }
+    }
+}
+
+impl ShipmentService for ShipmentServiceImpl {
+    fn create_shipment(&self, order_id: OrderId, payload: NewShipmentPayload) -> ServiceFuture<Option<Shipment>> {
+        if let Err(e) = payload.validate() {
+            return Box::new(future::err(e.context(Error::ParseError).into()));
+        }
+
+        let login_data = self.login_data.clone();
+
+        Box::new(self.db_pool.run(move |conn| {
+            run_in_transaction(conn, move |conn| {
+                repos::order::make_repo(login_data.clone())
+                    .select(
+                        conn,
+                        OrderFilter {
+                            id: Some(order_id.into()),
+                            ..Default::default()
+                        },
+                    )
+                    .map(|(mut orders, conn)| (orders.pop(), conn))
+                    .and_then(move |(order, conn)| -> RepoConnectionFuture<Option<Shipment>> {
+                        let order = match order {
+                            Some(order) => order,
+                            None => return Box::new(future::ok((None, conn))),
+                        };
+                        if let Err(e) = ensure_may_ship(&login_data, order.0.store) {
+                            return Box::new(future::err((e, conn)));
+                        }
+                        if !is_shippable(order.0.state) {
+                            return Box::new(future::err((
+                                format_err!("Order {} in state {} can not be shipped", order_id, order.0.state)
+                                    .context(Error::ForbiddenStateTransition)
+                                    .into(),
+                                conn,
+                            )));
+                        }
+
+                        let inserter = ShipmentInserter {
+                            id: ShipmentId::new(),
+                            order_id,
+                            customer: order.0.customer,
+                            store: order.0.store,
+                            carrier: payload.carrier,
+                            tracking_number: payload.tracking_number,
+                            lines: payload.lines,
+                        };
+                        Box::new(repos::shipment::make_repo(login_data).insert_exactly_one(conn, inserter).and_then(
+                            move |(shipment, conn)| ensure_quantity_shipped(conn, order_id).map(move |((), conn)| (Some(shipment), conn)),
+                        ))
+                    })
+            })
+        }))
+    }
+
+    fn get_shipments(&self, order_id: OrderId) -> ServiceFuture<Vec<Shipment>> {
+        let login_data = self.login_data.clone();
+
+        Box::new(self.db_pool.run(move |conn| {
+            repos::shipment::make_repo(login_data).select(
+                conn,
+                ShipmentFilter {
+                    order_id: Some(order_id.into()),
+                    ..Default::default()
+                }
+                .with_ordering(true),
+            )
+        }))
+    }
+
+    fn update_shipment(&self, shipment_id: ShipmentId, payload: ShipmentUpdatePayload) -> ServiceFuture<Option<Shipment>> {
+        if let Err(e) = payload.validate() {
+            return Box::new(future::err(e.context(Error::ParseError).into()));
+        }
+
+        let login_data = self.login_data.clone();
+        let order_service = OrderServiceImpl::new(self.db_pool.clone(), self.login_data.clone());
+
+        Box::new(self.db_pool.run(move |conn| {
+            run_in_transaction(conn, move |conn| {
+                repos::shipment::make_repo(login_data.clone())
+                    .select(
+                        conn,
+                        ShipmentFilter {
+                            id: Some(shipment_id.into()),
+                            ..Default::default()
+                        },
+                    )
+                    .map(|(mut shipments, conn)| (shipments.pop(), conn))
+                    .and_then(move |(shipment, conn)| -> RepoConnectionFuture<Option<Shipment>> {
+                        let shipment = match shipment {
+                            Some(shipment) => shipment,
+                            None => return Box::new(future::ok((None, conn))),
+                        };
+                        if let Err(e) = ensure_may_ship(&login_data, shipment.store) {
+                            return Box::new(future::err((e, conn)));
+                        }
+                        let order_id = shipment.order_id;
+
+                        Box::new(
+                            repos::shipment::make_repo(login_data)
+                                .update(
+                                    conn,
+                                    ShipmentUpdater {
+                                        mask: ShipmentFilter {
+                                            id: Some(shipment_id.into()),
+                                            ..Default::default()
+                                        },
+                                        data: payload.into(),
+                                    },
+                                )
+                                .and_then(move |(mut shipments, conn)| match shipments.pop() {
+                                    Some(shipment) => Ok((shipment, conn)),
+                                    None => Err((format_err!("Shipment {} not found", shipment_id), conn)),
+                                })
+                                .and_then(move |(shipment, conn)| {
+                                    ensure_quantity_shipped(conn, order_id).map(move |((), conn)| (shipment, conn))
+                                })
+                                .and_then(move |(shipment, conn)| {
+                                    select_order_delivered(conn, order_id)
+                                        .map(move |(order_delivered, conn)| (shipment, order_delivered, conn))
+                                })
+                                // The order is delivered in the same transaction, so the shipment is not delivered without it
+                                .and_then(move |(shipment, order_delivered, conn)| -> RepoConnectionFuture<Option<Shipment>> {
+                                    if !order_delivered {
+                                        return Box::new(future::ok((Some(shipment), conn)));
+                                    }
+
+                                    info!("Last shipment of order {} is delivered", order_id);
+                                    Box::new(
+                                        order_service
+                                            .set_order_state_in_transaction(
+                                                conn,
+                                                order_id,
+                                                OrderState::Delivered,
+                                                None,
+                                                CommitterRole::System,
+                                                OrderUpdatePrecondition::with_expected_state(OrderState::Sent),
+                                            )
+                                            .map(move |(_, conn)| (Some(shipment), conn)),
+                                    )
+                                }),
+                        )
+                    })
+            })
+        }))
+    }
+}
+
+/// Shipments are sent by the seller of the order, superadmins may send them on their behalf
+fn ensure_may_ship(login_data: &UserLogin, store: StoreId) -> Result<(), FailureError> {
+    if let RepoLogin::User { ref caller_roles, .. } = *login_data {
+        if caller_roles.iter().any(|role_entry| match role_entry.role {
+            UserRole::Superadmin => true,
+            UserRole::StoreManager(managed_store) => managed_store == store,
+        }) {
+            return Ok(());
+        }
+    }
+
+    Err(format_err!("Shipments of store {} are sent by its managers only", store)
+        .context(Error::Forbidden)
+        .into())
+}
+
+/// Fails if the shipments of the order hold units of lines that are not active or more units than the lines
+fn ensure_quantity_shipped(conn: RepoConnection, order_id: OrderId) -> RepoConnectionFuture<()> {
+    Box::new(
+        select_lines_and_shipments(conn, order_id).and_then(move |((lines, shipments), conn)| {
+            match check_shipped_quantity(order_id, &lines, &shipments) {
+                Ok(()) => Ok(((), conn)),
+                Err(e) => Err((e.context(Error::ParseError).into(), conn)),
+            }
+        }),
+    )
+}
+
+fn check_shipped_quantity(order_id: OrderId, lines: &[OrderLine], shipments: &[Shipment]) -> Result<(), FailureError> {
+    let ordered = lines.iter().fold(0, |sum, line| sum + line.quantity.0);
+    let shipped = shipments.iter().fold(0, |sum, shipment| sum + shipment.quantity.0);
+    if shipped > ordered {
+        bail!(
+            "Shipments of order {} hold {} units, only {} are ordered",
+            order_id,
+            shipped,
+            ordered
+        );
+    }
+
+    let shipment_lines = || shipments.iter().flat_map(|shipment| shipment.lines.iter());
+    for shipment_line in shipment_lines() {
+        let line = match lines.iter().find(|line| line.id == shipment_line.order_line_id) {
+            Some(line) => line,
+            None => bail!("Line {} is not an active line of order {}", shipment_line.order_line_id, order_id),
+        };
+        let shipped = shipment_lines()
+            .filter(|other| other.order_line_id == line.id)
+            .fold(0, |sum, other| sum + other.quantity.0);
+        if shipped > line.quantity.0 {
+            bail!(
+                "Shipments of line {} of order {} hold {} units, only {} are ordered",
+                line.id,
+                order_id,
+                shipped,
+                line.quantity.0
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether the order is sent and its shipments hold every unit of its active lines and are delivered
+fn select_order_delivered(conn: RepoConnection, order_id: OrderId) -> RepoConnectionFuture<bool> {
+    Box::new(
+        repos::order::make_su_repo()
+            .select(
+                conn,
+                OrderFilter {
+                    id: Some(order_id.into()),
+                    state: Some(OrderState::Sent.into()),
+                    ..Default::default()
+                },
+            )
+            .and_then(move |(orders, conn)| {
+                select_lines_and_shipments(conn, order_id)
+                    .map(move |((lines, shipments), conn)| (!orders.is_empty() && all_units_delivered(&lines, &shipments), conn))
+            }),
+    )
+}
+
+/// Active lines of the order along with its shipments
+fn select_lines_and_shipments(conn: RepoConnection, order_id: OrderId) -> RepoConnectionFuture<(Vec<OrderLine>, Vec<Shipment>)> {
+    Box::new(
+        repos::order_line::make_su_repo()
+            .select(conn, OrderLineFilter::active(order_id))
+            .and_then(move |(lines, conn)| {
+                repos::shipment::make_su_repo()
+                    .select(
+                        conn,
+                        ShipmentFilter {
+                            order_id: Some(order_id.into()),
+                            ..Default::default()
+                        },
+                    )
+                    .map(move |(shipments, conn)| ((lines, shipments), conn))
+            }),
+    )
+}
+
+/// Fails with `Error::ForbiddenStateTransition` unless every shipment of the order is delivered.
+/// Orders without shipments are tracked by their single track id and pass.
+pub fn ensure_shipments_delivered(conn: RepoConnection, order_id: OrderId) -> RepoConnectionFuture<()> {
+    Box::new(
+        repos::shipment::make_su_repo()
+            .select(
+                conn,
+                ShipmentFilter {
+                    order_id: Some(order_id.into()),
+                    status: Some(ShipmentStatus::InTransit.into()),
+                    ..Default::default()
+                },
+            )
+            .and_then(move |(shipments, conn)| {
+                if shipments.is_empty() {
+                    Ok(((), conn))
+                } else {
+                    Err((
+                        format_err!("Order {} has {} shipments in transit", order_id, shipments.len())
+                            .context(Error::ForbiddenStateTransition)
+                            .into(),
+                        conn,
+                    ))
+                }
+            }),
+    )
+}
@@ -0,0 +1,127 @@
+use futures::future;
+use futures::prelude::*;
+
+use super::ensure_superadmin;
+use super::types::ServiceFuture;
+use errors::*;
+use models::*;
+use repos;
+use repos::*;
+use types::*;
+
+use stq_db::repo::*;
+use stq_types::ProductId;
+
+const SUPERADMINS_ONLY: &str = "Taxes are managed by superadmins only";
+
+/// Service that manages tax rules and tax categories of products
+pub trait TaxService {
+    /// Returns all tax rules
+    fn get_rules(&self) -> ServiceFuture<Vec<TaxRule>>;
+    /// Adds the tax rule, it applies to orders created afterwards
+    fn add_rule(&self, rule: TaxRuleInserter) -> ServiceFuture<TaxRule>;
+    fn delete_rule(&self, rule_id: TaxRuleId) -> ServiceFuture<Option<TaxRule>>;
+    /// Sets the tax category of the product, replacing the previous one
+    fn set_product_category(&self, product_id: ProductId, tax_category: TaxCategory) -> ServiceFuture<ProductTaxCategory>;
+    /// Resets the tax category of the product to the standard one
+    fn delete_product_category(&self, product_id: ProductId) -> ServiceFuture<Option<ProductTaxCategory>>;
+}
+
+pub struct TaxServiceImpl {
+    db_pool: DbPool,
+    login_data: UserLogin,
+}
+
+impl TaxServiceImpl {
+    pub fn new(db_pool: DbPool, login_data: UserLogin) -> Self {
+        Self { db_pool, login_data }
+    }
+}
+
+impl TaxService for TaxServiceImpl {
+    fn get_rules(&self) -> ServiceFuture<Vec<TaxRule>> {
+        if let Err(e) = ensure_superadmin(&self.login_data, SUPERADMINS_ONLY) {
+            return Box::new(future::err(e));
+        }
+
+        Box::new(
+            self.db_pool
+                .run(|conn| repos::tax_rule::make_su_repo().select(conn, Default::default())),
+        )
+    }
+
+    fn add_rule(&self, rule: TaxRuleInserter) -> ServiceFuture<TaxRule> {
+        if let Err(e) = ensure_superadmin(&self.login_data, SUPERADMINS_ONLY) {
+            return Box::new(future::err(e));
+        }
+
+        if !rule.rate.is_finite() || rule.rate < 0.0 {
+            return Box::new(future::err(
+                format_err!("Tax rate has to be a non-negative share of 1, got {}", rule.rate)
+                    .context(Error::ParseError)
+                    .into(),
+            ));
+        }
+
+        Box::new(
+            self.db_pool
+                .run(move |conn| repos::tax_rule::make_su_repo().insert_exactly_one(conn, rule)),
+        )
+    }
+
+    fn delete_rule(&self, rule_id: TaxRuleId) -> ServiceFuture<Option<TaxRule>> {
+        if let Err(e) = ensure_superadmin(&self.login_data, SUPERADMINS_ONLY) {
+            return Box::new(future::err(e));
+        }
+
+        Box::new(self.db_pool.run(move |conn| {
+            repos::tax_rule::make_su_repo()
+                .delete(conn, TaxRuleFilter { id: Some(rule_id.into()) })
+                .map(|(mut rules, conn)| (rules.pop(), conn))
+        }))
+    }
+
+    fn set_product_category(&self, product_id: ProductId, tax_category: TaxCategory) -> ServiceFuture<ProductTaxCategory> {
+        if let Err(e) = ensure_superadmin(&self.login_data, SUPERADMINS_ONLY) {
+            return Box::new(future::err(e));
+        }
+
+        Box::new(self.db_pool.run(move |conn| {
+            run_in_transaction(conn, move |conn| {
+                repos::product_tax_category::make_su_repo()
+                    .delete(
+                        conn,
+                        ProductTaxCategoryFilter {
+                            product: Some(product_id.into()),
+                        },
+                    )
+                    .and_then(move |(_, conn)| {
+                        repos::product_tax_category::make_su_repo().insert_exactly_one(
+                            conn,
+                            ProductTaxCategory {
+                                product: product_id,
+                                tax_category,
+                            },
+                        )
+                    })
+            })
+        }))
+    }
+
+    fn delete_product_category(&self, product_id: ProductId) -> ServiceFuture<Option<ProductTaxCategory>> {
+        if let Err(e) = ensure_superadmin(&self.login_data, SUPERADMINS_ONLY) {
+            return Box::new(future::err(e));
+        }
+
+        Box::new(self.db_pool.run(move |conn| {
+            repos::product_tax_category::make_su_repo()
+                .delete(
+                    conn,
+                    ProductTaxCategoryFilter {
+                        product: Some(product_id.into()),
+                    },
+                )
+                .map(|(mut categories, conn)| (categories.pop(), conn))
+        }))
+    }
+}
@@ -0,0 +1,555 @@
+use chrono::prelude::*;
+use failure::Error as FailureError;
+use futures::future;
+use futures::prelude::*;
+use hex;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::rc::Rc;
+
+use super::calling_user;
+use super::order::{write_order_event, OrderService, OrderServiceImpl};
+use super::shipment::{ShipmentService, ShipmentServiceImpl};
+use super::types::ServiceFuture;
+use config::{route_carrier, CarrierRoute};
+use errors::*;
+use models::*;
+use repos;
+use repos::*;
+use types::*;
+
+use stq_api::orders::Order;
+use stq_db::repo::*;
+use stq_static_resources::{CommitterRole, OrderState};
+use stq_types::{OrderId, OrderIdentifier, UserId};
+
+/// Hex encoded HMAC-SHA256 of the pushed body made with the carrier secret from `sent_orders.push_secrets`, prefixed with `sha256=`
+pub const PUSH_SIGNATURE_HEADER: &str = "X-Carrier-Signature";
+const PUSH_SIGNATURE_PREFIX: &str = "sha256=";
+
+/// Service that keeps the tracking timeline of orders
+pub trait TrackingService {
+    /// Records activities the carrier reported for the parcel, returns the ones that were not recorded before
+    fn add_tracking_events(&self, parcel: TrackedParcel, activities: Vec<TrackingActivity>) -> ServiceFuture<Vec<TrackingEvent>>;
+    /// Returns tracking events of the order and its shipments in the order they happened
+    fn get_tracking(&self, order_id: OrderId) -> ServiceFuture<Vec<TrackingEvent>>;
+    /// Records the delivery state the carrier reported for the sent order and applies the rules of the state
+    /// once it changes, returns whether it changed
+    fn report_delivery_state(&self, order_id: OrderId, state: DeliveryState, rules: Vec<DeliveryRule>) -> ServiceFuture<bool>;
+    /// Marks the delivered parcel, the sent order is delivered along with its last parcel.
+    /// Delivery problems of the parcel are reported for its order. Returns whether the parcel changed.
+    fn apply_delivery_state(&self, parcel: TrackedParcel, state: DeliveryState, rules: Vec<DeliveryRule>) -> ServiceFuture<bool>;
+    /// Applies the carrier push to sent orders and undelivered shipments with its carrier and tracking number,
+    /// orders are routed to carriers with `routes`. Returns orders of the parcels it was applied to
+    fn push_tracking(&self, push: CarrierPush, rules: Vec<DeliveryRule>, routes: Vec<CarrierRoute>) -> ServiceFuture<Vec<OrderId>>;
+    /// Escalates the order staying in sent state since `state_since` past the tracking window once,
+    /// returns whether it was escalated
+    fn escalate_stale_order(&self, order_id: OrderId, state_since: DateTime<Utc>) -> ServiceFuture<bool>;
+}
+
+pub struct TrackingServiceImpl {
+    db_pool: DbPool,
+    login_data: UserLogin,
+}
+
+impl TrackingServiceImpl {
+    pub fn new(db_pool: DbPool, login_data: UserLogin) -> Self {
+        Self { db_pool, login_data }
+    }
+}
+
+impl TrackingService for TrackingServiceImpl {
+    fn add_tracking_events(&self, parcel: TrackedParcel, activities: Vec<TrackingActivity>) -> ServiceFuture<Vec<TrackingEvent>> {
+        if activities.is_empty() {
+            return Box::new(future::ok(vec![]));
+        }
+
+        let login_data = self.login_data.clone();
+
+        Box::new(self.db_pool.run(move |conn| {
+            run_in_transaction(conn, move |conn| {
+                let mut out: RepoConnectionFuture<Vec<TrackingEvent>> = Box::new(future::ok((vec![], conn)));
+
+                for activity in activities {
+                    out = Box::new(out.and_then({
+                        let login_data = login_data.clone();
+                        let parcel = parcel.clone();
+                        move |(mut events, conn)| {
+                            repos::tracking_event::make_repo(login_data)
+                                .insert(
+                                    conn,
+                                    TrackingEventInserter {
+                                        id: TrackingEventId::new(),
+                                        parcel,
+                                        activity,
+                                    },
+                                )
+                                .map(move |(inserted, conn)| {
+                                    events.extend(inserted);
+                                    (events, conn)
+                                })
+                        }
+                    }));
+                }
+
+                out
+            })
+        }))
+    }
+
+    fn get_tracking(&self, order_id: OrderId) -> ServiceFuture<Vec<TrackingEvent>> {
+        let login_data = self.login_data.clone();
+
+        Box::new(self.db_pool.run(move |conn| {
+            // Timeline is visible to whoever can see the order, even if nothing is tracked yet
+            repos::order::make_repo(login_data.clone())
+                .select(
+                    conn,
+                    OrderFilter {
+                        id: Some(order_id.into()),
+                        ..Default::default()
+                    },
+                )
+                .and_then(move |(_, conn)| {
+                    repos::tracking_event::make_repo(login_data).select(
+                        conn,
+                        TrackingEventFilter {
+                            order_id: Some(order_id.into()),
+                            ..Default::default()
+                        }
+                        .with_ordering(true),
+                    )
+                })
+        }))
+    }
+
+    fn report_delivery_state(&self, order_id: OrderId, state: DeliveryState, rules: Vec<DeliveryRule>) -> ServiceFuture<bool> {
+        let order_service = OrderServiceImpl::new(self.db_pool.clone(), self.login_data.clone());
+        let calling_user = calling_user(&self.login_data);
+
+        // Rules are applied along with the state, so a failed rule leaves the state unreported and it is retried on the next report
+        Box::new(self.db_pool.run(move |conn| {
+            run_in_transaction(conn, move |conn| {
+                select_order(conn, order_id).and_then(move |(order, conn)| -> RepoConnectionFuture<bool> {
+                    let reported_before = match order {
+                        Some(DbOrder(_, meta)) => meta.delivery_state == Some(state),
+                        None => return Box::new(future::ok((false, conn))),
+                    };
+
+                    if reported_before {
+                        return Box::new(future::ok((false, conn)));
+                    }
+
+                    let mut out: RepoConnectionFuture<()> = Box::new(
+                        repos::order::make_su_repo()
+                            .update(
+                                conn,
+                                OrderUpdater {
+                                    mask: OrderFilter {
+                                        id: Some(order_id.into()),
+                                        ..Default::default()
+                                    },
+                                    data: OrderUpdateData {
+                                        delivery_state: Some(state),
+                                        ..Default::default()
+                                    },
+                                },
+                            )
+                            .map(|(_, conn)| ((), conn)),
+                    );
+
+                    if state.is_problem() {
+                        info!("Carrier reported order {} as {}", order_id, state);
+
+                        let order_service = Rc::new(order_service);
+                        for rule in rules.into_iter().filter(|rule| rule.delivery_state == state) {
+                            let order_service = order_service.clone();
+                            out = Box::new(
+                                out.and_then(move |((), conn)| apply_delivery_rule(conn, &order_service, order_id, rule, calling_user)),
+                            );
+                        }
+                    }
+
+                    Box::new(out.map(|((), conn)| (true, conn)))
+                })
+            })
+        }))
+    }
+
+    fn apply_delivery_state(&self, parcel: TrackedParcel, state: DeliveryState, rules: Vec<DeliveryRule>) -> ServiceFuture<bool> {
+        match (parcel.shipment_id, state) {
+            (None, DeliveryState::Delivered) => {
+                info!("Change order {} with state {}", parcel.order_id, state);
+                Box::new(
+                    OrderServiceImpl::new(self.db_pool.clone(), self.login_data.clone())
+                        .set_order_state(
+                            OrderIdentifier::Id(parcel.order_id),
+                            OrderState::Delivered,
+                            None,
+                            None,
+                            CommitterRole::System,
+                            OrderUpdatePrecondition::with_expected_state(OrderState::Sent),
+                        )
+                        .map(|order| order.is_some()),
+                )
+            }
+            (None, state) => self.report_delivery_state(parcel.order_id, state, rules),
+            (Some(shipment_id), DeliveryState::Delivered) => {
+                info!("Change shipment {} of order {} with state {}", shipment_id, parcel.order_id, state);
+                Box::new(
+                    ShipmentServiceImpl::new(self.db_pool.clone(), self.login_data.clone())
+                        .update_shipment(
+                            shipment_id,
+                            ShipmentUpdatePayload {
+                                status: Some(ShipmentStatus::Delivered),
+                                ..Default::default()
+                            },
+                        )
+                        .map(|shipment| shipment.is_some()),
+                )
+            }
+            // Other shipments of the order may still be on their way, so only problems are reported
+            (Some(_), state) if state.is_problem() => Box::new(self.report_delivery_state(parcel.order_id, state, rules).map(|_| false)),
+            (Some(_), _) => Box::new(future::ok(false)),
+        }
+    }
+
+    fn push_tracking(&self, push: CarrierPush, rules: Vec<DeliveryRule>, routes: Vec<CarrierRoute>) -> ServiceFuture<Vec<OrderId>> {
+        let db_pool = self.db_pool.clone();
+        let login_data = self.login_data.clone();
+        let CarrierPush {
+            carrier,
+            tracking_number,
+            state,
+            activities,
+        } = push;
+
+        let parcels = self
+            .db_pool
+            .run(move |conn| select_pushed_parcels(conn, carrier, tracking_number, routes));
+
+        Box::new(parcels.and_then(move |parcels| {
+            let mut out: ServiceFuture<Vec<OrderId>> = Box::new(future::ok(vec![]));
+
+            for parcel in parcels {
+                out = Box::new(out.and_then({
+                    let service = TrackingServiceImpl::new(db_pool.clone(), login_data.clone());
+                    let activities = activities.clone();
+                    let rules = rules.clone();
+                    move |mut order_ids| {
+                        let order_id = parcel.order_id;
+                        service
+                            .add_tracking_events(parcel.clone(), activities)
+                            .and_then(move |_| service.apply_delivery_state(parcel, state, rules))
+                            .map(move |_| {
+                                order_ids.push(order_id);
+                                order_ids
+                            })
+                    }
+                }));
+            }
+
+            out
+        }))
+    }
+
+    fn escalate_stale_order(&self, order_id: OrderId, state_since: DateTime<Utc>) -> ServiceFuture<bool> {
+        let calling_user = calling_user(&self.login_data);
+
+        Box::new(
+            self.db_pool
+                .run(move |conn| run_in_transaction(conn, move |conn| escalate_order(conn, order_id, state_since, calling_user))),
+        )
+    }
+}
+
+/// Undelivered shipments of the carrier with the tracking number, and sent orders routed to the carrier with it as their track id
+/// unless they are shipped in boxes. Tracking numbers are unique per carrier only.
+fn select_pushed_parcels(
+    conn: RepoConnection,
+    carrier: String,
+    tracking_number: String,
+    routes: Vec<CarrierRoute>,
+) -> RepoConnectionFuture<Vec<TrackedParcel>> {
+    Box::new(
+        repos::shipment::make_su_repo()
+            .select(
+                conn,
+                ShipmentFilter {
+                    tracking_number: Some(tracking_number.clone().into()),
+                    ..Default::default()
+                },
+            )
+            .and_then({
+                let tracking_number = tracking_number.clone();
+                move |(shipments, conn)| {
+                    repos::order::make_su_repo()
+                        .select(
+                            conn,
+                            OrderFilter {
+                                state: Some(OrderState::Sent.into()),
+                                track_id: Some(Some(tracking_number).into()),
+                                ..Default::default()
+                            },
+                        )
+                        .map(move |(orders, conn)| ((shipments, orders), conn))
+                }
+            })
+            .and_then(move |((shipments, orders), conn)| {
+                let parcels = shipments
+                    .into_iter()
+                    .filter(|shipment| shipment.status != ShipmentStatus::Delivered && shipment.carrier.eq_ignore_ascii_case(&carrier))
+                    .map(|shipment| TrackedParcel {
+                        order_id: shipment.order_id,
+                        shipment_id: Some(shipment.id),
+                        customer: shipment.customer,
+                        store: shipment.store,
+                        carrier: shipment.carrier,
+                        tracking_number: shipment.tracking_number,
+                    })
+                    .collect::<Vec<_>>();
+                let mut out: RepoConnectionFuture<Vec<TrackedParcel>> = Box::new(future::ok((parcels, conn)));
+
+                let orders = orders.into_iter().filter(|&DbOrder(ref order, _)| {
+                    route_carrier(
+                        &routes,
+                        order.delivery_company.as_ref().map(|company| company.as_str()),
+                        order.company_package_id,
+                    )
+                    .eq_ignore_ascii_case(&carrier)
+                });
+                for DbOrder(order, _) in orders {
+                    out = Box::new(out.and_then({
+                        let carrier = carrier.clone();
+                        let tracking_number = tracking_number.clone();
+                        move |(mut parcels, conn)| {
+                            repos::shipment::make_su_repo()
+                                .select(
+                                    conn,
+                                    ShipmentFilter {
+                                        order_id: Some(order.id.into()),
+                                        ..Default::default()
+                                    },
+                                )
+                                .map(move |(shipments, conn)| {
+                                    if shipments.is_empty() {
+                                        parcels.push(TrackedParcel {
+                                            order_id: order.id,
+                                            shipment_id: None,
+                                            customer: order.customer,
+                                            store: order.store,
+                                            carrier,
+                                            tracking_number,
+                                        });
+                                    }
+                                    (parcels, conn)
+                                })
+                        }
+                    }));
+                }
+
+                out
+            }),
+    )
+}
+
+/// Checks the signature of the pushed body, the header value is expected as `sha256=<hex encoded HMAC-SHA256>`
+pub fn verify_push_signature(secret: &str, body: &str, signature: &str) -> Result<(), FailureError> {
+    let signature = signature.trim();
+    if !signature.starts_with(PUSH_SIGNATURE_PREFIX) {
+        bail!("Signature has to be prefixed with {}", PUSH_SIGNATURE_PREFIX);
+    }
+    let signature = hex::decode(&signature[PUSH_SIGNATURE_PREFIX.len()..]).map_err(|_| format_err!("Signature is not hex encoded"))?;
+
+    let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes()).map_err(|_| format_err!("Invalid push secret"))?;
+    mac.input(body.as_bytes());
+    mac.verify(&signature).map_err(|_| format_err!("Signature does not match the body"))
+}
+
+fn apply_delivery_rule(
+    conn: RepoConnection,
+    order_service: &OrderServiceImpl,
+    order_id: OrderId,
+    rule: DeliveryRule,
+    calling_user: UserId,
+) -> RepoConnectionFuture<()> {
+    let comment = delivery_issue_comment(rule.delivery_state);
+
+    match (rule.action, rule.order_state) {
+        (DeliveryIssueAction::SetState, Some(order_state)) => Box::new(
+            order_service
+                .set_order_state_in_transaction(
+                    conn,
+                    order_id,
+                    order_state,
+                    Some(comment),
+                    CommitterRole::System,
+                    OrderUpdatePrecondition::with_expected_state(OrderState::Sent),
+                )
+                .map(|(_, conn)| ((), conn)),
+        ),
+        (DeliveryIssueAction::SetState, None) => Box::new(future::err((
+            format_err!("Order state is required by the set_state action")
+                .context(Error::ParseError)
+                .into(),
+            conn,
+        ))),
+        (DeliveryIssueAction::Flag, _) => flag_order(conn, order_id, comment, calling_user),
+        (DeliveryIssueAction::Notify, _) => write_delivery_issue_event(conn, order_id),
+    }
+}
+
+/// Selects the order with its metadata, bypassing the ACL
+fn select_order(conn: RepoConnection, order_id: OrderId) -> RepoConnectionFuture<Option<DbOrder>> {
+    Box::new(
+        repos::order::make_su_repo()
+            .select(
+                conn,
+                OrderFilter {
+                    id: Some(order_id.into()),
+                    ..Default::default()
+                },
+            )
+            .map(|(mut orders, conn)| (orders.pop(), conn)),
+    )
+}
+
+/// Sets the attention reason of the sent order and records it in the order history
+fn flag_order(conn: RepoConnection, order_id: OrderId, reason: String, committer: UserId) -> RepoConnectionFuture<()> {
+    Box::new(
+        select_order(conn, order_id).and_then(move |(order, conn)| -> RepoConnectionFuture<()> {
+            let order = match order {
+                Some(order) => order,
+                None => return Box::new(future::ok(((), conn))),
+            };
+
+            let data = OrderUpdateData {
+                attention_reason: Some(Some(reason.clone())),
+                ..Default::default()
+            };
+            Box::new(update_with_comment(conn, order, data, reason, committer).map(|(_, conn)| ((), conn)))
+        }),
+    )
+}
+
+/// Flags the sent order staying in sent state past the tracking window and notifies about it with the `stale` order event.
+/// Orders are escalated once per stay in sent state, returns whether the order was escalated.
+fn escalate_order(conn: RepoConnection, order_id: OrderId, state_since: DateTime<Utc>, committer: UserId) -> RepoConnectionFuture<bool> {
+    Box::new(
+        select_order(conn, order_id).and_then(move |(order, conn)| -> RepoConnectionFuture<bool> {
+            let order = match order {
+                Some(order) => order,
+                None => return Box::new(future::ok((false, conn))),
+            };
+            let escalated_before = order
+                .1
+                .escalated_at
+                .map(|escalated_at| escalated_at >= state_since)
+                .unwrap_or(false);
+            if order.0.state != OrderState::Sent || escalated_before {
+                return Box::new(future::ok((false, conn)));
+            }
+
+            let reason = format!(
+                "Order is in sent state since {}, its carrier is not tracked anymore",
+                state_since.format("%Y-%m-%d")
+            );
+            let data = OrderUpdateData {
+                attention_reason: Some(Some(reason.clone())),
+                escalated_at: Some(Utc::now()),
+                ..Default::default()
+            };
+            Box::new(
+                update_with_comment(conn, order, data, reason, committer)
+                    .and_then(|(order, conn)| write_order_event(conn, su_order_event_repo_factory(), OrderEventType::Stale, &order.0))
+                    .map(|((), conn)| (true, conn)),
+            )
+        }),
+    )
+}
+
+/// Updates the order keeping its state and records the comment in the order history
+fn update_with_comment(
+    conn: RepoConnection,
+    order: DbOrder,
+    data: OrderUpdateData,
+    comment: String,
+    committer: UserId,
+) -> RepoConnectionFuture<DbOrder> {
+    let DbOrder(order, meta) = order;
+    let order_id = order.id;
+    let current_version = meta.version;
+
+    Box::new(
+        repos::order::make_su_repo()
+            .update(
+                conn,
+                OrderUpdater {
+                    mask: OrderFilter {
+                        id: Some(order_id.into()),
+                        version: Some(current_version.into()),
+                        ..Default::default()
+                    },
+                    data,
+                },
+            )
+            .and_then(move |(mut updated_orders, conn)| match updated_orders.pop() {
+                Some(updated_order) => Ok((updated_order, conn)),
+                None => Err((
+                    format_err!("Order {} was modified concurrently, version {} is stale", order_id, current_version)
+                        .context(Error::OrderConflict)
+                        .into(),
+                    conn,
+                )),
+            })
+            .and_then(move |(updated_order, conn)| {
+                repos::order_diff::make_su_repo()
+                    .insert_exactly_one(
+                        conn,
+                        OrderDiffInserter {
+                            parent: order_id,
+                            committer,
+                            committed_at: Utc::now(),
+                            state: order.state,
+                            comment: Some(comment),
+                            committer_role: CommitterRole::System,
+                        },
+                    )
+                    .map(move |(_, conn)| (updated_order, conn))
+            }),
+    )
+}
+
+fn write_delivery_issue_event(conn: RepoConnection, order_id: OrderId) -> RepoConnectionFuture<()> {
+    Box::new(
+        select_order(conn, order_id).and_then(move |(order, conn)| -> RepoConnectionFuture<()> {
+            match order {
+                Some(order) => write_order_event(conn, su_order_event_repo_factory(), OrderEventType::DeliveryIssue, &order.0),
+                None => Box::new(future::ok(((), conn))),
+            }
+        }),
+    )
+}
+
+fn su_order_event_repo_factory() -> Rc<Fn() -> Box<OrderEventRepo>> {
+    Rc::new(|| Box::new(repos::order_event::make_su_repo()) as Box<OrderEventRepo>)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifies_push_signature() {
+        let body = r#"{"carrier":"dhl","tracking_number":"JD014600003828","state":"delivered"}"#;
+        let mut mac = Hmac::<Sha256>::new_varkey(b"secret").unwrap();
+        mac.input(body.as_bytes());
+        let signature = format!("sha256={}", hex::encode(mac.result().code()));
+
+        assert!(verify_push_signature("secret", body, &signature).is_ok());
+        assert!(verify_push_signature("other secret", body, &signature).is_err());
+        assert!(verify_push_signature("secret", &body.replace("delivered", "exception"), &signature).is_err());
+        assert!(verify_push_signature("secret", body, &signature["sha256=".len()..]).is_err());
+        assert!(verify_push_signature("secret", body, "sha256=not hex").is_err());
+    }
+}
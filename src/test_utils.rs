@@ -0,0 +1,67 @@
+//! Helpers shared by database backed unit tests
+
+use bb8;
+use bb8_postgres::PostgresConnectionManager;
+use futures::prelude::*;
+use tokio_core::reactor::Core;
+use tokio_postgres::TlsMode;
+use uuid::Uuid;
+
+use stq_api::orders::*;
+use stq_static_resources::{Currency, CurrencyType, OrderState};
+use stq_types::*;
+
+use config::Config;
+use models::*;
+use services;
+use types::DbPool;
+
+pub fn create_db_pool(core: &mut Core) -> DbPool {
+    let config = Config::new().expect("Can't load app config!");
+    let manager = PostgresConnectionManager::new(config.db.dsn.clone(), || TlsMode::None).unwrap();
+    let remote = core.remote();
+    DbPool::from(
+        core.run(bb8::Pool::builder().build(manager, remote).map_err(|e| format_err!("{}", e)))
+            .expect("Failed to create connection pool"),
+    )
+}
+
+pub fn super_user() -> UserLogin {
+    services::system_login()
+}
+
+pub fn order_fixture() -> OrderInserter {
+    OrderInserter {
+        id: None,
+        created_from: None,
+        conversion_id: None,
+        customer: UserId(7234213),
+        store: StoreId(1001),
+        product: ProductId(634825),
+        price: Money::from_f64(100.0),
+        currency: Currency::RUB,
+        quantity: Quantity(1),
+        address: AddressFull::default(),
+        receiver_name: "Mr. Anderson".to_string(),
+        receiver_phone: "+14441234567".to_string(),
+        receiver_email: "arch@itect.com".to_string(),
+        delivery_company: None,
+        state: OrderState::New,
+        track_id: None,
+        pre_order: false,
+        pre_order_days: 0,
+        coupon_id: None,
+        coupon_percent: None,
+        coupon_discount: None,
+        product_discount: None,
+        total_amount: Money::from_f64(100.0),
+        company_package_id: None,
+        delivery_price: Money::zero(),
+        delivery_pricing: DeliveryPricing::PerUnit,
+        tax_amount: Money::zero(),
+        shipping_id: None,
+        product_cashback: None,
+        uuid: Uuid::new_v4(),
+        currency_type: CurrencyType::Fiat,
+    }
+}
@@ -14,6 +14,8 @@ pub mod common;
 
 use futures::{future, prelude::*};
 use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::TcpStream;
 use stq_api::orders::*;
 use stq_api::rpc_client::*;
 use stq_static_resources::{Currency, CurrencyType, OrderState};
@@ -102,6 +104,36 @@ impl RpcClient {
     }
 }
 
+/// Sends the request over a plain connection, so its status code can be checked. Returns the code along with the body
+fn send_request(base_url: &str, method: &str, path: &str, caller: Option<UserId>, headers: &[(&str, &str)], body: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(&base_url["http://".len()..]).unwrap();
+
+    let mut request = format!("{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n", method, path);
+    if let Some(caller) = caller {
+        request.push_str(&format!("Authorization: {}\r\n", caller.0));
+    }
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str(&format!(
+        "Content-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+        body.len(),
+        body
+    ));
+    stream.write_all(request.as_bytes()).unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let status = response
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .expect("Response has no status code");
+    let body = response.splitn(2, "\r\n\r\n").nth(1).unwrap_or_default().to_string();
+
+    (status, body)
+}
+
 #[test]
 fn test_services() {
     let base_url = common::setup();
@@ -450,3 +482,100 @@ fn test_services() {
         }
     }));
 }
+
+#[test]
+fn test_tracking_and_refund_requests() {
+    let base_url = common::setup();
+
+    tokio::run(future::ok(()).map(move |_| {
+        let customer = UserId(7234214);
+        let stranger = UserId(7234215);
+        let superadmin = UserId(1);
+        let product_id = ProductId(634827);
+
+        let rpc = RpcClient::new(&base_url, customer);
+        let su_rpc = RpcClient::new(&base_url, superadmin);
+
+        rpc.inner.clear_cart(customer.into()).wait().unwrap();
+        rpc.set_cart_items(hashset![CartItem {
+            id: CartItemId::new(),
+            customer: customer.into(),
+            product_id,
+            quantity: Quantity(2),
+            selected: true,
+            comment: String::new(),
+            store_id: StoreId(1001),
+            pre_order: false,
+            pre_order_days: 0,
+            coupon_id: None,
+            delivery_method_id: None,
+            currency_type: CurrencyType::Fiat,
+            user_country_code: None,
+        }]);
+
+        let conversion_id = ConversionId::new();
+        let orders = rpc
+            .inner
+            .convert_cart(
+                Some(conversion_id),
+                customer,
+                hashmap! {
+                    product_id => ProductSellerPrice {
+                        price: ProductPrice(100.0),
+                        currency: Currency::RUB,
+                        discount: None,
+                    },
+                },
+                AddressFull::default(),
+                "Mr. Anderson".to_string(),
+                "+14441234567".to_string(),
+                "arch@itect.com".to_string(),
+                HashMap::new(),
+                HashMap::new(),
+                HashMap::new(),
+                uuid::Uuid::new_v4(),
+                Some(CurrencyType::Fiat),
+            )
+            .wait()
+            .unwrap();
+        let order_id = orders[0].id;
+
+        // Tracking timeline is visible to the customer and the support only
+        let tracking_path = format!("/orders/by-id/{}/tracking", order_id);
+        let get_tracking = |caller| send_request(&base_url, "GET", &tracking_path, caller, &[], "");
+        let (status, body) = get_tracking(Some(customer));
+        assert_eq!(status, 200);
+        assert!(serde_json::from_str::<Vec<serde_json::Value>>(&body).unwrap().is_empty());
+        assert_eq!(get_tracking(Some(superadmin)).0, 200);
+        assert_eq!(get_tracking(Some(stranger)).0, 403);
+        assert_eq!(get_tracking(None).0, 403);
+
+        // Refunds are visible to the customer, but made by the seller or the support only
+        let refunds_path = format!("/orders/by-id/{}/refunds", order_id);
+        let get_refunds = |caller| send_request(&base_url, "GET", &refunds_path, caller, &[], "");
+        assert_eq!(get_refunds(Some(customer)).0, 200);
+        assert_eq!(get_refunds(Some(superadmin)).0, 200);
+        assert_eq!(get_refunds(Some(stranger)).0, 403);
+
+        let refund = r#"{"amount": 10.0, "reason": "Damaged on delivery"}"#;
+        let create_refund = |caller, body| send_request(&base_url, "POST", &refunds_path, caller, &[], body);
+        assert_eq!(create_refund(Some(customer), refund).0, 403);
+        assert_eq!(create_refund(Some(stranger), refund).0, 403);
+        assert_eq!(
+            create_refund(Some(superadmin), r#"{"amount": 0.0, "reason": "Damaged on delivery"}"#).0,
+            422
+        );
+        // The order is not paid yet
+        assert_eq!(create_refund(Some(superadmin), refund).0, 409);
+
+        // Carrier pushes are authorized by their signature only
+        let push = r#"{"carrier": "dhl", "tracking_number": "JD014600003828", "state": "delivered"}"#;
+        let push_tracking = |caller, headers: &[(&str, &str)]| send_request(&base_url, "POST", "/carrier_tracking", caller, headers, push);
+        assert_eq!(push_tracking(None, &[]).0, 403);
+        assert_eq!(push_tracking(Some(superadmin), &[]).0, 403);
+        assert_eq!(push_tracking(None, &[("X-Carrier-Signature", "sha256=00")]).0, 403);
+
+        su_rpc.inner.revert_cart_conversion(conversion_id).wait().unwrap();
+        rpc.inner.clear_cart(customer.into()).wait().unwrap();
+    }));
+}